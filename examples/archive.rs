@@ -0,0 +1,38 @@
+//! Convert color tables to and from the compact archive format.
+//!
+//! ```text
+//! cargo run --example archive -- pack <table dir> <archive>
+//! cargo run --example archive -- unpack <archive> <table dir>
+//! ```
+
+use color_table::{ColorTable, ColorTableConfig};
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let [command, from, to] = args.as_slice() else {
+        eprintln!("usage: archive (pack <table dir> <archive> | unpack <archive> <table dir>)");
+        std::process::exit(2);
+    };
+
+    let config = ColorTableConfig::default();
+    match command.as_str() {
+        "pack" => {
+            let ct = ColorTable::load(from, config).expect("failed to load color table");
+            let table_len = ct.file_len().expect("failed to read color table");
+            let archive_len = ct.write_archive(to).expect("failed to write archive");
+            eprintln!(
+                "packed {table_len} bytes into {archive_len} bytes ({:.1}%)",
+                archive_len as f64 / table_len as f64 * 100.0
+            );
+        }
+        "unpack" => {
+            let ct = ColorTable::from_archive(from, to, config).expect("failed to unpack archive");
+            ct.sync(None).expect("failed to sync color table");
+            eprintln!("unpacked {} fragments", ct.fragment_count());
+        }
+        _ => {
+            eprintln!("unknown command {command:?}");
+            std::process::exit(2);
+        }
+    }
+}
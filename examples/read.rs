@@ -11,7 +11,7 @@ fn main() {
     let now = std::time::Instant::now();
     let (colors, color_ids): (Vec<u32>, Vec<ColorId>) = ct
         .with_generation(0, |ct| {
-            (0..n)
+            (1..=n)
                 .map(|i| (i as u32, ct.new_color_class(i as u32).unwrap()))
                 .unzip()
         })
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+use roaring::RoaringBitmap;
+
+use crate::ColorFragmentIndex;
+
+/// Materialized bitmaps for the chains ending at selected fragments.
+///
+/// A checkpoint at fragment `f` holds the full bitmap of the color class whose head is `f`, so
+/// any class whose chain passes through `f` only needs to decode the fragments newer than `f`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BitmapCheckpoints {
+    bitmaps: HashMap<ColorFragmentIndex, RoaringBitmap>,
+}
+
+impl Encode for BitmapCheckpoints {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let mut entries = self.bitmaps.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(idx, _)| **idx);
+
+        Encode::encode(&(entries.len() as u64), encoder)?;
+        for (idx, bitmap) in entries {
+            let mut bytes = Vec::with_capacity(bitmap.serialized_size());
+            bitmap
+                .serialize_into(&mut bytes)
+                .map_err(|e| EncodeError::OtherString(e.to_string()))?;
+            Encode::encode(idx, encoder)?;
+            Encode::encode(&bytes, encoder)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<Context> Decode<Context> for BitmapCheckpoints {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let len: u64 = Decode::decode(decoder)?;
+
+        let mut bitmaps = HashMap::new();
+        for _ in 0..len {
+            let idx: ColorFragmentIndex = Decode::decode(decoder)?;
+            let bytes: Vec<u8> = Decode::decode(decoder)?;
+            let bitmap = RoaringBitmap::deserialize_from(bytes.as_slice())
+                .map_err(|e| DecodeError::OtherString(e.to_string()))?;
+            bitmaps.insert(idx, bitmap);
+        }
+
+        Ok(Self { bitmaps })
+    }
+}
+
+impl BitmapCheckpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the materialized bitmap for the chain ending at `idx`, if there is one.
+    #[inline]
+    pub fn get(&self, idx: &ColorFragmentIndex) -> Option<&RoaringBitmap> {
+        self.bitmaps.get(idx)
+    }
+
+    pub fn insert(&mut self, idx: ColorFragmentIndex, bitmap: RoaringBitmap) {
+        self.bitmaps.insert(idx, bitmap);
+    }
+
+    /// Renumber the checkpointed fragments, dropping those for which `f` returns `None`.
+    pub fn renumber(
+        &self,
+        mut f: impl FnMut(&ColorFragmentIndex) -> Option<ColorFragmentIndex>,
+    ) -> Self {
+        let bitmaps = self
+            .bitmaps
+            .iter()
+            .filter_map(|(idx, bitmap)| Some((f(idx)?, bitmap.clone())))
+            .collect();
+
+        Self { bitmaps }
+    }
+
+    /// Replace every bitmap with the result of `f`.
+    pub fn try_map<E>(
+        &self,
+        mut f: impl FnMut(&RoaringBitmap) -> Result<RoaringBitmap, E>,
+    ) -> Result<Self, E> {
+        let bitmaps = self
+            .bitmaps
+            .iter()
+            .map(|(idx, bitmap)| Ok((*idx, f(bitmap)?)))
+            .collect::<Result<_, E>>()?;
+
+        Ok(Self { bitmaps })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bitmaps.is_empty()
+    }
+}
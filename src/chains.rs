@@ -0,0 +1,138 @@
+use crate::{ColorFragment, ColorFragmentIndex};
+
+/// In-memory metadata about the chain of fragments ending at each fragment.
+///
+/// This is derived entirely from the parent pointers in the color table file, so it is not
+/// persisted; it is rebuilt with a single sequential pass when a table is loaded.
+#[derive(Debug, Clone)]
+pub struct Chains {
+    // number of fragments in the chain ending at each fragment (index 0 has depth 0)
+    depths: Vec<u32>,
+    // nearest checkpoint at or below each fragment (empty if checkpoints are disabled)
+    // a fragment is a checkpoint if its depth is a multiple of `interval`
+    checkpoints: Vec<ColorFragmentIndex>,
+    // number of links between checkpoints, or 0 if checkpoints are disabled
+    interval: u32,
+    // whether any removal fragment has been recorded
+    removals: bool,
+}
+
+impl Chains {
+    /// Create chain metadata for an empty table.
+    pub fn new(interval: u32) -> Self {
+        Self::with_capacity(interval, 1)
+    }
+
+    /// Create chain metadata for an empty table, with room for `capacity` fragments (including fragment 0).
+    pub fn with_capacity(interval: u32, capacity: usize) -> Self {
+        let mut depths = Vec::with_capacity(capacity);
+        depths.push(0);
+
+        let mut checkpoints = Vec::new();
+        if interval != 0 {
+            checkpoints.reserve(capacity);
+            checkpoints.push(ColorFragmentIndex(0));
+        }
+
+        Self {
+            depths,
+            checkpoints,
+            interval,
+            removals: false,
+        }
+    }
+
+    /// Record a new fragment with the given parent. Returns the index of the new fragment.
+    ///
+    /// The parent must already be recorded.
+    pub fn push(&mut self, parent: ColorFragmentIndex) -> ColorFragmentIndex {
+        let idx = ColorFragmentIndex(self.depths.len() as u32);
+        let depth = self.depths[parent.0 as usize] + 1;
+        self.depths.push(depth);
+
+        if self.interval != 0 {
+            let checkpoint = if depth.is_multiple_of(self.interval) {
+                idx
+            } else {
+                self.checkpoints[parent.0 as usize]
+            };
+            self.checkpoints.push(checkpoint);
+        }
+
+        idx
+    }
+
+    /// Record a new fragment. Returns the index of the new fragment.
+    ///
+    /// The parent of the fragment must already be recorded.
+    pub fn push_fragment(&mut self, fragment: &ColorFragment) -> ColorFragmentIndex {
+        self.removals |= fragment.is_removal();
+        self.push(fragment.parent())
+    }
+
+    /// Returns `true` if any removal fragment has been recorded. Chains can only be skipped over
+    /// without visiting each fragment if there are none.
+    #[inline]
+    pub fn has_removals(&self) -> bool {
+        self.removals
+    }
+
+    /// Get the number of recorded fragments (including fragment 0).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.depths.len()
+    }
+
+    /// Get the number of fragments in the chain ending at `idx`.
+    #[inline]
+    pub fn depth(&self, idx: &ColorFragmentIndex) -> u32 {
+        self.depths[idx.0 as usize]
+    }
+
+    /// Get the nearest checkpoint in the chain ending at `idx` (possibly `idx` itself).
+    ///
+    /// Returns `None` if checkpoints are disabled. Returns fragment 0 if there is no checkpoint.
+    #[inline]
+    pub fn checkpoint_at_or_below(&self, idx: &ColorFragmentIndex) -> Option<ColorFragmentIndex> {
+        self.checkpoints.get(idx.0 as usize).copied()
+    }
+
+    /// Approximate heap size in bytes.
+    #[cfg(feature = "typesize")]
+    pub fn heap_size(&self) -> usize {
+        self.depths.capacity() * std::mem::size_of::<u32>()
+            + self.checkpoints.capacity() * std::mem::size_of::<ColorFragmentIndex>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoints() {
+        let mut c = Chains::new(3);
+
+        // a single chain 1 <- 2 <- ... <- 7
+        let mut parent = ColorFragmentIndex(0);
+        for _ in 0..7 {
+            parent = c.push(parent);
+        }
+        // a fork of fragment 4 (depth 4)
+        let fork = c.push(ColorFragmentIndex(4));
+
+        assert_eq!(c.depth(&ColorFragmentIndex(7)), 7);
+        assert_eq!(c.depth(&fork), 5);
+
+        let cp = |i| c.checkpoint_at_or_below(&ColorFragmentIndex(i)).unwrap().0;
+        assert_eq!(
+            (1..=8).map(cp).collect::<Vec<_>>(),
+            vec![0, 0, 3, 3, 3, 6, 6, 3]
+        );
+
+        assert_eq!(
+            Chains::new(0).checkpoint_at_or_below(&ColorFragmentIndex(0)),
+            None
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+
+use crate::ColorFragmentIndex;
+
+/// Color classes created by [`GenerationGuard::new_or_existing_color_class`](crate::GenerationGuard::new_or_existing_color_class),
+/// keyed by the hash of their full bitmap.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClassHashes {
+    classes: HashMap<u128, ColorFragmentIndex>,
+}
+
+impl Encode for ClassHashes {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let mut entries = self.classes.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(_, idx)| **idx);
+
+        Encode::encode(&(entries.len() as u64), encoder)?;
+        for (hash, idx) in entries {
+            Encode::encode(hash, encoder)?;
+            Encode::encode(idx, encoder)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<Context> Decode<Context> for ClassHashes {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let len: u64 = Decode::decode(decoder)?;
+
+        let mut classes = HashMap::new();
+        for _ in 0..len {
+            let hash: u128 = Decode::decode(decoder)?;
+            let idx: ColorFragmentIndex = Decode::decode(decoder)?;
+            classes.insert(hash, idx);
+        }
+
+        Ok(Self { classes })
+    }
+}
+
+impl ClassHashes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the class with the given hash, if there is one.
+    #[inline]
+    pub fn get(&self, hash: u128) -> Option<ColorFragmentIndex> {
+        self.classes.get(&hash).copied()
+    }
+
+    pub fn insert(&mut self, hash: u128, idx: ColorFragmentIndex) {
+        self.classes.insert(hash, idx);
+    }
+
+    /// Renumber the classes, dropping those for which `f` returns `None`.
+    pub fn renumber(
+        &self,
+        mut f: impl FnMut(&ColorFragmentIndex) -> Option<ColorFragmentIndex>,
+    ) -> Self {
+        let classes = self
+            .classes
+            .iter()
+            .filter_map(|(hash, idx)| Some((*hash, f(idx)?)))
+            .collect();
+
+        Self { classes }
+    }
+
+    /// Replace the hash of every class with the result of `f`.
+    pub fn rehash(&self, mut f: impl FnMut(&ColorFragmentIndex) -> u128) -> Self {
+        let classes = self.classes.values().map(|idx| (f(idx), *idx)).collect();
+
+        Self { classes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+}
@@ -48,9 +48,11 @@
 //!   Together, they form a colored de Bruijn graph (?).
 
 use std::fs::File;
-use std::io::{self, BufWriter, Read, Write};
-use std::ops::Deref;
+use std::io::{self, Read, Seek, Write};
+use std::ops::{Range, RangeInclusive};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use bincode::{Decode, Encode};
 use bytemuck::{Pod, Zeroable};
@@ -63,16 +65,102 @@ cfg_if::cfg_if! {
     }
 }
 
+#[cfg(feature = "roaring")]
+use crate::bitmap_checkpoints::BitmapCheckpoints;
+use crate::chains::Chains;
+use crate::class_hashes::ClassHashes;
 use crate::generations::Generations;
-use crate::{ColorTableConfig, ColorTableError, Result};
-
-const TABLE_MAGIC: [u8; std::mem::size_of::<ColorFragment>()] = *b"CTBL\0\x00\x00\x01";
+use crate::heads::Heads;
+use crate::payloads::Payloads;
+use crate::running_counts::RunningCounts;
+use crate::sketches::Sketches;
+use crate::tombstones::Tombstones;
+use crate::{
+    ColorMode, ColorTableConfig, ColorTableError, FlushPolicy, GenerationPolicy, PathContext,
+    Result, ZeroColorPolicy,
+};
+
+mod archive;
+#[cfg(feature = "tokio")]
+mod async_api;
+mod backup;
+mod batch;
+mod compaction;
+mod direct;
+mod dot;
+mod fault;
+mod flusher;
+mod format;
+mod id_kind;
+mod import;
+mod lock;
+#[cfg(feature = "roaring")]
+mod lookup;
+mod map_options;
+mod mapping;
+mod matrix;
+mod merge;
+mod metrics;
+#[cfg(feature = "roaring")]
+mod multi;
+mod observer;
+mod payload;
+mod query;
+mod renumber;
+mod repair;
+mod reservation;
+#[cfg(feature = "roaring")]
+mod resolver;
+mod seal;
+mod shard;
+mod shared;
+mod similarity;
+mod status;
+mod storage;
+mod structure;
+mod tsv;
+mod verify;
+mod wait;
+
+use batch::BatchQueue;
+pub use batch::{ColorIdRange, FragmentBatch};
+pub use compaction::CompactionReport;
+use fault::FaultWatch;
+pub use flusher::FlusherHandle;
+use format::Header;
+pub use id_kind::IdKind;
+use lock::{TableLock, lock_shared, truncate_unshared};
+pub use map_options::{AccessPattern, MapOptions};
+pub use mapping::ColorIdMapping;
+pub use matrix::ColorTableBuilder;
+pub use metrics::Metrics;
+use metrics::MetricsSink;
+#[cfg(feature = "roaring")]
+pub use multi::{MultiMmapGuard, MultiTable};
+pub use observer::FragmentObserver;
+use observer::Observers;
+pub use repair::RepairReport;
+use reservation::Pending;
+#[cfg(feature = "roaring")]
+pub use resolver::ColorResolver;
+pub use seal::{SealInfo, SealedColorTable};
+pub use shard::{ShardedColorTable, ShardedMmapGuard};
+pub use shared::ColorTableReader;
+use shared::GenerationLog;
+pub use status::GenerationStatus;
+use storage::{ColorTableMmap, Writer};
+pub use verify::{VerifyIssue, VerifyLevel, VerifyReport};
+use wait::GenerationSignal;
+
+const TABLE_MAGIC: [u8; std::mem::size_of::<ColorFragment>()] = Header::CURRENT.to_bytes();
 
 /// The index of a color fragment in the color table.
 ///
 /// The fragment at index 0 is reserved as the parent of the "tail" fragment in a color class.
 /// Real fragment indexes start at 1.
-#[derive(Clone, Copy, Debug, Zeroable, Pod, Encode, Decode, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(
+    Clone, Copy, Debug, Zeroable, Pod, Encode, Decode, Hash, Ord, PartialOrd, Eq, PartialEq,
+)]
 #[cfg_attr(feature = "typesize", derive(TypeSize))]
 #[repr(transparent)]
 pub struct ColorFragmentIndex(pub u32); // up to 4b fragments/colors
@@ -133,6 +221,15 @@ impl From<&ColorId> for ColorFragmentIndex {
 pub struct ColorId(pub(crate) u32);
 
 impl ColorId {
+    /// The id of the null color class, which is always empty.
+    pub const NULL: Self = Self(0);
+
+    /// Returns `true` if this is the id of the null color class.
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        self.0 == 0
+    }
+
     /// Create a new `ColorId` from the given u32 value.
     #[inline]
     pub fn new(id: u32) -> Self {
@@ -160,89 +257,158 @@ impl From<&ColorFragmentIndex> for ColorId {
     }
 }
 
+// stored little-endian in fragments
+impl From<ColorFragmentIndex> for pack1::U32LE {
+    #[inline]
+    fn from(idx: ColorFragmentIndex) -> Self {
+        idx.0.into()
+    }
+}
+
+/// Flag set in the parent pointer of a removal fragment.
+const REMOVAL_FLAG: u32 = 1 << 31;
+/// Maximum number of fragments in a table, including the header. Parent pointers use their top bit
+/// to mark removal fragments, so fragment indices must stay below it.
+const MAX_FRAGMENTS: u32 = REMOVAL_FLAG;
+
 /// A color fragment in the color table.
 ///
 /// Each fragment in the color table contains a "partial color", representing 64 entries in a bitmap.
+///
+/// A removal fragment (see [`GenerationGuard::extend_color_class_remove`]) instead holds a single
+/// index, which is cleared from the fragments before it in the chain. Removal fragments are marked
+/// by the high bit of the parent pointer, so a table can hold at most `2^31` fragments.
+///
+/// Both fields are stored little-endian, so table files are portable across architectures. Use
+/// the accessors to read them in native byte order.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Zeroable, Pod)]
 pub struct ColorFragment {
-    parent_pointer: ColorFragmentIndex, // or Option<NonZero<ColorFragmentIndex>> or something like that
+    // raw parent index, with the removal flag
+    parent_pointer: pack1::U32LE,
     // unpadded u32
     color: pack1::U32LE,
 }
 
-/// Wrapper around a memory-mapped color table file.
-#[derive(Debug)]
-struct ColorTableMmap {
-    mmap: memmap2::Mmap,
-}
-
-#[cfg(feature = "typesize")]
-impl TypeSize for ColorTableMmap {
-    fn extra_size(&self) -> usize {
-        self.mmap.len()
+impl ColorFragment {
+    /// Get the index of the parent fragment. Index 0 means the fragment has no parent.
+    #[inline]
+    pub fn parent(&self) -> ColorFragmentIndex {
+        ColorFragmentIndex(self.parent_pointer.get() & !REMOVAL_FLAG)
     }
-}
-
-impl ColorTableMmap {
-    /// Create a new `ColorTableMmap` from the given file.
-    ///
-    /// # Safety
-    ///
-    /// The file must not be modified while mmapped.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if the file could not be mmapped.
-    unsafe fn new(file: File) -> Result<Self> {
-        // SAFETY: the caller must ensure that the file is not modified.
-        // we never modify the part of the file that is mmapped; we only append to the file, which should not cause any issues.
-        // if the file is truncated (by another process) while mmapped, kernel will send SIGBUS on access
-        let mmap = unsafe { memmap2::MmapOptions::new().map(&file)? };
-        #[cfg(unix)]
-        mmap.advise(memmap2::Advice::Random)?; // we are reading the file backwards, so tell the OS not to read ahead
 
-        Ok(Self { mmap })
+    /// Get the partial color stored in the fragment, or the removed index if this is a removal
+    /// fragment.
+    #[inline]
+    pub fn color(&self) -> u32 {
+        self.color.get()
     }
 
-    // may panic in theory, but POSIX standards should guarantee that the memory is aligned to the page size (4KiB)
+    /// Returns `true` if this is a removal fragment, which clears an index from its color class
+    /// instead of adding any.
     #[inline]
-    fn as_fragments(&self) -> &[ColorFragment] {
-        bytemuck::cast_slice(&self.mmap)
+    pub fn is_removal(&self) -> bool {
+        self.parent_pointer.get() & REMOVAL_FLAG != 0
     }
 
-    fn get_fragment(&self, index: &ColorFragmentIndex) -> Option<&ColorFragment> {
-        self.as_fragments().get(index.0 as usize)
+    /// Get a copy of the fragment pointing to another parent.
+    #[inline]
+    fn with_parent(&self, parent: ColorFragmentIndex) -> Self {
+        Self {
+            parent_pointer: (parent.0 | self.parent_pointer.get() & REMOVAL_FLAG).into(),
+            color: self.color,
+        }
     }
 }
 
-impl Deref for ColorTableMmap {
-    type Target = [ColorFragment];
-
-    fn deref(&self) -> &Self::Target {
-        self.as_fragments()
-    }
+/// How a color table was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// The table holds the lock on its directory (or is in memory), and can be written.
+    Write,
+    /// The table was loaded read-only, with the PID in the lock file if it could be read.
+    Shared(Option<u32>),
+    /// The table is sealed, and read-only.
+    Sealed,
 }
 
 /// Compact on-disk bitmap storage.
 #[derive(Debug)]
 pub struct ColorTable {
-    directory: PathBuf,
+    // `None` for in-memory color tables
+    directory: Option<PathBuf>,
+    // held until the table is dropped, so no other handle can write to the same files
+    // `None` for in-memory color tables
+    _lock: Option<TableLock>,
     config: Box<ColorTableConfig>,
-    // buffered writer for the color table file, and current head index
-    // the head index is only modified while holding the lock, so it stays in sync with the file
-    file: Mutex<(BufWriter<File>, ColorFragmentIndex)>,
+    // writer for the color table file (or in-memory fragments)
+    // locked by every append, so appends are serialized; readers only load `head`
+    file: Mutex<Writer>,
+    // index of the next fragment to be assigned
+    // only stored while holding the file lock; fragments below the head may still be pending, or
+    // being written to a reserved region of the file
+    head: AtomicU32,
+    // kind of the error of a failed write to a reserved region, after which the generation in
+    // progress is aborted when it ends
+    failed_write: Mutex<Option<io::ErrorKind>>,
+    // fragments waiting for reserved slots before them to be filled
+    // only locked while holding the file lock
+    pending: Mutex<Pending>,
 
     generation_lock: Mutex<()>,
-    generations: RwLock<Generations>,
+    // wakes threads waiting in `wait_for_generation_end`
+    generation_signal: GenerationSignal,
+    // log of ended generations, if they are published for other processes
+    // only locked while holding the generation lock
+    generation_log: Mutex<Option<GenerationLog>>,
+    // generations ended, and head, since the last end-of-generation flush
+    // only locked while holding the generation lock
+    unflushed: Mutex<(u32, ColorFragmentIndex)>,
+    // readers take a cheap snapshot of the generations; writers copy-on-write if a snapshot is live
+    generations: RwLock<Arc<Generations>>,
+    // first range of fragments outside of any generation when the table was loaded, or left by an
+    // aborted generation, after which no generation can be started
+    uncovered: Mutex<Option<Range<ColorFragmentIndex>>>,
+    // whether the table can be modified; set to sealed by `ColorTable::seal`
+    access: Access,
+    // chain depths and checkpoints for each fragment
+    // only pushed to while holding the file lock, so it stays in sync with the head index
+    chains: RwLock<Chains>,
+    // MinHash sketches of the class ending at each fragment of the ended generations
+    // only pushed to while holding the generation lock
+    sketches: RwLock<Sketches>,
+    // materialized bitmaps used as starting points by `ClassIter::into_bitmap`
+    #[cfg(feature = "roaring")]
+    bitmap_checkpoints: RwLock<Arc<BitmapCheckpoints>>,
+    // deduplicated color classes, by the hash of their bitmap
+    class_hashes: Mutex<ClassHashes>,
+    // deleted color classes
+    tombstones: RwLock<Tombstones>,
+    // heads of extended color classes, if `deferred_heads` is enabled
+    heads: RwLock<Heads>,
+    // heads of classes extended in the current generation, published when it ends
+    // only locked while holding the generation lock
+    pending_heads: Mutex<Vec<(ColorFragmentIndex, ColorFragmentIndex)>>,
+    // auxiliary values attached to fragments
+    payloads: RwLock<Payloads>,
+    // cardinality of the class ending at each fragment of the ended generations, if
+    // `running_counts` is enabled
+    // only extended while holding the generation lock
+    running_counts: RwLock<RunningCounts>,
+    // generations of the batches created with `ColorTable::batch` that are not committed yet
+    batches: BatchQueue,
+    observers: Observers,
+    metrics: MetricsSink,
 }
 
 #[cfg(feature = "typesize")]
 impl TypeSize for ColorTable {
     fn extra_size(&self) -> usize {
-        self.directory.capacity()
+        self.directory.as_ref().map_or(0, PathBuf::capacity)
             + self.config.extra_size()
-            + self.file.lock().0.capacity()
+            + self.file.lock().capacity()
+            + self.chains.read().heap_size()
+            + self.running_counts.read().heap_size()
             + (40 * (std::mem::size_of::<ColorFragmentIndex>() + std::mem::size_of::<(u64, u64)>()))
     }
 }
@@ -256,38 +422,151 @@ impl ColorTable {
     ///
     /// # Errors
     ///
-    /// Returns an error if the color table file could not be created (e.g. if the directory does not exist).
+    /// Returns an error if the color table file could not be created (e.g. if the directory does not exist),
+    /// or [`ColorTableError::Locked`] if another handle has a color table open in the directory
+    /// (including read-only, see [`ColorTable::load_read_only`]).
     pub fn new(dir: impl AsRef<Path>, config: ColorTableConfig) -> Result<Self> {
-        let file = File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(dir.as_ref().join(&config.color_table_file_name))?;
+        let lock = TableLock::acquire(&dir.as_ref().join(&config.lock_file_name), &config)?;
+        let path = dir.as_ref().join(&config.color_table_file_name);
+        let file = config
+            .open_file(
+                config
+                    .color_table_options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false),
+                &path,
+            )
+            .at(&path)?;
+        // tables loaded read-only may still map the old file
+        truncate_unshared(&file, 0, &path)?;
+
+        Self::create_in(dir.as_ref(), lock, file, config)
+    }
+
+    /// Creates a new `ColorTable` in an empty color table file.
+    fn create_in(
+        dir: &Path,
+        lock: TableLock,
+        file: File,
+        config: ColorTableConfig,
+    ) -> Result<Self> {
+        let mut file = Writer::open(file, &config)?;
+        // the header takes the place of fragment 0, so that fragment indices are offsets in the
+        // file; its magic, format version and flags are checked when the table is loaded
+        file.write_fragment(&bytemuck::cast(Header::for_config(&config).to_bytes()))?;
+
+        // a seal left behind by an earlier table in the directory would keep this one from loading
+        let seal_path = dir.join(&config.seal_file_name);
+        match std::fs::remove_file(&seal_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).at(seal_path),
+            _ => {}
+        }
 
-        let mut file = BufWriter::with_capacity(config.buffer_size, file);
-        // 12 bytes magic header to make offset calculations easier - maybe store len/format version/checksum later
-        // if this is ever accessed as a fragment (idx 0), the result is valid but meaningless
-        // currently not checked or validated
-        file.write_all(&TABLE_MAGIC)?;
+        let generation_log = config
+            .publish_generations
+            .then(|| {
+                GenerationLog::create(
+                    &dir.join(&config.generation_log_file_name),
+                    &Generations::new(),
+                    &config,
+                )
+            })
+            .transpose()?;
+        let chains = Chains::new(config.skip_interval);
+        let sketches = Sketches::new(config.sketch_size);
 
         Ok(Self {
-            directory: dir.as_ref().to_path_buf(),
+            directory: Some(dir.to_path_buf()),
+            _lock: Some(lock),
             config: Box::new(config),
-            file: Mutex::new((file, ColorFragmentIndex(1))),
+            file: Mutex::new(file),
+            head: AtomicU32::new(1),
+            failed_write: Mutex::new(None),
+            pending: Mutex::new(Pending::new()),
             generation_lock: Mutex::new(()),
-            generations: RwLock::new(Generations::new()),
+            generation_signal: GenerationSignal::default(),
+            generation_log: Mutex::new(generation_log),
+            unflushed: Mutex::new((0, ColorFragmentIndex(1))),
+            generations: RwLock::new(Arc::new(Generations::new())),
+            uncovered: Mutex::new(None),
+            access: Access::Write,
+            chains: RwLock::new(chains),
+            sketches: RwLock::new(sketches),
+            #[cfg(feature = "roaring")]
+            bitmap_checkpoints: RwLock::new(Arc::new(BitmapCheckpoints::new())),
+            class_hashes: Mutex::new(ClassHashes::new()),
+            tombstones: RwLock::new(Tombstones::new()),
+            heads: RwLock::new(Heads::new()),
+            pending_heads: Mutex::new(Vec::new()),
+            payloads: RwLock::new(Payloads::new()),
+            running_counts: RwLock::new(RunningCounts::new()),
+            batches: BatchQueue::default(),
+            observers: Observers::default(),
+            metrics: MetricsSink::default(),
         })
     }
 
+    /// Creates a new `ColorTable` that is kept entirely in memory.
+    ///
+    /// In-memory color tables support the same operations as file-backed ones, but nothing is
+    /// written to disk: [`ColorTable::sync`] does nothing, and the file names in `config` are ignored
+    /// (as is `publish_generations`).
+    pub fn in_memory(config: ColorTableConfig) -> Self {
+        let chains = Chains::new(config.skip_interval);
+        let sketches = Sketches::new(config.sketch_size);
+        let file = Writer::memory(config.color_mode);
+
+        Self {
+            directory: None,
+            _lock: None,
+            config: Box::new(config),
+            file: Mutex::new(file),
+            head: AtomicU32::new(1),
+            failed_write: Mutex::new(None),
+            pending: Mutex::new(Pending::new()),
+            generation_lock: Mutex::new(()),
+            generation_signal: GenerationSignal::default(),
+            generation_log: Mutex::new(None),
+            unflushed: Mutex::new((0, ColorFragmentIndex(1))),
+            generations: RwLock::new(Arc::new(Generations::new())),
+            uncovered: Mutex::new(None),
+            access: Access::Write,
+            chains: RwLock::new(chains),
+            sketches: RwLock::new(sketches),
+            #[cfg(feature = "roaring")]
+            bitmap_checkpoints: RwLock::new(Arc::new(BitmapCheckpoints::new())),
+            class_hashes: Mutex::new(ClassHashes::new()),
+            tombstones: RwLock::new(Tombstones::new()),
+            heads: RwLock::new(Heads::new()),
+            pending_heads: Mutex::new(Vec::new()),
+            payloads: RwLock::new(Payloads::new()),
+            running_counts: RwLock::new(RunningCounts::new()),
+            batches: BatchQueue::default(),
+            observers: Observers::default(),
+            metrics: MetricsSink::default(),
+        }
+    }
+
     /// Loads an existing `ColorTable` from the given directory, or creates a new one if loading fails.
     ///
     /// # Errors
     ///
-    /// Returns an error if both [`ColorTable::load`] and [`ColorTable::new`] fail.
+    /// Returns an error if both [`ColorTable::load`] and [`ColorTable::new`] fail, or if the existing
+    /// table was written in an unsupported format version or has fragments that don't belong to any
+    /// generation (it is not overwritten), or [`ColorTableError::Locked`] if another handle has the
+    /// table open.
     pub fn load_or_new(dir: impl AsRef<Path>, config: ColorTableConfig) -> Result<Self> {
-        if let Ok(table) = Self::load(&dir, config.clone()) {
-            return Ok(table);
+        match Self::load(&dir, config.clone()) {
+            Ok(table) => return Ok(table),
+            // don't overwrite a table written by a newer version, or one that can be repaired
+            Err(
+                err @ (ColorTableError::UnsupportedVersion { .. }
+                | ColorTableError::UncoveredFragments { .. }
+                | ColorTableError::Locked { .. }),
+            ) => return Err(err),
+            Err(_) => {}
         }
 
         Self::new(dir, config)
@@ -297,47 +576,251 @@ impl ColorTable {
     ///
     /// # Errors
     ///
-    /// Returns an error if the color table files could not be opened (e.g. if the directory or file does not exist).
+    /// Returns [`ColorTableError::Locked`] if another handle has the color table open (see
+    /// [`ColorTable::load_read_only`] to read it anyway), an error if the color table files could
+    /// not be opened (e.g. if the directory or file does not exist),
+    /// [`ColorTableError::CrossFileMismatch`] if the generations file refers to fragments past the end
+    /// of the color table file, or [`ColorTableError::UncoveredFragments`] if the color table file
+    /// has fragments that don't belong to any generation, e.g. if the table was not synced after its
+    /// last generation (see [`ColorTable::load_repaired`]).
     pub fn load(dir: impl AsRef<Path>, config: ColorTableConfig) -> Result<Self> {
-        let mut color_table = File::options()
+        let path = dir.as_ref().join(&config.color_table_file_name);
+        let lock = TableLock::acquire(&dir.as_ref().join(&config.lock_file_name), &config)?;
+        let color_table = config
+            .color_table_options()
             .read(true)
             .append(true)
-            .open(dir.as_ref().join(&config.color_table_file_name))?;
+            .open(&path)
+            .at(&path)?;
+
+        Self::load_from(dir.as_ref(), Some(lock), color_table, config, Access::Write)
+    }
+
+    /// Loads an existing `ColorTable` from the given directory for reading only, e.g. while another
+    /// handle has it open for writing.
+    ///
+    /// The table holds the generations that were last synced, and starting a generation returns
+    /// [`ColorTableError::Locked`], with the PID of the last writer if the lock file holds one. A
+    /// table loaded read-only takes a shared lock on the color table file, which keeps it from being
+    /// truncated (by [`ColorTable::new`] or [`ColorTable::load_repaired`]) until it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::Locked`] if the color table file is being truncated by another
+    /// handle, or an error if the table could not be loaded (see [`ColorTable::load`]).
+    pub fn load_read_only(dir: impl AsRef<Path>, config: ColorTableConfig) -> Result<Self> {
+        let dir = dir.as_ref();
+        let pid = TableLock::owner(&dir.join(&config.lock_file_name));
+        let path = dir.join(&config.color_table_file_name);
+        let color_table = File::open(&path).at(&path)?;
+
+        Self::load_from(dir, None, color_table, config, Access::Shared(pid))
+    }
+
+    /// Creates or loads a `ColorTable` using an already opened color table file.
+    ///
+    /// This gives full control over how the color table file is opened (e.g. its permissions, or
+    /// flags like `O_DIRECT`). The file must be readable and writable. If it is empty, a new table is
+    /// created in it, otherwise the table is loaded from it. The other files of the table are opened
+    /// in `dir` as usual.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the color table could not be created or loaded (see [`ColorTable::new`]
+    /// and [`ColorTable::load`]).
+    pub fn from_files(
+        dir: impl AsRef<Path>,
+        color_table: File,
+        config: ColorTableConfig,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        let lock = TableLock::acquire(&dir.join(&config.lock_file_name), &config)?;
+        let path = dir.join(&config.color_table_file_name);
+
+        if color_table.metadata().at(&path)?.len() == 0 {
+            Self::create_in(dir, lock, color_table, config)
+        } else {
+            Self::load_from(dir, Some(lock), color_table, config, Access::Write)
+        }
+    }
+
+    /// Loads a `ColorTable` from an opened color table file.
+    ///
+    /// Sealed tables are loaded read-only, and skip the checks for a table that was not synced
+    /// before it was closed. Tables loaded read-only only read up to the end of their last synced
+    /// generation.
+    fn load_from(
+        dir: &Path,
+        lock: Option<TableLock>,
+        mut color_table: File,
+        mut config: ColorTableConfig,
+        access: Access,
+    ) -> Result<Self> {
+        let path = dir.join(&config.color_table_file_name);
+        let sealed = access == Access::Sealed;
+        if !sealed && dir.join(&config.seal_file_name).exists() {
+            return Err(ColorTableError::Sealed);
+        }
+        if access != Access::Write {
+            lock_shared(&color_table, &path)?;
+        }
+        color_table.rewind().at(&path)?;
+
+        let generations_path = dir.join(&config.generations_file_name);
+        let mut generations =
+            Generations::open(&mut File::open(&generations_path).at(&generations_path)?)?;
+        if !sealed && generations.in_progress().is_some() {
+            // a generation recorded as in progress (e.g. by an older version) can never be ended,
+            // so its fragments are treated as uncovered
+            generations = generations.committed();
+        }
+
         let ct_size = color_table.metadata()?.len();
         if !ct_size.is_multiple_of(std::mem::size_of::<ColorFragment>() as u64) {
-            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+            return Err(ColorTableError::TrailingBytes { path, len: ct_size });
+        }
+        if ct_size / std::mem::size_of::<ColorFragment>() as u64 > u64::from(MAX_FRAGMENTS) {
+            return Err(ColorTableError::TableFull {
+                limit: MAX_FRAGMENTS,
+            });
         }
 
         // check magic header
         let mut buf = [0; std::mem::size_of::<ColorFragment>()];
-        color_table.read_exact(&mut buf)?;
-
-        if buf != TABLE_MAGIC {
+        if color_table.read_exact(&mut buf).is_err() {
             // file was probably truncated or corrupted
-            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+            return Err(ColorTableError::BadMagic { path });
         }
+        // the mode the table was created with takes precedence over the configured one
+        let header = Header::parse(buf, &path)?;
+        config.color_mode = header.color_mode();
+        config.running_counts = header.running_counts();
 
-        let head =
+        let mut head =
             ColorFragmentIndex((ct_size / std::mem::size_of::<ColorFragment>() as u64) as u32);
+        // a stale generations file (e.g. from a newer sync than the color table file) would point
+        // past the last fragment
+        if let (Some(generations_end), false) =
+            (generations.iter().map(|(range, _)| range.end).max(), sealed)
+        {
+            if generations_end > head {
+                return Err(ColorTableError::CrossFileMismatch {
+                    generations_end: generations_end.0,
+                    head: head.0,
+                });
+            }
+        }
+        // the fragments after the last synced generation are still being written by the other handle
+        if let Access::Shared(_) = access {
+            head = generations
+                .last_range_end()
+                .unwrap_or(ColorFragmentIndex(1));
+        }
+        // e.g. fragments of generations that ended after the generations file was last synced.
+        // generations started after them would leave them uncovered for good, so they have to be
+        // repaired before the table can be written to
+        let uncovered = generations.first_uncovered(head);
+        if let (Some(range), false) = (&uncovered, sealed) {
+            return Err(ColorTableError::UncoveredFragments {
+                start: range.start.0,
+                end: range.end.0,
+            });
+        }
 
-        let mut generations_reader = io::BufReader::new(File::open(
-            dir.as_ref().join(&config.generations_file_name),
-        )?);
-        let generations: RwLock<Generations> = RwLock::new(bincode::decode_from_std_read(
-            &mut generations_reader,
-            crate::BINCODE_CONFIG,
-        )?);
-
-        // copy
-        let buffer_size = config.buffer_size;
+        // rebuild chain metadata with a single sequential pass over the fragments
+        let chains = read_chains(
+            io::BufReader::with_capacity(config.buffer_size, &color_table),
+            head,
+            config.skip_interval,
+        )?;
+        let sketches = Sketches::new(config.sketch_size);
+
+        // the sidecar file is optional, since it is only written once bitmaps are materialized
+        #[cfg(feature = "roaring")]
+        let bitmap_checkpoints = read_sidecar(
+            &dir.join(&config.bitmap_checkpoints_file_name),
+            decode_sidecar,
+        )?
+        .unwrap_or_default();
+        let class_hashes = read_sidecar(&dir.join(&config.class_hashes_file_name), decode_sidecar)?
+            .unwrap_or_default();
+        let tombstones = read_sidecar(&dir.join(&config.tombstones_file_name), decode_sidecar)?
+            .unwrap_or_default();
+        let heads = read_sidecar(&dir.join(&config.heads_file_name), |file, path| {
+            let mut heads = Heads::read_from(file).at(path)?;
+            heads.compact();
+            Ok(heads)
+        })?
+        .unwrap_or_else(Heads::new);
+        let payloads = read_sidecar(&dir.join(&config.payloads_file_name), decode_sidecar)?
+            .unwrap_or_default();
+
+        // counts that don't end with a generation (e.g. from a newer sync than the color table
+        // file) are recomputed
+        let running_counts = if config.running_counts {
+            read_sidecar(&dir.join(&config.running_counts_file_name), |file, path| {
+                RunningCounts::read_from(file).at(path)
+            })?
+        } else {
+            None
+        }
+        .filter(|running_counts| {
+            let len = running_counts.len() as u32;
+            generations.iter().any(|(range, _)| range.end.0 == len)
+        })
+        .unwrap_or_default();
 
-        Ok(Self {
-            directory: dir.as_ref().to_path_buf(),
+        // the file may not have been opened in append mode
+        color_table.seek(io::SeekFrom::End(0)).at(&path)?;
+        let file = if access != Access::Write {
+            Writer::read_only(color_table)
+        } else {
+            Writer::open(color_table, &config)?
+        };
+        // the log is rewritten, since the table may have changed since it was last published
+        let generation_log = (config.publish_generations && access == Access::Write)
+            .then(|| {
+                GenerationLog::create(
+                    &dir.join(&config.generation_log_file_name),
+                    &generations,
+                    &config,
+                )
+            })
+            .transpose()?;
+
+        let table = Self {
+            directory: Some(dir.to_path_buf()),
+            _lock: lock,
             config: Box::new(config),
-            file: Mutex::new((BufWriter::with_capacity(buffer_size, color_table), head)),
+            file: Mutex::new(file),
+            head: AtomicU32::new(head.0),
+            failed_write: Mutex::new(None),
+            pending: Mutex::new(Pending::new()),
             generation_lock: Mutex::new(()),
-            generations,
-        })
+            generation_signal: GenerationSignal::default(),
+            generation_log: Mutex::new(generation_log),
+            unflushed: Mutex::new((0, head)),
+            generations: RwLock::new(Arc::new(generations)),
+            uncovered: Mutex::new(uncovered),
+            access,
+            chains: RwLock::new(chains),
+            sketches: RwLock::new(sketches),
+            #[cfg(feature = "roaring")]
+            bitmap_checkpoints: RwLock::new(Arc::new(bitmap_checkpoints)),
+            class_hashes: Mutex::new(class_hashes),
+            tombstones: RwLock::new(tombstones),
+            heads: RwLock::new(heads),
+            pending_heads: Mutex::new(Vec::new()),
+            payloads: RwLock::new(payloads),
+            running_counts: RwLock::new(running_counts),
+            batches: BatchQueue::default(),
+            observers: Observers::default(),
+            metrics: MetricsSink::default(),
+        };
+        table.update_sketches()?;
+        table.update_running_counts()?;
+
+        Ok(table)
     }
 
     /// Syncs the color table to disk.
@@ -346,210 +829,1669 @@ impl ColorTable {
     /// You may provide a [`ColorTableConfig`] to control where the files are saved.
     /// If no config is provided, the config that was used to create the color table is used.
     ///
+    /// This method does nothing for in-memory color tables.
+    ///
+    /// Only ended generations are recorded. Fragments of a generation in progress are written to the
+    /// color table file, but are only recorded by a sync after the generation ends.
+    ///
     /// # Errors
     ///
     /// Returns an error if the color table is currently mmapped, or if the color table files could not be updated.
     // maybe want to take config as an argument to avoid storing it in the struct
     pub fn sync(&self, config: Option<&ColorTableConfig>) -> Result<()> {
+        self.sync_with(config, true)
+    }
+
+    /// Syncs the color table to disk, trimming any preallocated space from the color table file
+    /// only if `trim` is set (see [`ColorTable::sync`]).
+    ///
+    /// Only ended generations are recorded, so a table loaded from the synced files never has a
+    /// generation in progress.
+    fn sync_with(&self, config: Option<&ColorTableConfig>, trim: bool) -> Result<()> {
         let config = config.unwrap_or(&self.config);
+        let Some(directory) = &self.directory else {
+            return Ok(());
+        };
+        // everything was synced when the table was sealed, or is synced by the handle writing it
+        if self.access != Access::Write {
+            return Ok(());
+        }
 
         // sync table to disk
-        self.file.lock().0.flush()?;
-
-        let mut generations_writer = io::BufWriter::new(File::create(
-            self.directory.join(&config.generations_file_name),
-        )?);
-        bincode::encode_into_std_write(
-            self.generations.read().deref(),
-            &mut generations_writer,
-            crate::BINCODE_CONFIG,
+        if trim {
+            self.file.lock().finish()?;
+        } else {
+            self.file.lock().sync_data()?;
+        }
+
+        write_generations(directory, &self.generations.read().committed(), config)?;
+
+        // don't leave a stale sidecar file behind (e.g. after compaction)
+        #[cfg(feature = "roaring")]
+        {
+            let bitmap_checkpoints = Arc::clone(&self.bitmap_checkpoints.read());
+            write_sidecar(
+                config,
+                &directory.join(&config.bitmap_checkpoints_file_name),
+                !bitmap_checkpoints.is_empty(),
+                |file, path| encode_sidecar(file, path, bitmap_checkpoints.as_ref()),
+            )?;
+        }
+
+        let class_hashes = self.class_hashes.lock().clone();
+        write_sidecar(
+            config,
+            &directory.join(&config.class_hashes_file_name),
+            !class_hashes.is_empty(),
+            |file, path| encode_sidecar(file, path, &class_hashes),
+        )?;
+
+        let tombstones = self.tombstones.read().clone();
+        write_sidecar(
+            config,
+            &directory.join(&config.tombstones_file_name),
+            !tombstones.is_empty(),
+            |file, path| encode_sidecar(file, path, &tombstones),
+        )?;
+
+        // links are only added between renumberings, so only the new ones are written. the links
+        // synced so far are only known to be in the table's own file
+        let own_file = config.heads_file_name == self.config.heads_file_name;
+        let has_heads = !self.heads.read().is_empty();
+        if !has_heads && own_file {
+            self.heads.write().mark_synced(0, 0);
+        }
+        write_sidecar(
+            config,
+            &directory.join(&config.heads_file_name),
+            has_heads,
+            |file, path| {
+                let (start, links, written) = {
+                    let heads = self.heads.read();
+                    if own_file {
+                        heads.unsynced(file.metadata().at(path)?.len())
+                    } else {
+                        (0, heads.links(), 0)
+                    }
+                };
+                Heads::sync_to(file, start, &links).at(path)?;
+                if own_file {
+                    let mut heads = self.heads.write();
+                    heads.mark_synced(start + links.len(), written);
+                    // keep resolving ids short, now that the file holds every link as published
+                    if heads.has_chains() {
+                        heads.compact();
+                    }
+                }
+                Ok(())
+            },
+        )?;
+
+        let payloads = self.payloads.read().clone();
+        write_sidecar(
+            config,
+            &directory.join(&config.payloads_file_name),
+            !payloads.is_empty(),
+            |file, path| encode_sidecar(file, path, &payloads),
+        )?;
+
+        // the counts only grow between rebuilds, so only the new ones are written
+        let has_running_counts = !self.running_counts.read().is_empty();
+        write_sidecar(
+            config,
+            &directory.join(&config.running_counts_file_name),
+            has_running_counts,
+            |file, path| {
+                let (start, counts) = {
+                    let running_counts = self.running_counts.read();
+                    let (start, counts) = running_counts.unsynced(file.metadata().at(path)?.len());
+                    (start, counts.to_vec())
+                };
+                RunningCounts::sync_to(file, start, &counts).at(path)?;
+                self.running_counts
+                    .write()
+                    .mark_synced(start + counts.len());
+                Ok(())
+            },
         )?;
 
         Ok(())
     }
 
-    /// Maps the color table to memory.
+    /// Deletes a color class.
+    ///
+    /// [`MmapGuard::color_class`] returns an empty iterator for a deleted color id, and
+    /// [`ColorTable::compact`] drops it from the live classes, so its fragments are reclaimed unless
+    /// other classes were forked or extended from it. Classes forked or extended from it are not
+    /// deleted. Deleting a class twice has no effect.
+    ///
+    /// Deleted ids are written to a sidecar file on [`ColorTable::sync`].
     ///
     /// # Errors
     ///
-    /// Returns an error if mmapping fails.
-    pub fn map(&self) -> Result<MmapGuard<'_>> {
-        // sync to disk
-        self.file.lock().0.flush()?;
+    /// Returns [`ColorTableError::InvalidColorId`] if the color id is invalid.
+    pub fn tombstone(&self, color_id: ColorId) -> Result<()> {
+        let idx = self
+            .head_fragment_index(&color_id)
+            .filter(|idx| idx.0 != 0)
+            .ok_or(ColorTableError::InvalidColorId(color_id.0))?;
+        self.check_writable()?;
+        self.tombstones.write().insert(idx);
 
-        // try_clone() here is ~equivalent to dup(2), so the new fd points to the same file object (this is what we want)
-        // SAFETY: `Self` will not modify the file while it is mmapped
-        let mmap = unsafe { ColorTableMmap::new(self.file.lock().0.get_ref().try_clone()?) }?;
+        Ok(())
+    }
 
-        Ok(MmapGuard(self, mmap))
+    /// Returns `true` if the color class has been deleted with [`ColorTable::tombstone`].
+    pub fn is_tombstoned(&self, color_id: &ColorId) -> bool {
+        self.tombstones.read().contains(&color_id.into())
     }
 
-    /// Write a fragment to the end of the file.
-    ///
-    /// Returns the index of the fragment.
-    ///
-    /// # Errors
+    /// Get the latest color id of the color class `color_id` belongs to, following the classes it
+    /// was extended into, so that ids stored before an extension (e.g. in a CQF) resolve to the
+    /// full class.
     ///
-    /// Returns an error if the color table is currently mmapped or if the color table file could not be updated.
-    #[inline]
-    fn write_fragment(&self, fragment: ColorFragment) -> Result<ColorFragmentIndex> {
-        let index = {
-            let mut guard = self.file.lock();
-            let index = guard.1;
-            let bytes = bytemuck::bytes_of(&fragment);
-            guard.0.write_all(bytes.as_ref())?;
-            guard.1 += 1;
-            index
-        };
+    /// Extensions are only tracked if `deferred_heads` is enabled in the [`ColorTableConfig`], and
+    /// only become visible here when their generation ends. Otherwise, and for invalid ids, this
+    /// returns `color_id` unchanged.
+    pub fn canonicalize(&self, color_id: ColorId) -> ColorId {
+        if !self.config.deferred_heads {
+            return color_id;
+        }
 
-        Ok(index)
+        self.head_fragment_index(&color_id)
+            .map_or(color_id, |idx| self.heads.read().resolve(idx).into())
     }
 
-    /// Perform an operation within a new generation.
+    /// Materializes the bitmaps of the given color classes as of their current head fragments.
     ///
-    /// The new generation number must be greater than the last generation.
-    /// The generation will automatically be ended when the provided closure returns.
+    /// [`ClassIter::into_bitmap`] starts from the nearest materialized bitmap in a chain, so this
+    /// speeds up queries against these classes and any classes extended or forked from them later.
+    /// Materialized bitmaps are written to a sidecar file on [`ColorTable::sync`].
     ///
-    /// If this function is called while another generation is in progress, it will block until the other generation has ended.
+    /// Returns the number of bitmaps materialized. Invalid color ids are skipped.
     ///
-    /// The color table can still be queried while a generation is in progress, but no changes will be visible until the generation is ended.
-    pub fn with_generation<R>(
-        &self,
-        generation: u64,
-        f: impl FnOnce(GenerationGuard<'_>) -> R,
-    ) -> Result<R> {
-        let _guard = self.generation_lock.lock();
-        self.generations
-            .write()
-            .start_new_generation_at(self.file.lock().1, generation)?;
-
-        // run the closure
-        let res = f(GenerationGuard { table: self });
-
-        self.generations
-            .write()
-            .end_current_generation_at(self.file.lock().1)?;
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails.
+    #[cfg(feature = "roaring")]
+    pub fn materialize_bitmaps(&self, ids: impl IntoIterator<Item = ColorId>) -> Result<usize> {
+        let ct_map = self.map()?;
+
+        let mut materialized = Vec::new();
+        for id in ids {
+            let iter = ct_map.color_class(&id);
+            if iter.len() == 0 {
+                continue;
+            }
+            materialized.push((id.into(), iter.into_bitmap()));
+        }
 
-        self.file.lock().0.flush()?;
+        let count = materialized.len();
+        let mut bitmap_checkpoints = self.bitmap_checkpoints.write();
+        let bitmap_checkpoints = Arc::make_mut(&mut bitmap_checkpoints);
+        for (idx, bitmap) in materialized {
+            bitmap_checkpoints.insert(idx, bitmap);
+        }
 
-        Ok(res)
+        Ok(count)
     }
 
+    /// Get the number of fragments written to the color table, not counting the header.
+    ///
+    /// This includes fragments of a generation in progress, and fragments that are still buffered.
     #[inline]
-    fn head_fragment_index(&self, color_id: &ColorId) -> Option<ColorFragmentIndex> {
-        if color_id.0 < self.file.lock().1.0 {
-            Some(color_id.into())
-        } else {
-            None
-        }
+    pub fn fragment_count(&self) -> u32 {
+        self.head().0 - 1
     }
-}
 
-pub struct GenerationGuard<'a> {
-    table: &'a ColorTable,
-}
+    /// Get the length of the color table file in bytes, including the header.
+    ///
+    /// Buffered fragments are flushed first, so this is the length the file will have once it is
+    /// synced. For in-memory color tables, this is the size the file would have.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if flushing the writer fails.
+    pub fn file_len(&self) -> Result<u64> {
+        Ok(self.file.lock().written_len()? as u64)
+    }
 
-impl<'a> GenerationGuard<'a> {
-    /// Creates a new color class.
+    /// Get the number of bytes of disk space allocated to the color table file, or `None` for
+    /// in-memory color tables and on non-Unix platforms.
     ///
-    /// Returns the index of the new color class.
-    /// You **MUST NOT** fork or extend the returned color class until the next generation.
-    pub fn new_color_class(&self, color: u32) -> Result<ColorId> {
-        let fragment = ColorFragment {
-            color: color.into(),
-            parent_pointer: ColorFragmentIndex(0),
-        };
+    /// This is at least [`ColorTable::file_len`] once the table is synced, and more if space was
+    /// preallocated (see `preallocate_fragments` in [`ColorTableConfig`]). Sparse files may use
+    /// less.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file metadata could not be read.
+    pub fn allocated_len(&self) -> Result<Option<u64>> {
+        Ok(self.file.lock().allocated_len()?)
+    }
 
-        let color_id = self.table.write_fragment(fragment)?.into();
+    /// Get the directory the color table is stored in, or `None` for in-memory color tables.
+    #[inline]
+    pub fn directory(&self) -> Option<&Path> {
+        self.directory.as_deref()
+    }
 
-        Ok(color_id)
+    /// Get the config the color table was created or loaded with.
+    #[inline]
+    pub fn config(&self) -> &ColorTableConfig {
+        &self.config
     }
 
-    /// Fork a color class.
+    /// Maps the color table to memory.
     ///
-    /// Returns the index of the new color class.
-    /// You **MUST NOT** fork or extend the returned color class until the next generation.
-    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the forked color class"]
-    pub fn fork_color_class(&self, parent: ColorId, color: u32) -> Result<ColorId> {
-        let Some(parent_idx) = self.table.head_fragment_index(&parent) else {
-            return Err(ColorTableError::InvalidColorId(parent.0));
-        };
-
-        let fragment = ColorFragment {
-            color: color.into(),
-            parent_pointer: parent_idx,
-        };
-
-        let color_id = self.table.write_fragment(fragment)?.into();
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails, or [`ColorTableError::UncoveredFragments`] if the table
+    /// has fragments that don't belong to any generation (see [`ColorTable::load_repaired`]).
+    pub fn map(&self) -> Result<MmapGuard<'_>> {
+        self.map_with(MapOptions::default())
+    }
 
-        Ok(color_id)
+    /// Maps the color table to memory, with the given access hints.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails, if the options could not be applied (e.g. if the
+    /// mapping could not be locked in RAM), or if the table has fragments that don't belong to any
+    /// generation (see [`ColorTable::map`]).
+    pub fn map_with(&self, options: MapOptions) -> Result<MmapGuard<'_>> {
+        self.check_coverage()?;
+        let mmap = self.mmap_to(self.mapped_end())?;
+        mmap.apply(&options, &self.generations.read())?;
+        Ok(MmapGuard(self, mmap, options))
     }
 
-    /// Extend a color class.
+    /// Maps only the fragments of the given generations to memory.
     ///
-    /// You **MUST NOT** extend the color class again until the next generation.
-    /// You may fork the color class after extending it within the same generation using the
-    /// *original* [`ColorId`] (passed as `parent`).
-    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the extended color class"]
-    pub fn extend_color_class(&self, parent: ColorId, color: u32) -> Result<ColorId> {
-        let Some(parent_idx) = self.table.head_fragment_index(&parent) else {
-            return Err(ColorTableError::InvalidColorId(parent.0));
+    /// This is useful for answering queries about recent generations without mapping a large
+    /// table. Color classes are truncated to the mapped fragments, so fragments from generations
+    /// before the range are never yielded. Generations in progress are not mapped, and the guard
+    /// is not extended by [`MmapGuard::refresh`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails.
+    pub fn map_range(&self, generations: RangeInclusive<u64>) -> Result<MmapGuard<'_>> {
+        self.check_coverage()?;
+        let fragments = self
+            .generations
+            .read()
+            .committed()
+            .iter()
+            .filter(|(_, generation)| generations.contains(generation))
+            .fold(
+                None,
+                |acc: Option<Range<ColorFragmentIndex>>, (range, _)| {
+                    Some(acc.map_or(range.clone(), |acc| {
+                        acc.start.min(range.start)..acc.end.max(range.end)
+                    }))
+                },
+            );
+
+        let mmap = match fragments {
+            Some(range) => self.file.lock().map_range(range)?,
+            None => ColorTableMmap::Partial {
+                start: ColorFragmentIndex(1),
+                fragments: Box::new(ColorTableMmap::Memory(Arc::new(Vec::new()))),
+            },
         };
 
-        let fragment = ColorFragment {
-            color: color.into(),
-            parent_pointer: parent_idx,
-        };
+        Ok(MmapGuard(self, mmap, MapOptions::default()))
+    }
 
-        let color_id = self.table.write_fragment(fragment)?.into();
+    /// Maps the color table to memory, returning a guard that owns a reference to the table.
+    ///
+    /// Unlike [`ColorTable::map`], the returned guard is `'static` and can be stored or sent to
+    /// other threads/tasks independently of the borrow of the table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails.
+    pub fn map_owned(self: &Arc<Self>) -> Result<OwnedMmapGuard> {
+        self.map_owned_with(MapOptions::default())
+    }
 
-        Ok(color_id)
+    /// Maps the color table to memory with the given access hints, returning a guard that owns a
+    /// reference to the table.
+    ///
+    /// See [`ColorTable::map_with`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails, or if the options could not be applied.
+    pub fn map_owned_with(self: &Arc<Self>, options: MapOptions) -> Result<OwnedMmapGuard> {
+        self.check_coverage()?;
+        let mmap = self.mmap_to(self.mapped_end())?;
+        mmap.apply(&options, &self.generations.read())?;
+        Ok(OwnedMmapGuard(Arc::clone(self), mmap, options))
     }
-}
 
-/// RAII guard for a memory-mapped color table.
-#[derive(Debug)]
-pub struct MmapGuard<'a>(&'a ColorTable, ColorTableMmap);
+    /// Check that every fragment belongs to a generation, so that the generation of each fragment
+    /// a [`ClassIter`] visits can be found.
+    fn check_coverage(&self) -> Result<()> {
+        match &*self.uncovered.lock() {
+            Some(range) => Err(ColorTableError::UncoveredFragments {
+                start: range.start.0,
+                end: range.end.0,
+            }),
+            None => Ok(()),
+        }
+    }
 
-impl<'a> MmapGuard<'a> {
-    /// Get a reference to the color table.
-    #[inline]
-    pub fn color_table(&self) -> &ColorTable {
-        self.0
+    /// The fragments written in the given generation.
+    fn generation_range(&self, generation: u64) -> Option<Range<ColorFragmentIndex>> {
+        self.generations
+            .read()
+            .iter()
+            .find(|&(_, g)| g == generation)
+            .map(|(range, _)| range.clone())
     }
 
-    /// Get the parent fragment of the given fragment, if it exists.
-    #[inline]
-    pub fn parent_of(&self, fragment: &ColorFragment) -> Option<&ColorFragment> {
-        let ptr = &fragment.parent_pointer;
-        if ptr == &ColorFragmentIndex(0) {
-            None
-        } else {
-            self.fragment(ptr)
-        }
+    /// Flush the writer and map the color table file.
+    fn mmap(&self) -> Result<ColorTableMmap> {
+        self.mmap_to(None)
     }
 
-    #[inline]
-    fn fragment(&self, idx: &ColorFragmentIndex) -> Option<&ColorFragment> {
-        if idx.0 == 0 {
+    /// Flush the writer and map the color table file, up to `end` if given.
+    fn mmap_to(&self, end: Option<ColorFragmentIndex>) -> Result<ColorTableMmap> {
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+        // a table loaded read-only only reads up to the end of its generations
+        let end = end.or_else(|| matches!(self.access, Access::Shared(_)).then(|| self.head()));
+        let mmap = self.file.lock().map(end)?;
+        trace_event!(
+            bytes = mmap.len() * size_of::<ColorFragment>(),
+            elapsed = ?started.elapsed(),
+            "mapped color table"
+        );
+
+        Ok(mmap)
+    }
+
+    /// The end of the fragments mapped by guards: the end of the last ended generation, or `None`
+    /// (everything written) while a generation is in progress.
+    fn mapped_end(&self) -> Option<ColorFragmentIndex> {
+        let generations = self.generations.read();
+        if generations.in_progress().is_some() {
             return None;
         }
 
-        self.1.get_fragment(idx)
+        Some(
+            generations
+                .last_range_end()
+                .unwrap_or(ColorFragmentIndex(1)),
+        )
+    }
+
+    /// Flush the writer and replace `mmap` with a new mapping, but only if the file has grown.
+    /// The options are applied to the new mapping.
+    fn remap(&self, mmap: &mut ColorTableMmap, options: &MapOptions) -> Result<()> {
+        let end = self.mapped_end();
+        if self.file.lock().remap(mmap, end)? {
+            mmap.apply(options, &self.generations.read())?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a fragment to the end of the file.
+    ///
+    /// Returns the index of the fragment.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the color table is currently mmapped or if the color table file could not be updated.
+    #[inline]
+    fn write_fragment(&self, fragment: ColorFragment) -> Result<ColorFragmentIndex> {
+        let index = {
+            let mut file = self.file.lock();
+            let index = self.head();
+            let mut pending = self.pending.lock();
+            self.check_quota(&file, index, 1)?;
+            if pending.is_empty() {
+                if let Err(e) = file.write_fragment(&fragment) {
+                    file.rollback(index.0 as usize * size_of::<ColorFragment>())?;
+                    return Err(e.into());
+                }
+
+                self.chains.write().push_fragment(&fragment);
+                self.observers
+                    .for_each(|observer| observer.on_fragment(index, &fragment));
+            } else {
+                pending.stage(index, std::slice::from_ref(&fragment));
+            }
+            self.advance_head(index, 1);
+
+            index
+        };
+        self.metrics.with(|metrics| metrics.fragments_written(1));
+
+        Ok(index)
+    }
+
+    /// Write fragments contiguously to the end of the file.
+    ///
+    /// The writer is locked to assign the indices. On Unix, fragments that don't fit in the
+    /// writer's buffer are written to a reserved region of the file after the writer is unlocked,
+    /// so that threads appending large batches don't wait for each other's writes.
+    ///
+    /// Returns the index of the first fragment.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the color table file could not be updated.
+    fn write_fragments(&self, fragments: &[ColorFragment]) -> Result<ColorFragmentIndex> {
+        let mut file = self.file.lock();
+        let start = self.head();
+        let mut pending = self.pending.lock();
+        self.check_quota(&file, start, fragments.len())?;
+        let mut region = None;
+        if !pending.is_empty() {
+            pending.stage(start, fragments);
+        } else if let Some(reserved) = file.reserve_region(size_of_val(fragments))? {
+            self.record(start, fragments);
+            region = Some(reserved);
+        } else {
+            self.append(&mut file, start, fragments)?;
+        }
+        self.advance_head(start, fragments.len() as u32);
+        drop((file, pending));
+
+        if let Some(region) = region {
+            if let Err(e) = region.write(bytemuck::cast_slice(fragments)) {
+                // the fragments are already recorded, so the generation can't end with them
+                *self.failed_write.lock() = Some(e.kind());
+                return Err(e.into());
+            }
+            trace_event!(
+                start = start.0,
+                fragments = fragments.len(),
+                bytes = size_of_val(fragments),
+                "wrote reserved region"
+            );
+        }
+        self.metrics
+            .with(|metrics| metrics.fragments_written(fragments.len() as u32));
+
+        Ok(start)
+    }
+
+    /// Check that writing `n` fragments starting at index `start` stays within the configured
+    /// `max_file_size` and `min_free_space`.
+    ///
+    /// The caller must hold the file lock.
+    fn check_quota(&self, file: &Writer, start: ColorFragmentIndex, n: usize) -> Result<()> {
+        check_capacity(start, n)?;
+        let bytes = n * size_of::<ColorFragment>();
+        if let Some(max_file_size) = self.config.max_file_size {
+            let len = u64::from(start.0) * size_of::<ColorFragment>() as u64;
+            if len + bytes as u64 > max_file_size {
+                return Err(ColorTableError::QuotaExceeded {
+                    bytes: bytes as u64,
+                    available: max_file_size.saturating_sub(len),
+                });
+            }
+        }
+
+        let disk_bytes = file.disk_bytes(bytes) as u64;
+        if let (Some(min_free_space), true) = (self.config.min_free_space, disk_bytes != 0) {
+            if let Some(free) = file.available_space()? {
+                let available = free.saturating_sub(min_free_space);
+                if disk_bytes > available {
+                    return Err(ColorTableError::QuotaExceeded {
+                        bytes: disk_bytes,
+                        available,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the pending fragments that are no longer waiting for reserved slots before them.
+    ///
+    /// The caller must hold the file lock.
+    fn write_ready(&self, file: &mut Writer, pending: &mut Pending) -> Result<()> {
+        let (start, ready) = pending.take_ready();
+        self.append(file, start, &ready)
+    }
+
+    /// Write fragments to the file and record them, starting at index `start`.
+    fn append(
+        &self,
+        file: &mut Writer,
+        start: ColorFragmentIndex,
+        fragments: &[ColorFragment],
+    ) -> Result<()> {
+        if let Err(e) = file.write_fragments(fragments) {
+            file.rollback(start.0 as usize * size_of::<ColorFragment>())?;
+            return Err(e.into());
+        }
+        trace_event!(
+            start = start.0,
+            fragments = fragments.len(),
+            bytes = size_of_val(fragments),
+            "appended fragments"
+        );
+        self.record(start, fragments);
+
+        Ok(())
+    }
+
+    /// Record written fragments, starting at index `start`, in the chain metadata and notify the
+    /// observers.
+    ///
+    /// The caller must hold the file lock.
+    fn record(&self, start: ColorFragmentIndex, fragments: &[ColorFragment]) {
+        let mut chains = self.chains.write();
+        for (i, fragment) in fragments.iter().enumerate() {
+            chains.push_fragment(fragment);
+            self.observers
+                .for_each(|observer| observer.on_fragment(start + i as u32, fragment));
+        }
+    }
+
+    /// Move the head past `n` fragments assigned at `start`.
+    ///
+    /// The caller must hold the file lock.
+    #[inline]
+    fn advance_head(&self, start: ColorFragmentIndex, n: u32) {
+        self.head.store(start.0 + n, Ordering::Release);
+    }
+
+    /// Perform an operation within a new generation.
+    ///
+    /// The new generation number must be greater than the last generation, unless the table was
+    /// configured with a different [`GenerationPolicy`].
+    /// The generation will automatically be ended when the provided closure returns.
+    ///
+    /// If this function is called while another generation is in progress, it will block until the other generation has ended.
+    ///
+    /// The color table can still be queried while a generation is in progress, but no changes will be visible until the generation is ended.
+    pub fn with_generation<R>(
+        &self,
+        generation: u64,
+        f: impl FnOnce(GenerationGuard<'_>) -> R,
+    ) -> Result<R> {
+        let _guard = self.generation_lock.lock();
+        let generation = self.policy_generation(generation);
+        self.run_generation(generation, self.allow_repeat(), f)
+    }
+
+    /// Perform an operation within a new generation numbered one higher than the last (or 0 if
+    /// there is none), regardless of the [`GenerationPolicy`].
+    ///
+    /// Behaves like [`ColorTable::with_generation`], and also returns the assigned generation number.
+    /// The number is picked from the table's own record of generations, so callers don't need to
+    /// keep track of the last generation themselves.
+    pub fn with_next_generation<R>(
+        &self,
+        f: impl FnOnce(GenerationGuard<'_>) -> R,
+    ) -> Result<(u64, R)> {
+        let _guard = self.generation_lock.lock();
+        let generation = self.next_generation();
+        self.run_generation(generation, self.allow_repeat(), f)
+            .map(|res| (generation, res))
+    }
+
+    /// Perform an operation within a new generation, without blocking.
+    ///
+    /// Behaves like [`ColorTable::with_generation`], except that if another generation is in progress,
+    /// this returns `Ok(None)` immediately instead of waiting for it to end.
+    pub fn try_with_generation<R>(
+        &self,
+        generation: u64,
+        f: impl FnOnce(GenerationGuard<'_>) -> R,
+    ) -> Result<Option<R>> {
+        let Some(_guard) = self.generation_lock.try_lock() else {
+            return Ok(None);
+        };
+        let generation = self.policy_generation(generation);
+        self.run_generation(generation, self.allow_repeat(), f)
+            .map(Some)
+    }
+
+    /// Returns an error if the table is sealed or loaded read-only.
+    fn check_writable(&self) -> Result<()> {
+        match self.access {
+            Access::Write => Ok(()),
+            Access::Shared(pid) => Err(ColorTableError::Locked { pid }),
+            Access::Sealed => Err(ColorTableError::Sealed),
+        }
+    }
+
+    /// Returns `true` if a generation may have the same number as the last one under the
+    /// configured [`GenerationPolicy`].
+    fn allow_repeat(&self) -> bool {
+        self.config.generation_policy == GenerationPolicy::NonDecreasing
+    }
+
+    /// The number following the last generation, or 0 if there is none.
+    fn next_generation(&self) -> u64 {
+        self.generations
+            .read()
+            .last_generation()
+            .map_or(0, |last| last + 1)
+    }
+
+    /// The number to use for a requested generation under the configured [`GenerationPolicy`].
+    fn policy_generation(&self, requested: u64) -> u64 {
+        match self.config.generation_policy {
+            GenerationPolicy::StrictlyIncreasing => requested,
+            GenerationPolicy::NonDecreasing => self
+                .generations
+                .read()
+                .last_generation()
+                .map_or(requested, |last| requested.max(last)),
+            GenerationPolicy::AutoAssign => self.next_generation(),
+        }
+    }
+
+    /// Returns an error if `parent_idx` was written earlier in the generation in progress, before
+    /// it was merged with a repeated generation, so that a class keeps one fragment per generation.
+    fn check_merged(&self, parent_idx: ColorFragmentIndex) -> Result<()> {
+        if !self.allow_repeat() {
+            return Ok(());
+        }
+        let generations = self.generations.read();
+        match generations.in_progress() {
+            Some((generation, start))
+                if parent_idx < start && generations.find(&parent_idx) == Some(generation) =>
+            {
+                Err(ColorTableError::AlreadyInGeneration {
+                    parent: parent_idx.0,
+                    generation,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Start a generation, run the closure, and end the generation. If `allow_repeat` is set, the
+    /// generation may have the same number as the last one, and is merged with it.
+    ///
+    /// The caller must hold `generation_lock`.
+    fn run_generation<R>(
+        &self,
+        generation: u64,
+        allow_repeat: bool,
+        f: impl FnOnce(GenerationGuard<'_>) -> R,
+    ) -> Result<R> {
+        self.check_writable()?;
+        // the new generation would start after the uncovered fragments
+        self.check_coverage()?;
+        let start = self.head();
+        Arc::make_mut(&mut self.generations.write()).start_generation_at(
+            start,
+            generation,
+            allow_repeat,
+        )?;
+        self.generation_signal.start();
+        self.observers
+            .for_each(|observer| observer.on_generation_start(generation, start));
+        #[cfg(feature = "tracing")]
+        let (_span, started) = (
+            tracing::debug_span!("generation", generation).entered(),
+            std::time::Instant::now(),
+        );
+        trace_event!(start = start.0, "generation started");
+
+        // run the closure
+        let res = f(GenerationGuard {
+            table: self,
+            generation,
+            start,
+            history: OnceLock::new(),
+        });
+
+        // reserved ids must be written within the generation; fill any that weren't, so that the
+        // fragments after them can still be written
+        let unwritten = {
+            let mut file = self.file.lock();
+            let mut pending = self.pending.lock();
+            let unwritten = pending.fill_unwritten();
+            let res = self.write_ready(&mut file, &mut pending);
+            drop((file, pending));
+            let res = res.and_then(|()| match self.failed_write.lock().take() {
+                Some(kind) => {
+                    Err(io::Error::new(kind, "failed to write a batch of fragments").into())
+                }
+                None => Ok(()),
+            });
+            if let Err(e) = res {
+                self.abort_generation(generation, start);
+                return Err(e);
+            }
+            unwritten
+        };
+
+        let head = self.head();
+        Arc::make_mut(&mut self.generations.write()).end_current_generation_at(head)?;
+        self.publish_heads();
+        self.generation_signal.end();
+
+        self.flush_generation(head)?;
+        self.update_sketches()?;
+        self.update_running_counts()?;
+        if let Some(generation_log) = self.generation_log.lock().as_mut() {
+            self.file.lock().flush()?;
+            generation_log.append(start, head, generation)?;
+        }
+        self.observers
+            .for_each(|observer| observer.on_generation_end(generation, head));
+        self.metrics
+            .with(|metrics| metrics.generation_ended(generation, head.0 - start.0));
+        trace_event!(
+            fragments = head.0 - start.0,
+            bytes = (head.0 - start.0) as usize * size_of::<ColorFragment>(),
+            elapsed = ?started.elapsed(),
+            "generation ended"
+        );
+
+        if unwritten != 0 {
+            return Err(ColorTableError::UnwrittenReservations {
+                generation,
+                count: unwritten,
+            });
+        }
+
+        Ok(res)
+    }
+
+    /// Drop the generation in progress, started at `start`, after its fragments could not be
+    /// written.
+    ///
+    /// The fragments written since `start` are left outside of any generation, so no generation
+    /// can be started until the table is reloaded with [`ColorTable::load_repaired`].
+    fn abort_generation(&self, generation: u64, start: ColorFragmentIndex) {
+        let mut generations = self.generations.write();
+        *generations = Arc::new(generations.committed());
+        drop(generations);
+        *self.uncovered.lock() = Some(start..self.head());
+        self.pending_heads.lock().clear();
+        self.generation_signal.end();
+        self.observers
+            .for_each(|observer| observer.on_generation_abort(generation));
+        trace_event!(start = start.0, "generation aborted");
+    }
+
+    /// Record in the header that the table contains removal fragments, before the first one is
+    /// written.
+    fn mark_removals(&self) -> Result<()> {
+        if self.chains.read().has_removals() {
+            return Ok(());
+        }
+        self.file
+            .lock()
+            .write_header(Header::for_config(&self.config).with_removals(true))?;
+
+        Ok(())
+    }
+
+    /// Flush the writer at the end of a generation, if the flush policy calls for it.
+    ///
+    /// The caller must hold `generation_lock`.
+    fn flush_generation(&self, head: ColorFragmentIndex) -> Result<()> {
+        let mut unflushed = self.unflushed.lock();
+        unflushed.0 += 1;
+
+        let flush = match self.config.flush_policy {
+            FlushPolicy::EveryGeneration => true,
+            FlushPolicy::EveryNGenerations(n) => unflushed.0 >= n,
+            FlushPolicy::EveryNBytes(n) => {
+                (head.0 - unflushed.1.0) as usize * std::mem::size_of::<ColorFragment>() >= n
+            }
+            FlushPolicy::Manual => false,
+        };
+
+        if flush {
+            self.file.lock().flush()?;
+            let bytes =
+                (head.0 - unflushed.1.0) as u64 * std::mem::size_of::<ColorFragment>() as u64;
+            self.metrics.with(|metrics| metrics.bytes_flushed(bytes));
+            *unflushed = (0, head);
+        }
+
+        Ok(())
+    }
+
+    /// Registers an observer that is notified of every fragment appended and every generation
+    /// started or ended from now on.
+    ///
+    /// Rewrites of the table (e.g. [`ColorTable::compact`]) are not reported.
+    pub fn subscribe(&self, observer: Box<dyn FragmentObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Registers counters that are updated as the table is written and queried, replacing any
+    /// previously registered ones.
+    pub fn set_metrics(&self, metrics: Box<dyn Metrics>) {
+        self.metrics.set(metrics);
+    }
+
+    /// Get the index of the next fragment to be written.
+    #[inline]
+    fn head(&self) -> ColorFragmentIndex {
+        ColorFragmentIndex(self.head.load(Ordering::Acquire))
+    }
+
+    /// Check that a fragment with the given color may be written, according to the
+    /// [`ZeroColorPolicy`].
+    #[inline]
+    fn check_color(&self, color: u32) -> Result<()> {
+        if color == 0 && self.config.zero_colors == ZeroColorPolicy::Reject {
+            return Err(ColorTableError::ZeroColor);
+        }
+        Ok(())
+    }
+
+    /// Pack the counts of the samples of a generation into a fragment color, if the table is in the
+    /// [`ColorMode::Counts`] mode.
+    fn color_of_counts(&self, counts: &[u8]) -> Result<u32> {
+        if self.config.color_mode != ColorMode::Counts {
+            return Err(ColorTableError::UnsupportedColorMode {
+                mode: self.config.color_mode,
+            });
+        }
+        debug_assert!(
+            counts.len() as u64 <= COUNTS_PER_FRAGMENT,
+            "{} counts given, but a fragment holds {COUNTS_PER_FRAGMENT}",
+            counts.len()
+        );
+
+        let max = (1 << COUNT_BITS) - 1;
+        Ok(counts
+            .iter()
+            .take(COUNTS_PER_FRAGMENT as usize)
+            .enumerate()
+            .fold(0, |color, (slot, &count)| {
+                color | u32::from(count).min(max) << (slot as u32 * COUNT_BITS)
+            }))
+    }
+
+    /// Returns `true` if a fragment with the given color adds nothing and should not be written,
+    /// according to the [`ZeroColorPolicy`].
+    #[inline]
+    fn skip_color(&self, color: u32) -> bool {
+        color == 0 && self.config.zero_colors == ZeroColorPolicy::Skip
+    }
+
+    /// Get the number of fragments in the chain ending at `idx`, or 0 if it is past the end of
+    /// the table.
+    #[inline]
+    fn chain_depth(&self, idx: &ColorFragmentIndex) -> u32 {
+        let chains = self.chains.read();
+        if (idx.0 as usize) < chains.len() {
+            chains.depth(idx)
+        } else {
+            0
+        }
+    }
+
+    /// Get the fragment to write after for a parent color id, which is the current head of its class
+    /// if `deferred_heads` is enabled.
+    #[inline]
+    fn parent_index(&self, parent: &ColorId) -> Option<ColorFragmentIndex> {
+        let idx = self.head_fragment_index(parent)?;
+        if self.config.deferred_heads {
+            Some(self.heads.read().resolve(idx))
+        } else {
+            Some(idx)
+        }
+    }
+
+    /// Record that the class of `parent`, whose head was `parent_idx`, was extended by `head`. The
+    /// new head is published when the generation ends.
+    fn defer_head(
+        &self,
+        parent: &ColorId,
+        parent_idx: ColorFragmentIndex,
+        head: ColorFragmentIndex,
+    ) {
+        // extending the null color class creates a new class
+        if !self.config.deferred_heads || parent_idx.0 == 0 {
+            return;
+        }
+
+        let mut pending = self.pending_heads.lock();
+        pending.push((parent_idx, head));
+        // link the given id directly, so it doesn't have to follow every head in between
+        if parent.0 != parent_idx.0 {
+            pending.push((parent.into(), head));
+        }
+    }
+
+    /// Publish the heads of the classes extended in the generation that just ended.
+    fn publish_heads(&self) {
+        let pending = std::mem::take(&mut *self.pending_heads.lock());
+        if pending.is_empty() {
+            return;
+        }
+
+        let mut heads = self.heads.write();
+        for (old, new) in pending {
+            heads.insert(old, new);
+        }
+    }
+
+    #[inline]
+    fn head_fragment_index(&self, color_id: &ColorId) -> Option<ColorFragmentIndex> {
+        if color_id.0 < self.head().0 {
+            Some(color_id.into())
+        } else {
+            None
+        }
+    }
+}
+
+/// Compute the chain metadata of every fragment in the table, reading fragments from `reader`.
+///
+/// `reader` must be positioned at fragment 1 (just past the magic header).
+///
+/// # Errors
+///
+/// Returns an error if reading fails, or if a fragment points to a parent that does not precede it.
+fn read_chains(mut reader: impl Read, head: ColorFragmentIndex, interval: u32) -> Result<Chains> {
+    let mut chains = Chains::with_capacity(interval, head.0 as usize);
+
+    let mut buf = [0; std::mem::size_of::<ColorFragment>()];
+    for idx in 1..head.0 {
+        reader.read_exact(&mut buf)?;
+        let fragment: ColorFragment = bytemuck::pod_read_unaligned(&buf);
+        if fragment.parent().0 >= idx {
+            // parents are always written before their children
+            return Err(ColorTableError::ParentNotBefore {
+                index: idx,
+                parent: fragment.parent().0,
+            });
+        }
+        chains.push_fragment(&fragment);
+    }
+
+    Ok(chains)
+}
+
+pub struct GenerationGuard<'a> {
+    table: &'a ColorTable,
+    generation: u64,
+    // first fragment of the generation
+    start: ColorFragmentIndex,
+    // the fragments of earlier generations, mapped on first use to walk their chains
+    history: OnceLock<ColorTableMmap>,
+}
+
+impl<'a> GenerationGuard<'a> {
+    /// The number of this generation.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Creates a new color class.
+    ///
+    /// Returns the index of the new color class.
+    /// You **MUST NOT** fork or extend the returned color class until the next generation.
+    pub fn new_color_class(&self, color: u32) -> Result<ColorId> {
+        self.table.check_color(color)?;
+        if self.table.skip_color(color) {
+            return Ok(ColorId::NULL);
+        }
+        let fragment = ColorFragment {
+            color: color.into(),
+            parent_pointer: ColorFragmentIndex(0).into(),
+        };
+
+        let color_id = self.table.write_fragment(fragment)?.into();
+
+        Ok(color_id)
+    }
+
+    /// Creates a new color class, unless a color class with the same bitmap was created by this
+    /// method before.
+    ///
+    /// `hash` must be a hash of the full bitmap of the new color class, such as
+    /// [`MmapGuard::class_hash`]. If a class with the same hash exists, its id is returned and
+    /// nothing is written; otherwise a new color class is created as with
+    /// [`GenerationGuard::new_color_class`], and remembered for later calls. Classes created by
+    /// other methods are never returned.
+    ///
+    /// The hashes are written to a sidecar file on [`ColorTable::sync`], so deduplication carries
+    /// over when the table is loaded again.
+    ///
+    /// You **MUST NOT** fork or extend the returned color class until the next generation.
+    pub fn new_or_existing_color_class(&self, color: u32, hash: u128) -> Result<ColorId> {
+        // held while writing, so concurrent calls with the same hash create a single class
+        let mut class_hashes = self.table.class_hashes.lock();
+        if let Some(idx) = class_hashes.get(hash) {
+            return Ok(idx.into());
+        }
+
+        let color_id = self.new_color_class(color)?;
+        if !color_id.is_null() {
+            class_hashes.insert(hash, color_id.into());
+        }
+
+        Ok(color_id)
+    }
+
+    /// Fork a color class.
+    ///
+    /// Returns the index of the new color class.
+    /// You **MUST NOT** fork or extend the returned color class until the next generation.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the forked color class"]
+    pub fn fork_color_class(&self, parent: ColorId, color: u32) -> Result<ColorId> {
+        self.table.check_color(color)?;
+        let Some(parent_idx) = self.table.parent_index(&parent) else {
+            return Err(ColorTableError::InvalidColorId(parent.0));
+        };
+        self.table.check_merged(parent_idx)?;
+
+        let fragment = ColorFragment {
+            color: color.into(),
+            parent_pointer: parent_idx.into(),
+        };
+
+        let color_id = self.table.write_fragment(fragment)?.into();
+
+        Ok(color_id)
+    }
+
+    /// Fork a color class as it was at the end of an earlier generation.
+    ///
+    /// The chain of `parent` is walked back to its newest fragment in or before
+    /// `as_of_generation`, and the new color class is forked from there, so fragments added to
+    /// `parent` after that generation are not part of the fork. If `parent` did not exist yet at
+    /// that generation, the fork is a new color class containing only `color`.
+    ///
+    /// The fragments of earlier generations are mapped the first time this is called in a
+    /// generation, and the mapping is reused by later calls.
+    ///
+    /// Returns the index of the new color class.
+    /// You **MUST NOT** fork or extend the returned color class until the next generation.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::InvalidColorId`] if `parent` is not a valid color id, or was
+    /// written in this generation and `as_of_generation` is earlier, or an error if the color table
+    /// could not be mapped.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the forked color class"]
+    pub fn fork_color_class_as_of(
+        &self,
+        parent: ColorId,
+        as_of_generation: u64,
+        color: u32,
+    ) -> Result<ColorId> {
+        self.table.check_color(color)?;
+        let Some(mut parent_idx) = self.table.parent_index(&parent) else {
+            return Err(ColorTableError::InvalidColorId(parent.0));
+        };
+
+        if as_of_generation < self.generation {
+            // the fragments of this generation may not be written yet, so they can't be walked
+            if parent_idx >= self.start {
+                return Err(ColorTableError::InvalidColorId(parent.0));
+            }
+            let mmap = self.history()?;
+            let generations = Arc::clone(&self.table.generations.read());
+            while parent_idx.0 != 0 {
+                let (Some(generation), Some(fragment)) =
+                    (generations.find(&parent_idx), mmap.fragment(&parent_idx))
+                else {
+                    return Err(ColorTableError::InvalidColorId(parent_idx.0));
+                };
+                if generation <= as_of_generation {
+                    break;
+                }
+                parent_idx = fragment.parent();
+            }
+        }
+        self.table.check_merged(parent_idx)?;
+
+        let fragment = ColorFragment {
+            color: color.into(),
+            parent_pointer: parent_idx.into(),
+        };
+
+        let color_id = self.table.write_fragment(fragment)?.into();
+
+        Ok(color_id)
+    }
+
+    /// Get the mapping of the fragments written before this generation, mapping them if they are
+    /// not mapped yet.
+    fn history(&self) -> Result<&ColorTableMmap> {
+        if let Some(mmap) = self.history.get() {
+            return Ok(mmap);
+        }
+        let mmap = self.table.mmap_to(Some(self.start))?;
+
+        Ok(self.history.get_or_init(|| mmap))
+    }
+
+    /// Extend a color class.
+    ///
+    /// You **MUST NOT** extend the color class again until the next generation.
+    /// You may fork the color class after extending it within the same generation using the
+    /// *original* [`ColorId`] (passed as `parent`). If `deferred_heads` is enabled in the
+    /// [`ColorTableConfig`], the original id keeps referring to the class in later generations, and
+    /// writes against it start from the returned id.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the extended color class"]
+    pub fn extend_color_class(&self, parent: ColorId, color: u32) -> Result<ColorId> {
+        self.table.check_color(color)?;
+        let Some(parent_idx) = self.table.parent_index(&parent) else {
+            return Err(ColorTableError::InvalidColorId(parent.0));
+        };
+        if self.table.skip_color(color) {
+            return Ok(parent);
+        }
+        self.table.check_merged(parent_idx)?;
+
+        let fragment = ColorFragment {
+            color: color.into(),
+            parent_pointer: parent_idx.into(),
+        };
+
+        let head = self.table.write_fragment(fragment)?;
+        self.table.defer_head(&parent, parent_idx, head);
+
+        Ok(head.into())
+    }
+
+    /// Creates a new color class from the positions (`0..32`) of the bits set in this generation.
+    ///
+    /// See [`GenerationGuard::new_color_class`].
+    ///
+    /// In debug builds, panics if a position is out of range; otherwise it is ignored.
+    pub fn new_with_samples(&self, samples_in_window: &[u8]) -> Result<ColorId> {
+        self.new_color_class(color_of_samples(samples_in_window))
+    }
+
+    /// Fork a color class, with the positions (`0..32`) of the bits set in this generation.
+    ///
+    /// See [`GenerationGuard::fork_color_class`].
+    ///
+    /// In debug builds, panics if a position is out of range; otherwise it is ignored.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the forked color class"]
+    pub fn fork_with_samples(&self, parent: ColorId, samples_in_window: &[u8]) -> Result<ColorId> {
+        self.fork_color_class(parent, color_of_samples(samples_in_window))
+    }
+
+    /// Extend a color class, with the positions (`0..32`) of the bits set in this generation.
+    ///
+    /// See [`GenerationGuard::extend_color_class`].
+    ///
+    /// In debug builds, panics if a position is out of range; otherwise it is ignored.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the extended color class"]
+    pub fn extend_with_samples(
+        &self,
+        parent: ColorId,
+        samples_in_window: &[u8],
+    ) -> Result<ColorId> {
+        self.extend_color_class(parent, color_of_samples(samples_in_window))
+    }
+
+    /// Creates a new color class from the counts of the 8 samples of this generation, in a table in
+    /// the [`ColorMode::Counts`] mode.
+    ///
+    /// See [`GenerationGuard::new_color_class`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::UnsupportedColorMode`] if the table holds presence bits.
+    ///
+    /// In debug builds, panics if more than 8 counts are given. Counts above 15 are saturated.
+    pub fn new_with_counts(&self, counts: &[u8]) -> Result<ColorId> {
+        self.new_color_class(self.table.color_of_counts(counts)?)
+    }
+
+    /// Fork a color class, with the counts of the 8 samples of this generation.
+    ///
+    /// See [`GenerationGuard::fork_color_class`] and [`GenerationGuard::new_with_counts`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::UnsupportedColorMode`] if the table holds presence bits, or
+    /// [`ColorTableError::InvalidColorId`] if `parent` is not a valid color id.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the forked color class"]
+    pub fn fork_with_counts(&self, parent: ColorId, counts: &[u8]) -> Result<ColorId> {
+        self.fork_color_class(parent, self.table.color_of_counts(counts)?)
+    }
+
+    /// Extend a color class, with the counts of the 8 samples of this generation.
+    ///
+    /// See [`GenerationGuard::extend_color_class`] and [`GenerationGuard::new_with_counts`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::UnsupportedColorMode`] if the table holds presence bits, or
+    /// [`ColorTableError::InvalidColorId`] if `parent` is not a valid color id.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the extended color class"]
+    pub fn extend_with_counts(&self, parent: ColorId, counts: &[u8]) -> Result<ColorId> {
+        self.extend_color_class(parent, self.table.color_of_counts(counts)?)
+    }
+
+    /// Extend a color class by removing indices from it, e.g. to withdraw samples.
+    ///
+    /// The indices set in `mask` are removed from the word of generation `generation`, which must
+    /// be older than the current generation. One removal fragment is written for each index, so
+    /// this is meant for rare removals; the removed indices are cleared from the class wherever it
+    /// is decoded. Removing an index that is not in the class has no effect.
+    ///
+    /// The first removal is recorded in the header of the color table file, so versions of this
+    /// crate that can't read removal fragments refuse to load the table.
+    ///
+    /// Returns the id of the extended color class, or `parent` itself if `mask` is 0. As with
+    /// [`GenerationGuard::extend_color_class`], you **MUST NOT** extend the color class again until
+    /// the next generation.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::InvalidColorId`] if `parent` is not a valid color id, or
+    /// [`ColorTableError::InvalidGeneration`] if `generation` is not older than the current
+    /// generation or its indices don't fit in a `u32`.
+    #[must_use = "`parent` is not modified; you must use the returned `ColorId` to refer to the extended color class"]
+    pub fn extend_color_class_remove(
+        &self,
+        parent: ColorId,
+        generation: u64,
+        mask: u32,
+    ) -> Result<ColorId> {
+        if self.table.config.color_mode != ColorMode::Presence {
+            return Err(ColorTableError::UnsupportedColorMode {
+                mode: self.table.config.color_mode,
+            });
+        }
+        let Some(mut parent_idx) = self.table.parent_index(&parent) else {
+            return Err(ColorTableError::InvalidColorId(parent.0));
+        };
+        if generation >= self.generation || generation >= 1 << (u32::BITS - u32::BITS.ilog2()) {
+            return Err(ColorTableError::InvalidGeneration(generation));
+        }
+
+        if mask != 0 {
+            self.table.mark_removals()?;
+        }
+
+        let base = (generation * u32::BITS as u64) as u32;
+        let old_head = parent_idx;
+        let mut mask = mask;
+        while mask != 0 {
+            let fragment = ColorFragment {
+                color: (base + mask.trailing_zeros()).into(),
+                parent_pointer: (parent_idx.0 | REMOVAL_FLAG).into(),
+            };
+            parent_idx = self.table.write_fragment(fragment)?;
+            mask &= mask - 1;
+        }
+        if parent_idx != old_head {
+            self.table.defer_head(&parent, old_head, parent_idx);
+        }
+
+        Ok(parent_idx.into())
+    }
+}
+
+/// RAII guard for a memory-mapped color table.
+#[derive(Debug)]
+pub struct MmapGuard<'a>(&'a ColorTable, ColorTableMmap, MapOptions);
+
+impl<'a> MmapGuard<'a> {
+    /// Get a reference to the color table.
+    #[inline]
+    pub fn color_table(&self) -> &ColorTable {
+        self.0
+    }
+
+    /// Get the parent fragment of the given fragment, if it exists.
+    #[inline]
+    pub fn parent_of(&self, fragment: &ColorFragment) -> Option<&ColorFragment> {
+        self.1.parent_of(fragment)
     }
 
     /// Get an iterator over the color class referred to by the given color id.
     ///
     /// Iterator items are `(partial color, generation)` pairs. The order in which pairs are yielded
-    /// is unspecified. Results may be stale if a generation is in progress.
+    /// is unspecified. Results may be stale if a generation is in progress. The iterator is empty if
+    /// the color class has been deleted with [`ColorTable::tombstone`].
     pub fn color_class(&self, color_id: &ColorId) -> ClassIter<'_> {
-        let idx = self
-            .0
-            .head_fragment_index(color_id)
-            .unwrap_or(ColorFragmentIndex(0)); // invalid color id will return an empty iterator
+        ClassIter::new(self.0, &self.1, color_id)
+    }
+
+    /// Get an iterator over the color class referred to by the given color id, as it was at the
+    /// end of the given generation.
+    ///
+    /// Fragments added in later generations are skipped, along with the indices they removed (see
+    /// [`GenerationGuard::extend_color_class_remove`]). See [`ClassIter::skip_newer_than`].
+    pub fn color_class_as_of(&self, color_id: &ColorId, generation: u64) -> ClassIter<'_> {
+        let mut iter = self.color_class(color_id);
+        iter.skip_newer_than(generation);
+        iter
+    }
+
+    /// Get the generation in which the color class referred to by the given color id was created,
+    /// i.e. the generation of its oldest fragment.
+    ///
+    /// With [`ColorTableConfig::skip_interval`](crate::ColorTableConfig) set, this skips along the
+    /// chain checkpoints instead of walking the whole chain. For a [`ColorTable::map_range`]
+    /// mapping, this is the generation of the oldest mapped fragment. Returns `None` if the class
+    /// is empty or not mapped.
+    pub fn created_in(&self, color_id: &ColorId) -> Option<u64> {
+        self.color_class(color_id)
+            .last()
+            .map(|(_, generation)| generation)
+    }
+
+    /// Get the generation in which the color class referred to by the given color id was last
+    /// extended (or created, if it was never extended), i.e. the generation of its head fragment.
+    ///
+    /// Returns `None` if the class is empty or not mapped.
+    pub fn last_extended_in(&self, color_id: &ColorId) -> Option<u64> {
+        self.color_class(color_id)
+            .next()
+            .map(|(_, generation)| generation)
+    }
+
+    /// Prefetch the fragments of the color class referred to by the given color id.
+    ///
+    /// The OS is asked to read ahead the pages containing each fragment (`MADV_WILLNEED`), so that
+    /// iterating over the class later (e.g. with [`ClassIter::into_indices`]) doesn't stall on a
+    /// page fault for every fragment. A deep chain is expected to touch every page before its
+    /// head, so they are all advised at once; otherwise, the chain is walked once, advising each
+    /// page before it is read. Does nothing on non-Unix platforms, or for in-memory tables.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the OS rejects the hint.
+    pub fn prefetch(&self, color_id: &ColorId) -> Result<()> {
+        let idx = color_id.into();
+        Ok(self.1.prefetch(idx, self.0.chain_depth(&idx))?)
+    }
+
+    /// Refresh the mapping to include fragments written since it was created.
+    ///
+    /// The writer is flushed, and the file is only remapped if it has grown; otherwise the
+    /// existing mapping is reused.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if flushing or mmapping fails.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.0.remap(&mut self.1, &self.2)
+    }
+
+    /// Run a query on the mapping, failing instead of crashing if the color table file was
+    /// truncated (e.g. by another process) while mapped.
+    ///
+    /// With the `fault-guard` feature on Linux, an access past the end of the truncated file
+    /// within `f` reads absent fragments instead of killing the process with `SIGBUS`, and the
+    /// result of `f` is discarded. Accesses outside of `f` are not guarded. Once an access has
+    /// faulted, the mapping is cut short for good, so this keeps failing until the guard is
+    /// dropped. Without the feature, this only runs `f`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::MappingFaulted`] if an access to the mapping faulted.
+    pub fn checked<R>(&self, f: impl FnOnce(&Self) -> R) -> Result<R> {
+        checked(&self.1, || f(self))
+    }
+
+    /// Get the fragments written in the given generation, in file order.
+    ///
+    /// Fragments that are not mapped (e.g. written after the mapping was created, or outside of a
+    /// [`ColorTable::map_range`] mapping) are skipped.
+    pub fn fragments_in_generation(
+        &self,
+        generation: u64,
+    ) -> impl Iterator<Item = (ColorFragmentIndex, &ColorFragment)> {
+        fragments_in(&self.1, self.0.generation_range(generation))
+    }
+
+    /// Get the ids of the color classes created, forked or extended in the given generation.
+    ///
+    /// Every fragment is the head of the color class it was written for, so these are the ids of
+    /// the fragments in the generation. Use [`ColorFragment::parent`] to tell new color
+    /// classes apart from the others.
+    pub fn classes_created_in(&self, generation: u64) -> impl Iterator<Item = ColorId> {
+        self.fragments_in_generation(generation)
+            .map(|(idx, _)| idx.into())
+    }
+}
+
+/// Owned RAII guard for a memory-mapped color table.
+///
+/// This is the `'static` counterpart of [`MmapGuard`], created with [`ColorTable::map_owned`].
+#[derive(Debug)]
+pub struct OwnedMmapGuard(Arc<ColorTable>, ColorTableMmap, MapOptions);
+
+impl OwnedMmapGuard {
+    /// Get a reference to the color table.
+    #[inline]
+    pub fn color_table(&self) -> &Arc<ColorTable> {
+        &self.0
+    }
+
+    /// Get the parent fragment of the given fragment, if it exists.
+    #[inline]
+    pub fn parent_of(&self, fragment: &ColorFragment) -> Option<&ColorFragment> {
+        self.1.parent_of(fragment)
+    }
+
+    /// Get an iterator over the color class referred to by the given color id.
+    ///
+    /// See [`MmapGuard::color_class`].
+    pub fn color_class(&self, color_id: &ColorId) -> ClassIter<'_> {
+        ClassIter::new(&self.0, &self.1, color_id)
+    }
+
+    /// Get an iterator over the color class referred to by the given color id, as it was at the
+    /// end of the given generation.
+    ///
+    /// See [`MmapGuard::color_class_as_of`].
+    pub fn color_class_as_of(&self, color_id: &ColorId, generation: u64) -> ClassIter<'_> {
+        let mut iter = self.color_class(color_id);
+        iter.skip_newer_than(generation);
+        iter
+    }
+
+    /// Get the generation in which the color class referred to by the given color id was created.
+    ///
+    /// See [`MmapGuard::created_in`].
+    pub fn created_in(&self, color_id: &ColorId) -> Option<u64> {
+        self.color_class(color_id)
+            .last()
+            .map(|(_, generation)| generation)
+    }
+
+    /// Get the generation in which the color class referred to by the given color id was last
+    /// extended.
+    ///
+    /// See [`MmapGuard::last_extended_in`].
+    pub fn last_extended_in(&self, color_id: &ColorId) -> Option<u64> {
+        self.color_class(color_id)
+            .next()
+            .map(|(_, generation)| generation)
+    }
+
+    /// Prefetch the fragments of the color class referred to by the given color id.
+    ///
+    /// See [`MmapGuard::prefetch`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the OS rejects the hint.
+    pub fn prefetch(&self, color_id: &ColorId) -> Result<()> {
+        let idx = color_id.into();
+        Ok(self.1.prefetch(idx, self.0.chain_depth(&idx))?)
+    }
+
+    /// Refresh the mapping to include fragments written since it was created.
+    ///
+    /// See [`MmapGuard::refresh`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if flushing or mmapping fails.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.0.remap(&mut self.1, &self.2)
+    }
+
+    /// Run a query on the mapping, failing instead of crashing if the color table file was
+    /// truncated while mapped.
+    ///
+    /// See [`MmapGuard::checked`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::MappingFaulted`] if an access to the mapping faulted.
+    pub fn checked<R>(&self, f: impl FnOnce(&Self) -> R) -> Result<R> {
+        checked(&self.1, || f(self))
+    }
+
+    /// Get the fragments written in the given generation, in file order.
+    ///
+    /// See [`MmapGuard::fragments_in_generation`].
+    pub fn fragments_in_generation(
+        &self,
+        generation: u64,
+    ) -> impl Iterator<Item = (ColorFragmentIndex, &ColorFragment)> {
+        fragments_in(&self.1, self.0.generation_range(generation))
+    }
+
+    /// Get the ids of the color classes created, forked or extended in the given generation.
+    ///
+    /// See [`MmapGuard::classes_created_in`].
+    pub fn classes_created_in(&self, generation: u64) -> impl Iterator<Item = ColorId> {
+        self.fragments_in_generation(generation)
+            .map(|(idx, _)| idx.into())
+    }
+}
+
+/// Run `f` with faults on mappings caught (see [`FaultWatch::guarded`]), failing with
+/// [`ColorTableError::MappingFaulted`] if an access to `mmap` faulted.
+fn checked<R>(mmap: &ColorTableMmap, f: impl FnOnce() -> R) -> Result<R> {
+    if mmap.faulted() {
+        return Err(ColorTableError::MappingFaulted);
+    }
+    let res = FaultWatch::guarded(f);
+    if mmap.faulted() {
+        return Err(ColorTableError::MappingFaulted);
+    }
+
+    Ok(res)
+}
 
-        ClassIter { map: self, idx }
+/// The mapped fragments in `range`, if any.
+fn fragments_in(
+    mmap: &ColorTableMmap,
+    range: Option<Range<ColorFragmentIndex>>,
+) -> impl Iterator<Item = (ColorFragmentIndex, &ColorFragment)> {
+    range
+        .into_iter()
+        .flat_map(|range| range.start.0..range.end.0)
+        .filter_map(|idx| {
+            let idx = ColorFragmentIndex(idx);
+            mmap.fragment(&idx).map(|fragment| (idx, fragment))
+        })
+}
+
+/// Check that `n` fragments starting at index `start` fit in a table (see [`MAX_FRAGMENTS`]).
+fn check_capacity(start: ColorFragmentIndex, n: usize) -> Result<()> {
+    if u64::from(start.0) + n as u64 > u64::from(MAX_FRAGMENTS) {
+        return Err(ColorTableError::TableFull {
+            limit: MAX_FRAGMENTS,
+        });
+    }
+
+    Ok(())
+}
+
+/// Read a sidecar file with `read`, or return `None` if it doesn't exist.
+fn read_sidecar<T>(
+    path: &Path,
+    read: impl FnOnce(&mut File, &Path) -> Result<T>,
+) -> Result<Option<T>> {
+    match File::open(path) {
+        Ok(mut file) => read(&mut file, path).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).at(path),
+    }
+}
+
+/// Decode a sidecar file written by [`encode_sidecar`].
+fn decode_sidecar<T: Decode<()>>(file: &mut File, _path: &Path) -> Result<T> {
+    Ok(bincode::decode_from_std_read(
+        &mut io::BufReader::new(file),
+        crate::BINCODE_CONFIG,
+    )?)
+}
+
+/// Write a sidecar file with `write` if `keep` is set, or remove it otherwise.
+///
+/// The file is opened without truncating it, so `write` can append to it; it is up to `write` to
+/// truncate it if needed.
+fn write_sidecar(
+    config: &ColorTableConfig,
+    path: &Path,
+    keep: bool,
+    write: impl FnOnce(&mut File, &Path) -> Result<()>,
+) -> Result<()> {
+    if !keep {
+        return match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).at(path),
+            _ => Ok(()),
+        };
     }
+
+    let mut file = config
+        .open_file(
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false),
+            path,
+        )
+        .at(path)?;
+    write(&mut file, path)
+}
+
+/// Replace the contents of a sidecar file with `value`, encoded with bincode, and sync it.
+fn encode_sidecar(file: &mut File, path: &Path, value: &impl Encode) -> Result<()> {
+    file.set_len(0).at(path)?;
+    file.rewind().at(path)?;
+    let mut writer = io::BufWriter::new(&mut *file);
+    bincode::encode_into_std_write(value, &mut writer, crate::BINCODE_CONFIG)?;
+    writer.flush().at(path)?;
+    drop(writer);
+
+    file.sync_all().at(path)
+}
+
+/// Write the generations file of the table in `dir`.
+fn write_generations(
+    dir: &Path,
+    generations: &Generations,
+    config: &ColorTableConfig,
+) -> Result<()> {
+    // the generations file may be mapped, so replace it rather than truncating it
+    let generations_path = dir.join(&config.generations_file_name);
+    let tmp_path = generations_path.with_extension("tmp");
+    let mut generations_writer = io::BufWriter::new(config.create_file(&tmp_path)?);
+    generations.write_to(&mut generations_writer, config.generations_format)?;
+    generations_writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|file| file.sync_all())
+        .at(&tmp_path)?;
+    std::fs::rename(&tmp_path, &generations_path).at(&generations_path)?;
+
+    Ok(())
 }
 
 impl Drop for ColorTable {
@@ -561,46 +2503,380 @@ impl Drop for ColorTable {
 /// Iterator over a color class.
 #[derive(Debug)]
 pub struct ClassIter<'c> {
-    map: &'c MmapGuard<'c>,
+    mmap: &'c ColorTableMmap,
+    // snapshot of the generations when the iterator was created, so iteration does not lock
+    generations: Arc<Generations>,
+    // only locked when skipping ahead
+    chains: &'c RwLock<Chains>,
+    #[cfg(feature = "roaring")]
+    bitmap_checkpoints: Arc<BitmapCheckpoints>,
     idx: ColorFragmentIndex,
+    // number of fragments left in the chain
+    remaining: usize,
+    // indices removed by the removal fragments visited so far
+    removed: Vec<u32>,
 }
 
 impl<'c> ClassIter<'c> {
+    fn new(table: &'c ColorTable, mmap: &'c ColorTableMmap, color_id: &ColorId) -> Self {
+        // deleted classes are empty
+        let color_id = if !color_id.is_null() && table.is_tombstoned(color_id) {
+            &ColorId(0)
+        } else {
+            color_id
+        };
+        let iter = Self::from_parts(
+            mmap,
+            Arc::clone(&table.generations.read()),
+            &table.chains,
+            // materialized bitmaps include fragments outside of a partial mapping
+            #[cfg(feature = "roaring")]
+            if mmap.is_partial() {
+                Arc::new(BitmapCheckpoints::new())
+            } else {
+                Arc::clone(&table.bitmap_checkpoints.read())
+            },
+            color_id,
+        );
+        table
+            .metrics
+            .with(|metrics| metrics.chain_length(iter.remaining));
+
+        iter
+    }
+
+    fn from_parts(
+        mmap: &'c ColorTableMmap,
+        generations: Arc<Generations>,
+        chains: &'c RwLock<Chains>,
+        #[cfg(feature = "roaring")] bitmap_checkpoints: Arc<BitmapCheckpoints>,
+        color_id: &ColorId,
+    ) -> Self {
+        // the mapping is a snapshot of the written fragments, so it is enough to validate the id
+        // invalid color id (or one written after the table was mapped) will return an empty iterator
+        let idx = Some(ColorFragmentIndex::from(color_id))
+            .filter(|idx| mmap.fragment(idx).is_some())
+            .unwrap_or(ColorFragmentIndex(0));
+
+        // all ancestors of a mapped fragment are also mapped, so the depth is exact, unless only
+        // part of the table is mapped
+        let remaining = if idx.0 == 0 {
+            0
+        } else if mmap.is_partial() {
+            std::iter::successors(mmap.fragment(&idx), |fragment| mmap.parent_of(fragment)).count()
+        } else {
+            chains.read().depth(&idx) as usize
+        };
+
+        Self {
+            mmap,
+            generations,
+            chains,
+            #[cfg(feature = "roaring")]
+            bitmap_checkpoints,
+            idx,
+            remaining,
+            removed: Vec::new(),
+        }
+    }
+
+    /// Advance the iterator past all fragments newer than the given generation.
+    ///
+    /// Indices removed in the skipped generations are not removed from the remaining fragments.
+    ///
+    /// If checkpoints are enabled (see [`ColorTableConfig`]), this skips over runs of fragments
+    /// without visiting each one.
+    pub fn skip_newer_than(&mut self, generation: u64) {
+        let chains = self.chains.read();
+        let generations = Arc::clone(&self.generations);
+        let newer =
+            |idx: &ColorFragmentIndex| generations.find(idx).is_some_and(|g| g > generation);
+
+        while self.idx != ColorFragmentIndex(0) && newer(&self.idx) {
+            match self.checkpoint_below(&chains) {
+                Some((target, distance)) if newer(&target) => self.jump(target, distance),
+                _ => self.step(),
+            }
+        }
+    }
+
+    /// Get the nearest checkpoint strictly below the current fragment, and its distance in links.
+    #[inline]
+    fn checkpoint_below(&self, chains: &Chains) -> Option<(ColorFragmentIndex, usize)> {
+        let frag = self.mmap.fragment(&self.idx)?;
+        let target = chains.checkpoint_at_or_below(&frag.parent())?;
+        // the checkpoint may be outside of a partial mapping
+        self.mmap.fragment(&target)?;
+
+        Some((
+            target,
+            (chains.depth(&self.idx) - chains.depth(&target)) as usize,
+        ))
+    }
+
+    #[inline]
+    fn jump(&mut self, target: ColorFragmentIndex, distance: usize) {
+        self.idx = target;
+        self.remaining -= distance;
+    }
+
+    /// Move to the parent fragment without yielding the current one. Removal fragments are not
+    /// applied.
+    #[inline]
+    fn step(&mut self) {
+        match self.mmap.fragment(&self.idx) {
+            Some(frag) => {
+                self.idx = frag.parent();
+                self.remaining -= 1;
+            }
+            None => {
+                self.idx = ColorFragmentIndex(0);
+                self.remaining = 0;
+            }
+        }
+    }
+
     /// Convert the iterator into a roaring bitmap.
+    ///
+    /// If a bitmap has been materialized for a fragment in the chain (see
+    /// [`ColorTable::materialize_bitmaps`]), decoding starts from there.
+    ///
+    /// Indices are truncated to `u32`, so generations from `2^27` onwards can't be represented.
+    /// Use [`ClassIter::into_treemap`] for tables with that many generations.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if an index does not fit in a `u32`.
     #[cfg(feature = "roaring")]
-    pub fn into_bitmap(self) -> roaring::RoaringBitmap {
-        let mut bitmap = roaring::RoaringBitmap::new();
-        let mut set = self.into_indices();
-        set.sort_unstable();
-        bitmap.extend(set.into_iter().map(|i| i as u32));
+    pub fn into_bitmap(mut self) -> roaring::RoaringBitmap {
+        // fragments are yielded newest first, so reversing them gives (mostly) ascending windows
+        // and bits can be appended without sorting
+        let mut fragments = Vec::new();
+        let mut bitmap = loop {
+            if let Some(bitmap) = self.bitmap_checkpoints.get(&self.idx) {
+                let mut bitmap = bitmap.clone();
+                for &idx in &self.removed {
+                    bitmap.remove(idx);
+                }
+                break bitmap;
+            }
+            match self.next() {
+                Some(fragment) => fragments.push(fragment),
+                None => break roaring::RoaringBitmap::new(),
+            }
+        };
+
+        for (mut color, gen_) in fragments.into_iter().rev() {
+            let base = gen_ * u32::BITS as u64;
+            debug_assert!(
+                base + u64::from(u32::BITS) <= 1 << u32::BITS,
+                "generation {gen_} overflows a u32 index, use into_treemap"
+            );
+            while color != 0 {
+                let idx = (base + color.trailing_zeros() as u64) as u32;
+                // try_push only succeeds if idx is greater than the current max
+                if bitmap.try_push(idx).is_err() {
+                    bitmap.insert(idx);
+                }
+                color &= color - 1;
+            }
+        }
         bitmap
     }
 
+    /// Convert the iterator into a roaring treemap, which holds 64-bit indices.
+    ///
+    /// Like [`ClassIter::into_bitmap`], but supports any generation.
+    #[cfg(feature = "roaring")]
+    pub fn into_treemap(mut self) -> roaring::RoaringTreemap {
+        let mut fragments = Vec::new();
+        let mut treemap = loop {
+            // materialized bitmaps only hold indices below 2^32
+            if let Some(bitmap) = self.bitmap_checkpoints.get(&self.idx) {
+                let mut treemap = roaring::RoaringTreemap::from_bitmaps([(0, bitmap.clone())]);
+                for &idx in &self.removed {
+                    treemap.remove(idx.into());
+                }
+                break treemap;
+            }
+            match self.next() {
+                Some(fragment) => fragments.push(fragment),
+                None => break roaring::RoaringTreemap::new(),
+            }
+        };
+
+        for (mut color, gen_) in fragments.into_iter().rev() {
+            let base = gen_ * u32::BITS as u64;
+            while color != 0 {
+                let idx = base + color.trailing_zeros() as u64;
+                if treemap.try_push(idx).is_err() {
+                    treemap.insert(idx);
+                }
+                color &= color - 1;
+            }
+        }
+        treemap
+    }
+
+    /// Convert the iterator into a dense bit vector of `len` bits.
+    ///
+    /// Each fragment holds a whole word of the bit vector, so the colors are copied into place
+    /// without decoding their indices.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the color class contains an index that is not less than `len`.
+    #[cfg(feature = "bitvec")]
+    pub fn into_bitvec(self, len: usize) -> bitvec::vec::BitVec<u32, bitvec::order::Lsb0> {
+        let mut bits = bitvec::vec::BitVec::repeat(false, len);
+        let words = bits.as_raw_mut_slice();
+        for (color, gen_) in self.filter(|(color, _)| *color != 0) {
+            let max = gen_ * u32::BITS as u64 + (u32::BITS - 1 - color.leading_zeros()) as u64;
+            assert!(
+                max < len as u64,
+                "index {max} out of range for bit vector of length {len}"
+            );
+            words[gen_ as usize] |= color;
+        }
+        bits
+    }
+
     /// Convert the iterator into a vector of indices.
     ///
     /// Indices are NOT sorted.
     pub fn into_indices(self) -> Vec<usize> {
-        #[inline]
-        fn decode_bitmap(buf: &mut Vec<usize>, mut bm: u32, k: u64) {
-            while bm != 0 {
-                let low = bm & bm.wrapping_neg();
-                let idx = bm.trailing_zeros() as u64;
-                buf.push((k * std::mem::size_of_val(&bm) as u64 * 8 + idx) as usize);
-                bm ^= low;
+        // collecting the fragments first lets the indices be allocated exactly
+        let fragments = self.collect::<Vec<_>>();
+        let len = fragments
+            .iter()
+            .map(|(color, _)| color.count_ones() as usize)
+            .sum();
+
+        let mut indices = Vec::with_capacity(len);
+        decode_words(&mut indices, fragments);
+
+        indices
+    }
+
+    /// Convert the iterator into `(sample, count)` pairs, sorted by sample, for a table in the
+    /// [`ColorMode::Counts`] mode.
+    ///
+    /// Counts of the same sample in several fragments (e.g. in forks and extensions written in the
+    /// same generation) are added up. Samples with a count of 0 are left out.
+    pub fn into_counts(self) -> Vec<(u64, u32)> {
+        let mut counts = std::collections::BTreeMap::new();
+        for (color, generation) in self {
+            for slot in 0..COUNTS_PER_FRAGMENT {
+                let count = color >> (slot as u32 * COUNT_BITS) & ((1 << COUNT_BITS) - 1);
+                if count != 0 {
+                    *counts
+                        .entry(generation * COUNTS_PER_FRAGMENT + slot)
+                        .or_insert(0) += count;
+                }
             }
         }
 
-        let mut indices = if let Some(len) = self.size_hint().1 {
-            Vec::with_capacity(len * 32) // reasonable estimate; in normal usage this will take about 15 kB at most
-        } else {
-            Vec::new()
-        };
+        counts.into_iter().collect()
+    }
+
+    /// Append the remaining indices of the color class to `buf`, so its allocation can be reused
+    /// across queries.
+    ///
+    /// Indices are NOT sorted. `buf` is not cleared first.
+    pub fn collect_indices_into(&mut self, buf: &mut Vec<usize>) {
+        decode_words(buf, self);
+    }
+
+    /// Append the remaining indices of the color class to `buf`, which stores up to `N` indices
+    /// inline.
+    ///
+    /// See [`ClassIter::collect_indices_into`].
+    #[cfg(feature = "smallvec")]
+    pub fn collect_indices_into_smallvec<const N: usize>(
+        &mut self,
+        buf: &mut smallvec::SmallVec<[usize; N]>,
+    ) {
+        decode_words(buf, self);
+    }
+}
+
+/// Buffers that indices can be decoded into.
+trait IndexBuf {
+    fn push(&mut self, idx: usize);
+
+    fn reserve(&mut self, additional: usize);
+}
+
+impl IndexBuf for Vec<usize> {
+    #[inline]
+    fn push(&mut self, idx: usize) {
+        Vec::push(self, idx);
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) {
+        Vec::reserve(self, additional);
+    }
+}
+
+#[cfg(feature = "smallvec")]
+impl<const N: usize> IndexBuf for smallvec::SmallVec<[usize; N]> {
+    #[inline]
+    fn push(&mut self, idx: usize) {
+        smallvec::SmallVec::push(self, idx);
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) {
+        smallvec::SmallVec::reserve(self, additional);
+    }
+}
 
-        for (color, gen_) in self {
-            decode_bitmap(&mut indices, color, gen_);
+/// Number of samples counted by each fragment in the [`ColorMode::Counts`] mode.
+const COUNTS_PER_FRAGMENT: u64 = 8;
+/// Width of each count, in bits.
+const COUNT_BITS: u32 = u32::BITS / COUNTS_PER_FRAGMENT as u32;
+
+/// Build a fragment color from bit positions within a generation.
+fn color_of_samples(samples: &[u8]) -> u32 {
+    samples.iter().fold(0, |color, &sample| {
+        debug_assert!(
+            u32::from(sample) < u32::BITS,
+            "sample position {sample} is out of range for a generation"
+        );
+        color | 1u32.checked_shl(u32::from(sample)).unwrap_or(0)
+    })
+}
+
+/// Decode the indices of a chain's fragments a 64-bit word at a time.
+///
+/// A chain visits generations in descending order, and holds at most one fragment per generation,
+/// so the fragments of generations `2k + 1` and `2k` are next to each other, and are decoded
+/// together as the high and low halves of word `k`.
+fn decode_words(buf: &mut impl IndexBuf, fragments: impl IntoIterator<Item = (u32, u64)>) {
+    let mut word = 0;
+    let mut word_index = 0;
+    for (color, generation) in fragments {
+        if color == 0 {
+            continue;
         }
+        if generation / 2 != word_index {
+            decode_word(buf, word, word_index);
+            word = 0;
+            word_index = generation / 2;
+        }
+        word |= u64::from(color) << (generation % 2 * u64::from(u32::BITS));
+    }
+    decode_word(buf, word, word_index);
+}
 
-        indices
+#[inline]
+fn decode_word(buf: &mut impl IndexBuf, mut word: u64, word_index: u64) {
+    buf.reserve(word.count_ones() as usize);
+    let base = word_index * u64::from(u64::BITS);
+    while word != 0 {
+        buf.push((base + u64::from(word.trailing_zeros())) as usize);
+        word &= word - 1;
     }
 }
 
@@ -609,36 +2885,74 @@ impl<'c> Iterator for ClassIter<'c> {
     type Item = (u32, u64); // color, generation
 
     fn next(&mut self) -> Option<Self::Item> {
-        let frag = self.map.fragment(&self.idx)?;
-
-        let res = (
-            frag.color.get(),
-            *self
-                .map
-                .color_table()
-                .generations
-                .read()
-                .find(&self.idx)
-                .expect("bug: missing generation"),
-        );
-        self.idx = frag.parent_pointer;
-        Some(res)
-    }
+        let frag = self.mmap.fragment(&self.idx)?;
+        // mappings are only created if every fragment belongs to a generation, but a
+        // `ColorTableReader` may follow a log that doesn't cover its fragments
+        let Some(generation) = self.generations.find(&self.idx) else {
+            self.idx = ColorFragmentIndex(0);
+            self.remaining = 0;
+            return None;
+        };
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        let lower = if self.idx == ColorFragmentIndex(0) {
+        // removals only clear indices of older fragments, and are yielded as empty words
+        let color = if frag.is_removal() {
+            self.removed.push(frag.color.get());
             0
         } else {
-            1
+            self.removed
+                .iter()
+                .filter(|&&idx| u64::from(idx / u32::BITS) == generation)
+                .fold(frag.color.get(), |color, idx| {
+                    color & !(1 << (idx % u32::BITS))
+                })
         };
+        self.idx = frag.parent();
+        self.remaining -= 1;
+        Some((color, generation))
+    }
 
-        let upper = self
-            .map
-            .color_table()
-            .generations
-            .read()
-            .find(&self.idx)
-            .map(|g| *g as usize + 1);
-        (lower, upper)
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+
+    fn nth(&mut self, mut n: usize) -> Option<Self::Item> {
+        if n >= self.remaining {
+            self.idx = ColorFragmentIndex(0);
+            self.remaining = 0;
+            return None;
+        }
+
+        if n > 0 {
+            let chains = self.chains.read();
+            // skipped removal fragments must still be applied, so they are visited one by one
+            if chains.has_removals() {
+                drop(chains);
+                for _ in 0..n {
+                    self.next();
+                }
+                return self.next();
+            }
+            while n > 0 {
+                match self.checkpoint_below(&chains) {
+                    Some((target, distance)) if distance <= n => {
+                        self.jump(target, distance);
+                        n -= distance;
+                    }
+                    _ => {
+                        self.step();
+                        n -= 1;
+                    }
+                }
+            }
+        }
+
+        self.next()
+    }
+
+    fn last(mut self) -> Option<Self::Item> {
+        // skips along the chain checkpoints, instead of visiting every fragment
+        self.nth(self.remaining.checked_sub(1)?)
     }
 }
+
+impl<'c> ExactSizeIterator for ClassIter<'c> {}
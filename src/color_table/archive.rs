@@ -0,0 +1,256 @@
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::lock::TableLock;
+use super::{
+    Access, ColorFragment, ColorFragmentIndex, ColorTable, Header, REMOVAL_FLAG, check_capacity,
+};
+use crate::generations::Generations;
+use crate::{ColorTableConfig, ColorTableError, PathContext, Result};
+
+/// Header of an archive file, followed by the generations and the fragments. The last byte is the
+/// archive version.
+const ARCHIVE_MAGIC: [u8; 8] = *b"CTBZ\0\0\0\x03";
+
+/// Version 1 archives don't have the single-bit flag.
+const ARCHIVE_VERSION_1: u8 = 1;
+
+/// Version 2 archives don't have the removal flag.
+const ARCHIVE_VERSION_2: u8 = 2;
+
+impl ColorTable {
+    /// Writes the color table to a compact archive file.
+    ///
+    /// Archives store each parent pointer as the distance back to the parent, and each color as a
+    /// variable-length integer, so fragments of recently extended classes, in generations with few
+    /// samples, take a few bytes instead of 8. Colors with a single bit set (e.g. a class gaining
+    /// one new sample) only store the position of the bit, so most such fragments take 2 bytes.
+    /// Archives can't be mapped or queried; use [`ColorTable::from_archive`] to turn one back into
+    /// a color table.
+    ///
+    /// This method blocks until no generation is in progress. Returns the size of the archive in
+    /// bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the archive could not be written.
+    pub fn write_archive(&self, path: impl AsRef<Path>) -> Result<u64> {
+        let path = path.as_ref();
+        let _guard = self.generation_lock.lock();
+        let generations = Arc::clone(&self.generations.read());
+        let mmap = self.mmap()?;
+
+        let mut writer = CountingWriter(BufWriter::new(self.config.create_file(path)?), 0);
+        writer.write_all(&ARCHIVE_MAGIC).at(path)?;
+        bincode::encode_into_std_write(generations.as_ref(), &mut writer, crate::BINCODE_CONFIG)?;
+        write_varint(&mut writer, mmap.len().saturating_sub(1) as u64).at(path)?;
+        for (idx, fragment) in mmap.iter().enumerate().skip(1) {
+            // 0 means no parent, since a fragment can't be its own parent
+            let distance = match fragment.parent().0 {
+                0 => 0,
+                parent => idx as u64 - u64::from(parent),
+            };
+            // the low bits of the distance flag removal fragments, and colors with a single bit set
+            let tag = (distance << 1 | u64::from(fragment.is_removal())) << 1;
+            let color = fragment.color.get();
+            if color.is_power_of_two() && !fragment.is_removal() {
+                write_varint(&mut writer, tag | 1).at(path)?;
+                writer.write_all(&[color.trailing_zeros() as u8]).at(path)?;
+            } else {
+                write_varint(&mut writer, tag).at(path)?;
+                write_varint(&mut writer, color.into()).at(path)?;
+            }
+        }
+
+        let CountingWriter(writer, len) = writer;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .at(path)?;
+
+        Ok(len)
+    }
+
+    /// Creates a color table in `dir` from an archive written by [`ColorTable::write_archive`].
+    ///
+    /// Any existing color table in the directory is overwritten. Materialized bitmaps, the hashes
+    /// of deduplicated color classes and deleted color ids are not archived.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::BadMagic`] if the file is not an archive, an error if the archive
+    /// is corrupted, [`ColorTableError::Locked`] if a color table is open in the directory, or an
+    /// I/O error if the files could not be written.
+    pub fn from_archive(
+        archive: impl AsRef<Path>,
+        dir: impl AsRef<Path>,
+        config: ColorTableConfig,
+    ) -> Result<Self> {
+        let (archive, dir) = (archive.as_ref(), dir.as_ref());
+        let lock = TableLock::acquire(&dir.join(&config.lock_file_name), &config)?;
+
+        let mut reader = BufReader::new(std::fs::File::open(archive).at(archive)?);
+        let mut magic = [0; ARCHIVE_MAGIC.len()];
+        if reader.read_exact(&mut magic).is_err() || magic[..7] != ARCHIVE_MAGIC[..7] {
+            return Err(ColorTableError::BadMagic {
+                path: archive.to_path_buf(),
+            });
+        }
+        let version = magic[7];
+        if ![ARCHIVE_VERSION_1, ARCHIVE_VERSION_2, ARCHIVE_MAGIC[7]].contains(&version) {
+            return Err(ColorTableError::UnsupportedVersion {
+                path: archive.to_path_buf(),
+                version,
+            });
+        }
+        let generations: Generations =
+            bincode::decode_from_std_read(&mut reader, crate::BINCODE_CONFIG)?;
+
+        let path = dir.join(&config.color_table_file_name);
+        let mut writer = BufWriter::with_capacity(config.buffer_size, config.create_file(&path)?);
+        writer
+            .write_all(&Header::for_config(&config).to_bytes())
+            .at(&path)?;
+        let len = read_varint(&mut reader).at(archive)?;
+        check_capacity(
+            ColorFragmentIndex(1),
+            usize::try_from(len).unwrap_or(usize::MAX),
+        )?;
+        let mut removals = false;
+        for idx in 1..=len {
+            let mut distance = read_varint(&mut reader).at(archive)?;
+            let single_bit = version >= ARCHIVE_VERSION_2 && take_flag(&mut distance);
+            let removal = version > ARCHIVE_VERSION_2 && take_flag(&mut distance);
+            removals |= removal;
+            let color = if single_bit {
+                let mut bit = [0];
+                reader.read_exact(&mut bit).at(archive)?;
+                1u64.checked_shl(bit[0].into()).unwrap_or(u64::MAX)
+            } else {
+                read_varint(&mut reader).at(archive)?
+            };
+            let parent = match distance {
+                0 => 0,
+                distance => idx
+                    .checked_sub(distance)
+                    .filter(|parent| *parent != 0)
+                    .ok_or(ColorTableError::ParentNotBefore {
+                        index: idx as u32,
+                        parent: idx.wrapping_sub(distance) as u32,
+                    })?,
+            };
+            let fragment = ColorFragment {
+                parent_pointer: (parent as u32 | if removal { REMOVAL_FLAG } else { 0 }).into(),
+                color: u32::try_from(color)
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+                    .at(archive)?
+                    .into(),
+            };
+            writer.write_all(bytemuck::bytes_of(&fragment)).at(&path)?;
+        }
+        if removals {
+            let header = Header::for_config(&config).with_removals(true);
+            writer
+                .seek(SeekFrom::Start(0))
+                .and_then(|_| writer.write_all(&header.to_bytes()))
+                .at(&path)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .at(&path)?;
+
+        let generations_path = dir.join(&config.generations_file_name);
+        let mut writer = BufWriter::new(config.create_file(&generations_path)?);
+        generations.write_to(&mut writer, config.generations_format)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .at(&generations_path)?;
+
+        // sidecar files of a previous table would refer to the wrong fragments
+        #[cfg_attr(not(feature = "roaring"), allow(unused_mut))]
+        let mut sidecars = vec![
+            dir.join(&config.class_hashes_file_name),
+            dir.join(&config.tombstones_file_name),
+            dir.join(&config.heads_file_name),
+            dir.join(&config.payloads_file_name),
+            dir.join(&config.running_counts_file_name),
+        ];
+        #[cfg(feature = "roaring")]
+        sidecars.push(dir.join(&config.bitmap_checkpoints_file_name));
+        for sidecar in sidecars {
+            match std::fs::remove_file(&sidecar) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).at(sidecar),
+                _ => {}
+            }
+        }
+
+        let color_table = config
+            .color_table_options()
+            .read(true)
+            .append(true)
+            .open(&path)
+            .at(&path)?;
+        Self::load_from(dir, Some(lock), color_table, config, Access::Write)
+    }
+}
+
+/// Writer that counts the bytes written through it.
+struct CountingWriter<W>(W, u64);
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1 += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Remove the low bit of `tag`, and return whether it was set.
+fn take_flag(tag: &mut u64) -> bool {
+    let set = *tag & 1 == 1;
+    *tag >>= 1;
+    set
+}
+
+/// Write an unsigned LEB128 integer.
+fn write_varint(writer: &mut impl Write, mut value: u64) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&buf[..len])
+}
+
+/// Read an unsigned LEB128 integer.
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(io::ErrorKind::InvalidData.into())
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use super::{ColorTable, GenerationGuard, OwnedMmapGuard};
+use crate::{ColorTableConfig, Result};
+
+impl ColorTable {
+    /// Syncs the color table to disk on the tokio blocking thread pool, so async callers don't
+    /// block an executor thread on file I/O.
+    ///
+    /// See [`ColorTable::sync`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sync fails, or if the runtime is shutting down and the sync could
+    /// not be run.
+    pub async fn sync_async(self: &Arc<Self>, config: Option<ColorTableConfig>) -> Result<()> {
+        let table = Arc::clone(self);
+        spawn_blocking(move || table.sync(config.as_ref())).await
+    }
+
+    /// Runs a generation on the tokio blocking thread pool, so async callers don't block an
+    /// executor thread while waiting for the generation lock or writing fragments.
+    ///
+    /// See [`ColorTable::with_generation`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the generation could not be started or ended, or if the runtime is
+    /// shutting down and the generation could not be run.
+    pub async fn with_generation_async<R: Send + 'static>(
+        self: &Arc<Self>,
+        generation: u64,
+        f: impl FnOnce(GenerationGuard<'_>) -> R + Send + 'static,
+    ) -> Result<R> {
+        let table = Arc::clone(self);
+        spawn_blocking(move || table.with_generation(generation, f)).await
+    }
+
+    /// Maps the color table and runs `f` on the mapping on the tokio blocking thread pool, so
+    /// async callers don't block an executor thread on page faults while walking chains.
+    ///
+    /// See [`ColorTable::map_owned`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the table could not be mapped, or if the runtime is shutting down and
+    /// `f` could not be run.
+    pub async fn query_async<R: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&OwnedMmapGuard) -> R + Send + 'static,
+    ) -> Result<R> {
+        let table = Arc::clone(self);
+        spawn_blocking(move || Ok(f(&table.map_owned()?))).await
+    }
+}
+
+/// Run `f` on the tokio blocking thread pool, resuming its panic on the calling task if it panics.
+async fn spawn_blocking<R: Send + 'static>(
+    f: impl FnOnce() -> Result<R> + Send + 'static,
+) -> Result<R> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        // blocking tasks are only cancelled if the runtime shuts down before they start
+        Err(e) => Err(std::io::Error::other(e).into()),
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::{ColorFragmentIndex, ColorTable, encode_sidecar, write_sidecar};
+use crate::heads::Heads;
+use crate::{PathContext, Result};
+
+impl ColorTable {
+    /// Copies a consistent snapshot of the color table to the given directory.
+    ///
+    /// The snapshot contains all generations that had ended when this method was called; a
+    /// generation in progress is not included, and is not waited for. Queries and writes can
+    /// continue while the files are copied. The copy uses the file names from this table's config;
+    /// absolute paths in the config are replaced by their file names, so all files are written to `dir`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the snapshot files could not be written (e.g. if the directory does not exist).
+    pub fn backup_to(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let generations = self.generations.read().committed();
+        let end = generations
+            .last_range_end()
+            .unwrap_or(ColorFragmentIndex(1));
+
+        // mapping flushes the writer, so all fragments of ended generations are included
+        let mmap = self.mmap()?;
+        let path = backup_path(dir, &self.config.color_table_file_name);
+        let mut dst =
+            BufWriter::with_capacity(self.config.buffer_size, self.config.create_file(&path)?);
+        dst.write_all(bytemuck::cast_slice(&mmap[..end.0 as usize]))?;
+        dst.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        drop(mmap);
+
+        let path = backup_path(dir, &self.config.generations_file_name);
+        let mut writer = BufWriter::new(self.config.create_file(&path)?);
+        generations.write_to(&mut writer, self.config.generations_format)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+
+        #[cfg(feature = "roaring")]
+        {
+            let bitmap_checkpoints = self
+                .bitmap_checkpoints
+                .read()
+                .renumber(|idx| (idx < &end).then_some(*idx));
+            write_sidecar(
+                &self.config,
+                &backup_path(dir, &self.config.bitmap_checkpoints_file_name),
+                !bitmap_checkpoints.is_empty(),
+                |file, path| encode_sidecar(file, path, &bitmap_checkpoints),
+            )?;
+        }
+
+        let class_hashes = self
+            .class_hashes
+            .lock()
+            .renumber(|idx| (idx < &end).then_some(*idx));
+        write_sidecar(
+            &self.config,
+            &backup_path(dir, &self.config.class_hashes_file_name),
+            !class_hashes.is_empty(),
+            |file, path| encode_sidecar(file, path, &class_hashes),
+        )?;
+
+        let tombstones = self
+            .tombstones
+            .read()
+            .renumber(|idx| (idx < &end).then_some(*idx));
+        write_sidecar(
+            &self.config,
+            &backup_path(dir, &self.config.tombstones_file_name),
+            !tombstones.is_empty(),
+            |file, path| encode_sidecar(file, path, &tombstones),
+        )?;
+
+        let heads = self
+            .heads
+            .read()
+            .renumber(|idx| (idx < &end).then_some(*idx));
+        write_sidecar(
+            &self.config,
+            &backup_path(dir, &self.config.heads_file_name),
+            !heads.is_empty(),
+            |file, path| Heads::sync_to(file, 0, &heads.links()).at(path),
+        )?;
+
+        let payloads = self
+            .payloads
+            .read()
+            .renumber(|idx| (idx < &end).then_some(*idx));
+        write_sidecar(
+            &self.config,
+            &backup_path(dir, &self.config.payloads_file_name),
+            !payloads.is_empty(),
+            |file, path| encode_sidecar(file, path, &payloads),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// The path of a file in the backup directory.
+fn backup_path(dir: &Path, path: &Path) -> PathBuf {
+    dir.join(path.file_name().unwrap_or(path.as_os_str()))
+}
@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+
+use parking_lot::Mutex;
+
+use super::{ColorFragment, ColorFragmentIndex, ColorId, ColorTable, GenerationGuard};
+use crate::{ColorTableError, GenerationPolicy, Result};
+
+/// A contiguous range of color ids, assigned to the fragments of a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ColorIdRange {
+    start: ColorId,
+    len: u32,
+}
+
+impl ColorIdRange {
+    pub(crate) fn new(start: ColorId, len: u32) -> Self {
+        Self { start, len }
+    }
+
+    /// Get the color id at the given offset in the range.
+    #[inline]
+    pub fn get(&self, offset: usize) -> Option<ColorId> {
+        (offset < self.len as usize).then(|| ColorId(self.start.0 + offset as u32))
+    }
+
+    /// Returns `true` if the given color id is in the range.
+    #[inline]
+    pub fn contains(&self, id: &ColorId) -> bool {
+        (self.start.0..self.start.0 + self.len).contains(&id.0)
+    }
+
+    /// Get the number of color ids in the range.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Returns `true` if the range is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get an iterator over the color ids in the range, in ascending order.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = ColorId> + '_ {
+        (self.start.0..self.start.0 + self.len).map(ColorId)
+    }
+}
+
+/// Fragments staged for a single append, created with [`GenerationGuard::batch`] or
+/// [`ColorTable::batch`].
+///
+/// A batch can be filled on its own thread without touching the table's writer; the fragments are
+/// only written, contiguously, by [`GenerationGuard::append_batch`] or
+/// [`ColorTable::commit_batch`]. Each staging method returns the
+/// offset of the fragment in the batch, which maps to its final [`ColorId`] through the returned
+/// [`ColorIdRange`].
+///
+/// A batch is bound to a generation when it is created, which determines the sample indices of its
+/// colors.
+///
+/// The same rules apply as for writing fragments directly: color classes created in a batch must
+/// not be forked or extended until the next generation.
+///
+/// The parents of staged forks and extensions are only resolved to fragments when the batch is
+/// appended, so with `deferred_heads` enabled in the [`ColorTableConfig`](crate::ColorTableConfig),
+/// a batch extends the head its parent has at that point, including extensions committed by
+/// batches for earlier generations after it was filled.
+#[derive(Debug)]
+pub struct FragmentBatch<'a> {
+    table: &'a ColorTable,
+    generation: u64,
+    // whether the batch is in the table's `BatchQueue`, i.e. was created by `ColorTable::batch`
+    queued: bool,
+    fragments: Vec<ColorFragment>,
+    // offset, parent id, and whether it is an extension, of each staged fork or extension
+    parents: Vec<(usize, ColorId, bool)>,
+}
+
+impl Drop for FragmentBatch<'_> {
+    fn drop(&mut self) {
+        if self.queued {
+            self.table.batches.release(self.generation);
+        }
+    }
+}
+
+impl<'a> FragmentBatch<'a> {
+    /// Stage a new color class.
+    ///
+    /// Returns the offset of the new color class in the batch.
+    pub fn new_color_class(&mut self, color: u32) -> usize {
+        self.push(ColorFragmentIndex(0), color)
+    }
+
+    /// Stage a fork of a color class.
+    ///
+    /// Returns the offset of the forked color class in the batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `parent` is not a valid color id.
+    pub fn fork_color_class(&mut self, parent: ColorId, color: u32) -> Result<usize> {
+        self.push_child(parent, color, false)
+    }
+
+    /// Stage an extension of a color class.
+    ///
+    /// Returns the offset of the extended color class in the batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `parent` is not a valid color id.
+    pub fn extend_color_class(&mut self, parent: ColorId, color: u32) -> Result<usize> {
+        self.push_child(parent, color, true)
+    }
+
+    /// Get the generation the batch is bound to.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Get the number of staged fragments.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.fragments.len()
+    }
+
+    /// Returns `true` if no fragments are staged.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Stage a fork or extension of `parent`, whose parent pointer is set when the batch is appended.
+    fn push_child(&mut self, parent: ColorId, color: u32, extends: bool) -> Result<usize> {
+        if self.table.parent_index(&parent).is_none() {
+            return Err(ColorTableError::InvalidColorId(parent.0));
+        }
+        let offset = self.push(ColorFragmentIndex(0), color);
+        self.parents.push((offset, parent, extends));
+        Ok(offset)
+    }
+
+    #[inline]
+    fn push(&mut self, parent_pointer: ColorFragmentIndex, color: u32) -> usize {
+        self.fragments.push(ColorFragment {
+            parent_pointer: parent_pointer.into(),
+            color: color.into(),
+        });
+        self.fragments.len() - 1
+    }
+}
+
+impl<'a> GenerationGuard<'a> {
+    /// Create an empty batch of fragments to be appended in this generation.
+    ///
+    /// The batch borrows the guard, so it can't outlive the generation it was created for; use
+    /// [`ColorTable::batch`] to fill a batch before its generation starts.
+    pub fn batch(&self) -> FragmentBatch<'_> {
+        FragmentBatch {
+            table: self.table,
+            generation: self.generation,
+            queued: false,
+            fragments: Vec::new(),
+            parents: Vec::new(),
+        }
+    }
+
+    /// Append all fragments of a batch, with a single acquisition of the table's writer.
+    ///
+    /// The batch is assigned its color ids while holding the writer, like any other append. On
+    /// Unix, batches that don't fit in the write buffer are then written at their offsets in the
+    /// color table file after the writer is released, so threads appending large batches in the
+    /// same generation write them concurrently. If such a write fails, the generation is aborted when it ends (see
+    /// [`FragmentObserver::on_generation_abort`](crate::FragmentObserver::on_generation_abort)).
+    ///
+    /// Returns the color ids assigned to the batch, in the order the fragments were staged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the batch belongs to a different color table,
+    /// [`ColorTableError::BatchGeneration`] if it is bound to another generation, an error if it
+    /// holds a fragment with no bits set and the table rejects them (see
+    /// [`ZeroColorPolicy`](crate::ZeroColorPolicy)), or if the color table file could not be
+    /// updated.
+    pub fn append_batch(&self, mut batch: FragmentBatch<'_>) -> Result<ColorIdRange> {
+        if !std::ptr::eq(self.table, batch.table) {
+            return Err(ColorTableError::ForeignBatch);
+        }
+        if batch.generation != self.generation {
+            return Err(ColorTableError::BatchGeneration {
+                batch: batch.generation,
+                generation: self.generation,
+            });
+        }
+        for fragment in &batch.fragments {
+            self.table.check_color(fragment.color.get())?;
+        }
+
+        // parents are resolved now, so extensions committed since the batch was filled are kept
+        let mut extends = Vec::new();
+        for &(offset, parent, extend) in &batch.parents {
+            let parent_idx = self
+                .table
+                .parent_index(&parent)
+                .ok_or(ColorTableError::InvalidColorId(parent.0))?;
+            self.table.check_merged(parent_idx)?;
+            batch.fragments[offset].parent_pointer = parent_idx.into();
+            if extend {
+                extends.push((parent, parent_idx, offset));
+            }
+        }
+
+        let start = self.table.write_fragments(&batch.fragments)?;
+        for (parent, parent_idx, offset) in extends {
+            self.table
+                .defer_head(&parent, parent_idx, start + offset as u32);
+        }
+
+        Ok(ColorIdRange::new(
+            start.into(),
+            batch.fragments.len() as u32,
+        ))
+    }
+}
+
+impl ColorTable {
+    /// Create an empty batch of fragments bound to a generation, to be committed with
+    /// [`ColorTable::commit_batch`].
+    ///
+    /// Unlike [`GenerationGuard::batch`], this does not wait for a generation, so any number of
+    /// batches can be filled concurrently, e.g. one per ingest thread working on disjoint color
+    /// classes. The generation lock is only held while a batch is committed.
+    ///
+    /// The generation is picked now, as by [`ColorTable::with_generation`], except that under
+    /// [`GenerationPolicy::AutoAssign`](crate::GenerationPolicy::AutoAssign) each batch is bound
+    /// to the generation after the last one started and after every batch still live, so batches
+    /// are numbered in the order they are created.
+    ///
+    /// Extending a class from several batches filled at the same time requires `deferred_heads`
+    /// in the [`ColorTableConfig`](crate::ColorTableConfig), since the id returned for the
+    /// extension by an earlier batch is only known once it is committed.
+    pub fn batch(&self, generation: u64) -> FragmentBatch<'_> {
+        let generation = match self.config.generation_policy {
+            GenerationPolicy::AutoAssign => self.batches.register_next(self.next_generation()),
+            GenerationPolicy::StrictlyIncreasing | GenerationPolicy::NonDecreasing => {
+                let generation = self.policy_generation(generation);
+                self.batches.register(generation);
+                generation
+            }
+        };
+
+        FragmentBatch {
+            table: self,
+            generation,
+            queued: true,
+            fragments: Vec::new(),
+            parents: Vec::new(),
+        }
+    }
+
+    /// Append all fragments of a batch in the generation it is bound to, as if by
+    /// [`ColorTable::with_generation`] and [`GenerationGuard::append_batch`].
+    ///
+    /// Each commit starts a generation under the table's
+    /// [`GenerationPolicy`](crate::GenerationPolicy), so several batches can only be committed in
+    /// the same generation under
+    /// [`GenerationPolicy::NonDecreasing`](crate::GenerationPolicy::NonDecreasing). Batches must
+    /// be committed in the order of their generations: committing a batch fails, without waiting,
+    /// while a batch created with [`ColorTable::batch`] for an earlier generation has not been
+    /// committed or dropped yet.
+    ///
+    /// Returns the color ids assigned to the batch, in the order the fragments were staged.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::EarlierBatchPending`] if a batch for an earlier generation is
+    /// still live, an error if the generation could not be started (see
+    /// [`ColorTable::with_generation`]), if the batch belongs to a different color table, or if the
+    /// color table file could not be updated.
+    pub fn commit_batch(&self, batch: FragmentBatch<'_>) -> Result<ColorIdRange> {
+        if !std::ptr::eq(self, batch.table) {
+            return Err(ColorTableError::ForeignBatch);
+        }
+        let generation = batch.generation;
+
+        let _guard = self.generation_lock.lock();
+        self.batches.check_earlier(generation)?;
+        self.run_generation(generation, self.allow_repeat(), |guard| {
+            guard.append_batch(batch)
+        })?
+    }
+}
+
+/// Generations of the batches created with [`ColorTable::batch`] that have not been committed or
+/// dropped yet, so that batches are committed in the order of their generations.
+#[derive(Debug, Default)]
+pub(super) struct BatchQueue {
+    // number of live batches bound to each generation
+    live: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl BatchQueue {
+    fn register(&self, generation: u64) {
+        *self.live.lock().entry(generation).or_default() += 1;
+    }
+
+    /// Register a batch bound to the generation after every live batch, or to `next` if it is later.
+    fn register_next(&self, next: u64) -> u64 {
+        let mut live = self.live.lock();
+        let generation = live
+            .last_key_value()
+            .map_or(next, |(&last, _)| next.max(last + 1));
+        live.insert(generation, 1);
+        generation
+    }
+
+    fn release(&self, generation: u64) {
+        let mut live = self.live.lock();
+        if let Some(count) = live.get_mut(&generation) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&generation);
+            }
+        }
+    }
+
+    /// Returns an error if a batch bound to a generation before `generation` is live.
+    fn check_earlier(&self, generation: u64) -> Result<()> {
+        match self.live.lock().range(..generation).next() {
+            Some((&pending, _)) => Err(ColorTableError::EarlierBatchPending {
+                batch: generation,
+                pending,
+            }),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use super::{
+    ColorFragmentIndex, ColorId, ColorIdMapping, ColorTable, GenerationLog, Header, Writer,
+};
+use crate::chains::Chains;
+use crate::{ColorTableError, PathContext, Result};
+
+/// Summary of a [`ColorTable::compact`] run.
+#[derive(Debug, Clone)]
+pub struct CompactionReport {
+    /// Number of fragments in the table before compaction.
+    pub fragments_before: u32,
+    /// Number of fragments in the table after compaction.
+    pub fragments_after: u32,
+    /// Mapping from old to new color ids, for every fragment that was kept.
+    pub mapping: ColorIdMapping,
+}
+
+impl ColorTable {
+    /// Rewrites the color table, keeping only fragments reachable from the given live color classes.
+    ///
+    /// Fragments are renumbered, so color ids change. Use [`CompactionReport::mapping`] to update
+    /// any stored color ids. Generations left without fragments are removed, but the numbering of
+    /// the remaining generations is unchanged. Deleted color classes (see [`ColorTable::tombstone`])
+    /// are not live, even if they are given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the table is read-only, if any live color id is invalid, or if the color
+    /// table files could not be rewritten. The color table is left unchanged if an error occurs
+    /// before the new fragment file replaces the old one.
+    pub fn compact(&mut self, live: impl IntoIterator<Item = ColorId>) -> Result<CompactionReport> {
+        self.check_writable()?;
+        let head = ColorFragmentIndex(*self.head.get_mut());
+        let mmap = self.mmap()?;
+
+        // mark all fragments reachable from the live heads
+        let mut reachable = vec![false; head.0 as usize];
+        for id in live {
+            let mut idx = self
+                .parent_index(&id)
+                .ok_or(ColorTableError::InvalidColorId(id.0))?;
+            // deleted classes are not live, even if they are passed in
+            let tombstones = self.tombstones.get_mut();
+            if tombstones.contains(&idx) || tombstones.contains(&(&id).into()) {
+                continue;
+            }
+            while idx.0 != 0 && !reachable[idx.0 as usize] {
+                reachable[idx.0 as usize] = true;
+                idx = mmap
+                    .fragment(&idx)
+                    .ok_or(ColorTableError::InvalidColorId(id.0))?
+                    .parent();
+            }
+        }
+
+        let generations = self.generations.get_mut().retain_fragments(|range| {
+            reachable[range.start.0 as usize..range.end.0 as usize]
+                .iter()
+                .filter(|r| **r)
+                .count() as u32
+        })?;
+
+        // kept fragments are renumbered in order, so parents still precede their children
+        let mut new_index = vec![ColorFragmentIndex(0); head.0 as usize];
+        let mut pairs = Vec::new();
+        let mut chains = Chains::new(self.config.skip_interval);
+
+        self.rewrite("compact", |writer| {
+            for (old, fragment) in mmap.iter().enumerate().skip(1) {
+                if !reachable[old] {
+                    continue;
+                }
+
+                let fragment = fragment.with_parent(new_index[fragment.parent().0 as usize]);
+                let idx = chains.push_fragment(&fragment);
+                new_index[old] = idx;
+                pairs.push((ColorId(old as u32), idx.into()));
+
+                writer.write_fragment(&fragment)?;
+            }
+
+            Ok(())
+        })?;
+        drop(mmap);
+
+        let fragments_after = pairs.len() as u32;
+        *self.head.get_mut() = fragments_after + 1;
+        *self.unflushed.get_mut() = (0, ColorFragmentIndex(fragments_after + 1));
+        *self.uncovered.get_mut() =
+            generations.first_uncovered(ColorFragmentIndex(fragments_after + 1));
+        *self.generations.get_mut() = Arc::new(generations);
+        *self.chains.get_mut() = chains;
+        #[cfg(feature = "roaring")]
+        {
+            let bitmap_checkpoints = self
+                .bitmap_checkpoints
+                .get_mut()
+                .renumber(|idx| Some(new_index[idx.0 as usize]).filter(|idx| idx.0 != 0));
+            *self.bitmap_checkpoints.get_mut() = Arc::new(bitmap_checkpoints);
+        }
+        let class_hashes = self
+            .class_hashes
+            .get_mut()
+            .renumber(|idx| Some(new_index[idx.0 as usize]).filter(|idx| idx.0 != 0));
+        *self.class_hashes.get_mut() = class_hashes;
+        // deleted classes are only kept if other live classes were built on them
+        let tombstones = self
+            .tombstones
+            .get_mut()
+            .renumber(|idx| Some(new_index[idx.0 as usize]).filter(|idx| idx.0 != 0));
+        *self.tombstones.get_mut() = tombstones;
+        let heads = self
+            .heads
+            .get_mut()
+            .renumber(|idx| Some(new_index[idx.0 as usize]).filter(|idx| idx.0 != 0));
+        *self.heads.get_mut() = heads;
+        let payloads = self
+            .payloads
+            .get_mut()
+            .renumber(|idx| Some(new_index[idx.0 as usize]).filter(|idx| idx.0 != 0));
+        *self.payloads.get_mut() = payloads;
+        self.rebuild_sketches()?;
+        self.rebuild_running_counts()?;
+
+        if let (Some(generation_log), Some(directory)) =
+            (self.generation_log.get_mut(), &self.directory)
+        {
+            *generation_log = GenerationLog::create(
+                &directory.join(&self.config.generation_log_file_name),
+                self.generations.get_mut(),
+                &self.config,
+            )?;
+        }
+
+        self.sync(None)?;
+
+        Ok(CompactionReport {
+            fragments_before: head.0 - 1,
+            fragments_after,
+            mapping: ColorIdMapping::from_sorted(pairs),
+        })
+    }
+
+    /// Replace the color table file with the fragments written by `write`.
+    ///
+    /// File-backed tables are rewritten to a temporary file with the given extension, which then
+    /// replaces the original. The table is left unchanged if `write` fails.
+    pub(super) fn rewrite(
+        &mut self,
+        extension: &str,
+        write: impl FnOnce(&mut Writer) -> Result<()>,
+    ) -> Result<()> {
+        let paths = self.directory.as_ref().map(|directory| {
+            let path = directory.join(&self.config.color_table_file_name);
+            let tmp_path = path.with_extension(extension);
+            (path, tmp_path)
+        });
+        let mut writer = match &paths {
+            Some((_, tmp_path)) => {
+                let mut file = BufWriter::with_capacity(
+                    self.config.buffer_size,
+                    self.config.create_file(tmp_path)?,
+                );
+                // removal fragments may be kept
+                let header = Header::for_config(&self.config)
+                    .with_removals(self.chains.get_mut().has_removals());
+                file.write_all(&header.to_bytes())?;
+                Writer::File(file)
+            }
+            None => Writer::memory(self.config.color_mode),
+        };
+
+        write(&mut writer)?;
+
+        if let (Writer::File(file), Some((path, tmp_path))) = (&mut writer, &paths) {
+            file.flush()?;
+            file.get_ref().sync_all()?;
+            std::fs::rename(tmp_path, path).at(path)?;
+
+            // reopen in append mode, like `ColorTable::load`
+            let file = self
+                .config
+                .color_table_options()
+                .read(true)
+                .append(true)
+                .open(path)
+                .at(path)?;
+            writer = Writer::open(file, &self.config)?;
+        }
+        *self.file.get_mut() = writer;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use bytemuck::{Pod, Zeroable};
+
+/// Alignment of direct writes, which must be a multiple of the logical block size of the device.
+const BLOCK_SIZE: usize = 4096;
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct Block([u8; BLOCK_SIZE]);
+
+/// A color table file that is written with direct I/O (`O_DIRECT`), bypassing the page cache.
+///
+/// Direct writes must be block-aligned, so fragments are buffered in an aligned buffer and written
+/// a whole buffer at a time. When flushed, the partial block at the end is written through the
+/// page cache instead, and kept in the buffer to be written again once the block is full.
+pub(super) struct DirectFile {
+    pub(super) file: File,
+    buffer: Box<[Block]>,
+    // number of bytes in the buffer
+    len: usize,
+    // file offset of the start of the buffer, which is block-aligned
+    offset: u64,
+}
+
+impl std::fmt::Debug for DirectFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirectFile")
+            .field("file", &self.file)
+            .field("capacity", &self.capacity())
+            .field("len", &self.len)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl DirectFile {
+    /// Start writing to the end of the file with direct I/O, with a buffer of (at least one block
+    /// and) about `capacity` bytes.
+    ///
+    /// Returns the file back if direct I/O is not supported, e.g. by the file system or on
+    /// platforms other than Linux.
+    pub(super) fn open(
+        mut file: File,
+        capacity: usize,
+    ) -> io::Result<std::result::Result<Self, File>> {
+        let mut buffer = vec![Block::zeroed(); capacity.div_ceil(BLOCK_SIZE).max(1)];
+        let end = file.metadata()?.len();
+        let offset = read_block(&mut file, &mut buffer, end)?;
+
+        if !set_direct(&file, true)? {
+            return Ok(Err(file));
+        }
+
+        Ok(Ok(Self {
+            file,
+            buffer: buffer.into_boxed_slice(),
+            len: (end - offset) as usize,
+            offset,
+        }))
+    }
+
+    /// Get the size of the buffer in bytes.
+    #[inline]
+    pub(super) fn capacity(&self) -> usize {
+        self.buffer.len() * BLOCK_SIZE
+    }
+
+    /// Get the number of bytes written, including buffered bytes.
+    #[inline]
+    pub(super) fn written_len(&self) -> u64 {
+        self.offset + self.len as u64
+    }
+
+    /// Get the number of bytes that writing `bytes` more would write to the disk right away, or 0
+    /// if they would only be buffered.
+    #[inline]
+    pub(super) fn disk_bytes(&self, bytes: usize) -> usize {
+        if self.len + bytes > self.capacity() {
+            self.len + bytes
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn write_all(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let n = bytes.len().min(self.capacity() - self.len);
+            bytemuck::cast_slice_mut(&mut self.buffer)[self.len..self.len + n]
+                .copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+
+            if self.len == self.capacity() {
+                write_at(&self.file, self.offset, bytemuck::cast_slice(&self.buffer))?;
+                self.offset += self.len as u64;
+                self.len = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the first bytes of the file in the buffer, if they are still buffered, so that
+    /// writing the buffer doesn't undo a change made to them through another handle.
+    pub(super) fn patch_start(&mut self, bytes: &[u8]) {
+        if self.offset == 0 {
+            let len = bytes.len().min(self.len);
+            bytemuck::cast_slice_mut(&mut self.buffer)[..len].copy_from_slice(&bytes[..len]);
+        }
+    }
+
+    /// Write the buffered bytes to the file, so they are visible to other handles of the file.
+    pub(super) fn flush(&mut self) -> io::Result<()> {
+        let blocks = self.len / BLOCK_SIZE;
+        let aligned = blocks * BLOCK_SIZE;
+        if blocks > 0 {
+            write_at(
+                &self.file,
+                self.offset,
+                bytemuck::cast_slice(&self.buffer[..blocks]),
+            )?;
+        }
+
+        // the partial block can't be written directly, as that would write past the end
+        let tail = self.len - aligned;
+        if tail > 0 {
+            let bytes: &[u8] = bytemuck::cast_slice(&self.buffer);
+            set_direct(&self.file, false)?;
+            let res = write_at(
+                &self.file,
+                self.offset + aligned as u64,
+                &bytes[aligned..self.len],
+            );
+            set_direct(&self.file, true)?;
+            res?;
+        }
+
+        if blocks > 0 {
+            // the partial block, if any, moves to the start of the buffer
+            self.buffer
+                .copy_within(blocks..blocks + tail.div_ceil(BLOCK_SIZE), 0);
+            self.offset += aligned as u64;
+            self.len = tail;
+        }
+
+        Ok(())
+    }
+
+    /// Roll the written bytes back to the first `len`, dropping whatever part of a failed write
+    /// reached the file or the buffer.
+    pub(super) fn rollback(&mut self, len: u64) -> io::Result<()> {
+        if self.file.metadata()?.len() > len {
+            self.file.set_len(len)?;
+        }
+        if len >= self.offset {
+            self.len = self.len.min((len - self.offset) as usize);
+            return Ok(());
+        }
+
+        set_direct(&self.file, false)?;
+        let res = read_block(&mut self.file, &mut self.buffer, len);
+        set_direct(&self.file, true)?;
+        self.offset = res?;
+        self.len = (len - self.offset) as usize;
+
+        Ok(())
+    }
+}
+
+impl Drop for DirectFile {
+    fn drop(&mut self) {
+        // like `BufWriter`, errors are ignored
+        let _ = self.flush();
+    }
+}
+
+fn write_at(mut file: &File, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)
+}
+
+/// Read the part of the block containing `end` that is before `end` into the start of `buffer`,
+/// returning the offset of the block.
+fn read_block(file: &mut File, buffer: &mut [Block], end: u64) -> io::Result<u64> {
+    let offset = end - end % BLOCK_SIZE as u64;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytemuck::cast_slice_mut(buffer)[..(end - offset) as usize])?;
+
+    Ok(offset)
+}
+
+/// Turn direct I/O on or off for the file. Appending is also turned off, since writes go to
+/// explicit offsets.
+///
+/// Returns `false` if direct I/O is not supported.
+#[cfg(target_os = "linux")]
+fn set_direct(file: &File, direct: bool) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the file descriptor is valid for the duration of the calls
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut flags = flags & !(libc::O_APPEND | libc::O_DIRECT);
+    if direct {
+        flags |= libc::O_DIRECT;
+    }
+    // SAFETY: see above
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags) } == -1 {
+        let err = io::Error::last_os_error();
+        // the file system doesn't support direct I/O (and the flags are unchanged)
+        if direct && err.raw_os_error() == Some(libc::EINVAL) {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct(_file: &File, direct: bool) -> io::Result<bool> {
+    Ok(!direct)
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::Arc;
+
+use super::{ColorFragmentIndex, ColorId, ColorTable, ColorTableMmap, MmapGuard, OwnedMmapGuard};
+use crate::Result;
+
+fn to_dot(
+    table: &ColorTable,
+    mmap: &ColorTableMmap,
+    ids: &[ColorId],
+    mut writer: impl Write,
+) -> Result<()> {
+    let generations = Arc::clone(&table.generations.read());
+
+    // every fragment on the chains of the given ids; shared ancestors are visited once
+    let mut nodes = BTreeSet::new();
+    for id in ids {
+        let mut idx = ColorFragmentIndex::from(id);
+        while let Some(fragment) = mmap.fragment(&idx) {
+            if !nodes.insert(idx) {
+                break;
+            }
+            idx = fragment.parent();
+        }
+    }
+
+    writeln!(writer, "digraph color_table {{")?;
+    writeln!(writer, "  rankdir=BT;")?;
+    writeln!(writer, "  node [shape=box];")?;
+    for idx in &nodes {
+        let fragment = mmap
+            .fragment(idx)
+            .expect("bug: visited fragment is not mapped");
+        let generation = generations
+            .find(idx)
+            .map_or_else(|| "?".to_string(), |generation| generation.to_string());
+        let label = if fragment.is_removal() {
+            format!("{}\\ngen {generation}\\nremove {}", idx.0, fragment.color())
+        } else {
+            format!(
+                "{}\\ngen {generation}\\n{} bits",
+                idx.0,
+                fragment.color().count_ones()
+            )
+        };
+        let style = if ids.contains(&ColorId::from(idx)) {
+            ", style=bold"
+        } else {
+            ""
+        };
+        writeln!(writer, "  f{} [label=\"{label}\"{style}];", idx.0)?;
+    }
+    // parents outside of the graph (the header, or fragments before a partial mapping) are omitted
+    for idx in &nodes {
+        let parent = mmap
+            .fragment(idx)
+            .expect("bug: visited fragment is not mapped")
+            .parent();
+        if nodes.contains(&parent) {
+            writeln!(writer, "  f{} -> f{};", idx.0, parent.0)?;
+        }
+    }
+    writeln!(writer, "}}")?;
+
+    Ok(())
+}
+
+impl MmapGuard<'_> {
+    /// Render the fragment chains of the given color classes as a GraphViz DOT graph.
+    ///
+    /// Each fragment on the chains is a node labeled with its index, generation, and number of set
+    /// bits (or the index it removes, for removal fragments), and each parent pointer is an edge
+    /// from child to parent. Fragments shared by several classes, e.g. the fragments before a fork,
+    /// appear once. The nodes of the given ids are drawn in bold.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to `writer` fails.
+    pub fn to_dot(&self, ids: &[ColorId], writer: impl Write) -> Result<()> {
+        to_dot(self.0, &self.1, ids, writer)
+    }
+}
+
+impl OwnedMmapGuard {
+    /// Render the fragment chains of the given color classes as a GraphViz DOT graph.
+    ///
+    /// See [`MmapGuard::to_dot`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to `writer` fails.
+    pub fn to_dot(&self, ids: &[ColorId], writer: impl Write) -> Result<()> {
+        to_dot(&self.0, &self.1, ids, writer)
+    }
+}
@@ -0,0 +1,242 @@
+/// Registration of a mapping with the `SIGBUS` handler of the `fault-guard` feature.
+///
+/// The handler only catches faults on a watched mapping while the faulting thread runs
+/// [`FaultWatch::guarded`]; any other access past the end of a truncated file kills the process,
+/// as usual. Without the feature (or on platforms other than Linux), this is empty.
+#[derive(Debug, Default)]
+pub(super) struct FaultWatch {
+    #[cfg(all(target_os = "linux", feature = "fault-guard"))]
+    slot: Option<handler::Slot>,
+}
+
+impl FaultWatch {
+    /// Watch the given mapping for faults.
+    ///
+    /// If too many mappings are already watched, the mapping is not watched.
+    #[cfg_attr(
+        not(all(target_os = "linux", feature = "fault-guard")),
+        allow(unused_variables)
+    )]
+    pub(super) fn new(mmap: &memmap2::Mmap) -> Self {
+        Self {
+            #[cfg(all(target_os = "linux", feature = "fault-guard"))]
+            slot: handler::Slot::register(mmap.as_ptr() as usize, mmap.len()),
+        }
+    }
+
+    /// Returns `true` if an access to the mapping faulted since it was created.
+    #[inline]
+    pub(super) fn faulted(&self) -> bool {
+        #[cfg(all(target_os = "linux", feature = "fault-guard"))]
+        return self.slot.as_ref().is_some_and(handler::Slot::faulted);
+        #[cfg(not(all(target_os = "linux", feature = "fault-guard")))]
+        false
+    }
+
+    /// Run `f` with faults on watched mappings caught on this thread: a faulting access reads a
+    /// page of zeros instead, and the mapping is marked as faulted.
+    #[inline]
+    pub(super) fn guarded<R>(f: impl FnOnce() -> R) -> R {
+        #[cfg(all(target_os = "linux", feature = "fault-guard"))]
+        return handler::guarded(f);
+        #[cfg(not(all(target_os = "linux", feature = "fault-guard")))]
+        f()
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "fault-guard"))]
+mod handler {
+    use std::cell::Cell;
+    use std::sync::OnceLock;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use libc::{c_int, c_void, siginfo_t};
+
+    /// Maximum number of mappings watched at once.
+    const SLOTS: usize = 256;
+
+    // the handler can't take locks or allocate, so the watched address ranges are kept in fixed
+    // arrays of atomics; a slot whose end is 0 matches no address
+    static CLAIMED: [AtomicBool; SLOTS] = [const { AtomicBool::new(false) }; SLOTS];
+    // incremented before and after each update of a range, so the handler can tell if it read both
+    // bounds of the same range (the version is odd while the range is being updated)
+    static VERSIONS: [AtomicUsize; SLOTS] = [const { AtomicUsize::new(0) }; SLOTS];
+    static STARTS: [AtomicUsize; SLOTS] = [const { AtomicUsize::new(0) }; SLOTS];
+    static ENDS: [AtomicUsize; SLOTS] = [const { AtomicUsize::new(0) }; SLOTS];
+    static FAULTED: [AtomicBool; SLOTS] = [const { AtomicBool::new(false) }; SLOTS];
+
+    thread_local! {
+        // set while the thread runs `guarded`; const-initialized, so the handler can read it
+        // without allocating
+        static ARMED: Cell<bool> = const { Cell::new(false) };
+    }
+
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    /// The handler that was installed before ours, or `None` if installing ours failed.
+    static PREVIOUS: OnceLock<Option<Previous>> = OnceLock::new();
+
+    struct Previous(libc::sigaction);
+
+    // SAFETY: `sigaction` is plain data (a handler address, flags and a signal mask)
+    unsafe impl Send for Previous {}
+    // SAFETY: see above
+    unsafe impl Sync for Previous {}
+
+    /// A claimed slot, released on drop.
+    #[derive(Debug)]
+    pub(super) struct Slot(usize);
+
+    impl Slot {
+        /// Claim a slot for the `len` bytes at `start`, installing the handler if needed.
+        pub(super) fn register(start: usize, len: usize) -> Option<Self> {
+            if len == 0 || PREVIOUS.get_or_init(install).is_none() {
+                return None;
+            }
+
+            let slot = CLAIMED.iter().position(|claimed| {
+                claimed
+                    .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            })?;
+            FAULTED[slot].store(false, Ordering::SeqCst);
+            publish(slot, start, start + len);
+
+            Some(Self(slot))
+        }
+
+        #[inline]
+        pub(super) fn faulted(&self) -> bool {
+            FAULTED[self.0].load(Ordering::Acquire)
+        }
+    }
+
+    impl Drop for Slot {
+        fn drop(&mut self) {
+            publish(self.0, 0, 0);
+            CLAIMED[self.0].store(false, Ordering::Release);
+        }
+    }
+
+    /// Run `f` with the handler catching faults on watched mappings on this thread.
+    pub(super) fn guarded<R>(f: impl FnOnce() -> R) -> R {
+        /// Restores the previous state when dropped, even if `f` panics.
+        struct Disarm(bool);
+
+        impl Drop for Disarm {
+            fn drop(&mut self) {
+                ARMED.set(self.0);
+            }
+        }
+
+        let _disarm = Disarm(ARMED.replace(true));
+        f()
+    }
+
+    /// Update the range of a claimed slot.
+    fn publish(slot: usize, start: usize, end: usize) {
+        VERSIONS[slot].fetch_add(1, Ordering::SeqCst);
+        STARTS[slot].store(start, Ordering::SeqCst);
+        ENDS[slot].store(end, Ordering::SeqCst);
+        VERSIONS[slot].fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Read the range of a slot, or `None` if it is being updated.
+    ///
+    /// The handler may interrupt the thread updating the slot, so this doesn't wait for the update.
+    /// A slot is only updated while its mapping isn't accessed (before it is first accessed, and
+    /// before it is unmapped), so the slot can't match the faulting address in the meantime.
+    fn range(slot: usize) -> Option<std::ops::Range<usize>> {
+        loop {
+            let version = VERSIONS[slot].load(Ordering::SeqCst);
+            if version % 2 == 1 {
+                return None;
+            }
+            let start = STARTS[slot].load(Ordering::SeqCst);
+            let end = ENDS[slot].load(Ordering::SeqCst);
+            if VERSIONS[slot].load(Ordering::SeqCst) == version {
+                return Some(start..end);
+            }
+        }
+    }
+
+    /// Install the handler, returning the previous one.
+    fn install() -> Option<Previous> {
+        // SAFETY: sysconf has no preconditions
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        PAGE_SIZE.store(usize::try_from(page_size).ok()?, Ordering::Relaxed);
+
+        // SAFETY: all-zero is a valid `sigaction`, and both pointers are valid
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction =
+                handle as extern "C" fn(c_int, *mut siginfo_t, *mut c_void) as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(libc::SIGBUS, &action, &mut previous) != 0 {
+                return None;
+            }
+            Some(Previous(previous))
+        }
+    }
+
+    /// Replace the faulting page with a page of zeros if it is in a watched mapping and the thread
+    /// runs [`guarded`], so that the access reads absent fragments (ending any chain there), and
+    /// mark the mapping as faulted. Other faults are passed on to the previous handler.
+    extern "C" fn handle(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+        // SAFETY: the kernel passes a valid `siginfo_t` to SA_SIGINFO handlers
+        let addr = unsafe { (*info).si_addr() } as usize;
+        let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+        let armed = ARMED.try_with(Cell::get).unwrap_or(false);
+
+        for (slot, faulted) in FAULTED.iter().enumerate().filter(|_| armed) {
+            if range(slot).is_some_and(|range| range.contains(&addr)) {
+                let page = addr - addr % page_size;
+                // SAFETY: the page is part of a live mapping, so nothing else can be mapped there,
+                // and mmap is async-signal-safe
+                let mapped = unsafe {
+                    libc::mmap(
+                        page as *mut c_void,
+                        page_size,
+                        libc::PROT_READ,
+                        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                        -1,
+                        0,
+                    )
+                };
+                if mapped != libc::MAP_FAILED {
+                    faulted.store(true, Ordering::Release);
+                    return;
+                }
+            }
+        }
+
+        let Some(Some(Previous(previous))) = PREVIOUS.get() else {
+            return;
+        };
+        match previous.sa_sigaction {
+            libc::SIG_DFL | libc::SIG_IGN => {
+                // restore the default action, so the access faults again and kills the process
+                // (ignoring SIGBUS would only repeat the fault forever)
+                // SAFETY: all-zero is a valid `sigaction` (with the default action), and
+                // sigaction is async-signal-safe
+                unsafe {
+                    let action: libc::sigaction = std::mem::zeroed();
+                    libc::sigaction(signal, &action, std::ptr::null_mut());
+                }
+            }
+            handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                // SAFETY: the previous handler was installed with this signature
+                let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                    unsafe { std::mem::transmute(handler) };
+                handler(signal, info, context);
+            }
+            handler => {
+                // SAFETY: the previous handler was installed with this signature
+                let handler: extern "C" fn(c_int) = unsafe { std::mem::transmute(handler) };
+                handler(signal);
+            }
+        }
+    }
+}
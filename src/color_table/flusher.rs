@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::ColorTable;
+use crate::{ColorTableError, Result};
+
+/// Handle to a background thread that periodically syncs a color table, created with
+/// [`ColorTable::spawn_flusher`].
+///
+/// The thread is stopped when the handle is dropped, or when the color table is dropped.
+#[derive(Debug)]
+pub struct FlusherHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl FlusherHandle {
+    /// Stop the background thread and wait for it to exit.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error that stopped the thread, if a sync failed, or
+    /// [`ColorTableError::FlusherPanicked`] if the thread panicked.
+    pub fn stop(mut self) -> Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+
+        self.stop.store(true, Ordering::Release);
+        thread.thread().unpark();
+        thread
+            .join()
+            .unwrap_or(Err(ColorTableError::FlusherPanicked))
+    }
+}
+
+impl Drop for FlusherHandle {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+impl ColorTable {
+    /// Spawns a background thread that calls [`ColorTable::sync`] every `interval`, bounding the
+    /// window of unsynced data for long-running writers.
+    ///
+    /// Unlike [`ColorTable::sync`], the periodic syncs keep any preallocated space at the end of the
+    /// color table file, so it isn't trimmed and grown again on every sync.
+    ///
+    /// The thread only holds a weak reference to the table between syncs, and exits once the table
+    /// is dropped or the first sync fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the thread could not be spawned.
+    pub fn spawn_flusher(self: &Arc<Self>, interval: Duration) -> Result<FlusherHandle> {
+        let table = Arc::downgrade(self);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::Builder::new()
+            .name("color-table-flusher".to_string())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || run_flusher(&table, &stop, interval)
+            })?;
+
+        Ok(FlusherHandle {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+fn run_flusher(table: &Weak<ColorTable>, stop: &AtomicBool, interval: Duration) -> Result<()> {
+    loop {
+        // parking may wake up early, so keep parking until the interval has passed
+        let deadline = std::time::Instant::now() + interval;
+        loop {
+            if stop.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                break;
+            }
+            std::thread::park_timeout(deadline - now);
+        }
+
+        let Some(table) = table.upgrade() else {
+            return Ok(());
+        };
+        table.sync_with(None, false)?;
+    }
+}
@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use bytemuck::{Pod, Zeroable};
+
+use super::lock::{TableLock, lock_exclusive};
+use super::{ColorFragment, ColorFragmentIndex, ColorTable};
+use crate::{ColorMode, ColorTableConfig, ColorTableError, PathContext, Result};
+
+/// Header of the color table file, stored in place of fragment 0.
+///
+/// The version is stored last, so the header written by version 1 (`CTBL\0\0\0\x01`) reads as
+/// version 1 with no widths recorded.
+///
+/// Versions 1 and 2 stored parent pointers in native byte order, so they are only readable as-is
+/// on little-endian hosts. Version 3 stores every field little-endian.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Zeroable, Pod)]
+pub(super) struct Header {
+    magic: [u8; 4],
+    // see `FLAG_*`
+    flags: u8,
+    index_width: u8,
+    fragment_width: u8,
+    version: u8,
+}
+
+const MAGIC: [u8; 4] = *b"CTBL";
+
+/// The fragments hold counts ([`ColorMode::Counts`]) instead of presence bits.
+const FLAG_COUNTS: u8 = 1;
+/// The cardinality of each fragment's class is stored in a sidecar file (see `running_counts` in
+/// [`ColorTableConfig`]).
+const FLAG_RUNNING_COUNTS: u8 = 2;
+/// The table may contain removal fragments (see
+/// [`GenerationGuard::extend_color_class_remove`](super::GenerationGuard::extend_color_class_remove)),
+/// whose parent pointers have their top bit set. Versions that don't know this flag would read
+/// them as fragments with a parent past the end of the table, so they refuse the table instead.
+const FLAG_REMOVALS: u8 = 4;
+const KNOWN_FLAGS: u8 = FLAG_COUNTS | FLAG_RUNNING_COUNTS | FLAG_REMOVALS;
+
+/// The format version written by this version of the crate.
+pub(super) const FORMAT_VERSION: u8 = 3;
+
+impl Header {
+    pub(super) const CURRENT: Self = Self {
+        magic: MAGIC,
+        flags: 0,
+        index_width: size_of::<ColorFragmentIndex>() as u8,
+        fragment_width: size_of::<ColorFragment>() as u8,
+        version: FORMAT_VERSION,
+    };
+
+    /// The current header for a table in the given color mode.
+    pub(super) const fn for_mode(mode: ColorMode) -> Self {
+        let flags = match mode {
+            ColorMode::Presence => 0,
+            ColorMode::Counts => FLAG_COUNTS,
+        };
+        Self {
+            flags,
+            ..Self::CURRENT
+        }
+    }
+
+    /// The current header for a table created with the given config.
+    pub(super) const fn for_config(config: &ColorTableConfig) -> Self {
+        let header = Self::for_mode(config.color_mode);
+        if config.running_counts {
+            Self {
+                flags: header.flags | FLAG_RUNNING_COUNTS,
+                ..header
+            }
+        } else {
+            header
+        }
+    }
+
+    /// The header with the flag for removal fragments set if `removals` is `true`.
+    pub(super) const fn with_removals(self, removals: bool) -> Self {
+        if removals {
+            Self {
+                flags: self.flags | FLAG_REMOVALS,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+
+    pub(super) const fn to_bytes(self) -> [u8; size_of::<ColorFragment>()] {
+        let [a, b, c, d] = self.magic;
+        [
+            a,
+            b,
+            c,
+            d,
+            self.flags,
+            self.index_width,
+            self.fragment_width,
+            self.version,
+        ]
+    }
+
+    /// Parse and validate the header of the color table file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::BadMagic`] if the bytes are not a color table header, or
+    /// [`ColorTableError::UnsupportedVersion`] if the file was written in a format this version of
+    /// the crate can't read.
+    pub(super) fn parse(bytes: [u8; size_of::<ColorFragment>()], path: &Path) -> Result<Self> {
+        let header = Self::parse_any(bytes, path)?;
+        // older versions need their parent pointers swapped by `migrate` first
+        if cfg!(target_endian = "big") && header.version < FORMAT_VERSION {
+            return Err(ColorTableError::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version: header.version,
+            });
+        }
+
+        Ok(header)
+    }
+
+    /// Parse and validate a header of any known version, regardless of the host byte order.
+    fn parse_any(bytes: [u8; size_of::<ColorFragment>()], path: &Path) -> Result<Self> {
+        let header: Self = bytemuck::cast(bytes);
+        if header.magic != MAGIC {
+            return Err(ColorTableError::BadMagic {
+                path: path.to_path_buf(),
+            });
+        }
+
+        let supported = match header.version {
+            // version 1 doesn't record widths, but always used the current layout
+            1 => header.flags == 0 && header.index_width == 0 && header.fragment_width == 0,
+            2 => {
+                header
+                    == Self {
+                        version: 2,
+                        ..Self::CURRENT
+                    }
+            }
+            FORMAT_VERSION => {
+                header.flags & !KNOWN_FLAGS == 0 && Self { flags: 0, ..header } == Self::CURRENT
+            }
+            _ => false,
+        };
+        if !supported {
+            return Err(ColorTableError::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version: header.version,
+            });
+        }
+
+        Ok(header)
+    }
+
+    #[inline]
+    pub(super) fn version(&self) -> u8 {
+        self.version
+    }
+
+    #[inline]
+    pub(super) fn running_counts(&self) -> bool {
+        self.flags & FLAG_RUNNING_COUNTS != 0
+    }
+
+    #[inline]
+    pub(super) fn color_mode(&self) -> ColorMode {
+        if self.flags & FLAG_COUNTS != 0 {
+            ColorMode::Counts
+        } else {
+            ColorMode::Presence
+        }
+    }
+}
+
+impl ColorTable {
+    /// Upgrades the color table in `dir` to the current on-disk format, in place.
+    ///
+    /// Files are renamed from the names in `from_config` to those in `to_config`. The generation
+    /// log is not carried over, and is rewritten when the table is loaded with
+    /// [`publish_generations`](ColorTableConfig) enabled. Returns the format version the table was
+    /// upgraded from.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the table is not a color table or has an unsupported version,
+    /// [`ColorTableError::Locked`] if the table is open (including read-only, see
+    /// [`ColorTable::load_read_only`]), or an I/O error if the files could not be updated.
+    pub fn migrate(
+        dir: impl AsRef<Path>,
+        from_config: &ColorTableConfig,
+        to_config: &ColorTableConfig,
+    ) -> Result<u8> {
+        let dir = dir.as_ref();
+        let _lock = TableLock::acquire(&dir.join(&from_config.lock_file_name), from_config)?;
+
+        let path = dir.join(&from_config.color_table_file_name);
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .at(&path)?;
+        // tables loaded read-only hold a shared lock on the file while they map it
+        lock_exclusive(&file, &path)?;
+        let mut buf = [0; size_of::<ColorFragment>()];
+        if file.read_exact(&mut buf).is_err() {
+            return Err(ColorTableError::BadMagic { path });
+        }
+        let version = Header::parse_any(buf, &path)?.version();
+
+        if version != FORMAT_VERSION {
+            if cfg!(target_endian = "big") {
+                swap_parent_pointers(&mut file).at(&path)?;
+            }
+            file.seek(SeekFrom::Start(0))
+                .and_then(|_| file.write_all(&Header::CURRENT.to_bytes()))
+                .and_then(|()| file.sync_all())
+                .at(&path)?;
+        }
+        drop(file);
+
+        let mut renames = vec![
+            (
+                &from_config.color_table_file_name,
+                &to_config.color_table_file_name,
+            ),
+            (
+                &from_config.generations_file_name,
+                &to_config.generations_file_name,
+            ),
+        ];
+        #[cfg(feature = "roaring")]
+        if dir.join(&from_config.bitmap_checkpoints_file_name).exists() {
+            renames.push((
+                &from_config.bitmap_checkpoints_file_name,
+                &to_config.bitmap_checkpoints_file_name,
+            ));
+        }
+        if dir.join(&from_config.class_hashes_file_name).exists() {
+            renames.push((
+                &from_config.class_hashes_file_name,
+                &to_config.class_hashes_file_name,
+            ));
+        }
+        if dir.join(&from_config.tombstones_file_name).exists() {
+            renames.push((
+                &from_config.tombstones_file_name,
+                &to_config.tombstones_file_name,
+            ));
+        }
+        if dir.join(&from_config.heads_file_name).exists() {
+            renames.push((&from_config.heads_file_name, &to_config.heads_file_name));
+        }
+        if dir.join(&from_config.payloads_file_name).exists() {
+            renames.push((
+                &from_config.payloads_file_name,
+                &to_config.payloads_file_name,
+            ));
+        }
+        if dir.join(&from_config.running_counts_file_name).exists() {
+            renames.push((
+                &from_config.running_counts_file_name,
+                &to_config.running_counts_file_name,
+            ));
+        }
+        for (from, to) in renames {
+            if from != to {
+                std::fs::rename(dir.join(from), dir.join(to)).at(dir.join(from))?;
+            }
+        }
+
+        match std::fs::remove_file(dir.join(&from_config.generation_log_file_name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).at(dir.join(&from_config.generation_log_file_name))
+            }
+            _ => Ok(version),
+        }
+    }
+}
+
+/// Convert the native-endian parent pointers written by big-endian hosts before version 3 to
+/// little-endian, in place.
+fn swap_parent_pointers(file: &mut File) -> std::io::Result<()> {
+    const FRAGMENTS_PER_CHUNK: usize = 1 << 16;
+
+    let fragment_size = size_of::<ColorFragment>();
+    let mut buf = vec![0; FRAGMENTS_PER_CHUNK * fragment_size];
+    let mut offset = fragment_size as u64;
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let len = len - len % fragment_size;
+        if len == 0 {
+            return Ok(());
+        }
+
+        for fragment in buf[..len].chunks_exact_mut(fragment_size) {
+            fragment[..4].reverse();
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&buf[..len])?;
+        offset += len as u64;
+    }
+}
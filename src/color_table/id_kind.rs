@@ -0,0 +1,69 @@
+use super::{ColorFragmentIndex, ColorId, ColorTable, ColorTableMmap, MmapGuard, OwnedMmapGuard};
+
+/// What a color id refers to, as returned by [`MmapGuard::id_kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdKind {
+    /// The null color class.
+    Null,
+    /// The head fragment of a color class: no fragment was written after it in its chain.
+    Head,
+    /// An interior fragment of a longer chain. The id still refers to the class as it was before
+    /// the later fragments were written.
+    Interior {
+        /// The current head of the class, if heads are tracked (see
+        /// [`ColorTable::canonicalize`]).
+        head: Option<ColorId>,
+    },
+    /// The id is past the end of the table, or not mapped.
+    OutOfRange,
+}
+
+fn id_kind(table: &ColorTable, mmap: &ColorTableMmap, id: &ColorId) -> IdKind {
+    let idx = ColorFragmentIndex::from(id);
+    if idx.0 == 0 {
+        return IdKind::Null;
+    }
+    if mmap.fragment(&idx).is_none() {
+        return IdKind::OutOfRange;
+    }
+
+    if table.config.deferred_heads {
+        return match table.canonicalize(*id) {
+            head if head == *id => IdKind::Head,
+            head => IdKind::Interior { head: Some(head) },
+        };
+    }
+
+    // extensions and forks are both written as children, so any later child makes this interior
+    let later = (idx.0 + 1) as usize - mmap.indices().start.0 as usize;
+    if mmap[later..]
+        .iter()
+        .any(|fragment| fragment.parent() == idx)
+    {
+        IdKind::Interior { head: None }
+    } else {
+        IdKind::Head
+    }
+}
+
+impl MmapGuard<'_> {
+    /// Classify the given color id, e.g. to debug ids read from another index.
+    ///
+    /// If `deferred_heads` is enabled in the [`ColorTableConfig`](crate::ColorTableConfig), an id
+    /// is interior if its class has been extended, and the current head is returned. Otherwise,
+    /// the fragments after the id are scanned for children, and since forks and extensions are
+    /// stored the same way, an id that has only been forked from is also reported as interior. For
+    /// a [`ColorTable::map_range`] mapping, only the mapped fragments are scanned.
+    pub fn id_kind(&self, id: &ColorId) -> IdKind {
+        id_kind(self.0, &self.1, id)
+    }
+}
+
+impl OwnedMmapGuard {
+    /// Classify the given color id.
+    ///
+    /// See [`MmapGuard::id_kind`].
+    pub fn id_kind(&self, id: &ColorId) -> IdKind {
+        id_kind(&self.0, &self.1, id)
+    }
+}
@@ -0,0 +1,71 @@
+use std::iter::Peekable;
+
+#[cfg(feature = "roaring")]
+use roaring::RoaringBitmap;
+
+use super::{ColorId, ColorTable};
+use crate::{ColorTableError, Result};
+
+impl ColorTable {
+    /// Append color classes given as bitmaps of indices (e.g. samples), such as existing indexes
+    /// stored as roaring bitmaps.
+    ///
+    /// Bitmaps are sliced into windows of 32 indices, and each window that holds any index is
+    /// written as its own generation: index `i` is written in generation `i / 32`, which is where
+    /// [`ColorTable::classes_containing`] looks for it. Classes are created in their first window and
+    /// extended in each later one, so the table should have no generations yet. Empty bitmaps are
+    /// mapped to [`ColorId::NULL`] without writing anything.
+    ///
+    /// Returns the color id of each bitmap, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a generation is not greater than the last generation of the table, or if
+    /// the color table file could not be updated. If an error occurs partway through, the
+    /// generations written so far are kept.
+    #[cfg(feature = "roaring")]
+    pub fn import_bitmaps<'a>(
+        &self,
+        bitmaps: impl IntoIterator<Item = &'a RoaringBitmap>,
+    ) -> Result<Vec<ColorId>> {
+        self.import_windows(bitmaps.into_iter().map(RoaringBitmap::iter))
+    }
+
+    /// Write classes given as ascending indices, one generation per window of 32 indices.
+    pub(super) fn import_windows<I: Iterator<Item = u32>>(
+        &self,
+        classes: impl IntoIterator<Item = I>,
+    ) -> Result<Vec<ColorId>> {
+        let mut classes = classes
+            .into_iter()
+            .map(Iterator::peekable)
+            .collect::<Vec<Peekable<I>>>();
+        let mut new_ids = vec![ColorId::NULL; classes.len()];
+        // the next window is the lowest one any class has indices left in
+        while let Some(window) = classes
+            .iter_mut()
+            .filter_map(|class| class.peek().map(|idx| idx / u32::BITS))
+            .min()
+        {
+            self.with_generation(u64::from(window), |ct| {
+                for (class, new_id) in classes.iter_mut().zip(new_ids.iter_mut()) {
+                    let mut color = 0;
+                    while let Some(idx) = class.next_if(|idx| idx / u32::BITS == window) {
+                        color |= 1 << (idx % u32::BITS);
+                    }
+                    if color == 0 {
+                        continue;
+                    }
+                    *new_id = if new_id.is_null() {
+                        ct.new_color_class(color)?
+                    } else {
+                        ct.extend_color_class(*new_id, color)?
+                    };
+                }
+                Ok::<_, ColorTableError>(())
+            })??;
+        }
+
+        Ok(new_ids)
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use crate::{ColorTableConfig, ColorTableError, PathContext, Result};
+
+/// Exclusive advisory lock on a color table, held for the lifetime of a writable table.
+///
+/// The lock file contains the PID of the owning process. Tables opened read-only instead take a
+/// shared lock on the color table file itself (see [`lock_shared`]), which keeps it from being
+/// truncated while they map it. Locking is only supported on Unix; on other platforms, the lock
+/// file is written but not locked.
+#[derive(Debug)]
+pub(super) struct TableLock {
+    // the lock is released when the file is closed
+    _file: File,
+}
+
+impl TableLock {
+    /// Lock the table using the lock file at `path`, creating it if necessary.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::Locked`] if another handle holds the lock, or an I/O error if
+    /// the lock file could not be opened.
+    pub(super) fn acquire(path: &Path, config: &ColorTableConfig) -> Result<Self> {
+        let mut file = config
+            .open_file(
+                File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false),
+                path,
+            )
+            .at(path)?;
+
+        if !try_flock(&file, Flock::Exclusive).at(path)? {
+            return Err(ColorTableError::Locked {
+                pid: read_pid(&mut file),
+            });
+        }
+
+        file.set_len(0).at(path)?;
+        file.rewind().at(path)?;
+        write!(file, "{}", std::process::id()).at(path)?;
+
+        Ok(Self { _file: file })
+    }
+
+    /// Get the PID written to the lock file at `path` by the last handle that held the lock, if
+    /// it can be read. The lock is not taken, so the PID may be stale.
+    pub(super) fn owner(path: &Path) -> Option<u32> {
+        read_pid(&mut File::open(path).ok()?)
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+/// Take a shared lock on the color table file opened at `path`, released when the file is closed.
+///
+/// # Errors
+///
+/// Returns [`ColorTableError::Locked`] if the file is being truncated by another handle.
+pub(super) fn lock_shared(file: &File, path: &Path) -> Result<()> {
+    if try_flock(file, Flock::Shared).at(path)? {
+        Ok(())
+    } else {
+        Err(ColorTableError::Locked { pid: None })
+    }
+}
+
+/// Take an exclusive lock on the color table file opened at `path`, released when the file is
+/// closed, so that it can be rewritten in place.
+///
+/// # Errors
+///
+/// Returns [`ColorTableError::Locked`] if a read-only table has the file open (see
+/// [`lock_shared`]).
+pub(super) fn lock_exclusive(file: &File, path: &Path) -> Result<()> {
+    if try_flock(file, Flock::Exclusive).at(path)? {
+        Ok(())
+    } else {
+        Err(ColorTableError::Locked { pid: None })
+    }
+}
+
+/// Truncate an opened color table file at `path` to `len` bytes, unless another handle holds a shared lock
+/// on it (see [`lock_shared`]).
+///
+/// # Errors
+///
+/// Returns [`ColorTableError::Locked`] if a read-only table has the file open, or an I/O error if
+/// it could not be truncated.
+pub(super) fn truncate_unshared(file: &File, len: u64, path: &Path) -> Result<()> {
+    if !try_flock(file, Flock::Exclusive).at(path)? {
+        return Err(ColorTableError::Locked { pid: None });
+    }
+    let res = file.set_len(len);
+    try_flock(file, Flock::Unlock).at(path)?;
+
+    res.at(path)
+}
+
+#[derive(Clone, Copy)]
+enum Flock {
+    Shared,
+    Exclusive,
+    Unlock,
+}
+
+/// Try to take (or release) a lock on the file without blocking. Returns `false` if the file is
+/// already locked. The lock is released when the file is closed.
+#[cfg(unix)]
+fn try_flock(file: &File, op: Flock) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let op = match op {
+        Flock::Shared => libc::LOCK_SH,
+        Flock::Exclusive => libc::LOCK_EX,
+        Flock::Unlock => libc::LOCK_UN,
+    };
+    // SAFETY: the file descriptor is valid for the duration of the call
+    if unsafe { libc::flock(file.as_raw_fd(), op | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(not(unix))]
+fn try_flock(_file: &File, _op: Flock) -> io::Result<bool> {
+    Ok(true)
+}
@@ -0,0 +1,376 @@
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use roaring::RoaringBitmap;
+
+use super::query::{Words, cardinality};
+use super::{ColorFragmentIndex, ColorId, ColorTable, ColorTableMmap, MmapGuard, OwnedMmapGuard};
+use crate::Result;
+
+/// Decode many color classes at once, reading and decoding each fragment at most once.
+///
+/// Parents always precede their children, so walking all chains together from the highest index
+/// to the lowest visits fragments in descending file order. When chains reach a common fragment
+/// (e.g. the fragments before a fork), they continue as a single shared chain whose bitmap is
+/// decoded once, and added to the bitmap of each class at the end.
+fn color_classes(table: &ColorTable, mmap: &ColorTableMmap, ids: &[ColorId]) -> Vec<RoaringBitmap> {
+    let generations = Arc::clone(&table.generations.read());
+    // materialized bitmaps include fragments outside of a partial mapping
+    let bitmap_checkpoints =
+        (!mmap.is_partial()).then(|| Arc::clone(&table.bitmap_checkpoints.read()));
+
+    // one chain per query to start with, plus one for each point where chains merge
+    let mut bitmaps = vec![RoaringBitmap::new(); ids.len()];
+    let mut removed = vec![Vec::new(); ids.len()];
+    // the shared chain each chain continues as, if it merged with another
+    let mut continues_as = vec![None; ids.len()];
+    // max-heap of (next fragment, chain)
+    let mut frontier = ids
+        .iter()
+        .enumerate()
+        .filter(|(_, id)| !table.is_tombstoned(id))
+        .map(|(query, id)| (ColorFragmentIndex::from(id), query))
+        .filter(|(idx, _)| mmap.fragment(idx).is_some())
+        .collect::<BinaryHeap<_>>();
+
+    while let Some((idx, mut chain)) = frontier.pop() {
+        // merge every other chain waiting on this fragment
+        if frontier.peek().is_some_and(|&(next, _)| next == idx) {
+            let shared = bitmaps.len();
+            bitmaps.push(RoaringBitmap::new());
+            removed.push(Vec::new());
+            continues_as.push(None);
+            continues_as[chain] = Some(shared);
+            while let Some(&(next, other)) = frontier.peek() {
+                if next != idx {
+                    break;
+                }
+                frontier.pop();
+                continues_as[other] = Some(shared);
+            }
+            chain = shared;
+        }
+
+        if let Some(bitmap) = bitmap_checkpoints
+            .as_ref()
+            .and_then(|checkpoints| checkpoints.get(&idx))
+        {
+            bitmaps[chain] |= bitmap;
+            continue;
+        }
+
+        let Some(fragment) = mmap.fragment(&idx) else {
+            continue;
+        };
+        // mappings are only created if every fragment belongs to a generation
+        let Some(generation) = generations.find(&idx) else {
+            continue;
+        };
+        let base = generation * u32::BITS as u64;
+        debug_assert!(
+            base + u64::from(u32::BITS) <= 1 << u32::BITS,
+            "generation {generation} overflows a u32 index"
+        );
+        if fragment.is_removal() {
+            // removals only clear indices of older fragments, so they can be applied last
+            removed[chain].push(fragment.color.get());
+        } else {
+            let mut color = fragment.color.get();
+            while color != 0 {
+                bitmaps[chain].insert((base + color.trailing_zeros() as u64) as u32);
+                color &= color - 1;
+            }
+        }
+
+        if mmap.fragment(&fragment.parent()).is_some() {
+            frontier.push((fragment.parent(), chain));
+        }
+    }
+
+    // shared chains are created after the chains that merge into them, so they are complete by
+    // the time they are added to those
+    for chain in (0..bitmaps.len()).rev() {
+        if let Some(shared) = continues_as[chain] {
+            let (head, tail) = bitmaps.split_at_mut(shared);
+            head[chain] |= &tail[0];
+            let shared_removed = removed[shared].clone();
+            removed[chain].extend(shared_removed);
+        }
+        for &idx in &removed[chain] {
+            bitmaps[chain].remove(idx);
+        }
+    }
+    bitmaps.truncate(ids.len());
+    bitmaps
+}
+
+/// Intersect many color classes without decoding any of them in full.
+///
+/// The words of each class are in descending generation order, so the classes can be merged like
+/// sorted lists: only generations present in every class are AND-ed together.
+fn intersect_many(table: &ColorTable, mmap: &ColorTableMmap, ids: &[ColorId]) -> RoaringBitmap {
+    let mut ids = ids.to_vec();
+    // with running counts, sizes are cheap: an empty class makes the intersection empty without
+    // walking any chain, and the smallest classes, which run out first, are checked first
+    if table.config.running_counts && ids.len() > 1 {
+        let mut sized = ids
+            .iter()
+            .map(|id| (cardinality(table, mmap, id), *id))
+            .collect::<Vec<_>>();
+        if sized.iter().any(|&(cardinality, _)| cardinality == 0) {
+            return RoaringBitmap::new();
+        }
+        sized.sort_unstable();
+        ids = sized.into_iter().map(|(_, id)| id).collect();
+    }
+
+    let mut classes = ids
+        .iter()
+        .map(|id| Words::new(table, mmap, id))
+        .collect::<Vec<_>>();
+    let Some(mut heads) = classes
+        .iter_mut()
+        .map(Iterator::next)
+        .collect::<Option<Vec<_>>>()
+    else {
+        // the intersection with an empty class is empty
+        return RoaringBitmap::new();
+    };
+
+    // (generation, color), newest first
+    let mut words = Vec::new();
+    'merge: loop {
+        let target = heads.iter().map(|&(_, generation)| generation).min();
+        let Some(target) = target else {
+            break;
+        };
+
+        for (head, class) in heads.iter_mut().zip(&mut classes) {
+            // skip generations that are missing from another class
+            while head.1 > target {
+                match class.next() {
+                    Some(next) => *head = next,
+                    None => break 'merge,
+                }
+            }
+        }
+
+        if heads.iter().all(|&(_, generation)| generation == target) {
+            let color = heads.iter().fold(u32::MAX, |acc, &(color, _)| acc & color);
+            if color != 0 {
+                words.push((target, color));
+            }
+            for (head, class) in heads.iter_mut().zip(&mut classes) {
+                match class.next() {
+                    Some(next) => *head = next,
+                    None => break 'merge,
+                }
+            }
+        }
+    }
+
+    words_to_bitmap(words)
+}
+
+/// Find the indices present in at least `k` of the given color classes.
+///
+/// Like [`intersect_many`], the chains are merged one generation at a time, newest first.
+fn threshold_union(
+    table: &ColorTable,
+    mmap: &ColorTableMmap,
+    ids: &[ColorId],
+    k: usize,
+) -> RoaringBitmap {
+    let k = k.max(1);
+    let mut classes = ids
+        .iter()
+        .map(|id| Words::new(table, mmap, id))
+        .collect::<Vec<_>>();
+    // max-heap of (generation, color, class)
+    let mut frontier = classes
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(class, iter)| {
+            iter.next()
+                .map(|(color, generation)| (generation, color, class))
+        })
+        .collect::<BinaryHeap<_>>();
+
+    // (generation, color), newest first
+    let mut words = Vec::new();
+    // once fewer than k classes have fragments left, no older index can be in k of them
+    while frontier.len() >= k {
+        let Some(&(generation, ..)) = frontier.peek() else {
+            break;
+        };
+
+        let mut counts = [0usize; u32::BITS as usize];
+        while let Some(&(next, mut color, class)) = frontier.peek() {
+            if next != generation {
+                break;
+            }
+            frontier.pop();
+            while color != 0 {
+                counts[color.trailing_zeros() as usize] += 1;
+                color &= color - 1;
+            }
+            if let Some((color, generation)) = classes[class].next() {
+                frontier.push((generation, color, class));
+            }
+        }
+
+        let color = counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count >= k)
+            .fold(0u32, |acc, (bit, _)| acc | 1 << bit);
+        if color != 0 {
+            words.push((generation, color));
+        }
+    }
+
+    words_to_bitmap(words)
+}
+
+/// Build a bitmap from `(generation, color)` words, newest first.
+fn words_to_bitmap(words: Vec<(u64, u32)>) -> RoaringBitmap {
+    let mut bitmap = RoaringBitmap::new();
+    for (generation, mut color) in words.into_iter().rev() {
+        let base = generation * u32::BITS as u64;
+        while color != 0 {
+            bitmap.insert((base + color.trailing_zeros() as u64) as u32);
+            color &= color - 1;
+        }
+    }
+    bitmap
+}
+
+impl ColorTable {
+    /// Find every color class that contains the given index (e.g. a sample).
+    ///
+    /// Returns the ids of all color classes whose bitmaps contain `sample`, including the old ids
+    /// of classes that have since been extended. Only fragments written in or after the generation
+    /// holding `sample` can contain it, so the table is scanned from the start of that generation.
+    /// Deleted color classes (see [`ColorTable::tombstone`]) are not returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails.
+    pub fn classes_containing(&self, sample: u64) -> Result<RoaringBitmap> {
+        let generation = sample / u64::from(u32::BITS);
+        let bit = 1 << (sample % u64::from(u32::BITS));
+
+        let Some(range) = self.generation_range(generation) else {
+            return Ok(RoaringBitmap::new());
+        };
+
+        let mmap = self.mmap()?;
+        let mut classes = RoaringBitmap::new();
+        // parents precede their children, so one forward pass finds every descendant
+        for (idx, fragment) in mmap.iter().enumerate().skip(range.start.0 as usize) {
+            let idx = idx as u32;
+            let contains = if fragment.is_removal() {
+                u64::from(fragment.color.get()) != sample && classes.contains(fragment.parent().0)
+            } else {
+                (idx < range.end.0 && fragment.color.get() & bit != 0)
+                    || classes.contains(fragment.parent().0)
+            };
+            if contains {
+                // indices are increasing, so this always appends
+                let _ = classes.try_push(idx);
+            }
+        }
+        // descendants of deleted classes are found above, but the deleted ids themselves are not
+        // returned
+        for idx in self.tombstones.read().iter() {
+            classes.remove(idx.0);
+        }
+
+        Ok(classes)
+    }
+
+    /// Transpose the given color classes into a sample-major representation.
+    ///
+    /// Returns one bitmap per sample (index) of the table, holding the positions in `ids` of the
+    /// classes that contain the sample. Every generation started so far has 32 samples, so the
+    /// result has 32 bitmaps per generation, whether or not the samples are set in any class. The
+    /// classes are decoded together as in [`MmapGuard::color_classes`], in one pass over the
+    /// mapped table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails.
+    pub fn transpose(&self, ids: &[ColorId]) -> Result<Vec<RoaringBitmap>> {
+        let mmap = self.mmap()?;
+        // read after mapping, so every mapped fragment is in a generation counted here
+        let samples = self
+            .generations
+            .read()
+            .last_generation()
+            .map_or(0, |generation| (generation + 1) * u64::from(u32::BITS));
+        let mut transposed = vec![RoaringBitmap::new(); samples as usize];
+        for (class, bitmap) in color_classes(self, &mmap, ids).into_iter().enumerate() {
+            for sample in bitmap {
+                // classes are visited in order, so this always appends
+                let _ = transposed[sample as usize].try_push(class as u32);
+            }
+        }
+
+        Ok(transposed)
+    }
+}
+
+impl MmapGuard<'_> {
+    /// Decode the color classes referred to by the given color ids into bitmaps, in the same order.
+    ///
+    /// Equivalent to calling [`ClassIter::into_bitmap`](super::ClassIter::into_bitmap) for each id,
+    /// but fragments are read in descending file order across all classes, and fragments shared by
+    /// several classes are only read once. This is much faster for large batches of ids than
+    /// decoding each class separately.
+    pub fn color_classes(&self, ids: &[ColorId]) -> Vec<RoaringBitmap> {
+        color_classes(self.0, &self.1, ids)
+    }
+
+    /// Intersect the color classes referred to by the given color ids.
+    ///
+    /// The classes are walked together one generation at a time, and only generations present in
+    /// every class are decoded, so no class is ever materialized in full. Walking stops as soon as
+    /// any class runs out of fragments. An empty slice of ids yields an empty bitmap.
+    pub fn intersect_many(&self, ids: &[ColorId]) -> RoaringBitmap {
+        intersect_many(self.0, &self.1, ids)
+    }
+
+    /// Find the indices present in at least `k` of the color classes referred to by the given
+    /// color ids.
+    ///
+    /// A `k` of 1 is the union of the classes, and a `k` of `ids.len()` is their intersection. A
+    /// `k` of 0 is treated as 1. Like [`MmapGuard::intersect_many`], the classes are walked
+    /// together one generation at a time without being materialized.
+    pub fn threshold_union(&self, ids: &[ColorId], k: usize) -> RoaringBitmap {
+        threshold_union(self.0, &self.1, ids, k)
+    }
+}
+
+impl OwnedMmapGuard {
+    /// Decode the color classes referred to by the given color ids into bitmaps, in the same order.
+    ///
+    /// See [`MmapGuard::color_classes`].
+    pub fn color_classes(&self, ids: &[ColorId]) -> Vec<RoaringBitmap> {
+        color_classes(&self.0, &self.1, ids)
+    }
+
+    /// Intersect the color classes referred to by the given color ids.
+    ///
+    /// See [`MmapGuard::intersect_many`].
+    pub fn intersect_many(&self, ids: &[ColorId]) -> RoaringBitmap {
+        intersect_many(&self.0, &self.1, ids)
+    }
+
+    /// Find the indices present in at least `k` of the color classes referred to by the given
+    /// color ids.
+    ///
+    /// See [`MmapGuard::threshold_union`].
+    pub fn threshold_union(&self, ids: &[ColorId], k: usize) -> RoaringBitmap {
+        threshold_union(&self.0, &self.1, ids, k)
+    }
+}
@@ -0,0 +1,182 @@
+use std::io;
+use std::ops::{Range, RangeInclusive};
+
+use typed_builder::TypedBuilder;
+
+use super::{ColorFragmentIndex, ColorTableMmap};
+use crate::generations::Generations;
+
+/// Expected access pattern of a mapping, passed to the OS as a hint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessPattern {
+    /// Fragments are read in no particular order, so the OS should not read ahead. Color classes
+    /// are read by following parent pointers backwards, so this suits most queries.
+    #[default]
+    Random,
+    /// Fragments are read in order (e.g. when scanning the whole table).
+    Sequential,
+    /// No particular access pattern; use the OS defaults.
+    Normal,
+}
+
+/// Options for mapping a color table, used with [`ColorTable::map_with`](super::ColorTable::map_with).
+///
+/// Options only affect file-backed tables on Unix, and are applied again when a mapping is
+/// refreshed.
+#[derive(Debug, Clone, Default, TypedBuilder)]
+pub struct MapOptions {
+    /// Expected access pattern of the whole mapping (`madvise(2)`).
+    #[builder(default)]
+    access: AccessPattern,
+    /// Generations whose fragments will be needed soon, and should be read ahead
+    /// (`MADV_WILLNEED`). Useful for the most recent generations of a large table.
+    #[builder(default, setter(strip_option))]
+    will_need: Option<RangeInclusive<u64>>,
+    /// Whether to lock the mapping in RAM (`mlock(2)`), so its pages are never evicted.
+    ///
+    /// Locking fails if the mapping is larger than the process' locked memory limit.
+    #[builder(setter(into), default)]
+    lock: bool,
+    /// Whether to ask for the mapping to be backed by transparent huge pages (`MADV_HUGEPAGE`).
+    ///
+    /// This is only a hint, and is ignored if it is not supported (e.g. on anything but Linux, or by
+    /// the file system).
+    #[builder(setter(into), default)]
+    huge_pages: bool,
+}
+
+impl ColorTableMmap {
+    /// Apply mapping options, given the generations of the mapped table.
+    #[cfg(unix)]
+    pub(super) fn apply(&self, options: &MapOptions, generations: &Generations) -> io::Result<()> {
+        use memmap2::Advice;
+
+        // byte offset of the mapping in the file
+        let (mmap, base) = match self {
+            Self::File(_, mmap) => (mmap, 0),
+            Self::Partial { start, fragments } => match &**fragments {
+                Self::File(_, mmap) => (mmap, start.0 as usize * size_of::<super::ColorFragment>()),
+                _ => return Ok(()),
+            },
+            Self::Memory(_) => return Ok(()),
+        };
+
+        mmap.advise(match options.access {
+            AccessPattern::Random => Advice::Random,
+            AccessPattern::Sequential => Advice::Sequential,
+            AccessPattern::Normal => Advice::Normal,
+        })?;
+
+        #[cfg(target_os = "linux")]
+        if options.huge_pages {
+            // not supported by every file system
+            let _ = mmap.advise(Advice::HugePage);
+        }
+
+        if let Some(will_need) = &options.will_need {
+            let fragments = generations
+                .iter()
+                .filter(|(_, generation)| will_need.contains(generation))
+                .fold(None, |acc: Option<(usize, usize)>, (range, _)| {
+                    let (start, end) = (range.start.0 as usize, range.end.0 as usize);
+                    Some(acc.map_or((start, end), |(s, e)| (s.min(start), e.max(end))))
+                });
+
+            if let Some((start, end)) = fragments {
+                let fragment_size = std::mem::size_of::<super::ColorFragment>();
+                // generations may extend past the end of an older mapping
+                let end = (end * fragment_size).saturating_sub(base).min(mmap.len());
+                let start = (start * fragment_size).saturating_sub(base).min(end);
+                if start < end {
+                    mmap.advise_range(Advice::WillNeed, start, end - start)?;
+                }
+            }
+        }
+
+        if options.lock {
+            mmap.lock()?;
+        }
+
+        Ok(())
+    }
+
+    /// Ask the OS to read the pages containing the given fragment and all of its ancestors, given
+    /// the number of fragments in its chain.
+    ///
+    /// Pages are found from fragment indices, so each page is advised before it is read.
+    /// Ancestors precede their children, so the chain is within the mapped fragments up to `idx`:
+    /// if it has at least as many fragments as they span pages, that whole range is advised at
+    /// once, and the chain isn't walked at all. Otherwise, the chain is walked, advising the page
+    /// of each fragment before reading its parent pointer.
+    pub(super) fn prefetch(&self, idx: ColorFragmentIndex, depth: u32) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let fragment_size = size_of::<super::ColorFragment>();
+            let Some(end) = self.byte_offset(&idx).map(|offset| offset + fragment_size) else {
+                return Ok(());
+            };
+            if depth as usize >= end.div_ceil(page_size()) {
+                return self.advise_will_need(0..end);
+            }
+
+            let mut last_page = None;
+            let mut idx = idx;
+            while let Some(offset) = self.byte_offset(&idx) {
+                let page = offset / page_size();
+                if last_page != Some(page) {
+                    self.advise_will_need(page * page_size()..(page + 1) * page_size())?;
+                    last_page = Some(page);
+                }
+                let Some(fragment) = self.fragment(&idx) else {
+                    break;
+                };
+                idx = fragment.parent();
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (idx, depth);
+
+        Ok(())
+    }
+
+    /// Get the offset of a fragment from the start of the mapping, if it is mapped.
+    #[cfg(unix)]
+    fn byte_offset(&self, idx: &ColorFragmentIndex) -> Option<usize> {
+        let indices = self.indices();
+        (idx.0 != 0 && indices.contains(idx))
+            .then(|| (idx.0 - indices.start.0) as usize * size_of::<super::ColorFragment>())
+    }
+
+    /// Advise that the given byte range of the mapping is needed. The range is clamped to the
+    /// mapping.
+    #[cfg(unix)]
+    fn advise_will_need(&self, range: Range<usize>) -> io::Result<()> {
+        let mmap = match self {
+            Self::File(_, mmap) => mmap,
+            Self::Partial { fragments, .. } => return fragments.advise_will_need(range),
+            Self::Memory(_) => return Ok(()),
+        };
+
+        let end = range.end.min(mmap.len());
+        if range.start >= end {
+            return Ok(());
+        }
+        mmap.advise_range(memmap2::Advice::WillNeed, range.start, end - range.start)
+    }
+
+    #[cfg(not(unix))]
+    pub(super) fn apply(
+        &self,
+        _options: &MapOptions,
+        _generations: &Generations,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(size).unwrap_or(4096)
+}
@@ -0,0 +1,43 @@
+use bincode::{Decode, Encode};
+
+use crate::ColorId;
+
+/// A mapping from old to new color ids, produced by operations that renumber fragments.
+///
+/// Only color ids that still exist after the operation are present in the mapping.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct ColorIdMapping {
+    // sorted by old id
+    pairs: Vec<(ColorId, ColorId)>,
+}
+
+impl ColorIdMapping {
+    /// Create a mapping from `(old, new)` pairs, which must be sorted by old id.
+    pub(crate) fn from_sorted(pairs: Vec<(ColorId, ColorId)>) -> Self {
+        debug_assert!(pairs.is_sorted_by_key(|(old, _)| *old));
+        Self { pairs }
+    }
+
+    /// Get the new color id for the given old color id, if it still exists.
+    pub fn get(&self, old: &ColorId) -> Option<ColorId> {
+        self.pairs
+            .binary_search_by_key(old, |(old, _)| *old)
+            .ok()
+            .map(|i| self.pairs[i].1)
+    }
+
+    /// Get the number of color ids in the mapping.
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Returns `true` if the mapping is empty.
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Get an iterator over `(old, new)` pairs, sorted by old id.
+    pub fn iter(&self) -> impl Iterator<Item = (ColorId, ColorId)> + '_ {
+        self.pairs.iter().copied()
+    }
+}
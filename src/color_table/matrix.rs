@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use super::{ColorId, ColorTable};
+use crate::Result;
+
+/// Builds color classes from dense presence matrices, one epoch of samples at a time.
+///
+/// Each epoch is a matrix with a row per color class (e.g. per k-mer or unitig), identified by a
+/// key, and a column per sample. Columns are chunked into windows of 32 samples, and each window
+/// is written as one generation with a single batch: epoch samples `0..32` go to the first
+/// generation of the epoch, `32..64` to the next one, and so on. A row whose key was seen in an
+/// earlier epoch extends that color class; other rows create new ones.
+pub struct ColorTableBuilder<'t, K> {
+    table: &'t ColorTable,
+    next_generation: u64,
+    ids: HashMap<K, ColorId>,
+}
+
+impl<'t, K: Hash + Eq + Clone> ColorTableBuilder<'t, K> {
+    /// Create a builder that writes to `table`, starting at generation `first_generation`.
+    pub fn new(table: &'t ColorTable, first_generation: u64) -> Self {
+        Self {
+            table,
+            next_generation: first_generation,
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Write an epoch given as rows of booleans, where `row[j]` is set if the class holds sample
+    /// `j` of the epoch.
+    ///
+    /// The epoch takes one generation per window of 32 samples of its widest row, even for
+    /// windows where no row has a sample. Keys should be unique within an epoch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a generation is not greater than the last generation of the table, or
+    /// if the color table file could not be updated. Windows written before the error are kept.
+    pub fn add_epoch<'r>(&mut self, rows: impl IntoIterator<Item = (K, &'r [bool])>) -> Result<()> {
+        let rows = rows
+            .into_iter()
+            .map(|(key, row)| {
+                let words = row
+                    .chunks(u32::BITS as usize)
+                    .map(|chunk| {
+                        chunk
+                            .iter()
+                            .enumerate()
+                            .fold(0, |word, (bit, &set)| word | u32::from(set) << bit)
+                    })
+                    .collect::<Vec<_>>();
+                (key, words)
+            })
+            .collect::<Vec<_>>();
+        self.write_epoch(&rows)
+    }
+
+    /// Write an epoch given as rows of packed words, where bit `j` of `row[w]` is set if the class
+    /// holds sample `w * 32 + j` of the epoch.
+    ///
+    /// See [`ColorTableBuilder::add_epoch`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a generation is not greater than the last generation of the table, or
+    /// if the color table file could not be updated.
+    pub fn add_packed_epoch<'r>(
+        &mut self,
+        rows: impl IntoIterator<Item = (K, &'r [u32])>,
+    ) -> Result<()> {
+        self.write_epoch(&rows.into_iter().collect::<Vec<_>>())
+    }
+
+    fn write_epoch(&mut self, rows: &[(K, impl AsRef<[u32]>)]) -> Result<()> {
+        let windows = rows
+            .iter()
+            .map(|(_, words)| words.as_ref().len())
+            .max()
+            .unwrap_or(0);
+
+        let mut staged = Vec::new();
+        for window in 0..windows {
+            let generation = self.next_generation + window as u64;
+            let mut batch = self.table.batch(generation);
+            // rows in the order their fragments were staged
+            staged.clear();
+            for (row, (key, words)) in rows.iter().enumerate() {
+                let color = words.as_ref().get(window).copied().unwrap_or(0);
+                if color == 0 {
+                    continue;
+                }
+                match self.ids.get(key) {
+                    Some(&id) => batch.extend_color_class(id, color)?,
+                    None => batch.new_color_class(color),
+                };
+                staged.push(row);
+            }
+            if batch.is_empty() {
+                continue;
+            }
+
+            let range = self.table.commit_batch(batch)?;
+            for (&row, id) in staged.iter().zip(range.iter()) {
+                self.ids.insert(rows[row].0.clone(), id);
+            }
+        }
+        self.next_generation += windows as u64;
+
+        Ok(())
+    }
+
+    /// Get the current color id of the class with the given key, if it has any samples.
+    pub fn get(&self, key: &K) -> Option<ColorId> {
+        self.ids.get(key).copied()
+    }
+
+    /// Get the generation the next epoch will start at.
+    pub fn next_generation(&self) -> u64 {
+        self.next_generation
+    }
+
+    /// Consume the builder, returning the color id of each key.
+    pub fn into_ids(self) -> HashMap<K, ColorId> {
+        self.ids
+    }
+}
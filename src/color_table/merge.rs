@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use super::{ColorFragmentIndex, ColorId, ColorIdMapping, ColorTable};
+use crate::{ColorTableError, Result};
+
+impl ColorTable {
+    /// Appends all fragments of another color table to this one.
+    ///
+    /// Each generation `g` of `other` becomes generation `g + generation_offset` in this table, so
+    /// `generation_offset` must be chosen such that the shifted generations are all greater than the
+    /// last generation of this table. Parent pointers are rewritten to point to the appended fragments.
+    ///
+    /// Returns the mapping from color ids in `other` to color ids in this table.
+    ///
+    /// This method blocks until no generation is in progress in either table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `other` is in a different [`ColorMode`](crate::ColorMode), if `other`
+    /// contains fragments outside of any generation, if a shifted generation is invalid, or if the
+    /// color table file could not be updated. Nothing is written if `other` is inconsistent, but if
+    /// an error occurs partway through writing, the generations merged so far are kept.
+    pub fn merge_from(&self, other: &ColorTable, generation_offset: u64) -> Result<ColorIdMapping> {
+        if std::ptr::eq(self, other) {
+            return Err(ColorTableError::MergeIntoSelf);
+        }
+        // fragments of tables in different modes can't be mixed
+        if self.config.color_mode != other.config.color_mode {
+            return Err(ColorTableError::UnsupportedColorMode {
+                mode: other.config.color_mode,
+            });
+        }
+
+        let _guard = self.generation_lock.lock();
+        let _other_guard = other.generation_lock.lock();
+
+        let other_map = other.mmap()?;
+        let other_generations = Arc::clone(&other.generations.read());
+        let other_head = other.head();
+
+        // fragments are appended contiguously, so every index is shifted by the same amount
+        let shift = self.head().0 - 1;
+
+        // check that the generations of `other` cover all of its fragments before writing anything
+        let mut ranges = Vec::new();
+        let mut expected_start = ColorFragmentIndex(1);
+        for (range, generation) in other_generations.iter() {
+            if range.start != expected_start {
+                return Err(ColorTableError::UncoveredFragments {
+                    start: expected_start.0,
+                    end: range.start.0,
+                });
+            }
+            expected_start = range.end;
+
+            let generation = generation
+                .checked_add(generation_offset)
+                .ok_or(ColorTableError::InvalidGeneration(generation))?;
+            ranges.push((range.clone(), generation));
+        }
+
+        if expected_start != other_head {
+            return Err(ColorTableError::UncoveredFragments {
+                start: expected_start.0,
+                end: other_head.0,
+            });
+        }
+
+        for (range, generation) in ranges {
+            self.run_generation(generation, self.allow_repeat(), |_| {
+                for idx in range.start.0..range.end.0 {
+                    let fragment = other_map
+                        .get_fragment(&ColorFragmentIndex(idx))
+                        .ok_or(ColorTableError::InvalidColorId(idx))?;
+                    let parent = match fragment.parent() {
+                        ColorFragmentIndex(0) => ColorFragmentIndex(0),
+                        parent => parent + shift,
+                    };
+
+                    self.write_fragment(fragment.with_parent(parent))?;
+                }
+
+                Ok::<_, ColorTableError>(())
+            })??;
+        }
+
+        self.file.lock().flush()?;
+
+        let mut payloads = self.payloads.write();
+        for (idx, payload) in other.payloads.read().iter() {
+            payloads.set(idx + shift, payload);
+        }
+        drop(payloads);
+
+        let pairs = (1..other_head.0)
+            .map(|idx| (ColorId(idx), ColorId(idx + shift)))
+            .collect();
+
+        Ok(ColorIdMapping::from_sorted(pairs))
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+use parking_lot::RwLock;
+
+/// Counters for monitoring a color table, registered with [`ColorTable::set_metrics`](super::ColorTable::set_metrics).
+///
+/// Every method has an empty default implementation, so implementors only need to handle the
+/// counters they export (e.g. to Prometheus). Methods are called synchronously, some while the
+/// table's writer is locked, so they should only update counters.
+pub trait Metrics: Send + Sync {
+    /// Called after fragments are written (or reserved slots are filled), with the number of
+    /// fragments.
+    fn fragments_written(&self, count: u32) {
+        let _ = count;
+    }
+
+    /// Called after the writer is flushed at the end of a generation (see
+    /// [`FlushPolicy`](crate::FlushPolicy)), with the number of bytes written since the previous
+    /// flush.
+    fn bytes_flushed(&self, bytes: u64) {
+        let _ = bytes;
+    }
+
+    /// Called after a generation is ended, with the number of fragments written in it.
+    fn generation_ended(&self, generation: u64, fragments: u32) {
+        let _ = (generation, fragments);
+    }
+
+    /// Called when a color class is queried, with the number of fragments in its chain.
+    fn chain_length(&self, length: usize) {
+        let _ = length;
+    }
+}
+
+/// The metrics registered with a color table, if any.
+#[derive(Default)]
+pub(super) struct MetricsSink(RwLock<Option<Box<dyn Metrics>>>);
+
+impl fmt::Debug for MetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsSink")
+            .field("set", &self.0.read().is_some())
+            .finish()
+    }
+}
+
+impl MetricsSink {
+    pub(super) fn set(&self, metrics: Box<dyn Metrics>) {
+        *self.0.write() = Some(metrics);
+    }
+
+    #[inline]
+    pub(super) fn with(&self, f: impl FnOnce(&dyn Metrics)) {
+        if let Some(metrics) = self.0.read().as_deref() {
+            f(metrics);
+        }
+    }
+}
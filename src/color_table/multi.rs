@@ -0,0 +1,194 @@
+use std::path::Path;
+
+use roaring::RoaringBitmap;
+
+use super::{ColorId, ColorTable, MmapGuard};
+use crate::{ColorTableConfig, ColorTableError, Result};
+
+/// Several color tables queried as one, e.g. one table per cohort of samples.
+///
+/// Each table has its own sample space, starting at an offset into the combined sample space.
+/// Sample (index) `i` of the table at offset `o` is sample `o + i` of the combined sample space.
+/// A color class of the combined sample space is given by one color id per table, in the order the
+/// tables were added, with the null color id for tables that don't hold the class.
+#[derive(Debug)]
+pub struct MultiTable {
+    tables: Vec<ColorTable>,
+    // sorted, one per table
+    offsets: Vec<u32>,
+}
+
+impl MultiTable {
+    /// Queries the given tables as one, each starting at the given offset of the combined sample
+    /// space.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::OverlappingSampleSpaces`] if the samples of a table (32 per
+    /// generation) extend past the offset of the next table, or past the end of the combined
+    /// sample space.
+    pub fn new(tables: impl IntoIterator<Item = (ColorTable, u32)>) -> Result<Self> {
+        let (tables, offsets) = tables.into_iter().unzip();
+        let multi = Self { tables, offsets };
+        multi.check_offsets()?;
+
+        Ok(multi)
+    }
+
+    /// Loads the tables in the given directories with [`ColorTable::load`], using `config`, and
+    /// queries them as one (see [`MultiTable::new`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a table could not be loaded, or if the sample spaces of the tables
+    /// overlap.
+    pub fn load(
+        tables: impl IntoIterator<Item = (impl AsRef<Path>, u32)>,
+        config: ColorTableConfig,
+    ) -> Result<Self> {
+        let tables = tables
+            .into_iter()
+            .map(|(dir, offset)| Ok((ColorTable::load(dir, config.clone())?, offset)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::new(tables)
+    }
+
+    /// Get the tables, in the order they were added.
+    #[inline]
+    pub fn tables(&self) -> &[ColorTable] {
+        &self.tables
+    }
+
+    /// Get the offset of each table in the combined sample space, in the order the tables were
+    /// added.
+    #[inline]
+    pub fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+
+    /// Find the table holding a sample of the combined sample space, and the index of the sample
+    /// in that table.
+    ///
+    /// Returns `None` if the sample is before the offset of the first table.
+    pub fn locate(&self, sample: u32) -> Option<(usize, u32)> {
+        let table = self.offsets.partition_point(|&offset| offset <= sample);
+        let table = table.checked_sub(1)?;
+
+        Some((table, sample - self.offsets[table]))
+    }
+
+    /// Maps all tables to memory.
+    ///
+    /// Tables may have grown since they were added, so the sample spaces are checked again.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::OverlappingSampleSpaces`] if the sample spaces of the tables
+    /// now overlap, or an error if mmapping a table fails (see [`ColorTable::map`]).
+    pub fn map(&self) -> Result<MultiMmapGuard<'_>> {
+        self.check_offsets()?;
+        let guards = self
+            .tables
+            .iter()
+            .map(ColorTable::map)
+            .collect::<Result<_>>()?;
+
+        Ok(MultiMmapGuard {
+            table: self,
+            guards,
+        })
+    }
+
+    /// Check that the samples of each table end before the offset of the next one.
+    fn check_offsets(&self) -> Result<()> {
+        for (table, (ct, &offset)) in self.tables.iter().zip(&self.offsets).enumerate() {
+            // a generation holds 32 samples, whether or not they are all set
+            let samples = ct
+                .generations
+                .read()
+                .last_generation()
+                .map_or(0, |generation| (generation + 1) * u64::from(u32::BITS));
+            let end = self
+                .offsets
+                .get(table + 1)
+                .map_or(1 << u32::BITS, |&next| u64::from(next));
+            if u64::from(offset) + samples > end {
+                return Err(ColorTableError::OverlappingSampleSpaces { table });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// RAII guard for the memory-mapped tables of a [`MultiTable`].
+#[derive(Debug)]
+pub struct MultiMmapGuard<'a> {
+    table: &'a MultiTable,
+    guards: Vec<MmapGuard<'a>>,
+}
+
+impl MultiMmapGuard<'_> {
+    /// Decode the color class given by one color id per table into a bitmap of the combined
+    /// sample space.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ids` does not have one color id per table.
+    pub fn color_class(&self, ids: &[ColorId]) -> RoaringBitmap {
+        assert_eq!(
+            ids.len(),
+            self.guards.len(),
+            "expected one color id per table"
+        );
+
+        let mut bitmap = RoaringBitmap::new();
+        for ((guard, &offset), id) in self.guards.iter().zip(&self.table.offsets).zip(ids) {
+            bitmap.extend(
+                guard
+                    .color_class(id)
+                    .into_bitmap()
+                    .iter()
+                    .map(|i| offset + i),
+            );
+        }
+        bitmap
+    }
+
+    /// Intersect the color classes given by one color id per table each, in the combined sample
+    /// space.
+    ///
+    /// The sample spaces of the tables are disjoint, so the classes are intersected within each
+    /// table with [`MmapGuard::intersect_many`], and the results are combined. An empty slice of
+    /// classes yields an empty bitmap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a class does not have one color id per table.
+    pub fn intersect_many(&self, ids: &[impl AsRef<[ColorId]>]) -> RoaringBitmap {
+        let classes = ids.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        for class in &classes {
+            assert_eq!(
+                class.len(),
+                self.guards.len(),
+                "expected one color id per table"
+            );
+        }
+
+        let mut bitmap = RoaringBitmap::new();
+        let mut column = Vec::with_capacity(classes.len());
+        for (table, (guard, &offset)) in self.guards.iter().zip(&self.table.offsets).enumerate() {
+            column.clear();
+            column.extend(classes.iter().map(|class| class[table]));
+            bitmap.extend(guard.intersect_many(&column).iter().map(|i| offset + i));
+        }
+        bitmap
+    }
+
+    /// Get the mapping of each table.
+    #[inline]
+    pub fn tables(&self) -> &[MmapGuard<'_>] {
+        &self.guards
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+
+use parking_lot::RwLock;
+
+use super::{ColorFragment, ColorFragmentIndex};
+
+/// Observer of changes to a color table, registered with [`ColorTable::subscribe`](super::ColorTable::subscribe).
+///
+/// Observers are called synchronously on the writing thread, in the same order as the changes are
+/// made. Fragment callbacks are called while the table's writer is locked, so they should return
+/// quickly and must not write to the color table.
+pub trait FragmentObserver: Send + Sync {
+    /// Called after a fragment is appended at the given index.
+    fn on_fragment(&self, index: ColorFragmentIndex, fragment: &ColorFragment) {
+        let _ = (index, fragment);
+    }
+
+    /// Called after a generation is started. `head` is the index of the first fragment the generation may contain.
+    fn on_generation_start(&self, generation: u64, head: ColorFragmentIndex) {
+        let _ = (generation, head);
+    }
+
+    /// Called after a generation is ended. `head` is the index of the first fragment after the generation.
+    fn on_generation_end(&self, generation: u64, head: ColorFragmentIndex) {
+        let _ = (generation, head);
+    }
+
+    /// Called instead of [`on_generation_end`](Self::on_generation_end) if the fragments of a
+    /// generation could not be written. The generation is dropped, and the table can't start
+    /// another one until it is reloaded with
+    /// [`ColorTable::load_repaired`](super::ColorTable::load_repaired).
+    fn on_generation_abort(&self, generation: u64) {
+        let _ = generation;
+    }
+}
+
+/// The observers registered with a color table.
+#[derive(Default)]
+pub(super) struct Observers(RwLock<Vec<Box<dyn FragmentObserver>>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("len", &self.0.read().len())
+            .finish()
+    }
+}
+
+impl Observers {
+    pub(super) fn push(&self, observer: Box<dyn FragmentObserver>) {
+        self.0.write().push(observer);
+    }
+
+    #[inline]
+    pub(super) fn for_each(&self, f: impl Fn(&dyn FragmentObserver)) {
+        for observer in self.0.read().iter() {
+            f(observer.as_ref());
+        }
+    }
+}
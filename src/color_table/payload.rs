@@ -0,0 +1,50 @@
+use super::{
+    ColorFragmentIndex, ColorId, ColorTable, ColorTableMmap, GenerationGuard, MmapGuard,
+    OwnedMmapGuard,
+};
+use crate::{ColorTableError, Result};
+
+fn payload_of(table: &ColorTable, mmap: &ColorTableMmap, idx: &ColorFragmentIndex) -> Option<u64> {
+    mmap.fragment(idx)?;
+    Some(table.payloads.read().get(idx))
+}
+
+impl GenerationGuard<'_> {
+    /// Attach a payload (e.g. a batch id or an abundance) to the fragment of the given color id,
+    /// replacing any previous payload.
+    ///
+    /// Payloads are written to a sidecar file on [`ColorTable::sync`], and move with their
+    /// fragments when the table is compacted or merged. A payload of 0 is the same as no payload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `id` does not refer to a written fragment.
+    pub fn set_payload(&self, id: ColorId, payload: u64) -> Result<()> {
+        let idx = ColorFragmentIndex::from(id);
+        if idx.0 == 0 || idx >= self.table.head() {
+            return Err(ColorTableError::InvalidColorId(id.0));
+        }
+        self.table.payloads.write().set(idx, payload);
+
+        Ok(())
+    }
+}
+
+impl MmapGuard<'_> {
+    /// Get the payload attached to a fragment (see [`GenerationGuard::set_payload`]), or 0 if it has
+    /// none.
+    ///
+    /// Returns `None` if the fragment is not mapped.
+    pub fn payload_of(&self, idx: &ColorFragmentIndex) -> Option<u64> {
+        payload_of(self.0, &self.1, idx)
+    }
+}
+
+impl OwnedMmapGuard {
+    /// Get the payload attached to a fragment.
+    ///
+    /// See [`MmapGuard::payload_of`].
+    pub fn payload_of(&self, idx: &ColorFragmentIndex) -> Option<u64> {
+        payload_of(&self.0, &self.1, idx)
+    }
+}
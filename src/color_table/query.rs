@@ -0,0 +1,538 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use super::{
+    COUNT_BITS, COUNTS_PER_FRAGMENT, ClassIter, ColorFragmentIndex, ColorId, ColorTable,
+    ColorTableMmap, MmapGuard, OwnedMmapGuard, fragments_in,
+};
+use crate::generations::Generations;
+use crate::running_counts::RunningCounts;
+use crate::{ColorMode, Result};
+
+/// The colors of a color class, one word per generation, newest first.
+///
+/// A chain visits generations in descending order. Classes that were forked or extended in the
+/// same generation they were created in have several fragments in that generation, which are
+/// combined.
+pub(super) struct Words<'c>(ClassIter<'c>);
+
+impl<'c> Words<'c> {
+    pub(super) fn new(table: &'c ColorTable, mmap: &'c ColorTableMmap, id: &ColorId) -> Self {
+        Self(ClassIter::new(table, mmap, id))
+    }
+
+    /// Returns `true` if both classes have the same remaining words: they are at the same
+    /// fragment, and remove the same indices from it.
+    fn shares_rest_with(&self, other: &Self) -> bool {
+        self.0.idx == other.0.idx && self.0.removed == other.0.removed
+    }
+
+    fn next_generation(&self) -> Option<u64> {
+        self.0.mmap.fragment(&self.0.idx)?;
+        self.0.generations.find(&self.0.idx)
+    }
+}
+
+impl Iterator for Words<'_> {
+    type Item = (u32, u64); // color, generation
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut color, generation) = self.0.next()?;
+        while self.next_generation() == Some(generation) {
+            color |= self.0.next()?.0;
+        }
+        Some((color, generation))
+    }
+}
+
+/// Sizes of two color classes and of their intersection.
+///
+/// Once both chains reach the same fragment, the rest of the classes are shared, so only one of
+/// them is walked to the end.
+fn overlap(table: &ColorTable, mmap: &ColorTableMmap, a: &ColorId, b: &ColorId) -> (u64, u64, u64) {
+    let mut a = Words::new(table, mmap, a);
+    let mut b = Words::new(table, mmap, b);
+    let (mut size_a, mut size_b, mut shared) = (0, 0, 0);
+
+    let (mut head_a, mut head_b) = if a.shares_rest_with(&b) {
+        (None, None)
+    } else {
+        (a.next(), b.next())
+    };
+    loop {
+        match (head_a, head_b) {
+            (Some((color_a, gen_a)), Some((color_b, gen_b))) if gen_a == gen_b => {
+                size_a += u64::from(color_a.count_ones());
+                size_b += u64::from(color_b.count_ones());
+                shared += u64::from((color_a & color_b).count_ones());
+                if a.shares_rest_with(&b) {
+                    break;
+                }
+                head_a = a.next();
+                head_b = b.next();
+            }
+            (Some((color, gen_a)), Some((_, gen_b))) if gen_a > gen_b => {
+                size_a += u64::from(color.count_ones());
+                head_a = a.next();
+            }
+            (Some((color, _)), None) => {
+                size_a += u64::from(color.count_ones());
+                head_a = a.next();
+            }
+            (_, Some((color, _))) => {
+                size_b += u64::from(color.count_ones());
+                head_b = b.next();
+            }
+            (None, None) => break,
+        }
+    }
+
+    let rest = a
+        .map(|(color, _)| u64::from(color.count_ones()))
+        .sum::<u64>();
+    (size_a + rest, size_b + rest, shared + rest)
+}
+
+/// Add 1 to the counter of every index in each of the given color classes.
+fn accumulate(
+    table: &ColorTable,
+    mmap: &ColorTableMmap,
+    ids: impl IntoIterator<Item = ColorId>,
+    counts: &mut [u32],
+) {
+    for id in ids {
+        for (mut color, generation) in Words::new(table, mmap, &id) {
+            let base = (generation * u32::BITS as u64) as usize;
+            while color != 0 {
+                counts[base + color.trailing_zeros() as usize] += 1;
+                color &= color - 1;
+            }
+        }
+    }
+}
+
+/// FNV-1a, which is simple enough to be stable across releases.
+pub(super) fn class_hash(table: &ColorTable, mmap: &ColorTableMmap, id: &ColorId) -> u128 {
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    // hash the words oldest first, skipping empty words, so equal bitmaps hash equally however
+    // their fragments are laid out
+    let words = Words::new(table, mmap, id)
+        .filter(|&(color, _)| color != 0)
+        .collect::<Vec<_>>();
+    let mut hash = OFFSET_BASIS;
+    for (color, generation) in words.into_iter().rev() {
+        for byte in generation
+            .to_le_bytes()
+            .into_iter()
+            .chain(color.to_le_bytes())
+        {
+            hash ^= u128::from(byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    hash
+}
+
+/// Heads of the color classes in the mapping: fragments that are not the parent of any mapped
+/// fragment, and have not been deleted.
+pub(super) fn heads<'m>(
+    table: &'m ColorTable,
+    mmap: &ColorTableMmap,
+) -> impl Iterator<Item = ColorId> + 'm {
+    let indices = mmap.indices();
+    let start = indices.start.0.max(1);
+    let mut referenced = vec![false; (indices.end.0.saturating_sub(start)) as usize];
+    for idx in start..indices.end.0 {
+        if let Some(fragment) = mmap.fragment(&ColorFragmentIndex(idx)) {
+            // parents outside of a partial mapping are skipped
+            if let Some(parent) = fragment.parent().0.checked_sub(start) {
+                referenced[parent as usize] = true;
+            }
+        }
+    }
+
+    referenced
+        .into_iter()
+        .zip(start..)
+        .filter(|&(referenced, _)| !referenced)
+        .map(|(_, idx)| ColorId(idx))
+        .filter(|id| !table.is_tombstoned(id))
+}
+
+/// Number of samples set in a word: set bits, or non-zero counts in the counts color mode.
+fn word_cardinality(mode: ColorMode, color: u32) -> u32 {
+    match mode {
+        ColorMode::Counts => (0..COUNTS_PER_FRAGMENT)
+            .filter(|&slot| color >> (slot as u32 * COUNT_BITS) & ((1 << COUNT_BITS) - 1) != 0)
+            .count() as u32,
+        ColorMode::Presence => color.count_ones(),
+    }
+}
+
+/// Returns `true` if the chain ending at `idx` holds the index `removed`, taking the removals on the
+/// way into account.
+fn chain_contains(
+    mmap: &ColorTableMmap,
+    generations: &Generations,
+    mut idx: ColorFragmentIndex,
+    removed: u32,
+) -> bool {
+    let target = u64::from(removed / u32::BITS);
+    while let Some(fragment) = mmap.fragment(&idx) {
+        let Some(generation) = generations.find(&idx) else {
+            return false;
+        };
+        if generation < target {
+            return false;
+        }
+        if fragment.is_removal() {
+            if fragment.color() == removed {
+                return false;
+            }
+        } else if generation == target && fragment.color() & 1 << (removed % u32::BITS) != 0 {
+            return true;
+        }
+        idx = fragment.parent();
+    }
+    false
+}
+
+/// Extend the cardinalities of the classes ending at the mapped fragments, indexed from the start
+/// of the mapping, up to the fragment before `end`.
+///
+/// `counts` must end with a whole generation. Parents precede their children, so each fragment
+/// adds the samples of its word to the cardinality of its parent. Fragments of a class in the same
+/// generation are combined first, so samples set in several of them are counted once, and
+/// removals subtract the removed sample if it was still in the class.
+fn count_fragments(
+    table: &ColorTable,
+    mmap: &ColorTableMmap,
+    generations: &Generations,
+    counts: &mut Vec<u32>,
+    end: ColorFragmentIndex,
+) {
+    let mode = table.config.color_mode;
+    let start = mmap.indices().start.0;
+    let from = start + counts.len() as u32;
+    // combined word of the class in the generation of each fragment counted here; parents in the
+    // same generation as a fragment are never before `from`
+    let mut words = Vec::new();
+
+    for idx in from..end.0.min(mmap.indices().end.0) {
+        let idx = ColorFragmentIndex(idx);
+        // fragments outside of any generation end their chains, like in `ClassIter`
+        let Some((fragment, generation)) = mmap.fragment(&idx).zip(generations.find(&idx)) else {
+            counts.push(0);
+            words.push(0);
+            continue;
+        };
+        let parent = fragment.parent();
+        // parents outside of a partial mapping also end the chain
+        let parent_count = parent
+            .0
+            .checked_sub(start)
+            .map_or(0, |p| counts[p as usize]);
+        let parent_word = match parent.0.checked_sub(from) {
+            Some(p) if generations.find(&parent) == Some(generation) => words[p as usize],
+            _ => 0,
+        };
+
+        let (count, word) = if fragment.is_removal() {
+            let removed = chain_contains(mmap, generations, parent, fragment.color());
+            (parent_count - u32::from(removed), parent_word)
+        } else {
+            let word = parent_word | fragment.color();
+            let count =
+                parent_count - word_cardinality(mode, parent_word) + word_cardinality(mode, word);
+            (count, word)
+        };
+        counts.push(count);
+        words.push(word);
+    }
+}
+
+/// Cardinality of the class ending at every mapped fragment, indexed from the start of the mapping.
+///
+/// The stored running counts are used as a starting point if they are enabled, so only the
+/// fragments after them are counted.
+fn cardinalities(table: &ColorTable, mmap: &ColorTableMmap) -> Vec<u32> {
+    let generations = Arc::clone(&table.generations.read());
+    let mut counts = Vec::new();
+    if table.config.running_counts && !mmap.is_partial() {
+        let running_counts = table.running_counts.read();
+        // the counts may cover generations that ended after the table was mapped
+        let len = running_counts.len().min(mmap.indices().end.0 as usize);
+        counts.extend_from_slice(&running_counts.as_slice()[..len]);
+    }
+    count_fragments(table, mmap, &generations, &mut counts, mmap.indices().end);
+
+    counts
+}
+
+/// Cardinality of a color class, from the stored running counts if possible.
+pub(super) fn cardinality(table: &ColorTable, mmap: &ColorTableMmap, id: &ColorId) -> u64 {
+    let idx = ColorFragmentIndex::from(id);
+    if table.config.running_counts
+        && !mmap.is_partial()
+        && mmap.fragment(&idx).is_some()
+        && !table.is_tombstoned(id)
+    {
+        if let Some(count) = table.running_counts.read().get(&idx) {
+            return u64::from(count);
+        }
+    }
+
+    let mode = table.config.color_mode;
+    Words::new(table, mmap, id)
+        .map(|(color, _)| u64::from(word_cardinality(mode, color)))
+        .sum()
+}
+
+impl ColorTable {
+    /// Extend the running counts to the fragments of the ended generations, if they are enabled.
+    pub(super) fn update_running_counts(&self) -> Result<()> {
+        if !self.config.running_counts {
+            return Ok(());
+        }
+        let generations = Arc::clone(&self.generations.read());
+        let Some(end) = generations.last_range_end() else {
+            return Ok(());
+        };
+
+        let mut running_counts = self.running_counts.write();
+        if running_counts.len() >= end.0 as usize {
+            return Ok(());
+        }
+        let mmap = self.mmap()?;
+        count_fragments(self, &mmap, &generations, running_counts.as_mut_vec(), end);
+
+        Ok(())
+    }
+
+    /// Recount the running counts from scratch, after fragments or generations were rewritten.
+    pub(super) fn rebuild_running_counts(&mut self) -> Result<()> {
+        *self.running_counts.get_mut() = RunningCounts::new();
+        self.update_running_counts()
+    }
+}
+
+/// The `k` color classes with the most samples, largest first.
+fn top_k_by_cardinality(
+    table: &ColorTable,
+    mmap: &ColorTableMmap,
+    k: usize,
+) -> Vec<(ColorId, u64)> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let cardinalities = cardinalities(table, mmap);
+    let start = mmap.indices().start.0;
+
+    // min-heap of the k largest so far; among equal sizes, the highest id is dropped first
+    let mut top = BinaryHeap::with_capacity(k + 1);
+    for id in heads(table, mmap) {
+        let cardinality = u64::from(cardinalities[(id.0 - start) as usize]);
+        top.push(Reverse((cardinality, Reverse(id))));
+        if top.len() > k {
+            top.pop();
+        }
+    }
+
+    top.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((cardinality, Reverse(id)))| (id, cardinality))
+        .collect()
+}
+
+/// Bits added by the fragments of the generations after `a_gen`, up to and including `b_gen`.
+fn diff<'m>(
+    table: &ColorTable,
+    mmap: &'m ColorTableMmap,
+    a_gen: u64,
+    b_gen: u64,
+) -> impl Iterator<Item = (ColorId, u32)> + 'm {
+    let ranges = table
+        .generations
+        .read()
+        .committed()
+        .iter()
+        .filter(|&(_, generation)| a_gen < generation && generation <= b_gen)
+        .map(|(range, _)| range.clone())
+        .collect::<Vec<_>>();
+
+    ranges
+        .into_iter()
+        .flat_map(|range| fragments_in(mmap, Some(range)))
+        .filter(|(_, fragment)| !fragment.is_removal() && fragment.color() != 0)
+        .map(|(idx, fragment)| (idx.into(), fragment.color()))
+}
+
+fn jaccard((size_a, size_b, shared): (u64, u64, u64)) -> f64 {
+    let union = size_a + size_b - shared;
+    if union == 0 {
+        return 1.0;
+    }
+    shared as f64 / union as f64
+}
+
+fn containment((size_a, _, shared): (u64, u64, u64)) -> f64 {
+    if size_a == 0 {
+        return 1.0;
+    }
+    shared as f64 / size_a as f64
+}
+
+impl MmapGuard<'_> {
+    /// Add 1 to `counts[i]` for every color class that contains index `i`, for each of the given
+    /// color ids.
+    ///
+    /// Fragments are decoded straight into the counters, without building any intermediate
+    /// collection. Ids that appear several times are counted several times.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a color class contains an index that is not less than `counts.len()`.
+    pub fn accumulate(&self, ids: impl IntoIterator<Item = ColorId>, counts: &mut [u32]) {
+        accumulate(self.0, &self.1, ids, counts);
+    }
+
+    /// Jaccard similarity of two color classes, the size of their intersection over the size of
+    /// their union.
+    ///
+    /// Fragments shared by both classes are only read once. Two empty classes have a similarity
+    /// of 1.
+    pub fn jaccard(&self, a: &ColorId, b: &ColorId) -> f64 {
+        jaccard(overlap(self.0, &self.1, a, b))
+    }
+
+    /// Get the ids of the color classes that have not been extended or forked: the fragments that
+    /// are not the parent of any other mapped fragment.
+    ///
+    /// Forking a color class does not end it, but its head is then the parent of the fork, so it
+    /// is not yielded. Deleted color classes (see [`ColorTable::tombstone`]) are not yielded either.
+    /// The mapping is scanned once when this method is called, which allocates a byte per fragment.
+    pub fn iter_heads(&self) -> impl Iterator<Item = ColorId> {
+        heads(self.0, &self.1)
+    }
+
+    /// Get the bits gained by color classes between the end of generation `a_gen` and the end of
+    /// generation `b_gen`.
+    ///
+    /// Yields an item for every fragment written in a generation after `a_gen`, up to and
+    /// including `b_gen`, in file order: the id of the color class it created or extended, and the
+    /// bits it added. The bits are in the word of the generation the fragment was written in (see
+    /// [`MmapGuard::last_extended_in`]). Empty fragments and removals are skipped. Nothing is
+    /// yielded if `b_gen` is not after `a_gen`.
+    pub fn diff(&self, a_gen: u64, b_gen: u64) -> impl Iterator<Item = (ColorId, u32)> {
+        diff(self.0, &self.1, a_gen, b_gen)
+    }
+
+    /// Containment of color class `a` in color class `b`, the fraction of `a` that is also in `b`.
+    ///
+    /// Fragments shared by both classes are only read once. An empty class is fully contained in
+    /// any class.
+    pub fn containment(&self, a: &ColorId, b: &ColorId) -> f64 {
+        containment(overlap(self.0, &self.1, a, b))
+    }
+
+    /// A hash of the color class referred to by the given color id, which only depends on the
+    /// indices in the class.
+    ///
+    /// Classes with the same indices have the same hash, even across tables, so identical classes
+    /// can be found by comparing hashes. The hash function is stable across releases of this crate,
+    /// so hashes can be stored.
+    ///
+    /// The class is decoded to compute the hash. Color ids refer to immutable fragments, so the
+    /// hash of an id never changes and can be cached by the caller.
+    pub fn class_hash(&self, color_id: &ColorId) -> u128 {
+        class_hash(self.0, &self.1, color_id)
+    }
+
+    /// Get the cardinality (number of samples) of the color class referred to by the given color
+    /// id.
+    ///
+    /// If `running_counts` is enabled in the [`ColorTableConfig`](crate::ColorTableConfig), this
+    /// is a single lookup for classes whose fragments are all in ended generations. Otherwise, the
+    /// class is walked without being decoded, counting the samples of each word.
+    pub fn cardinality(&self, color_id: &ColorId) -> u64 {
+        cardinality(self.0, &self.1, color_id)
+    }
+
+    /// Get the `k` largest color classes and their cardinalities (number of samples), largest
+    /// first.
+    ///
+    /// Only the heads of the color classes are considered (see [`MmapGuard::iter_heads`]), and
+    /// classes of the same size are ordered by id. The cardinality of every class is found in a
+    /// single pass over the mapping, from the popcounts of the fragments, without decoding any
+    /// class. This allocates 8 bytes per fragment. If `running_counts` is enabled in the
+    /// [`ColorTableConfig`](crate::ColorTableConfig), only the fragments of generations that
+    /// haven't been counted yet are read.
+    pub fn top_k_by_cardinality(&self, k: usize) -> Vec<(ColorId, u64)> {
+        top_k_by_cardinality(self.0, &self.1, k)
+    }
+}
+
+impl OwnedMmapGuard {
+    /// Add 1 to `counts[i]` for every color class that contains index `i`, for each of the given
+    /// color ids.
+    ///
+    /// See [`MmapGuard::accumulate`].
+    pub fn accumulate(&self, ids: impl IntoIterator<Item = ColorId>, counts: &mut [u32]) {
+        accumulate(&self.0, &self.1, ids, counts);
+    }
+
+    /// Jaccard similarity of two color classes.
+    ///
+    /// See [`MmapGuard::jaccard`].
+    pub fn jaccard(&self, a: &ColorId, b: &ColorId) -> f64 {
+        jaccard(overlap(&self.0, &self.1, a, b))
+    }
+
+    /// Get the ids of the color classes that have not been extended or forked.
+    ///
+    /// See [`MmapGuard::iter_heads`].
+    pub fn iter_heads(&self) -> impl Iterator<Item = ColorId> {
+        heads(&self.0, &self.1)
+    }
+
+    /// Get the bits gained by color classes between the end of generation `a_gen` and the end of
+    /// generation `b_gen`.
+    ///
+    /// See [`MmapGuard::diff`].
+    pub fn diff(&self, a_gen: u64, b_gen: u64) -> impl Iterator<Item = (ColorId, u32)> {
+        diff(&self.0, &self.1, a_gen, b_gen)
+    }
+
+    /// Containment of color class `a` in color class `b`.
+    ///
+    /// See [`MmapGuard::containment`].
+    pub fn containment(&self, a: &ColorId, b: &ColorId) -> f64 {
+        containment(overlap(&self.0, &self.1, a, b))
+    }
+
+    /// A hash of the color class referred to by the given color id, which only depends on the
+    /// indices in the class.
+    ///
+    /// See [`MmapGuard::class_hash`].
+    pub fn class_hash(&self, color_id: &ColorId) -> u128 {
+        class_hash(&self.0, &self.1, color_id)
+    }
+
+    /// Get the cardinality (number of samples) of the color class referred to by the given color
+    /// id.
+    ///
+    /// See [`MmapGuard::cardinality`].
+    pub fn cardinality(&self, color_id: &ColorId) -> u64 {
+        cardinality(&self.0, &self.1, color_id)
+    }
+
+    /// Get the `k` largest color classes and their cardinalities, largest first.
+    ///
+    /// See [`MmapGuard::top_k_by_cardinality`].
+    pub fn top_k_by_cardinality(&self, k: usize) -> Vec<(ColorId, u64)> {
+        top_k_by_cardinality(&self.0, &self.1, k)
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use super::query::class_hash;
+use super::{ColorFragment, ColorTable, GenerationLog};
+use crate::{ColorTableError, Result};
+
+impl ColorTable {
+    /// Renumbers the generations of the color table, e.g. to make sparse generation numbers
+    /// consecutive, or to move them past the generations of another table before
+    /// [`ColorTable::merge_from`].
+    ///
+    /// Every generation `g` becomes `f(g)`, so `f` must be strictly increasing over the
+    /// generations of the table. Since the generation of a fragment is the word its bits belong
+    /// to, indices move with their generation: index `i` becomes `f(i / 32) * 32 + i % 32`. Indices
+    /// in removal fragments (see [`GenerationGuard::extend_color_class_remove`](super::GenerationGuard::extend_color_class_remove))
+    /// and materialized bitmaps are renumbered the same way, which calls `f` on the generations
+    /// they refer to, and the hashes of deduplicated color classes are recomputed. Color ids do not
+    /// change.
+    ///
+    /// The files are synced afterwards. The color table file is only rewritten if it holds removal
+    /// fragments.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::GenerationNotIncreasing`] if `f` does not preserve the order of
+    /// the generations, [`ColorTableError::InvalidGeneration`] if a renumbered removal or
+    /// materialized bitmap holds an index that does not fit in a `u32`, or an error if the files
+    /// could not be rewritten. The table is left unchanged if an error occurs before the files are
+    /// written.
+    pub fn renumber_generations(&mut self, mut f: impl FnMut(u64) -> u64) -> Result<()> {
+        let generations = self.generations.get_mut().renumber(&mut f)?;
+        let mut renumber_index = |idx: u32| {
+            let generation = f(u64::from(idx / u32::BITS));
+            u32::try_from(generation * u64::from(u32::BITS))
+                .ok()
+                .and_then(|base| base.checked_add(idx % u32::BITS))
+                .ok_or(ColorTableError::InvalidGeneration(generation))
+        };
+
+        #[cfg(feature = "roaring")]
+        let bitmap_checkpoints = self.bitmap_checkpoints.get_mut().try_map(|bitmap| {
+            bitmap
+                .iter()
+                .map(&mut renumber_index)
+                .collect::<Result<roaring::RoaringBitmap>>()
+        })?;
+
+        if self.chains.get_mut().has_removals() {
+            let mmap = self.mmap()?;
+            self.rewrite("renumber", |writer| {
+                for fragment in mmap.iter().skip(1) {
+                    let fragment = match fragment.is_removal() {
+                        true => ColorFragment {
+                            color: renumber_index(fragment.color())?.into(),
+                            ..*fragment
+                        },
+                        false => *fragment,
+                    };
+                    writer.write_fragment(&fragment)?;
+                }
+
+                Ok(())
+            })?;
+            let head = self.head();
+            *self.unflushed.get_mut() = (0, head);
+        }
+
+        *self.generations.get_mut() = Arc::new(generations);
+        #[cfg(feature = "roaring")]
+        {
+            *self.bitmap_checkpoints.get_mut() = Arc::new(bitmap_checkpoints);
+        }
+
+        // deleted classes are hashed like any other class
+        let tombstones = std::mem::take(self.tombstones.get_mut());
+        let mmap = self.mmap()?;
+        let class_hashes = self
+            .class_hashes
+            .lock()
+            .rehash(|idx| class_hash(self, &mmap, &idx.into()));
+        drop(mmap);
+        *self.class_hashes.get_mut() = class_hashes;
+        *self.tombstones.get_mut() = tombstones;
+        // indices move with their generation, so every sketch changes
+        self.rebuild_sketches()?;
+        self.rebuild_running_counts()?;
+
+        if let (Some(generation_log), Some(directory)) =
+            (self.generation_log.get_mut(), &self.directory)
+        {
+            *generation_log = GenerationLog::create(
+                &directory.join(&self.config.generation_log_file_name),
+                self.generations.get_mut(),
+                &self.config,
+            )?;
+        }
+
+        self.sync(None)
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::path::Path;
+
+use super::{
+    Access, ColorFragment, ColorFragmentIndex, ColorTable, TableLock, truncate_unshared,
+    write_generations,
+};
+use crate::generations::Generations;
+use crate::{ColorTableConfig, PathContext, Result};
+
+/// What [`ColorTable::load_repaired`] discarded from the color table files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of bytes of a partially written fragment at the end of the file.
+    pub trailing_bytes: u64,
+    /// Number of whole fragments after the last generation in the generations file, or after the
+    /// first fragments that don't belong to any generation.
+    pub fragments: u32,
+    /// Number of the generation the generations file recorded as in progress, if any.
+    pub in_progress: Option<u64>,
+    /// Number of generations discarded because they start after fragments that don't belong to
+    /// any generation.
+    pub generations: usize,
+}
+
+impl RepairReport {
+    /// Returns `true` if nothing was discarded.
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.trailing_bytes == 0
+            && self.fragments == 0
+            && self.in_progress.is_none()
+            && self.generations == 0
+    }
+}
+
+impl ColorTable {
+    /// Loads an existing `ColorTable` from the given directory, first repairing a color table file
+    /// left behind by a crash.
+    ///
+    /// [`ColorTable::load`] refuses a file whose length is not a multiple of the fragment size, or
+    /// that has fragments that don't belong to any generation. This method instead truncates the
+    /// file after the last generation in the generations file, which discards a partially written
+    /// fragment at the end, along with any fragments of a generation that was in progress (or ended
+    /// but not synced) when the table was closed. A generation recorded as in progress is
+    /// discarded from the generations file. If fragments before the last generation don't belong
+    /// to any generation, the file is truncated before them, and the generations after them are
+    /// discarded too. The returned [`RepairReport`] tells what was discarded.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::Locked`](crate::ColorTableError::Locked) if another handle has the
+    /// table open, even read-only, an error if the files could not be opened, truncated or
+    /// rewritten, or if the repaired table could not be loaded (see [`ColorTable::load`]).
+    pub fn load_repaired(
+        dir: impl AsRef<Path>,
+        config: ColorTableConfig,
+    ) -> Result<(Self, RepairReport)> {
+        let dir = dir.as_ref();
+        let lock = TableLock::acquire(&dir.join(&config.lock_file_name), &config)?;
+        let path = dir.join(&config.color_table_file_name);
+        let color_table = config
+            .color_table_options()
+            .read(true)
+            .append(true)
+            .open(&path)
+            .at(&path)?;
+
+        let generations_path = dir.join(&config.generations_file_name);
+        let generations =
+            Generations::open(&mut File::open(&generations_path).at(&generations_path)?)?;
+
+        let fragment_size = std::mem::size_of::<ColorFragment>() as u64;
+        let len = color_table.metadata().at(&path)?.len();
+        let head = ColorFragmentIndex((len / fragment_size) as u32);
+        let committed = generations.committed();
+        // the header is never discarded
+        let end = match committed.first_uncovered(head) {
+            Some(uncovered) => uncovered.start,
+            None => committed.last_range_end().unwrap_or(ColorFragmentIndex(1)),
+        };
+        let repaired = committed.truncate(end);
+
+        let mut report = RepairReport {
+            in_progress: generations.in_progress().map(|(generation, _)| generation),
+            generations: committed.iter().count() - repaired.iter().count(),
+            ..RepairReport::default()
+        };
+        // drop the generations first, so the table can be repaired again if truncating fails
+        if report.in_progress.is_some() || report.generations != 0 {
+            write_generations(dir, &repaired, &config)?;
+        }
+
+        let end = u64::from(end.0) * fragment_size;
+        if len > end {
+            report.trailing_bytes = len % fragment_size;
+            report.fragments = ((len - end) / fragment_size) as u32;
+            // tables loaded read-only may map the discarded fragments
+            truncate_unshared(&color_table, end, &path)?;
+            color_table.sync_data().at(&path)?;
+        }
+
+        let table = Self::load_from(dir, Some(lock), color_table, config, Access::Write)?;
+
+        Ok((table, report))
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+
+use bytemuck::Zeroable;
+
+use super::{
+    ColorFragment, ColorFragmentIndex, ColorId, ColorIdRange, ColorTable, GenerationGuard,
+    check_capacity,
+};
+use crate::{ColorTableError, Result};
+
+/// Fragments that have been assigned indices but not written to the file yet.
+///
+/// Once a range of ids is reserved, every later fragment is staged here too, since the file is
+/// append-only. Fragments are written as soon as all slots before them are filled.
+#[derive(Debug)]
+pub(super) struct Pending {
+    // index of the first slot
+    start: ColorFragmentIndex,
+    // `None` for reserved slots that have not been filled yet
+    slots: VecDeque<Option<ColorFragment>>,
+}
+
+impl Pending {
+    pub(super) fn new() -> Self {
+        Self {
+            start: ColorFragmentIndex(0),
+            slots: VecDeque::new(),
+        }
+    }
+
+    #[inline]
+    pub(super) fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Add `n` unfilled slots, starting at `head`.
+    pub(super) fn reserve(&mut self, head: ColorFragmentIndex, n: u32) {
+        if self.is_empty() {
+            self.start = head;
+        }
+        self.slots.extend(std::iter::repeat_n(None, n as usize));
+    }
+
+    /// Add filled slots, starting at `head`.
+    pub(super) fn stage(&mut self, head: ColorFragmentIndex, fragments: &[ColorFragment]) {
+        if self.is_empty() {
+            self.start = head;
+        }
+        self.slots.extend(fragments.iter().copied().map(Some));
+    }
+
+    /// Fill the reserved slot at `idx`.
+    fn fill(&mut self, idx: ColorFragmentIndex, fragment: ColorFragment) -> Result<()> {
+        let slot = idx
+            .0
+            .checked_sub(self.start.0)
+            .and_then(|offset| self.slots.get_mut(offset as usize))
+            .filter(|slot| slot.is_none())
+            .ok_or(ColorTableError::InvalidColorId(idx.0))?;
+        *slot = Some(fragment);
+
+        Ok(())
+    }
+
+    /// Fill all unfilled slots with empty fragments. Returns the number of slots filled.
+    pub(super) fn fill_unwritten(&mut self) -> usize {
+        self.slots
+            .iter_mut()
+            .filter(|slot| slot.is_none())
+            .map(|slot| *slot = Some(ColorFragment::zeroed()))
+            .count()
+    }
+
+    /// Remove the filled slots at the front. Returns the index of the first removed slot and the fragments.
+    pub(super) fn take_ready(&mut self) -> (ColorFragmentIndex, Vec<ColorFragment>) {
+        let start = self.start;
+        let mut ready = Vec::new();
+        while let Some(Some(fragment)) = self.slots.front() {
+            ready.push(*fragment);
+            self.slots.pop_front();
+        }
+        self.start += ready.len() as u32;
+
+        (start, ready)
+    }
+}
+
+impl ColorTable {
+    /// Fill a reserved slot, writing any fragments that are no longer waiting for earlier slots.
+    fn write_reserved(&self, idx: ColorFragmentIndex, fragment: ColorFragment) -> Result<()> {
+        let mut file = self.file.lock();
+        let mut pending = self.pending.lock();
+        pending.fill(idx, fragment)?;
+        self.write_ready(&mut file, &mut pending)?;
+        drop((file, pending));
+        self.metrics.with(|metrics| metrics.fragments_written(1));
+
+        Ok(())
+    }
+}
+
+impl<'a> GenerationGuard<'a> {
+    /// Reserve `n` consecutive color ids, to be written later in this generation with
+    /// [`new_color_class_at`](Self::new_color_class_at), [`fork_color_class_at`](Self::fork_color_class_at)
+    /// or [`extend_color_class_at`](Self::extend_color_class_at).
+    ///
+    /// Fragments written after the reservation (by any method) are held in memory until every
+    /// reserved id before them has been written. Reserved ids that are still unwritten when the
+    /// generation ends are written as empty color classes, and the generation returns an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::TableFull`] if the reserved ids would not fit in the table.
+    pub fn reserve_ids(&self, n: u32) -> Result<ColorIdRange> {
+        let _file = self.table.file.lock();
+        let head = self.table.head();
+        check_capacity(head, n as usize)?;
+        self.table.pending.lock().reserve(head, n);
+        self.table.advance_head(head, n);
+
+        Ok(ColorIdRange::new(head.into(), n))
+    }
+
+    /// Write a new color class to a reserved id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `id` is not reserved, or has already been written.
+    pub fn new_color_class_at(&self, id: ColorId, color: u32) -> Result<()> {
+        self.table.check_color(color)?;
+        self.table.write_reserved(
+            id.into(),
+            ColorFragment {
+                color: color.into(),
+                parent_pointer: ColorFragmentIndex(0).into(),
+            },
+        )
+    }
+
+    /// Write a fork of a color class to a reserved id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `parent` is not a valid color id older than `id`, or if `id` is not
+    /// reserved or has already been written.
+    pub fn fork_color_class_at(&self, id: ColorId, parent: ColorId, color: u32) -> Result<()> {
+        self.table.check_color(color)?;
+        let parent_idx = self.reserved_parent_index(id, parent)?;
+        self.table.write_reserved(
+            id.into(),
+            ColorFragment {
+                color: color.into(),
+                parent_pointer: parent_idx.into(),
+            },
+        )
+    }
+
+    /// Write an extension of a color class to a reserved id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `parent` is not a valid color id older than `id`, or if `id` is not
+    /// reserved or has already been written.
+    pub fn extend_color_class_at(&self, id: ColorId, parent: ColorId, color: u32) -> Result<()> {
+        self.table.check_color(color)?;
+        let parent_idx = self.reserved_parent_index(id, parent)?;
+        self.table.write_reserved(
+            id.into(),
+            ColorFragment {
+                color: color.into(),
+                parent_pointer: parent_idx.into(),
+            },
+        )?;
+        self.table.defer_head(&parent, parent_idx, id.into());
+
+        Ok(())
+    }
+
+    #[inline]
+    fn reserved_parent_index(&self, id: ColorId, parent: ColorId) -> Result<ColorFragmentIndex> {
+        // parents are always written before their children
+        self.table
+            .parent_index(&parent)
+            .filter(|idx| idx.0 < id.0)
+            .ok_or(ColorTableError::InvalidColorId(parent.0))
+    }
+}
@@ -0,0 +1,30 @@
+use roaring::RoaringBitmap;
+
+use super::{ColorId, MmapGuard, OwnedMmapGuard};
+
+/// Resolves color ids to the samples in their color classes.
+///
+/// Code that only needs to look up color classes (e.g. a k-mer index that stores a [`ColorId`]
+/// per k-mer) can depend on this trait instead of on the mapping types of this crate.
+pub trait ColorResolver {
+    /// Get the samples (indices) in the color class of `id`.
+    fn samples(&self, id: ColorId) -> RoaringBitmap;
+}
+
+impl ColorResolver for MmapGuard<'_> {
+    fn samples(&self, id: ColorId) -> RoaringBitmap {
+        self.color_class(&id).into_bitmap()
+    }
+}
+
+impl ColorResolver for OwnedMmapGuard {
+    fn samples(&self, id: ColorId) -> RoaringBitmap {
+        self.color_class(&id).into_bitmap()
+    }
+}
+
+impl<T: ColorResolver + ?Sized> ColorResolver for &T {
+    fn samples(&self, id: ColorId) -> RoaringBitmap {
+        (**self).samples(id)
+    }
+}
@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use bincode::{Decode, Encode};
+use xxhash_rust::xxh3::Xxh3;
+
+use super::{Access, ColorFragment, ColorTable, MapOptions, MmapGuard};
+use crate::generations::Generations;
+use crate::{ColorTableConfig, ColorTableError, PathContext, Result};
+
+/// The finalization record of a sealed color table, written to the seal file by
+/// [`ColorTable::seal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct SealInfo {
+    /// Number of fragments in the table, not counting the header.
+    pub fragment_count: u32,
+    /// Index following the last fragment, i.e. the length of the color table file in fragments.
+    pub head: u32,
+    /// The last generation written to the table, if any.
+    pub last_generation: Option<u64>,
+    /// Checksum (XXH3) of the fragments and generations of the table.
+    pub checksum: u64,
+}
+
+/// A color table that was sealed with [`ColorTable::seal`], and can no longer be modified.
+///
+/// All read-only operations are available through [`SealedColorTable::color_table`]; operations
+/// that would modify the table fail with [`ColorTableError::Sealed`].
+#[derive(Debug)]
+pub struct SealedColorTable {
+    table: ColorTable,
+    info: SealInfo,
+}
+
+impl ColorTable {
+    /// Seals the color table, making it immutable.
+    ///
+    /// The table is synced, and a seal file recording the number of fragments, the head index, the
+    /// last generation and a checksum of the fragments and generations is written, so that
+    /// published tables can be checked to be final and intact (see
+    /// [`SealedColorTable::verify_seal`]). A sealed table can't be loaded with
+    /// [`ColorTable::load`]; load it with [`SealedColorTable::load`] instead. In-memory tables are
+    /// sealed without writing anything.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the table is read-only (see [`ColorTable::load_read_only`]), if syncing or
+    /// mmapping the table fails, or if the seal file could not be written.
+    pub fn seal(mut self) -> Result<SealedColorTable> {
+        self.check_writable()?;
+        self.sync(None)?;
+        let info = self.seal_info()?;
+
+        if let Some(directory) = &self.directory {
+            let path = directory.join(&self.config.seal_file_name);
+            let tmp_path = path.with_extension("tmp");
+            let mut writer = io::BufWriter::new(self.config.create_file(&tmp_path)?);
+            bincode::encode_into_std_write(info, &mut writer, crate::BINCODE_CONFIG)?;
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|file| file.sync_all())
+                .at(&tmp_path)?;
+            std::fs::rename(&tmp_path, &path).at(&path)?;
+        }
+        self.access = Access::Sealed;
+
+        Ok(SealedColorTable { table: self, info })
+    }
+
+    /// Computes the seal of the table as it is now.
+    fn seal_info(&self) -> Result<SealInfo> {
+        let mmap = self.mmap()?;
+        let generations = self.generations.read();
+
+        Ok(SealInfo {
+            fragment_count: self.fragment_count(),
+            head: self.head().0,
+            last_generation: generations.last_generation(),
+            checksum: checksum(bytemuck::cast_slice(mmap.as_fragments()), &generations),
+        })
+    }
+}
+
+impl SealedColorTable {
+    /// Loads a sealed color table from the given directory.
+    ///
+    /// The files are opened read-only and the table only takes a shared lock, so any number of
+    /// processes can load a sealed table at once. Since the table was synced when it was sealed, the recovery
+    /// checks of [`ColorTable::load`] are skipped; only the length of the color table file is
+    /// checked against the seal. Use [`SealedColorTable::verify_seal`] to check the contents.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::NotSealed`] if the table has no seal file,
+    /// [`ColorTableError::SealMismatch`] if the color table file doesn't have the sealed length, or
+    /// an error if the table could not be loaded (see [`ColorTable::load`]).
+    pub fn load(dir: impl AsRef<Path>, config: ColorTableConfig) -> Result<Self> {
+        let dir = dir.as_ref();
+        let seal_path = dir.join(&config.seal_file_name);
+        let info: SealInfo = match File::open(&seal_path) {
+            Ok(file) => {
+                bincode::decode_from_std_read(&mut io::BufReader::new(file), crate::BINCODE_CONFIG)?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ColorTableError::NotSealed);
+            }
+            Err(e) => return Err(e).at(seal_path),
+        };
+
+        let path = dir.join(&config.color_table_file_name);
+        let color_table = File::open(&path).at(&path)?;
+        let len = color_table.metadata().at(&path)?.len();
+        if len != u64::from(info.head) * size_of::<ColorFragment>() as u64 {
+            return Err(ColorTableError::SealMismatch);
+        }
+
+        let table = ColorTable::load_from(dir, None, color_table, config, Access::Sealed)?;
+
+        Ok(Self { table, info })
+    }
+
+    /// Get the seal of the table.
+    #[inline]
+    pub fn seal_info(&self) -> &SealInfo {
+        &self.info
+    }
+
+    /// Get a reference to the sealed color table.
+    #[inline]
+    pub fn color_table(&self) -> &ColorTable {
+        &self.table
+    }
+
+    /// Maps the color table to memory.
+    ///
+    /// See [`ColorTable::map`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails.
+    pub fn map(&self) -> Result<MmapGuard<'_>> {
+        self.table.map()
+    }
+
+    /// Maps the color table to memory, with the given access hints.
+    ///
+    /// See [`ColorTable::map_with`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails, or if the options could not be applied.
+    pub fn map_with(&self, options: MapOptions) -> Result<MmapGuard<'_>> {
+        self.table.map_with(options)
+    }
+
+    /// Checks that the fragments and generations of the table match the seal, by recomputing its
+    /// checksum.
+    ///
+    /// This reads the whole color table file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::SealMismatch`] if the table doesn't match the seal, or an error
+    /// if mmapping fails.
+    pub fn verify_seal(&self) -> Result<()> {
+        if self.table.seal_info()? != self.info {
+            return Err(ColorTableError::SealMismatch);
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of fragment bytes hashed at a time.
+const CHECKSUM_BLOCK_SIZE: usize = 1 << 20;
+
+/// The XXH3 hash of the fragment bytes, hashed in blocks of [`CHECKSUM_BLOCK_SIZE`] bytes,
+/// followed by the range and number of each generation.
+fn checksum(fragments: &[u8], generations: &Generations) -> u64 {
+    let mut hasher = Xxh3::new();
+    for block in fragments.chunks(CHECKSUM_BLOCK_SIZE) {
+        hasher.update(block);
+    }
+    for (range, generation) in generations.iter() {
+        hasher.update(&range.start.0.to_le_bytes());
+        hasher.update(&range.end.0.to_le_bytes());
+        hasher.update(&generation.to_le_bytes());
+    }
+
+    hasher.digest()
+}
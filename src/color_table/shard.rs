@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::query::heads;
+use super::{ClassIter, ColorFragmentIndex, ColorId, ColorIdMapping, ColorTable, MmapGuard};
+use crate::{ColorTableConfig, ColorTableError, PathContext, Result};
+
+impl ColorTable {
+    /// Splits the color table into `n` smaller tables, in subdirectories `shard-0`, `shard-1`, ...
+    /// of `dir`.
+    ///
+    /// The heads of the color classes (see [`MmapGuard::iter_heads`]) are split into `n` ranges of
+    /// consecutive color ids with about the same number of heads, and each shard holds the classes
+    /// of one range. Fragments shared by classes in different shards are copied into each of them,
+    /// so the shards may hold more fragments in total than the table. Deleted classes are dropped,
+    /// and each shard keeps the generations, payloads and deletions of its fragments.
+    ///
+    /// Color ids are renumbered within each shard. The mapping from color ids of this table to
+    /// color ids of the shard is written to a manifest in each shard directory, which
+    /// [`ShardedColorTable`] uses to route queries by the original color ids.
+    ///
+    /// Returns the shard directories. This method blocks until no generation is in progress.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::InvalidShardCount`] if `n` is 0, or an error if the table could
+    /// not be mapped, if it has fragments outside of any generation, or if a shard could not be
+    /// written.
+    pub fn shard(&self, dir: impl AsRef<Path>, n: usize) -> Result<Vec<PathBuf>> {
+        if n == 0 {
+            return Err(ColorTableError::InvalidShardCount(n));
+        }
+        let _guard = self.generation_lock.lock();
+        self.check_coverage()?;
+
+        let mmap = self.mmap()?;
+        let generations = Arc::clone(&self.generations.read());
+        let head = self.head();
+        let heads = heads(self, &mmap).collect::<Vec<_>>();
+
+        let mut dirs = Vec::with_capacity(n);
+        // new index of each fragment in the current shard, or 0 if it isn't in the shard
+        let mut new_indices = vec![0; head.0 as usize];
+        for shard in 0..n {
+            let shard_heads = &heads[shard * heads.len() / n..(shard + 1) * heads.len() / n];
+
+            // mark the fragments of the shard's classes
+            new_indices.fill(0);
+            for id in shard_heads {
+                let mut idx = ColorFragmentIndex::from(id);
+                while idx.0 != 0 && new_indices[idx.0 as usize] == 0 {
+                    new_indices[idx.0 as usize] = u32::MAX;
+                    idx = mmap
+                        .fragment(&idx)
+                        .ok_or(ColorTableError::InvalidColorId(idx.0))?
+                        .parent();
+                }
+            }
+
+            let shard_dir = dir.as_ref().join(format!("shard-{shard}"));
+            std::fs::create_dir_all(&shard_dir).at(&shard_dir)?;
+            let table = ColorTable::new(&shard_dir, self.config.as_ref().clone())?;
+            {
+                let _shard_guard = table.generation_lock.lock();
+                for (range, generation) in generations.iter() {
+                    if !(range.start.0..range.end.0).any(|i| new_indices[i as usize] != 0) {
+                        continue;
+                    }
+                    table.run_generation(generation, table.allow_repeat(), |_| {
+                        for i in range.start.0..range.end.0 {
+                            if new_indices[i as usize] == 0 {
+                                continue;
+                            }
+                            let fragment = mmap
+                                .fragment(&ColorFragmentIndex(i))
+                                .ok_or(ColorTableError::InvalidColorId(i))?;
+                            // parents are written before their children, so they are renumbered
+                            let parent =
+                                ColorFragmentIndex(new_indices[fragment.parent().0 as usize]);
+                            new_indices[i as usize] =
+                                table.write_fragment(fragment.with_parent(parent))?.0;
+                        }
+
+                        Ok::<_, ColorTableError>(())
+                    })??;
+                }
+            }
+
+            let payloads = self.payloads.read();
+            let tombstones = self.tombstones.read();
+            let mut pairs = Vec::new();
+            for (i, &new) in new_indices.iter().enumerate().skip(1) {
+                if new == 0 {
+                    continue;
+                }
+                let (old, new) = (ColorFragmentIndex(i as u32), ColorFragmentIndex(new));
+                let payload = payloads.get(&old);
+                if payload != 0 {
+                    table.payloads.write().set(new, payload);
+                }
+                if tombstones.contains(&old) {
+                    table.tombstones.write().insert(new);
+                }
+                pairs.push((ColorId(old.0), ColorId(new.0)));
+            }
+            drop((payloads, tombstones));
+            table.sync(None)?;
+
+            let path = shard_dir.join(&self.config.shard_manifest_file_name);
+            let mut writer = io::BufWriter::new(self.config.create_file(&path)?);
+            bincode::encode_into_std_write(
+                ColorIdMapping::from_sorted(pairs),
+                &mut writer,
+                crate::BINCODE_CONFIG,
+            )?;
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|file| file.sync_all())
+                .at(&path)?;
+
+            dirs.push(shard_dir);
+        }
+
+        Ok(dirs)
+    }
+}
+
+/// A color table split into shards with [`ColorTable::shard`], queried by the color ids of the
+/// original table.
+#[derive(Debug)]
+pub struct ShardedColorTable {
+    shards: Vec<ColorTable>,
+    // mapping from original color ids to color ids of each shard
+    mappings: Vec<ColorIdMapping>,
+}
+
+impl ShardedColorTable {
+    /// Loads the shards in the given directories, as returned by [`ColorTable::shard`].
+    ///
+    /// Each shard is loaded with [`ColorTable::load`], using `config`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a shard or its manifest could not be loaded.
+    pub fn load(
+        dirs: impl IntoIterator<Item = impl AsRef<Path>>,
+        config: ColorTableConfig,
+    ) -> Result<Self> {
+        let mut shards = Vec::new();
+        let mut mappings = Vec::new();
+        for dir in dirs {
+            let dir = dir.as_ref();
+            let path = dir.join(&config.shard_manifest_file_name);
+            let file = File::open(&path).at(&path)?;
+            mappings.push(bincode::decode_from_std_read(
+                &mut io::BufReader::new(file),
+                crate::BINCODE_CONFIG,
+            )?);
+            shards.push(ColorTable::load(dir, config.clone())?);
+        }
+
+        Ok(Self { shards, mappings })
+    }
+
+    /// Get the shards, in the order they were loaded.
+    #[inline]
+    pub fn shards(&self) -> &[ColorTable] {
+        &self.shards
+    }
+
+    /// Find the shard holding the class referred to by a color id of the original table, and its
+    /// color id in that shard.
+    ///
+    /// Classes shared by several shards are routed to the first of them. Returns `None` if no
+    /// shard holds the class, e.g. if it was deleted before the table was split.
+    pub fn route(&self, color_id: &ColorId) -> Option<(usize, ColorId)> {
+        self.mappings
+            .iter()
+            .enumerate()
+            .find_map(|(shard, mapping)| Some((shard, mapping.get(color_id)?)))
+    }
+
+    /// Maps all shards to memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping a shard fails (see [`ColorTable::map`]).
+    pub fn map(&self) -> Result<ShardedMmapGuard<'_>> {
+        let guards = self
+            .shards
+            .iter()
+            .map(ColorTable::map)
+            .collect::<Result<_>>()?;
+
+        Ok(ShardedMmapGuard {
+            table: self,
+            guards,
+        })
+    }
+}
+
+/// RAII guard for the memory-mapped shards of a [`ShardedColorTable`].
+#[derive(Debug)]
+pub struct ShardedMmapGuard<'a> {
+    table: &'a ShardedColorTable,
+    guards: Vec<MmapGuard<'a>>,
+}
+
+impl ShardedMmapGuard<'_> {
+    /// Get an iterator over the color class referred to by a color id of the original table, or
+    /// `None` if no shard holds it.
+    ///
+    /// See [`ShardedColorTable::route`] and [`MmapGuard::color_class`].
+    pub fn color_class(&self, color_id: &ColorId) -> Option<ClassIter<'_>> {
+        let (shard, id) = self.table.route(color_id)?;
+
+        Some(self.guards[shard].color_class(&id))
+    }
+
+    /// Get the mapping of each shard.
+    #[inline]
+    pub fn shards(&self) -> &[MmapGuard<'_>] {
+        &self.guards
+    }
+}
@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytemuck::{Pod, Zeroable};
+use parking_lot::RwLock;
+
+#[cfg(feature = "roaring")]
+use super::BitmapCheckpoints;
+use super::{ClassIter, ColorFragment, ColorFragmentIndex, ColorId, ColorTableMmap};
+use crate::chains::Chains;
+use crate::generations::Generations;
+use crate::{ColorTableConfig, ColorTableError, PathContext, Result};
+
+/// A generation boundary, as published in the generation log.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+struct GenerationRecord {
+    start: ColorFragmentIndex,
+    end: ColorFragmentIndex,
+    generation: u64,
+}
+
+/// Append-only log of ended generations, which lets other processes follow a table as it is written.
+///
+/// Only generations that contain fragments are recorded. A generation is appended to the log after
+/// its fragments have been flushed, so every fragment covered by the log can be read from the file.
+#[derive(Debug)]
+pub(super) struct GenerationLog(File);
+
+impl GenerationLog {
+    /// Replace the log at `path` with one containing the given generations.
+    ///
+    /// The log is replaced atomically, so readers never see a partially written log.
+    pub(super) fn create(
+        path: &Path,
+        generations: &Generations,
+        config: &ColorTableConfig,
+    ) -> Result<Self> {
+        let records = generations
+            .committed()
+            .iter()
+            .map(|(range, generation)| GenerationRecord {
+                start: range.start,
+                end: range.end,
+                generation,
+            })
+            .collect::<Vec<_>>();
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = config.create_file(&tmp_path)?;
+        file.write_all(bytemuck::cast_slice(&records))
+            .and_then(|()| file.sync_all())
+            .at(&tmp_path)?;
+        std::fs::rename(&tmp_path, path).at(path)?;
+
+        Ok(Self(File::options().append(true).open(path).at(path)?))
+    }
+
+    /// Publish an ended generation. Its fragments must already be flushed.
+    pub(super) fn append(
+        &mut self,
+        start: ColorFragmentIndex,
+        end: ColorFragmentIndex,
+        generation: u64,
+    ) -> io::Result<()> {
+        if start == end {
+            return Ok(());
+        }
+
+        self.0.write_all(bytemuck::bytes_of(&GenerationRecord {
+            start,
+            end,
+            generation,
+        }))
+    }
+}
+
+/// Read-only view of a color table that is being written by another process.
+///
+/// The writer must publish its generations (see [`ColorTableConfig`]). The reader only sees
+/// generations that have ended, and picks up new ones on [`ColorTableReader::refresh`]. Readers don't
+/// lock the table, so any number of them can follow a writer.
+///
+/// If the writer reopens or compacts the table, the generation log is replaced, and readers must be
+/// reopened to see any further changes.
+#[derive(Debug)]
+pub struct ColorTableReader {
+    color_table: PathBuf,
+    generation_log: File,
+    // number of bytes of the generation log that have been read
+    log_offset: u64,
+    generations: Arc<Generations>,
+    chains: RwLock<Chains>,
+    mmap: ColorTableMmap,
+    #[cfg(feature = "roaring")]
+    bitmap_checkpoints: Arc<BitmapCheckpoints>,
+}
+
+impl ColorTableReader {
+    /// Opens a reader for the color table in the given directory, and reads all published generations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the color table or generation log could not be opened, or if they are
+    /// inconsistent.
+    pub fn open(dir: impl AsRef<Path>, config: &ColorTableConfig) -> Result<Self> {
+        let color_table = dir.as_ref().join(&config.color_table_file_name);
+        let log_path = dir.as_ref().join(&config.generation_log_file_name);
+        let generation_log = File::open(&log_path).at(&log_path)?;
+
+        // SAFETY: the writer never modifies the published part of the file
+        let mmap = unsafe {
+            ColorTableMmap::new(
+                File::open(&color_table).at(&color_table)?,
+                Some(std::mem::size_of::<ColorFragment>()),
+            )?
+        };
+
+        let mut reader = Self {
+            color_table,
+            generation_log,
+            log_offset: 0,
+            generations: Arc::new(Generations::new()),
+            chains: RwLock::new(Chains::new(config.skip_interval)),
+            mmap,
+            #[cfg(feature = "roaring")]
+            bitmap_checkpoints: Arc::new(BitmapCheckpoints::new()),
+        };
+        reader.refresh()?;
+
+        Ok(reader)
+    }
+
+    /// Reads generations published since the last refresh, and remaps the table if it has grown.
+    ///
+    /// Returns `true` if any new generations were read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or mmapping fails, or if the published generations are
+    /// inconsistent with the table.
+    pub fn refresh(&mut self) -> Result<bool> {
+        const RECORD_SIZE: u64 = std::mem::size_of::<GenerationRecord>() as u64;
+
+        // a record may be partially written, so only read whole records
+        let len = self.generation_log.metadata()?.len();
+        let end = len - len % RECORD_SIZE;
+        if end <= self.log_offset {
+            return Ok(false);
+        }
+
+        let mut buf = vec![0; (end - self.log_offset) as usize];
+        self.generation_log.seek(SeekFrom::Start(self.log_offset))?;
+        self.generation_log.read_exact(&mut buf)?;
+
+        let mut generations = Generations::clone(&self.generations);
+        let mut head = ColorFragmentIndex(1);
+        for record in buf.chunks_exact(RECORD_SIZE as usize) {
+            let record: GenerationRecord = bytemuck::pod_read_unaligned(record);
+            // the writer may have merged repeated generations, depending on its policy
+            generations.start_generation_at(record.start, record.generation, true)?;
+            generations.end_current_generation_at(record.end)?;
+            head = record.end;
+        }
+
+        let file = File::open(&self.color_table).at(&self.color_table)?;
+        let len = head.0 as usize * std::mem::size_of::<ColorFragment>();
+        let file_len = file.metadata().at(&self.color_table)?.len();
+        if (file_len as usize) < len {
+            // mapping past the end of the file would fault on access
+            return Err(ColorTableError::Truncated {
+                path: self.color_table.clone(),
+                len: file_len,
+                expected: len as u64,
+            });
+        }
+        // SAFETY: see `ColorTableReader::open`
+        let mmap = unsafe { ColorTableMmap::new(file, Some(len))? };
+
+        // extend the chain metadata with the new fragments
+        let chains = self.chains.get_mut();
+        let new_fragments = mmap
+            .get(chains.len()..)
+            .ok_or_else(|| ColorTableError::Truncated {
+                path: self.color_table.clone(),
+                len: file_len,
+                expected: (chains.len() * std::mem::size_of::<ColorFragment>()) as u64,
+            })?;
+        // parents are always written before their children
+        if let Some((fragment, index)) = new_fragments
+            .iter()
+            .zip(chains.len() as u32..)
+            .find(|(fragment, idx)| fragment.parent().0 >= *idx)
+        {
+            return Err(ColorTableError::ParentNotBefore {
+                index,
+                parent: fragment.parent().0,
+            });
+        }
+        for fragment in new_fragments {
+            chains.push_fragment(fragment);
+        }
+
+        self.generations = Arc::new(generations);
+        self.mmap = mmap;
+        self.log_offset = end;
+
+        Ok(true)
+    }
+
+    /// Get an iterator over the color class referred to by the given color id.
+    ///
+    /// See [`MmapGuard::color_class`](super::MmapGuard::color_class). Color ids written after the
+    /// last refresh return an empty iterator.
+    pub fn color_class(&self, color_id: &ColorId) -> ClassIter<'_> {
+        ClassIter::from_parts(
+            &self.mmap,
+            Arc::clone(&self.generations),
+            &self.chains,
+            #[cfg(feature = "roaring")]
+            Arc::clone(&self.bitmap_checkpoints),
+            color_id,
+        )
+    }
+
+    /// Run a query on the mapping, failing instead of crashing if the color table file was
+    /// truncated while mapped.
+    ///
+    /// See [`MmapGuard::checked`](super::MmapGuard::checked).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::MappingFaulted`] if an access to the mapping faulted.
+    pub fn checked<R>(&self, f: impl FnOnce(&Self) -> R) -> Result<R> {
+        super::checked(&self.mmap, || f(self))
+    }
+}
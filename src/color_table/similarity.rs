@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use super::{ColorFragmentIndex, ColorId, ColorTable};
+use crate::sketches::Sketches;
+use crate::{ColorMode, ColorTableError, Result};
+
+impl ColorTable {
+    /// Find the color classes whose Jaccard similarity to the class `id` is at least
+    /// `min_jaccard`.
+    ///
+    /// Only head classes (those no other fragment was written after) of ended generations are
+    /// searched, and deleted classes and `id` itself are skipped. Candidates are first compared by
+    /// their sketches (see `sketch_size` in [`ColorTableConfig`](crate::ColorTableConfig)), and
+    /// only those with an estimated similarity of at least `min_jaccard` are decoded to compute
+    /// their exact similarity. Since the estimate is approximate, a class whose similarity is just
+    /// above `min_jaccard` may be missed; larger sketches make this less likely. Classes built with
+    /// removal fragments have no exact sketch, so they are always decoded.
+    ///
+    /// Returns the color ids of the similar classes, in ascending order. An empty class is not
+    /// similar to any class.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ColorTableError::SketchesDisabled`] if `sketch_size` is 0,
+    /// [`ColorTableError::UnsupportedColorMode`] in the [`ColorMode::Counts`] color mode, or an
+    /// error if mmapping fails.
+    pub fn similar_classes(&self, id: &ColorId, min_jaccard: f64) -> Result<Vec<ColorId>> {
+        if self.config.color_mode != ColorMode::Presence {
+            return Err(ColorTableError::UnsupportedColorMode {
+                mode: self.config.color_mode,
+            });
+        }
+        if self.config.sketch_size == 0 {
+            return Err(ColorTableError::SketchesDisabled);
+        }
+
+        let ct_map = self.map()?;
+        let mut query = ct_map.color_class(id).into_indices();
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        query.sort_unstable();
+
+        let sketches = self.sketches.read();
+        let tombstones = self.tombstones.read();
+        let sketch = sketches.sketch(query.iter().map(|&idx| idx as u64));
+
+        let mut similar = Vec::new();
+        let mut indices = Vec::new();
+        for idx in sketches.heads() {
+            let candidate = ColorId::from(idx);
+            if candidate == *id || tombstones.contains(&idx) {
+                continue;
+            }
+            let (candidate_sketch, exact) = sketches
+                .get(&idx)
+                .expect("bug: head fragment has no sketch");
+            if exact && Sketches::estimate(&sketch, candidate_sketch) < min_jaccard {
+                continue;
+            }
+
+            indices.clear();
+            ct_map
+                .color_class(&candidate)
+                .collect_indices_into(&mut indices);
+            indices.sort_unstable();
+            if jaccard(&query, &indices) >= min_jaccard {
+                similar.push(candidate);
+            }
+        }
+
+        Ok(similar)
+    }
+
+    /// Extend the sketches to the fragments of the ended generations, if sketches are enabled.
+    pub(super) fn update_sketches(&self) -> Result<()> {
+        if self.config.sketch_size == 0 || self.config.color_mode != ColorMode::Presence {
+            return Ok(());
+        }
+        let generations = Arc::clone(&self.generations.read());
+        let Some(end) = generations.last_range_end() else {
+            return Ok(());
+        };
+
+        let mut sketches = self.sketches.write();
+        if sketches.len() >= end.0 as usize {
+            return Ok(());
+        }
+        let mmap = self.mmap()?;
+        for i in sketches.len() as u32..end.0 {
+            let idx = ColorFragmentIndex(i);
+            let Some(fragment) = mmap.fragment(&idx) else {
+                break;
+            };
+            sketches.push(fragment, generations.find(&idx));
+        }
+
+        Ok(())
+    }
+
+    /// Rebuild the sketches from scratch, after fragments or generations were rewritten.
+    pub(super) fn rebuild_sketches(&mut self) -> Result<()> {
+        *self.sketches.get_mut() = Sketches::new(self.config.sketch_size);
+        self.update_sketches()
+    }
+}
+
+/// The Jaccard similarity of two sets of sorted indices.
+fn jaccard(a: &[usize], b: &[usize]) -> f64 {
+    let (mut i, mut j, mut intersection) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                intersection += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    let union = a.len() + b.len() - intersection;
+
+    intersection as f64 / union as f64
+}
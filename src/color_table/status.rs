@@ -0,0 +1,41 @@
+use super::ColorTable;
+
+/// The state of the generations of a color table, as returned by [`ColorTable::generation_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationStatus {
+    /// No generation has been started yet.
+    NotStarted,
+    /// No generation is in progress.
+    Idle {
+        /// The number of the last generation.
+        last: u64,
+    },
+    /// A generation is in progress.
+    InProgress {
+        /// The number of the generation.
+        generation: u64,
+        /// The number of fragments written in the generation so far.
+        fragments: u32,
+    },
+}
+
+impl ColorTable {
+    /// Get the state of the generations of the color table: whether a generation is in progress,
+    /// its number, and how many fragments it has written so far.
+    ///
+    /// Fragments reserved but not yet written (see [`GenerationGuard::reserve_ids`](super::GenerationGuard::reserve_ids))
+    /// are not counted. The state may change as soon as this returns.
+    pub fn generation_state(&self) -> GenerationStatus {
+        let generations = self.generations.read();
+        match generations.in_progress() {
+            Some((generation, start)) => GenerationStatus::InProgress {
+                generation,
+                fragments: self.head().0 - start.0,
+            },
+            None => match generations.last_generation() {
+                Some(last) => GenerationStatus::Idle { last },
+                None => GenerationStatus::NotStarted,
+            },
+        }
+    }
+}
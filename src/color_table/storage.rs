@@ -0,0 +1,751 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+#[cfg(feature = "typesize")]
+use typesize::TypeSize;
+
+use super::direct::DirectFile;
+use super::fault::FaultWatch;
+use super::{ColorFragment, ColorFragmentIndex, Header};
+use crate::ColorMode;
+use crate::{ColorTableConfig, Result};
+
+/// Destination for appended fragments.
+#[derive(Debug)]
+pub(super) enum Writer {
+    /// Buffered writer for the color table file.
+    File(BufWriter<File>),
+    /// Writable memory map of the color table file, which is grown in large chunks.
+    Mapped(MappedFile),
+    /// Color table file written with direct I/O, bypassing the page cache.
+    Direct(DirectFile),
+    /// Fragments kept in memory, starting with the magic header at index 0.
+    ///
+    /// Mappings share the vector, so appending after the table has been mapped copies it once.
+    Memory(Arc<Vec<ColorFragment>>),
+}
+
+impl Writer {
+    /// Create a writer that appends to the given file, as configured by `config`.
+    pub(super) fn open(mut file: File, config: &ColorTableConfig) -> Result<Self> {
+        if let Some(fragments) = config.preallocate_fragments {
+            allocate(
+                &file,
+                fragments * std::mem::size_of::<ColorFragment>() as u64,
+            )?;
+        }
+
+        if config.direct_io && config.preallocate_size == 0 {
+            match DirectFile::open(file, config.buffer_size)? {
+                Ok(direct) => return Ok(Self::Direct(direct)),
+                // not supported, so fall back to buffered writes
+                Err(buffered) => file = buffered,
+            }
+        }
+
+        if config.preallocate_size == 0 {
+            // large appends are written at their offsets (see `Writer::reserve_region`), so the
+            // file is written at its end without append mode
+            clear_append(&file)?;
+            file.seek(SeekFrom::End(0))?;
+            return Ok(Self::File(BufWriter::with_capacity(
+                config.buffer_size,
+                file,
+            )));
+        }
+
+        Ok(Self::Mapped(MappedFile::new(
+            file,
+            config.preallocate_size,
+        )?))
+    }
+
+    /// Create a writer for a file that is never written to, e.g. of a sealed table.
+    pub(super) fn read_only(file: File) -> Self {
+        Self::File(BufWriter::with_capacity(0, file))
+    }
+
+    /// Create an in-memory writer containing only the magic header.
+    pub(super) fn memory(mode: ColorMode) -> Self {
+        Self::Memory(Arc::new(vec![bytemuck::cast(
+            Header::for_mode(mode).to_bytes(),
+        )]))
+    }
+
+    /// Replace the header at the start of the file.
+    pub(super) fn write_header(&mut self, header: Header) -> io::Result<()> {
+        let bytes = header.to_bytes();
+        match self {
+            Self::File(file) => write_header(file.get_ref(), &bytes),
+            Self::Mapped(file) => {
+                file.mmap[..bytes.len()].copy_from_slice(&bytes);
+                Ok(())
+            }
+            Self::Direct(file) => {
+                file.patch_start(&bytes);
+                write_header(&file.file, &bytes)
+            }
+            Self::Memory(fragments) => {
+                Arc::make_mut(fragments)[0] = bytemuck::cast(bytes);
+                Ok(())
+            }
+        }
+    }
+
+    #[inline]
+    pub(super) fn write_fragment(&mut self, fragment: &ColorFragment) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.write_all(bytemuck::bytes_of(fragment)),
+            Self::Mapped(file) => file.write_all(bytemuck::bytes_of(fragment)),
+            Self::Direct(file) => file.write_all(bytemuck::bytes_of(fragment)),
+            Self::Memory(fragments) => {
+                Arc::make_mut(fragments).push(*fragment);
+                Ok(())
+            }
+        }
+    }
+
+    #[inline]
+    pub(super) fn write_fragments(&mut self, fragments: &[ColorFragment]) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.write_all(bytemuck::cast_slice(fragments)),
+            Self::Mapped(file) => file.write_all(bytemuck::cast_slice(fragments)),
+            Self::Direct(file) => file.write_all(bytemuck::cast_slice(fragments)),
+            Self::Memory(vec) => {
+                Arc::make_mut(vec).extend_from_slice(fragments);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reserve `len` bytes at the end of the file, to be written with [`Region::write`] without
+    /// holding the writer.
+    ///
+    /// Returns `None` if the bytes should be written by the writer instead: if they fit in its
+    /// buffer, or if it doesn't support positioned writes.
+    pub(super) fn reserve_region(&mut self, len: usize) -> io::Result<Option<Region>> {
+        match self {
+            // a read-only writer has no buffer
+            #[cfg(unix)]
+            Self::File(file) if file.capacity() != 0 && len >= file.capacity() => {
+                flush_file(file)?;
+                let inner = file.get_mut();
+                let offset = inner.metadata()?.len();
+                // later writes go after the region, and the file length still covers everything
+                // written, even before the region is
+                inner.set_len(offset + len as u64)?;
+                inner.seek(SeekFrom::End(0))?;
+
+                Ok(Some(Region {
+                    file: inner.try_clone()?,
+                    offset,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Make the written fragments visible to other handles of the file.
+    pub(super) fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(file) => {
+                #[cfg(feature = "tracing")]
+                let (bytes, started) = (file.buffer().len(), std::time::Instant::now());
+                flush_file(file)?;
+                trace_event!(bytes, elapsed = ?started.elapsed(), "flushed color table");
+                Ok(())
+            }
+            Self::Direct(file) => file.flush(),
+            // writes to a shared mapping are visible immediately
+            Self::Mapped(_) | Self::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Flush the writer, trim any preallocated space so the file only contains written fragments,
+    /// and sync the file to disk.
+    pub(super) fn finish(&mut self) -> io::Result<()> {
+        if let Self::Mapped(file) = self {
+            file.trim()?;
+        }
+        self.sync_data()
+    }
+
+    /// Flush the writer and sync the file to disk, keeping any preallocated space for the
+    /// fragments still to be written.
+    pub(super) fn sync_data(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+        self.flush()?;
+        match self {
+            Self::File(file) => {
+                // `FlushFileBuffers` on Windows
+                file.get_ref().sync_data()?;
+            }
+            Self::Mapped(file) => {
+                file.mmap.flush()?;
+                file.file.sync_data()?;
+            }
+            Self::Direct(file) => file.file.sync_data()?,
+            Self::Memory(_) => {}
+        }
+        trace_event!(elapsed = ?started.elapsed(), "synced color table");
+
+        Ok(())
+    }
+
+    /// Flush the writer and map the written fragments, up to `end` if given.
+    ///
+    /// The mapped length is fixed when mapping, so accessing the mapping never reads past the end
+    /// of the file as it was at that time. In-memory tables are always mapped whole.
+    pub(super) fn map(&mut self, end: Option<ColorFragmentIndex>) -> Result<ColorTableMmap> {
+        // flushes the writer
+        let len = self.mapped_len(end)?;
+        match self {
+            // try_clone() here is ~equivalent to dup(2), so the new fd points to the same file object (this is what we want)
+            // SAFETY: `ColorTable` will not modify the file while it is mmapped
+            Self::File(file) => unsafe {
+                ColorTableMmap::new(file.get_ref().try_clone()?, Some(len))
+            },
+            // SAFETY: the written part of the file is never modified
+            Self::Mapped(file) => unsafe { ColorTableMmap::new(file.file.try_clone()?, Some(len)) },
+            // SAFETY: see above
+            Self::Direct(file) => unsafe { ColorTableMmap::new(file.file.try_clone()?, Some(len)) },
+            Self::Memory(fragments) => Ok(ColorTableMmap::Memory(Arc::clone(fragments))),
+        }
+    }
+
+    /// Flush the writer and get the number of bytes to map, up to `end` if given.
+    fn mapped_len(&mut self, end: Option<ColorFragmentIndex>) -> Result<usize> {
+        let len = self.written_len()?;
+
+        Ok(end.map_or(len, |end| {
+            len.min(end.0 as usize * std::mem::size_of::<ColorFragment>())
+        }))
+    }
+
+    /// Flush the writer and map the given range of written fragments.
+    ///
+    /// The range must not be empty.
+    pub(super) fn map_range(&mut self, range: Range<ColorFragmentIndex>) -> Result<ColorTableMmap> {
+        let fragments = match self {
+            Self::File(file) => {
+                flush_file(file)?;
+                // SAFETY: see `Writer::map`
+                unsafe { ColorTableMmap::new_range(file.get_ref().try_clone()?, range.clone())? }
+            }
+            // SAFETY: see `Writer::map`
+            Self::Mapped(file) => unsafe {
+                ColorTableMmap::new_range(file.file.try_clone()?, range.clone())?
+            },
+            Self::Direct(file) => {
+                file.flush()?;
+                // SAFETY: see `Writer::map`
+                unsafe { ColorTableMmap::new_range(file.file.try_clone()?, range.clone())? }
+            }
+            Self::Memory(fragments) => ColorTableMmap::Memory(Arc::new(
+                fragments[range.start.0 as usize..range.end.0 as usize].to_vec(),
+            )),
+        };
+
+        Ok(ColorTableMmap::Partial {
+            start: range.start,
+            fragments: Box::new(fragments),
+        })
+    }
+
+    /// Flush the writer and get the number of bytes written, including the magic header.
+    pub(super) fn written_len(&mut self) -> Result<usize> {
+        Ok(match self {
+            Self::File(file) => {
+                flush_file(file)?;
+                file.get_ref().metadata()?.len() as usize
+            }
+            Self::Mapped(file) => file.len,
+            Self::Direct(file) => {
+                file.flush()?;
+                file.written_len() as usize
+            }
+            Self::Memory(fragments) => fragments.len() * std::mem::size_of::<ColorFragment>(),
+        })
+    }
+
+    /// Flush the writer and replace `mmap` with a new mapping up to `end` (see [`Writer::map`]), but
+    /// only if more fragments have been written.
+    ///
+    /// Returns `true` if `mmap` was replaced.
+    pub(super) fn remap(
+        &mut self,
+        mmap: &mut ColorTableMmap,
+        end: Option<ColorFragmentIndex>,
+    ) -> Result<bool> {
+        // partial mappings cover a fixed range of generations
+        if mmap.is_partial() {
+            return Ok(false);
+        }
+
+        if self.mapped_len(end)? > std::mem::size_of_val(mmap.as_fragments()) {
+            *mmap = self.map(end)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Get the number of bytes that writing `bytes` more would write to the disk right away, or 0
+    /// if they would only be buffered (or kept in memory).
+    pub(super) fn disk_bytes(&self, bytes: usize) -> usize {
+        match self {
+            Self::File(file) if file.buffer().len() + bytes > file.capacity() => {
+                file.buffer().len() + bytes
+            }
+            Self::Mapped(file) if file.len + bytes > file.mmap.len() => {
+                (file.len + bytes).next_multiple_of(file.growth) - file.mmap.len()
+            }
+            Self::Direct(file) => file.disk_bytes(bytes),
+            _ => 0,
+        }
+    }
+
+    /// Get the free space of the volume the file is on, if it is known.
+    pub(super) fn available_space(&self) -> io::Result<Option<u64>> {
+        match self {
+            Self::File(file) => available_space(file.get_ref()),
+            Self::Mapped(file) => available_space(&file.file),
+            Self::Direct(file) => available_space(&file.file),
+            Self::Memory(_) => Ok(None),
+        }
+    }
+
+    /// Get the number of bytes of disk space allocated to the file, if it is known.
+    ///
+    /// This may be more than the written length, e.g. if the file was preallocated.
+    pub(super) fn allocated_len(&self) -> io::Result<Option<u64>> {
+        match self {
+            Self::File(file) => allocated_len(file.get_ref()),
+            Self::Mapped(file) => allocated_len(&file.file),
+            Self::Direct(file) => allocated_len(&file.file),
+            Self::Memory(_) => Ok(None),
+        }
+    }
+
+    /// Roll the written fragments back to the first `len` bytes after a failed write, dropping
+    /// whatever part of the write reached the file or the buffer.
+    ///
+    /// `len` must be a multiple of the fragment size, and at most the number of bytes written
+    /// before the failed write.
+    pub(super) fn rollback(&mut self, len: usize) -> io::Result<()> {
+        match self {
+            Self::File(file) => rollback_file(file, len),
+            Self::Mapped(file) => {
+                file.len = file.len.min(len);
+                Ok(())
+            }
+            Self::Direct(file) => file.rollback(len as u64),
+            Self::Memory(fragments) => {
+                let len = len / std::mem::size_of::<ColorFragment>();
+                if fragments.len() > len {
+                    Arc::make_mut(fragments).truncate(len);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Approximate heap size in bytes.
+    #[cfg(feature = "typesize")]
+    pub(super) fn capacity(&self) -> usize {
+        match self {
+            Self::File(file) => file.capacity(),
+            // not heap memory
+            Self::Mapped(_) => 0,
+            Self::Direct(file) => file.capacity(),
+            Self::Memory(fragments) => fragments.capacity() * std::mem::size_of::<ColorFragment>(),
+        }
+    }
+}
+
+/// Flush a buffered color table file. If the flush fails partway through (e.g. when the disk is
+/// full), the file is truncated to whole fragments, and the rest stays buffered.
+fn flush_file(file: &mut BufWriter<File>) -> io::Result<()> {
+    let Err(e) = file.flush() else {
+        return Ok(());
+    };
+    let len = file.get_ref().metadata()?.len() as usize + file.buffer().len();
+    rollback_file(file, len)?;
+
+    Err(e)
+}
+
+/// Roll a buffered color table file back to the first `len` bytes written to it, so that the
+/// file only holds whole fragments and the buffer holds the rest.
+fn rollback_file(file: &mut BufWriter<File>, len: usize) -> io::Result<()> {
+    let capacity = file.capacity();
+    let placeholder = BufWriter::with_capacity(0, file.get_ref().try_clone()?);
+    let (mut inner, buffered) = std::mem::replace(file, placeholder).into_parts();
+    let buffered = buffered.unwrap_or_else(|e| e.into_inner());
+
+    // the bytes written so far are the file followed by the buffer
+    let file_len = inner.metadata()?.len() as usize;
+    let mut buffer = Vec::new();
+    if file_len >= len {
+        inner.set_len(len as u64)?;
+    } else {
+        // move the partial fragment at the end of the file back into the buffer
+        let aligned = file_len - file_len % std::mem::size_of::<ColorFragment>();
+        buffer.resize(file_len - aligned, 0);
+        inner.seek(SeekFrom::Start(aligned as u64))?;
+        inner.read_exact(&mut buffer)?;
+        inner.set_len(aligned as u64)?;
+        buffer.extend_from_slice(&buffered[..(len - file_len).min(buffered.len())]);
+    }
+    // the file may not have been opened in append mode
+    inner.seek(SeekFrom::End(0))?;
+
+    *file = BufWriter::with_capacity(capacity.max(buffer.len()), inner);
+    // fits in the buffer, so nothing is written to the file
+    file.write_all(&buffer)
+}
+
+/// Bytes reserved at the end of the color table file with [`Writer::reserve_region`].
+#[derive(Debug)]
+pub(super) struct Region {
+    file: File,
+    offset: u64,
+}
+
+impl Region {
+    /// Write the reserved bytes. Several regions can be written concurrently.
+    #[cfg(unix)]
+    pub(super) fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(&self.file, bytes, self.offset)
+    }
+
+    #[cfg(not(unix))]
+    pub(super) fn write(&self, _bytes: &[u8]) -> io::Result<()> {
+        unreachable!("regions are only reserved on Unix")
+    }
+}
+
+/// Clear the append flag of a file opened in append mode, where positioned writes would append.
+#[cfg(unix)]
+fn clear_append(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the file descriptor is valid for the duration of the calls
+    unsafe {
+        let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+        if flags == -1
+            || (flags & libc::O_APPEND != 0
+                && libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_APPEND) == -1)
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn clear_append(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Overwrite the header of a color table file.
+///
+/// The file may be open in append mode, where positioned writes append on some platforms, so the
+/// header is written through a mapping instead.
+fn write_header(file: &File, bytes: &[u8]) -> io::Result<()> {
+    // SAFETY: other mappings of the table never read the header as a fragment
+    let mut mmap = unsafe { memmap2::MmapOptions::new().len(bytes.len()).map_mut(file)? };
+    mmap.copy_from_slice(bytes);
+    mmap.flush()
+}
+
+/// Get the free space available to unprivileged users on the volume of the file.
+#[cfg(unix)]
+fn available_space(file: &File) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the file descriptor is valid for the duration of the call, and `stat` is only read
+    // if the call succeeds, which initializes it
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    // the field types are narrower than `u64` on some platforms
+    #[allow(clippy::useless_conversion)]
+    Ok(Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize)))
+}
+
+#[cfg(not(unix))]
+fn available_space(_file: &File) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Allocate disk space for the first `len` bytes of the file, without changing its length, so
+/// that appending up to `len` bytes doesn't have to allocate.
+///
+/// Does nothing if the file system doesn't support it, or on platforms other than Linux.
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let len =
+        libc::off_t::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: the file descriptor is valid for the duration of the call
+    if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } != 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::ENOSYS) => Ok(()),
+            _ => Err(err),
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Get the number of bytes of disk space allocated to the file.
+#[cfg(unix)]
+fn allocated_len(file: &File) -> io::Result<Option<u64>> {
+    use std::os::unix::fs::MetadataExt;
+
+    // `blocks` is in 512-byte units, whatever the block size of the file system
+    Ok(Some(file.metadata()?.blocks() * 512))
+}
+
+#[cfg(not(unix))]
+fn allocated_len(_file: &File) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// A color table file that is written through a writable memory map.
+///
+/// The file is grown in chunks of `growth` bytes, so it may be longer than the written fragments
+/// until it is trimmed.
+#[derive(Debug)]
+pub(super) struct MappedFile {
+    file: File,
+    mmap: memmap2::MmapMut,
+    // number of bytes written
+    len: usize,
+    growth: usize,
+}
+
+impl MappedFile {
+    fn new(file: File, growth: usize) -> io::Result<Self> {
+        let len = file.metadata()?.len() as usize;
+        // SAFETY: `ColorTable` is the only writer of the file
+        let mmap = unsafe { memmap2::MmapMut::map_mut(&file)? };
+
+        Ok(Self {
+            file,
+            mmap,
+            len,
+            growth,
+        })
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let end = self.len + bytes.len();
+        if end > self.mmap.len() {
+            self.file
+                .set_len(end.next_multiple_of(self.growth) as u64)?;
+            // SAFETY: see `MappedFile::new`
+            self.mmap = unsafe { memmap2::MmapMut::map_mut(&self.file)? };
+        }
+
+        self.mmap[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+
+        Ok(())
+    }
+
+    fn trim(&mut self) -> io::Result<()> {
+        if self.mmap.len() == self.len {
+            return Ok(());
+        }
+
+        self.mmap.flush()?;
+        // Windows can't shrink a file while it is mapped, so release our mapping first
+        self.mmap = memmap2::MmapMut::map_anon(1)?;
+        let res = self.file.set_len(self.len as u64);
+        // SAFETY: see `MappedFile::new`
+        self.mmap = unsafe { memmap2::MmapMut::map_mut(&self.file)? };
+
+        match res {
+            // the file is still mapped by another handle (e.g. a `MmapGuard`). the preallocated space
+            // is trimmed by a later sync, or discarded when the table is loaded
+            Err(e) if is_mapped_file_error(&e) => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// Returns `true` if the error was caused by shrinking a file that is mapped, which Windows
+/// doesn't allow.
+fn is_mapped_file_error(err: &io::Error) -> bool {
+    // ERROR_USER_MAPPED_FILE
+    cfg!(windows) && err.raw_os_error() == Some(1224)
+}
+
+/// Wrapper around a memory-mapped color table file, or a snapshot of an in-memory color table.
+#[derive(Debug)]
+pub(super) enum ColorTableMmap {
+    /// A mapped file. The watch is dropped first, so the mapping is no longer watched once it is
+    /// unmapped.
+    File(FaultWatch, memmap2::Mmap),
+    Memory(Arc<Vec<ColorFragment>>),
+    /// Only the fragments from `start` onwards, e.g. those of a range of generations.
+    ///
+    /// Fragments outside of the range are treated as absent, so chains end at the first fragment
+    /// before `start`.
+    Partial {
+        start: ColorFragmentIndex,
+        fragments: Box<ColorTableMmap>,
+    },
+}
+
+#[cfg(feature = "typesize")]
+impl TypeSize for ColorTableMmap {
+    fn extra_size(&self) -> usize {
+        match self {
+            Self::File(_, mmap) => mmap.len(),
+            // shared with the writer
+            Self::Memory(_) => 0,
+            Self::Partial { fragments, .. } => fragments.extra_size(),
+        }
+    }
+}
+
+impl ColorTableMmap {
+    /// Create a new `ColorTableMmap` from the first `len` bytes of the given file, or the whole file if `len` is `None`.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified while mmapped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file could not be mmapped.
+    pub(super) unsafe fn new(file: File, len: Option<usize>) -> Result<Self> {
+        // SAFETY: the caller must ensure that the file is not modified.
+        // we never modify the part of the file that is mmapped; we only append to the file, which should not cause any issues.
+        // if the file is truncated (by another process) while mmapped, kernel will send SIGBUS on access
+        let mut options = memmap2::MmapOptions::new();
+        if let Some(len) = len {
+            options.len(len);
+        }
+        let mmap = unsafe { options.map(&file)? };
+        #[cfg(unix)]
+        mmap.advise(memmap2::Advice::Random)?; // we are reading the file backwards, so tell the OS not to read ahead
+
+        let watch = FaultWatch::new(&mmap);
+        Ok(Self::File(watch, mmap))
+    }
+
+    /// Create a new `ColorTableMmap` of the given range of fragments of the file.
+    ///
+    /// # Safety
+    ///
+    /// See [`ColorTableMmap::new`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file could not be mmapped.
+    unsafe fn new_range(file: File, range: Range<ColorFragmentIndex>) -> Result<Self> {
+        let fragment_size = std::mem::size_of::<ColorFragment>();
+        // the offset doesn't need to be page aligned, and is a multiple of the fragment alignment
+        let mut options = memmap2::MmapOptions::new();
+        options
+            .offset((range.start.0 as usize * fragment_size) as u64)
+            .len((range.end.0 - range.start.0) as usize * fragment_size);
+        // SAFETY: see `ColorTableMmap::new`
+        let mmap = unsafe { options.map(&file)? };
+        #[cfg(unix)]
+        mmap.advise(memmap2::Advice::Random)?;
+
+        let watch = FaultWatch::new(&mmap);
+        Ok(Self::File(watch, mmap))
+    }
+
+    /// Returns `true` if an access to the mapped file faulted because it was truncated (only
+    /// detected with the `fault-guard` feature).
+    #[inline]
+    pub(super) fn faulted(&self) -> bool {
+        match self {
+            Self::File(watch, _) => watch.faulted(),
+            Self::Memory(_) => false,
+            Self::Partial { fragments, .. } => fragments.faulted(),
+        }
+    }
+
+    /// Returns `true` if only part of the table is mapped.
+    #[inline]
+    pub(super) fn is_partial(&self) -> bool {
+        matches!(self, Self::Partial { .. })
+    }
+
+    /// Get the indices of the mapped fragments, including the header for full mappings.
+    #[inline]
+    pub(super) fn indices(&self) -> Range<ColorFragmentIndex> {
+        let start = match self {
+            Self::Partial { start, .. } => *start,
+            _ => ColorFragmentIndex(0),
+        };
+        start..start + self.as_fragments().len() as u32
+    }
+
+    /// Get the mapped fragments. For partial mappings, the first fragment is at `start`, not 0.
+    // may panic in theory, but POSIX standards should guarantee that the memory is aligned to the page size (4KiB)
+    #[inline]
+    pub(super) fn as_fragments(&self) -> &[ColorFragment] {
+        match self {
+            Self::File(_, mmap) => bytemuck::cast_slice(mmap),
+            Self::Memory(fragments) => fragments,
+            Self::Partial { fragments, .. } => fragments.as_fragments(),
+        }
+    }
+
+    #[inline]
+    pub(super) fn get_fragment(&self, index: &ColorFragmentIndex) -> Option<&ColorFragment> {
+        match self {
+            Self::Partial { start, fragments } => fragments
+                .as_fragments()
+                .get(index.0.checked_sub(start.0)? as usize),
+            _ => self.as_fragments().get(index.0 as usize),
+        }
+    }
+
+    /// Get the fragment at the given index, treating index 0 as absent.
+    #[inline]
+    pub(super) fn fragment(&self, idx: &ColorFragmentIndex) -> Option<&ColorFragment> {
+        if idx.0 == 0 {
+            return None;
+        }
+
+        self.get_fragment(idx)
+    }
+
+    /// Get the parent fragment of the given fragment, if it exists.
+    #[inline]
+    pub(super) fn parent_of(&self, fragment: &ColorFragment) -> Option<&ColorFragment> {
+        self.fragment(&fragment.parent())
+    }
+}
+
+impl Deref for ColorTableMmap {
+    type Target = [ColorFragment];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_fragments()
+    }
+}
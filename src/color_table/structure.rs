@@ -0,0 +1,91 @@
+use std::io::Write;
+
+use super::{ColorFragmentIndex, ColorTable, GenerationStatus};
+use crate::Result;
+
+impl ColorTable {
+    /// Write a JSON document describing the structure of the color table, for debugging.
+    ///
+    /// The document holds the number of fragments, the generation state, and the fragment range of
+    /// each ended generation. If `fragment_limit` is given, up to that many fragments are also
+    /// written as `(index, parent, color)` records, with the color as a hex string, and
+    /// `fragments_truncated` is set if there were more.
+    ///
+    /// ```json
+    /// {
+    ///   "fragment_count": 2,
+    ///   "state": {"kind": "idle", "last": 1},
+    ///   "generations": [{"generation": 1, "start": 1, "end": 3, "fragments": 2}],
+    ///   "fragments": [{"index": 1, "parent": 0, "color": "0x00000005", "removal": false}, ...],
+    ///   "fragments_truncated": false
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails or if writing to `writer` fails.
+    pub fn dump_structure(
+        &self,
+        mut writer: impl Write,
+        fragment_limit: Option<usize>,
+    ) -> Result<()> {
+        let generations = self.generations.read().committed();
+
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"fragment_count\": {},", self.fragment_count())?;
+        let state = match self.generation_state() {
+            GenerationStatus::NotStarted => r#"{"kind": "not_started"}"#.to_string(),
+            GenerationStatus::Idle { last } => format!(r#"{{"kind": "idle", "last": {last}}}"#),
+            GenerationStatus::InProgress {
+                generation,
+                fragments,
+            } => format!(
+                r#"{{"kind": "in_progress", "generation": {generation}, "fragments": {fragments}}}"#
+            ),
+        };
+        writeln!(writer, "  \"state\": {state},")?;
+
+        write!(writer, "  \"generations\": [")?;
+        for (i, (range, generation)) in generations.iter().enumerate() {
+            let separator = if i > 0 { "," } else { "" };
+            write!(
+                writer,
+                "{separator}\n    {{\"generation\": {generation}, \"start\": {}, \"end\": {}, \"fragments\": {}}}",
+                range.start.0,
+                range.end.0,
+                range.end.0 - range.start.0
+            )?;
+        }
+        write!(writer, "\n  ]")?;
+
+        if let Some(limit) = fragment_limit {
+            let mmap = self.mmap()?;
+            // the header is not a fragment
+            let count = mmap.len().saturating_sub(1);
+            write!(writer, ",\n  \"fragments\": [")?;
+            for i in 0..count.min(limit) {
+                let idx = ColorFragmentIndex(i as u32 + 1);
+                let Some(fragment) = mmap.fragment(&idx) else {
+                    break;
+                };
+                let separator = if i > 0 { "," } else { "" };
+                write!(
+                    writer,
+                    "{separator}\n    {{\"index\": {}, \"parent\": {}, \"color\": \"{:#010x}\", \"removal\": {}}}",
+                    idx.0,
+                    fragment.parent().0,
+                    fragment.color(),
+                    fragment.is_removal()
+                )?;
+            }
+            write!(
+                writer,
+                "\n  ],\n  \"fragments_truncated\": {}",
+                count > limit
+            )?;
+        }
+        writeln!(writer, "\n}}")?;
+
+        Ok(())
+    }
+}
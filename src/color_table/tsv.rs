@@ -0,0 +1,97 @@
+use std::io::{BufRead, Write};
+
+use super::{ColorId, ColorIdMapping, ColorTable};
+use crate::{ColorTableError, Result};
+
+impl ColorTable {
+    /// Write the given color classes as tab-separated text.
+    ///
+    /// Each class is written on its own line as its color id, a tab, and its indices in ascending
+    /// order separated by commas. Empty classes have nothing after the tab. The output can be read
+    /// back with [`ColorTable::import_tsv`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if mmapping fails or if writing to `writer` fails.
+    pub fn dump_tsv(
+        &self,
+        mut writer: impl Write,
+        ids: impl IntoIterator<Item = ColorId>,
+    ) -> Result<()> {
+        let ct_map = self.map()?;
+        let mut indices = Vec::new();
+        for id in ids {
+            indices.clear();
+            ct_map.color_class(&id).collect_indices_into(&mut indices);
+            indices.sort_unstable();
+
+            write!(writer, "{}\t", id.as_u32())?;
+            for (i, idx) in indices.iter().enumerate() {
+                if i > 0 {
+                    writer.write_all(b",")?;
+                }
+                write!(writer, "{idx}")?;
+            }
+            writer.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    /// Append color classes read from tab-separated text, as written by [`ColorTable::dump_tsv`].
+    ///
+    /// Indices are grouped into windows of 32, and each window that holds any index is written as
+    /// its own generation: index `i` is written in generation `i / 32`. Classes are created in
+    /// their first window and extended in each later one, so the table should have no generations
+    /// yet. Classes with no indices are mapped to [`ColorId::NULL`] without writing anything.
+    ///
+    /// Returns the mapping from the color ids in the input to color ids in this table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a line is malformed or a color id appears twice (nothing is written in
+    /// that case), if a generation is not greater than the last generation of the table, or if the
+    /// color table file could not be updated.
+    pub fn import_tsv(&self, reader: impl BufRead) -> Result<ColorIdMapping> {
+        let invalid = |line: usize, reason: &'static str| ColorTableError::InvalidTsv {
+            line: line + 1,
+            reason,
+        };
+
+        // (old id, line, sorted indices)
+        let mut classes = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let (id, indices) = line
+                .split_once('\t')
+                .ok_or_else(|| invalid(n, "missing tab after color id"))?;
+            let id = id.parse().map_err(|_| invalid(n, "invalid color id"))?;
+            let mut indices = indices
+                .split(',')
+                .filter(|idx| !idx.is_empty())
+                .map(str::parse::<u32>)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid(n, "invalid index"))?;
+            indices.sort_unstable();
+            indices.dedup();
+            classes.push((ColorId(id), n, indices));
+        }
+        classes.sort_by_key(|&(id, n, _)| (id, n));
+        if let Some(duplicate) = classes.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(invalid(duplicate[1].1, "duplicate color id"));
+        }
+
+        let new_ids = self.import_windows(
+            classes
+                .iter()
+                .map(|(_, _, indices)| indices.iter().copied()),
+        )?;
+
+        Ok(ColorIdMapping::from_sorted(
+            classes.iter().map(|&(id, _, _)| id).zip(new_ids).collect(),
+        ))
+    }
+}
@@ -0,0 +1,146 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{ColorFragmentIndex, ColorTable, Header, TABLE_MAGIC};
+use crate::Result;
+
+/// How thoroughly [`ColorTable::verify`] checks a color table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// Check the file length, the magic header, and the generation ranges.
+    #[default]
+    Quick,
+    /// Also check the parent pointer of every fragment.
+    Full,
+}
+
+/// A problem found by [`ColorTable::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyIssue {
+    /// The file length is not a multiple of the fragment size.
+    TrailingBytes { len: u64 },
+    /// The header is missing, corrupted, or has an unsupported format version.
+    BadMagic,
+    /// A fragment's parent does not precede it.
+    ParentNotBefore {
+        index: ColorFragmentIndex,
+        parent: ColorFragmentIndex,
+    },
+    /// A range of fragments does not belong to any generation.
+    Uncovered { range: Range<ColorFragmentIndex> },
+    /// A generation range extends past the last fragment.
+    PastEnd {
+        range: Range<ColorFragmentIndex>,
+        generation: u64,
+    },
+    /// A generation is not greater than the generation before it.
+    OutOfOrder {
+        range: Range<ColorFragmentIndex>,
+        generation: u64,
+        previous: u64,
+    },
+}
+
+/// Result of [`ColorTable::verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of fragments in the table.
+    pub fragments: u32,
+    /// Number of generations that contain fragments.
+    pub generations: usize,
+    /// Problems found, in the order they were found.
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problems were found.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl ColorTable {
+    /// Checks the color table for corruption.
+    ///
+    /// Problems with the table are reported in the returned [`VerifyReport`], not as errors.
+    ///
+    /// This method blocks until no generation is in progress.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the color table file could not be read.
+    pub fn verify(&self, level: VerifyLevel) -> Result<VerifyReport> {
+        let _guard = self.generation_lock.lock();
+        let mut report = VerifyReport::default();
+
+        let len = self.file.lock().written_len()? as u64;
+        let fragment_size = std::mem::size_of_val(&TABLE_MAGIC) as u64;
+        if !len.is_multiple_of(fragment_size) {
+            // the fragments can't be mapped, so nothing else can be checked
+            report.issues.push(VerifyIssue::TrailingBytes { len });
+            return Ok(report);
+        }
+
+        let mmap = self.mmap()?;
+        let head = ColorFragmentIndex(mmap.len() as u32);
+        report.fragments = head.0.saturating_sub(1);
+
+        if mmap
+            .first()
+            .is_none_or(|header| Header::parse(bytemuck::cast(*header), Path::new("")).is_err())
+        {
+            report.issues.push(VerifyIssue::BadMagic);
+        }
+
+        let generations = Arc::clone(&self.generations.read());
+        let mut covered = ColorFragmentIndex(1);
+        let mut previous = None;
+        for (range, generation) in generations.iter() {
+            report.generations += 1;
+
+            if range.start > covered {
+                report.issues.push(VerifyIssue::Uncovered {
+                    range: covered..range.start,
+                });
+            }
+            covered = covered.max(range.end);
+
+            if range.end > head {
+                report.issues.push(VerifyIssue::PastEnd {
+                    range: range.clone(),
+                    generation,
+                });
+            }
+
+            if let Some(previous) = previous.filter(|previous| *previous >= generation) {
+                report.issues.push(VerifyIssue::OutOfOrder {
+                    range: range.clone(),
+                    generation,
+                    previous,
+                });
+            }
+            previous = Some(generation);
+        }
+        if covered < head {
+            report.issues.push(VerifyIssue::Uncovered {
+                range: covered..head,
+            });
+        }
+
+        if level == VerifyLevel::Full {
+            for (index, fragment) in mmap.iter().enumerate().skip(1) {
+                let index = ColorFragmentIndex(index as u32);
+                if fragment.parent() >= index {
+                    report.issues.push(VerifyIssue::ParentNotBefore {
+                        index,
+                        parent: fragment.parent(),
+                    });
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+
+use super::ColorTable;
+
+/// Wakes threads waiting for the generation in progress to end.
+#[derive(Debug, Default)]
+pub(super) struct GenerationSignal {
+    // whether a generation is in progress, and the number of generations ended so far
+    state: Mutex<(bool, u64)>,
+    ended: Condvar,
+}
+
+impl GenerationSignal {
+    pub(super) fn start(&self) {
+        self.state.lock().0 = true;
+    }
+
+    pub(super) fn end(&self) {
+        let mut state = self.state.lock();
+        *state = (false, state.1 + 1);
+        self.ended.notify_all();
+    }
+}
+
+impl ColorTable {
+    /// Wait until the generation in progress, if any, has ended, so that its changes are visible
+    /// to [`ColorTable::map`].
+    ///
+    /// Returns `true` immediately if no generation is in progress, or `false` if the timeout
+    /// elapsed before the generation ended. Generations started after this is called are not
+    /// waited for.
+    pub fn wait_for_generation_end(&self, timeout: Duration) -> bool {
+        let signal = &self.generation_signal;
+        let mut state = signal.state.lock();
+        let (in_progress, ended) = *state;
+        if !in_progress {
+            return true;
+        }
+
+        !signal
+            .ended
+            .wait_while_for(&mut state, |state| state.1 == ended, timeout)
+            .timed_out()
+    }
+}
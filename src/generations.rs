@@ -1,14 +1,54 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
 use std::ops::Range;
+use std::sync::Arc;
 
 use bincode::de::Decoder;
 use bincode::enc::Encoder;
 use bincode::error::{DecodeError, EncodeError};
 use bincode::{Decode, Encode};
+use bytemuck::{Pod, Zeroable};
 use rangemap::RangeMap;
 
-use crate::{ColorFragmentIndex, ColorTableError, Result};
+use crate::{ColorFragmentIndex, ColorTableError, GenerationsFormat, Result};
 
-#[derive(Debug, PartialEq, Eq, Encode, Decode)]
+/// Magic bytes at the start of a generations file in the flat encoding. A bincode-encoded file
+/// starts with the variant of the generation state instead, which is never this large.
+const FLAT_MAGIC: [u8; 4] = *b"CTGN";
+const FLAT_VERSION: u32 = 1;
+
+/// Header of a generations file in the flat encoding.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+struct FlatHeader {
+    magic: [u8; 4],
+    version: pack1::U32LE,
+    // 0 if no generation has been started, 1 if the last one ended, 2 if one is in progress
+    state: pack1::U32LE,
+    // first fragment of the generation in progress
+    start: pack1::U32LE,
+    generation: pack1::U64LE,
+}
+
+/// A generation range in the flat encoding, which is a sorted array of these after the header.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+struct FlatRange {
+    start: pack1::U32LE,
+    end: pack1::U32LE,
+    generation: pack1::U64LE,
+}
+
+impl FlatRange {
+    fn to_range(self) -> (Range<ColorFragmentIndex>, u64) {
+        (
+            ColorFragmentIndex(self.start.get())..ColorFragmentIndex(self.end.get()),
+            self.generation.get(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
 enum GenerationState {
     // no generation has been started
     None,
@@ -18,20 +58,62 @@ enum GenerationState {
     InProgress(u64, ColorFragmentIndex),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Generation ranges mapped in place from a generations file in the flat encoding, which are
+/// binary-searched instead of being copied into a [`RangeMap`].
+struct FlatIndex {
+    mmap: memmap2::Mmap,
+}
+
+impl FlatIndex {
+    fn ranges(&self) -> &[FlatRange] {
+        bytemuck::cast_slice(&self.mmap[size_of::<FlatHeader>()..])
+    }
+}
+
+impl std::fmt::Debug for FlatIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FlatIndex")
+            .field("ranges", &self.ranges().len())
+            .finish()
+    }
+}
+
+/// The first `len` ranges of a mapped generations file.
+#[derive(Debug, Clone)]
+struct Frozen {
+    index: Arc<FlatIndex>,
+    len: usize,
+}
+
+impl Frozen {
+    fn ranges(&self) -> &[FlatRange] {
+        &self.index.ranges()[..self.len]
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Generations {
+    // ranges read in place from the generations file, which all precede `ranges`
+    frozen: Option<Frozen>,
     ranges: RangeMap<ColorFragmentIndex, u64>,
     state: GenerationState,
 }
 
+impl PartialEq for Generations {
+    fn eq(&self, other: &Self) -> bool {
+        self.state == other.state && self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Generations {}
+
 impl Encode for Generations {
     fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
         Encode::encode(&self.state, encoder)?;
         Encode::encode(
             &self
-                .ranges
                 .iter()
-                .map(|(range, generation)| (range.start, range.end, *generation))
+                .map(|(range, generation)| (range.start, range.end, generation))
                 .collect::<Vec<_>>(),
             encoder,
         )?;
@@ -63,6 +145,7 @@ impl<Context> Decode<Context> for Generations {
             ))?;
 
         Ok(Self {
+            frozen: None,
             ranges: generations,
             state,
         })
@@ -72,6 +155,7 @@ impl<Context> Decode<Context> for Generations {
 impl Generations {
     pub const fn new() -> Self {
         Self {
+            frozen: None,
             ranges: RangeMap::new(),
             state: GenerationState::None,
         }
@@ -79,54 +163,113 @@ impl Generations {
 
     /// Get the end of the last generation
     #[inline]
-    fn last_range_end(&self) -> Option<&ColorFragmentIndex> {
-        self.ranges.last_range_value().map(|(range, _)| &range.end)
+    pub fn last_range_end(&self) -> Option<ColorFragmentIndex> {
+        self.last_range_value().map(|(range, _)| range.end)
     }
 
-    #[expect(dead_code)]
-    fn range_of(&self, generation: u64) -> Option<&Range<ColorFragmentIndex>> {
+    fn last_range_value(&self) -> Option<(Range<ColorFragmentIndex>, u64)> {
         self.ranges
-            .iter()
-            .find_map(|(range, generation_)| (*generation_ == generation).then_some(range))
+            .last_range_value()
+            .map(|(range, generation)| (range.clone(), *generation))
+            .or_else(|| {
+                let frozen = self.frozen.as_ref()?;
+                frozen.ranges().last().copied().map(FlatRange::to_range)
+            })
+    }
+
+    #[expect(dead_code)]
+    fn range_of(&self, generation: u64) -> Option<Range<ColorFragmentIndex>> {
+        self.iter()
+            .find_map(|(range, generation_)| (generation_ == generation).then_some(range))
+    }
+
+    /// Move the last mapped range into memory, so that a repeated generation can be merged with it.
+    fn thaw_last(&mut self) {
+        if !self.ranges.is_empty() {
+            return;
+        }
+        if let Some(frozen) = self.frozen.as_mut().filter(|frozen| frozen.len > 0) {
+            let (range, generation) = frozen.ranges()[frozen.len - 1].to_range();
+            frozen.len -= 1;
+            self.ranges.insert(range, generation);
+        }
     }
 
-    /// Start a new generation at the given head fragment
-    pub fn start_new_generation_at(
+    /// Get the number of the last generation started, if any.
+    #[inline]
+    pub fn last_generation(&self) -> Option<u64> {
+        match self.state {
+            GenerationState::None => None,
+            GenerationState::Ended(generation) | GenerationState::InProgress(generation, _) => {
+                Some(generation)
+            }
+        }
+    }
+
+    /// Get the number and first fragment of the generation in progress, if any.
+    #[inline]
+    pub fn in_progress(&self) -> Option<(u64, ColorFragmentIndex)> {
+        match self.state {
+            GenerationState::InProgress(generation, start) => Some((generation, start)),
+            GenerationState::None | GenerationState::Ended(_) => None,
+        }
+    }
+
+    /// Start a new generation at the given head fragment. If `allow_repeat` is set, the generation may
+    /// have the same number as the last one, and its fragments are added to that generation.
+    pub fn start_generation_at(
         &mut self,
         head: ColorFragmentIndex,
         generation: u64,
+        allow_repeat: bool,
     ) -> Result<()> {
         match self.state {
             GenerationState::None => {
                 // first generation must start at 1
-                if !matches!(head, ColorFragmentIndex(1)) {
-                    return Err(ColorTableError::InvalidGenerationState {
-                        expected: "first generation starts at fragment 1".to_string(),
-                        actual: format!("{head:?}"),
+                if head.0 < 1 {
+                    return Err(ColorTableError::GenerationOverlap {
+                        generation,
+                        start: head.0,
+                        previous_end: 1,
+                    });
+                }
+                if head.0 > 1 {
+                    return Err(ColorTableError::UncoveredFragments {
+                        start: 1,
+                        end: head.0,
                     });
                 }
                 self.ranges.insert(head..head + 1, generation);
                 self.state = GenerationState::InProgress(generation, head);
                 Ok(())
             }
-            GenerationState::Ended(last_generation) if last_generation < generation => {
+            GenerationState::Ended(last_generation)
+                if last_generation < generation
+                    || (allow_repeat && last_generation == generation) =>
+            {
                 // don't overlap with previous generation
-                if self.last_range_end().is_some_and(|last| last > &head) {
-                    return Err(ColorTableError::InvalidGenerationState {
-                        expected: format!(
-                            "no overlapping with previous generation (last: {:?})",
-                            self.last_range_end()
-                        ),
-                        actual: format!("{head:?}"),
+                if let Some(last) = self.last_range_end().filter(|last| *last > head) {
+                    return Err(ColorTableError::GenerationOverlap {
+                        generation,
+                        start: head.0,
+                        previous_end: last.0,
                     });
                 }
 
+                if last_generation == generation {
+                    self.thaw_last();
+                }
                 self.ranges.insert(head..head + 1, generation);
 
                 self.state = GenerationState::InProgress(generation, head);
                 Ok(())
             }
-            _ => Err(ColorTableError::InvalidGeneration(generation)),
+            GenerationState::Ended(last) => {
+                Err(ColorTableError::GenerationNotIncreasing { generation, last })
+            }
+            GenerationState::InProgress(generation, _) => {
+                Err(ColorTableError::GenerationInProgress { generation })
+            }
         }
     }
 
@@ -135,12 +278,11 @@ impl Generations {
         match self.state {
             GenerationState::InProgress(generation, old_head) if head > old_head => {
                 debug_assert!(
-                    self.ranges
-                        .last_range_value()
-                        .is_some_and(
-                            |(range, _)| range.end == range.start + 1 && range.start == old_head
-                        ),
-                    "expected last generation to be a singleton starting at old head position ({:?}), got {:?}",
+                    self.ranges.last_range_value().is_some_and(
+                        // a repeated generation is merged with the previous one
+                        |(range, _)| range.end == old_head + 1 && range.start <= old_head
+                    ),
+                    "expected last generation to end right after the old head position ({:?}), got {:?}",
                     old_head,
                     self.ranges.last_range_value(),
                 );
@@ -157,18 +299,289 @@ impl Generations {
                 Ok(())
             }
             GenerationState::None | GenerationState::Ended(_) => {
-                Err(ColorTableError::InvalidGenerationState {
-                    expected: "generation in progress".to_string(),
-                    actual: format!("{:?}", self.state),
-                })
+                Err(ColorTableError::NoGenerationInProgress)
+            }
+        }
+    }
+
+    /// Rebuild the generations after fragments have been removed from the table.
+    ///
+    /// `kept_in` returns the number of fragments kept in each range. Kept fragments are assumed to be
+    /// renumbered contiguously from fragment 1, preserving their order. Generations left empty are removed.
+    pub fn retain_fragments(
+        &self,
+        mut kept_in: impl FnMut(&Range<ColorFragmentIndex>) -> u32,
+    ) -> Result<Self> {
+        if let GenerationState::InProgress(generation, _) = self.state {
+            return Err(ColorTableError::GenerationInProgress { generation });
+        }
+
+        let mut ranges = RangeMap::new();
+        let mut head = ColorFragmentIndex(1);
+        for (range, generation) in self.iter() {
+            let kept = kept_in(&range);
+            if kept > 0 {
+                ranges.insert(head..head + kept, generation);
+                head += kept;
             }
         }
+
+        Ok(Self {
+            frozen: None,
+            ranges,
+            state: self.state.clone(),
+        })
     }
 
-    /// Find the generation a fragment belongs to
+    /// Renumber the generations with `f`, which must map them to strictly increasing numbers.
+    pub fn renumber(&self, mut f: impl FnMut(u64) -> u64) -> Result<Self> {
+        let mut ranges = RangeMap::new();
+        // (old, new) number of the last generation
+        let mut last: Option<(u64, u64)> = None;
+        let mut renumber = |generation: u64| {
+            // the last ended generation is the generation of the last range, unless it is empty
+            if let Some((_, new)) = last.filter(|&(old, _)| old == generation) {
+                return Ok(new);
+            }
+            let new = f(generation);
+            if let Some((_, last)) = last.filter(|&(_, last)| new <= last) {
+                return Err(ColorTableError::GenerationNotIncreasing {
+                    generation: new,
+                    last,
+                });
+            }
+            last = Some((generation, new));
+            Ok(new)
+        };
+
+        for (range, generation) in self.iter() {
+            ranges.insert(range, renumber(generation)?);
+        }
+        let state = match self.state {
+            GenerationState::None => GenerationState::None,
+            GenerationState::Ended(generation) => GenerationState::Ended(renumber(generation)?),
+            GenerationState::InProgress(generation, _) => {
+                return Err(ColorTableError::GenerationInProgress { generation });
+            }
+        };
+
+        Ok(Self {
+            frozen: None,
+            ranges,
+            state,
+        })
+    }
+
+    /// Get a copy of the generations without the generation in progress, if any.
+    ///
+    /// If a generation is in progress, the returned state is ended at the last generation that
+    /// contains any fragments.
+    pub fn committed(&self) -> Self {
+        let mut committed = self.clone();
+        if let GenerationState::InProgress(_, head) = self.state {
+            // the placeholder range of a generation in progress may have been read from the file
+            committed.thaw_last();
+            committed.ranges.remove(head..head + 1);
+            committed.state = match committed.last_range_value() {
+                Some((_, generation)) => GenerationState::Ended(generation),
+                None => GenerationState::None,
+            };
+        }
+
+        committed
+    }
+
+    /// Get a copy of the generations without the generation in progress, and without any ranges
+    /// that end after `end`.
+    ///
+    /// If any ranges are removed, the returned state is ended at the last generation that is kept.
+    pub fn truncate(&self, end: ColorFragmentIndex) -> Self {
+        let committed = self.committed();
+        if committed.last_range_end().is_none_or(|last| last <= end) {
+            return committed;
+        }
+
+        let ranges: RangeMap<_, _> = committed
+            .iter()
+            .filter(|(range, _)| range.end <= end)
+            .collect();
+        let state = match ranges.last_range_value() {
+            Some((_, generation)) => GenerationState::Ended(*generation),
+            None => GenerationState::None,
+        };
+
+        Self {
+            frozen: None,
+            ranges,
+            state,
+        }
+    }
+
+    /// Iterate over the generation ranges, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (Range<ColorFragmentIndex>, u64)> {
+        let frozen = self.frozen.iter().flat_map(Frozen::ranges);
+        frozen.copied().map(FlatRange::to_range).chain(
+            self.ranges
+                .iter()
+                .map(|(range, generation)| (range.clone(), *generation)),
+        )
+    }
+
+    /// Find the generation a fragment belongs to. Every fragment from the start of a generation in
+    /// progress belongs to it.
     #[inline]
-    pub fn find(&self, idx: &ColorFragmentIndex) -> Option<&u64> {
-        self.ranges.get(idx)
+    pub fn find(&self, idx: &ColorFragmentIndex) -> Option<u64> {
+        if let GenerationState::InProgress(generation, start) = self.state {
+            if *idx >= start {
+                return Some(generation);
+            }
+        }
+        if let Some(frozen) = &self.frozen {
+            let ranges = frozen.ranges();
+            let i = ranges.partition_point(|range| range.end.get() <= idx.0);
+            if let Some(range) = ranges.get(i).filter(|range| range.start.get() <= idx.0) {
+                return Some(range.generation.get());
+            }
+        }
+        self.ranges.get(idx).copied()
+    }
+
+    /// Get the first range of fragments before `head` that does not belong to any generation.
+    pub fn first_uncovered(&self, head: ColorFragmentIndex) -> Option<Range<ColorFragmentIndex>> {
+        let mut covered = ColorFragmentIndex(1);
+        for (range, _) in self.iter() {
+            if range.start > covered {
+                return Some(covered..range.start);
+            }
+            covered = covered.max(range.end);
+        }
+
+        // the generation in progress covers every fragment after its start
+        (covered < head && self.in_progress().is_none()).then_some(covered..head)
+    }
+
+    /// Write the generations in the given format.
+    pub fn write_to(&self, writer: &mut impl Write, format: GenerationsFormat) -> Result<()> {
+        match format {
+            GenerationsFormat::Bincode => {
+                bincode::encode_into_std_write(self, writer, crate::BINCODE_CONFIG)?;
+            }
+            GenerationsFormat::Flat => {
+                let (state, start, generation) = match self.state {
+                    GenerationState::None => (0, ColorFragmentIndex(0), 0),
+                    GenerationState::Ended(generation) => (1, ColorFragmentIndex(0), generation),
+                    GenerationState::InProgress(generation, start) => (2, start, generation),
+                };
+                let header = FlatHeader {
+                    magic: FLAT_MAGIC,
+                    version: FLAT_VERSION.into(),
+                    state: state.into(),
+                    start: start.0.into(),
+                    generation: generation.into(),
+                };
+                writer.write_all(bytemuck::bytes_of(&header))?;
+                for (range, generation) in self.iter() {
+                    let range = FlatRange {
+                        start: range.start.0.into(),
+                        end: range.end.0.into(),
+                        generation: generation.into(),
+                    };
+                    writer.write_all(bytemuck::bytes_of(&range))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Open a generations file. A file in the flat encoding is mapped and searched in place, and
+    /// only generations started after opening it are kept in memory.
+    pub fn open(file: &mut File) -> Result<Self> {
+        let mut magic = [0; 4];
+        let is_flat = file.metadata()?.len() >= size_of::<FlatHeader>() as u64
+            && file.read_exact(&mut magic).is_ok()
+            && magic == FLAT_MAGIC;
+        file.rewind()?;
+        if !is_flat {
+            return Self::read_from(file);
+        }
+
+        // Safety: the generations file is only replaced, never modified in place, while it is open
+        let mmap = unsafe { memmap2::Mmap::map(&*file)? };
+        let (state, records) = Self::parse_flat(&mmap)?;
+        Self::check_ranges(records)?;
+        let len = records.len();
+        let index = Arc::new(FlatIndex { mmap });
+
+        Ok(Self {
+            frozen: Some(Frozen { index, len }),
+            ranges: RangeMap::new(),
+            state,
+        })
+    }
+
+    /// Read generations written in either format, detecting which one was used.
+    pub fn read_from(reader: &mut impl Read) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.starts_with(&FLAT_MAGIC) {
+            return Ok(Self::decode_flat(&bytes)?);
+        }
+
+        let (generations, _) = bincode::decode_from_slice(&bytes, crate::BINCODE_CONFIG)?;
+        Ok(generations)
+    }
+
+    fn decode_flat(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (state, records) = Self::parse_flat(bytes)?;
+        Self::check_ranges(records)?;
+        let ranges = records.iter().copied().map(FlatRange::to_range).collect();
+
+        Ok(Self {
+            frozen: None,
+            ranges,
+            state,
+        })
+    }
+
+    fn parse_flat(bytes: &[u8]) -> Result<(GenerationState, &[FlatRange]), DecodeError> {
+        let header_len = size_of::<FlatHeader>();
+        let header: FlatHeader = bytes
+            .get(..header_len)
+            .map(bytemuck::pod_read_unaligned)
+            .ok_or(DecodeError::Other("truncated generations header"))?;
+        if header.version.get() != FLAT_VERSION {
+            return Err(DecodeError::Other("unsupported generations version"));
+        }
+        let state = match header.state.get() {
+            0 => GenerationState::None,
+            1 => GenerationState::Ended(header.generation.get()),
+            2 => GenerationState::InProgress(
+                header.generation.get(),
+                ColorFragmentIndex(header.start.get()),
+            ),
+            _ => return Err(DecodeError::Other("invalid generation state")),
+        };
+
+        let records = bytemuck::try_cast_slice::<_, FlatRange>(&bytes[header_len..])
+            .map_err(|_| DecodeError::Other("truncated generation range"))?;
+
+        Ok((state, records))
+    }
+
+    /// Check that ranges are non-empty and sorted, so they can be binary-searched.
+    fn check_ranges(records: &[FlatRange]) -> Result<(), DecodeError> {
+        let mut last_end = 0;
+        for record in records {
+            if record.start.get() < last_end || record.start.get() >= record.end.get() {
+                return Err(DecodeError::Other(
+                    "generations do not match (overlapping ranges?)",
+                ));
+            }
+            last_end = record.end.get();
+        }
+
+        Ok(())
     }
 }
 
@@ -182,21 +595,21 @@ mod tests {
         let mut head = ColorFragmentIndex(1);
 
         // start generation 1
-        g.start_new_generation_at(head, 1).unwrap();
+        g.start_generation_at(head, 1, false).unwrap();
 
         // end generation 1
         head += 10;
         g.end_current_generation_at(head).unwrap();
 
         // start generation 2
-        g.start_new_generation_at(head, 2).unwrap();
+        g.start_generation_at(head, 2, false).unwrap();
 
         // end generation 2
         head += 5;
         g.end_current_generation_at(head).unwrap();
 
         // start generation 4 (skip one)
-        g.start_new_generation_at(head, 4).unwrap();
+        g.start_generation_at(head, 4, false).unwrap();
 
         // end generation 4
         head += 123456;
@@ -208,5 +621,11 @@ mod tests {
         assert_eq!(g, deser);
 
         dbg!(&deser);
+
+        for format in [GenerationsFormat::Bincode, GenerationsFormat::Flat] {
+            let mut bytes = Vec::new();
+            g.write_to(&mut bytes, format).unwrap();
+            assert_eq!(Generations::read_from(&mut &bytes[..]).unwrap(), g);
+        }
     }
 }
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::ColorFragmentIndex;
+
+/// Head fragments of extended color classes, tracked when `deferred_heads` is enabled in the
+/// [`ColorTableConfig`](crate::ColorTableConfig).
+///
+/// Each extended fragment points to the fragment that extended it, so following the links from
+/// any fragment of a class leads to its current head.
+///
+/// The links are stored as a log of little-endian `u32` pairs `(old, new)`, replayed in order on
+/// load, so a sync only appends the links published since the last one. The file is only
+/// rewritten when the links are renumbered (e.g. by compaction).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Heads {
+    next: BTreeMap<ColorFragmentIndex, ColorFragmentIndex>,
+    // links inserted since the last sync, in order
+    unsynced: Vec<(ColorFragmentIndex, ColorFragmentIndex)>,
+    // number of links known to be in the file, as last read or written, or `None` if the file
+    // no longer matches the links
+    synced: Option<usize>,
+}
+
+const LINK_SIZE: usize = 2 * size_of::<u32>();
+
+impl Heads {
+    pub fn new() -> Self {
+        Self {
+            synced: Some(0),
+            ..Self::default()
+        }
+    }
+
+    /// Read the links from a file written by [`Heads::sync_to`].
+    pub fn read_from(file: &mut File) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let links = bytes.chunks_exact(LINK_SIZE);
+        // a partially written link is dropped by the next sync
+        let synced = links.remainder().is_empty().then_some(links.len());
+
+        let mut heads = Self {
+            synced,
+            ..Self::default()
+        };
+        for link in links {
+            let old = u32::from_le_bytes([link[0], link[1], link[2], link[3]]);
+            let new = u32::from_le_bytes([link[4], link[5], link[6], link[7]]);
+            heads
+                .next
+                .insert(ColorFragmentIndex(old), ColorFragmentIndex(new));
+        }
+
+        Ok(heads)
+    }
+
+    /// Get the current head of the class containing `idx`.
+    pub fn resolve(&self, mut idx: ColorFragmentIndex) -> ColorFragmentIndex {
+        while let Some(next) = self.next.get(&idx) {
+            idx = *next;
+        }
+        idx
+    }
+
+    /// Record that `old` was extended by `new`.
+    pub fn insert(&mut self, old: ColorFragmentIndex, new: ColorFragmentIndex) {
+        self.next.insert(old, new);
+        self.unsynced.push((old, new));
+    }
+
+    /// Point every link at the current head of its class, so resolving any id takes a single
+    /// step until the class is extended again.
+    ///
+    /// The links in the file still resolve to the same heads, so it is left as is.
+    pub fn compact(&mut self) {
+        let compacted = self
+            .next
+            .iter()
+            .filter(|(_, new)| self.next.contains_key(new))
+            .map(|(old, new)| (*old, self.resolve(*new)))
+            .collect::<Vec<_>>();
+        self.next.extend(compacted);
+    }
+
+    /// Returns `true` if following a link may lead to another link (see [`Heads::compact`]).
+    pub fn has_chains(&self) -> bool {
+        self.next.values().any(|new| self.next.contains_key(new))
+    }
+
+    /// Get the position of the first link that is not in the file yet, given the length of the
+    /// file, the links from there on, and the number of unsynced links they include.
+    ///
+    /// If the file doesn't hold the links written by the last sync (e.g. after renumbering), all
+    /// links are returned, to be rewritten from the start.
+    pub fn unsynced(
+        &self,
+        file_len: u64,
+    ) -> (usize, Vec<(ColorFragmentIndex, ColorFragmentIndex)>, usize) {
+        match self.synced {
+            Some(synced) if file_len == (synced * LINK_SIZE) as u64 => {
+                (synced, self.unsynced.clone(), self.unsynced.len())
+            }
+            _ => (0, self.links(), self.unsynced.len()),
+        }
+    }
+
+    /// Get every link, to write a file from scratch.
+    pub fn links(&self) -> Vec<(ColorFragmentIndex, ColorFragmentIndex)> {
+        self.next.iter().map(|(old, new)| (*old, *new)).collect()
+    }
+
+    /// Write the links returned by [`Heads::unsynced`] to the file at `start`, and sync it.
+    ///
+    /// Anything in the file after the written links is discarded.
+    pub fn sync_to(
+        file: &mut File,
+        start: usize,
+        links: &[(ColorFragmentIndex, ColorFragmentIndex)],
+    ) -> io::Result<()> {
+        let offset = (start * LINK_SIZE) as u64;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut writer = BufWriter::new(&mut *file);
+        for (old, new) in links {
+            writer.write_all(&old.0.to_le_bytes())?;
+            writer.write_all(&new.0.to_le_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        file.sync_data()
+    }
+
+    /// Record that the first `len` links are in the file, including the first `written` unsynced
+    /// links.
+    pub fn mark_synced(&mut self, len: usize, written: usize) {
+        self.unsynced.drain(..written.min(self.unsynced.len()));
+        self.synced = Some(len);
+    }
+
+    /// Renumber the fragments, dropping links for which `f` returns `None` at either end.
+    pub fn renumber(
+        &self,
+        mut f: impl FnMut(&ColorFragmentIndex) -> Option<ColorFragmentIndex>,
+    ) -> Self {
+        let next = self
+            .next
+            .iter()
+            .filter_map(|(old, new)| Some((f(old)?, f(new)?)))
+            .collect();
+
+        Self {
+            next,
+            unsynced: Vec::new(),
+            synced: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.next.is_empty()
+    }
+}
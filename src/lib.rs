@@ -2,13 +2,42 @@
 
 #![cfg_attr(not(test), deny(clippy::unwrap_used))]
 
+/// Emit a [`tracing`](https://docs.rs/tracing) event, if the `tracing` feature is enabled.
+macro_rules! trace_event {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($args)*);
+    };
+}
+
 mod color_table;
-pub use color_table::{ColorFragment, ColorFragmentIndex, ColorId, ColorTable, GenerationGuard};
+pub use color_table::{
+    AccessPattern, ClassIter, ColorFragment, ColorFragmentIndex, ColorId, ColorIdMapping,
+    ColorIdRange, ColorTable, ColorTableBuilder, ColorTableReader, CompactionReport, FlusherHandle,
+    FragmentBatch, FragmentObserver, GenerationGuard, GenerationStatus, IdKind, MapOptions,
+    Metrics, MmapGuard, OwnedMmapGuard, RepairReport, SealInfo, SealedColorTable,
+    ShardedColorTable, ShardedMmapGuard, VerifyIssue, VerifyLevel, VerifyReport,
+};
+#[cfg(feature = "roaring")]
+pub use color_table::{ColorResolver, MultiMmapGuard, MultiTable};
 
+#[cfg(feature = "roaring")]
+pub(crate) mod bitmap_checkpoints;
+pub(crate) mod chains;
+pub(crate) mod class_hashes;
 pub(crate) mod generations;
+pub(crate) mod heads;
+pub(crate) mod payloads;
+pub(crate) mod running_counts;
+pub(crate) mod sketches;
+pub(crate) mod tombstones;
 
 #[cfg(feature = "roaring")]
 pub use ::roaring;
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
 use thiserror::Error;
 use typed_builder::TypedBuilder;
 #[cfg(feature = "typesize")]
@@ -18,6 +47,12 @@ use typesize::derive::TypeSize;
 pub enum ColorTableError {
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("I/O error on {}: {source}", .path.display())]
+    File {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
     #[error("serialization error: {0}")]
     Serialization(#[from] bincode::error::EncodeError),
     #[error("deserialization error: {0}")]
@@ -26,8 +61,186 @@ pub enum ColorTableError {
     InvalidColorId(u32),
     #[error("invalid generation: {0}")]
     InvalidGeneration(u64),
-    #[error("invalid generation state. expected: {expected}, got: {actual}")]
-    InvalidGenerationState { expected: String, actual: String },
+    #[error("generation {generation} is not greater than the last generation ({last})")]
+    GenerationNotIncreasing { generation: u64, last: u64 },
+    #[error("generation {generation} is already in progress")]
+    GenerationInProgress { generation: u64 },
+    #[error("no generation is in progress")]
+    NoGenerationInProgress,
+    #[error(
+        "generation {generation} starts at fragment {start}, before the end of the previous generation ({previous_end})"
+    )]
+    GenerationOverlap {
+        generation: u64,
+        start: u32,
+        previous_end: u32,
+    },
+    #[error("fragments {start}..{end} do not belong to any generation")]
+    UncoveredFragments { start: u32, end: u32 },
+    #[error("{count} reserved color ids were not written in generation {generation}")]
+    UnwrittenReservations { generation: u64, count: usize },
+    #[error("the batch was created by another color table")]
+    ForeignBatch,
+    #[error("cannot merge a color table into itself")]
+    MergeIntoSelf,
+    #[error("{}: missing or corrupted magic header", .path.display())]
+    BadMagic { path: PathBuf },
+    #[error("{}: unsupported format version {version}", .path.display())]
+    UnsupportedVersion { path: PathBuf, version: u8 },
+    #[error("{}: length {len} is not a multiple of the fragment size", .path.display())]
+    TrailingBytes { path: PathBuf, len: u64 },
+    #[error("{}: expected at least {expected} bytes, found {len}", .path.display())]
+    Truncated {
+        path: PathBuf,
+        len: u64,
+        expected: u64,
+    },
+    #[error("fragment {index} points to parent {parent}, which does not precede it")]
+    ParentNotBefore { index: u32, parent: u32 },
+    #[error("color table is locked by another {}", .pid.map_or_else(|| "handle".to_string(), |pid| format!("process (pid {pid})")))]
+    Locked { pid: Option<u32> },
+    #[error("fragments with no bits set are not allowed")]
+    ZeroColor,
+    #[error("line {line}: {reason}")]
+    InvalidTsv { line: usize, reason: &'static str },
+    #[error("not supported by a color table in the {mode:?} color mode")]
+    UnsupportedColorMode { mode: ColorMode },
+    #[error("sketches are disabled (sketch_size is 0)")]
+    SketchesDisabled,
+    #[error("disk quota exceeded: {bytes} bytes needed, {available} bytes available")]
+    QuotaExceeded { bytes: u64, available: u64 },
+    #[error(
+        "generations file covers fragments up to {generations_end}, but the color table file ends at fragment {head}"
+    )]
+    CrossFileMismatch { generations_end: u32, head: u32 },
+    #[error("the color table file was truncated while mapped")]
+    MappingFaulted,
+    #[error("the color table is sealed")]
+    Sealed,
+    #[error("the color table is not sealed")]
+    NotSealed,
+    #[error("the color table does not match its seal")]
+    SealMismatch,
+    #[error("invalid number of shards: {0}")]
+    InvalidShardCount(usize),
+    #[error("the sample space of table {table} overlaps the next table")]
+    OverlappingSampleSpaces { table: usize },
+    #[error("the background flusher thread panicked")]
+    FlusherPanicked,
+    #[error("the color table can't hold more than {limit} fragments")]
+    TableFull { limit: u32 },
+    #[error("the batch is bound to generation {batch}, not generation {generation}")]
+    BatchGeneration { batch: u64, generation: u64 },
+    #[error("the batch for generation {batch} must wait for the batch for generation {pending}")]
+    EarlierBatchPending { batch: u64, pending: u64 },
+    #[error("fragment {parent} was already written in generation {generation}")]
+    AlreadyInGeneration { parent: u32, generation: u64 },
+}
+
+impl ColorTableError {
+    /// A short, stable identifier for the kind of error, suitable for metrics and logs.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io(_) | Self::File { .. } => "io",
+            Self::Serialization(_) => "serialization",
+            Self::Deserialization(_) => "deserialization",
+            Self::InvalidColorId(_) => "invalid_color_id",
+            Self::InvalidGeneration(_) => "invalid_generation",
+            Self::GenerationNotIncreasing { .. } => "generation_not_increasing",
+            Self::GenerationInProgress { .. } => "generation_in_progress",
+            Self::NoGenerationInProgress => "no_generation_in_progress",
+            Self::GenerationOverlap { .. } => "generation_overlap",
+            Self::UncoveredFragments { .. } => "uncovered_fragments",
+            Self::UnwrittenReservations { .. } => "unwritten_reservations",
+            Self::ForeignBatch => "foreign_batch",
+            Self::MergeIntoSelf => "merge_into_self",
+            Self::BadMagic { .. } => "bad_magic",
+            Self::UnsupportedVersion { .. } => "unsupported_version",
+            Self::TrailingBytes { .. } => "trailing_bytes",
+            Self::Truncated { .. } => "truncated",
+            Self::ParentNotBefore { .. } => "parent_not_before",
+            Self::Locked { .. } => "locked",
+            Self::ZeroColor => "zero_color",
+            Self::InvalidTsv { .. } => "invalid_tsv",
+            Self::UnsupportedColorMode { .. } => "unsupported_color_mode",
+            Self::SketchesDisabled => "sketches_disabled",
+            Self::QuotaExceeded { .. } => "quota_exceeded",
+            Self::CrossFileMismatch { .. } => "cross_file_mismatch",
+            Self::MappingFaulted => "mapping_faulted",
+            Self::Sealed => "sealed",
+            Self::NotSealed => "not_sealed",
+            Self::SealMismatch => "seal_mismatch",
+            Self::InvalidShardCount(_) => "invalid_shard_count",
+            Self::OverlappingSampleSpaces { .. } => "overlapping_sample_spaces",
+            Self::FlusherPanicked => "flusher_panicked",
+            Self::TableFull { .. } => "table_full",
+            Self::BatchGeneration { .. } => "batch_generation",
+            Self::EarlierBatchPending { .. } => "earlier_batch_pending",
+            Self::AlreadyInGeneration { .. } => "already_in_generation",
+        }
+    }
+
+    /// Returns `true` if the error indicates that the files on disk are corrupted, so retrying
+    /// won't help and the table has to be restored or rebuilt.
+    pub fn is_corruption(&self) -> bool {
+        match self {
+            Self::Deserialization(_)
+            | Self::BadMagic { .. }
+            | Self::TrailingBytes { .. }
+            | Self::Truncated { .. }
+            | Self::ParentNotBefore { .. }
+            | Self::CrossFileMismatch { .. }
+            | Self::MappingFaulted
+            | Self::SealMismatch => true,
+            _ => self.io_error().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof
+                )
+            }),
+        }
+    }
+
+    /// Returns `true` if the error may go away on its own, so the operation can be retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Locked { .. } | Self::EarlierBatchPending { .. } => true,
+            _ => self.io_error().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    std::io::ErrorKind::Interrupted
+                        | std::io::ErrorKind::WouldBlock
+                        | std::io::ErrorKind::TimedOut
+                        | std::io::ErrorKind::ResourceBusy
+                        | std::io::ErrorKind::StorageFull
+                        | std::io::ErrorKind::OutOfMemory
+                )
+            }),
+        }
+    }
+
+    /// The underlying I/O error, if any.
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        match self {
+            Self::Io(e) | Self::File { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Attach the path of the file an I/O error occurred on.
+pub(crate) trait PathContext<T> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> PathContext<T> for std::io::Result<T> {
+    #[inline]
+    fn at(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| ColorTableError::File {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
 }
 
 type Result<T, E = ColorTableError> = std::result::Result<T, E>;
@@ -38,16 +251,278 @@ const BUFFER_SIZE: usize = 1 << 20; // 1 MiB
 
 const FILE_NAME_COLOR_TABLE: &str = "color_table";
 const FILE_NAME_GENERATIONS: &str = "generations";
+const FILE_NAME_LOCK: &str = "lock";
+const FILE_NAME_GENERATION_LOG: &str = "generation_log";
+#[cfg(feature = "roaring")]
+const FILE_NAME_BITMAP_CHECKPOINTS: &str = "bitmap_checkpoints";
+const FILE_NAME_CLASS_HASHES: &str = "class_hashes";
+const FILE_NAME_TOMBSTONES: &str = "tombstones";
+const FILE_NAME_HEADS: &str = "heads";
+const FILE_NAME_PAYLOADS: &str = "payloads";
+const FILE_NAME_RUNNING_COUNTS: &str = "running_counts";
+const FILE_NAME_SEAL: &str = "seal";
+const FILE_NAME_SHARD_MANIFEST: &str = "shard_manifest";
 
 #[derive(Debug, Clone, TypedBuilder)]
 #[cfg_attr(feature = "typesize", derive(TypeSize))]
 pub struct ColorTableConfig {
     #[builder(setter(into), default = BUFFER_SIZE)]
     buffer_size: usize,
-    #[builder(setter(into), default = String::from(FILE_NAME_COLOR_TABLE))]
-    color_table_file_name: String,
-    #[builder(setter(into), default = String::from(FILE_NAME_GENERATIONS))]
-    generations_file_name: String,
+    /// Path of the color table file.
+    ///
+    /// Like all file paths in the config, this is resolved relative to the table directory, unless it
+    /// is absolute. Absolute paths let the files of a table live on different volumes.
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_COLOR_TABLE))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    color_table_file_name: PathBuf,
+    /// Path of the generations file.
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_GENERATIONS))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    generations_file_name: PathBuf,
+    /// Path of the lock file.
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_LOCK))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    lock_file_name: PathBuf,
+    /// Path of the generation log.
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_GENERATION_LOG))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    generation_log_file_name: PathBuf,
+    /// Path of the materialized bitmaps file.
+    #[cfg(feature = "roaring")]
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_BITMAP_CHECKPOINTS))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    bitmap_checkpoints_file_name: PathBuf,
+    /// Path of the file of deduplicated color classes (see
+    /// [`GenerationGuard::new_or_existing_color_class`]).
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_CLASS_HASHES))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    class_hashes_file_name: PathBuf,
+    /// Path of the file of deleted color classes (see [`ColorTable::tombstone`]).
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_TOMBSTONES))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    tombstones_file_name: PathBuf,
+    /// Path of the file of extended color class heads (see `deferred_heads`).
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_HEADS))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    heads_file_name: PathBuf,
+    /// Path of the file of fragment payloads (see [`GenerationGuard::set_payload`]).
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_PAYLOADS))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    payloads_file_name: PathBuf,
+    /// Path of the file of running counts (see `running_counts`).
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_RUNNING_COUNTS))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    running_counts_file_name: PathBuf,
+    /// Path of the seal file of a sealed table (see [`ColorTable::seal`]).
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_SEAL))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    seal_file_name: PathBuf,
+    /// Path of the manifest of a shard, mapping color ids of the original table to color ids of
+    /// the shard (see [`ColorTable::shard`]).
+    #[builder(setter(into), default = PathBuf::from(FILE_NAME_SHARD_MANIFEST))]
+    #[cfg_attr(feature = "typesize", typesize(with = path_extra_size))]
+    shard_manifest_file_name: PathBuf,
+    /// Number of links between checkpoints in each fragment chain, or 0 to disable checkpoints.
+    ///
+    /// Checkpoints let [`ClassIter`] skip over long runs of fragments (see [`ClassIter::skip_newer_than`]),
+    /// at the cost of 4 bytes of memory per fragment.
+    #[builder(setter(into), default)]
+    skip_interval: u32,
+    /// Number of MinHash values kept for each fragment, or 0 to disable sketches.
+    ///
+    /// Sketches let [`ColorTable::similar_classes`] skip classes that are unlikely to be similar
+    /// without decoding them, at the cost of `4 * sketch_size` bytes of memory per fragment. They
+    /// are built at the end of each generation (which maps the table) and when a table is loaded
+    /// or compacted. Ignored in the [`ColorMode::Counts`] color mode.
+    #[builder(setter(into), default)]
+    sketch_size: usize,
+    /// Number of bytes to grow the color table file by at a time, or 0 to disable preallocation.
+    ///
+    /// If nonzero, fragments are written through a writable memory map of the file instead of a
+    /// buffered writer, and the file is grown in chunks of this size. The unused space is trimmed
+    /// on [`ColorTable::sync`]. A file left with unused space by a process that exited without
+    /// syncing has fragments outside of any generation, so it has to be loaded with
+    /// [`ColorTable::load_repaired`].
+    #[builder(setter(into), default)]
+    preallocate_size: usize,
+    /// Number of fragments to allocate disk space for up front, or `None` to allocate as the file
+    /// grows.
+    ///
+    /// When the table is created or loaded, disk space for this many fragments (including the
+    /// header) is allocated with `fallocate`, without changing the length of the file, so that
+    /// appending fragments doesn't fragment the file or stall on allocation (e.g. on parallel file
+    /// systems). The length of the file stays the number of bytes written (see
+    /// [`ColorTable::file_len`]), and the allocated space is reported by
+    /// [`ColorTable::allocated_len`]. Ignored on platforms other than Linux, and by file systems
+    /// that don't support it.
+    #[builder(default, setter(strip_option))]
+    preallocate_fragments: Option<u64>,
+    /// Whether to write the color table file with direct I/O (`O_DIRECT`), bypassing the page
+    /// cache.
+    ///
+    /// This keeps bulk ingestion from filling the page cache with data that is written once and
+    /// rarely read back soon. Fragments are buffered in a block-aligned buffer of about
+    /// `buffer_size` bytes, which is written whole; flushing also writes the partial block at the
+    /// end through the page cache. Falls back to buffered writes if the file system doesn't support
+    /// direct I/O, on platforms other than Linux, and if `preallocate_size` is nonzero.
+    #[builder(setter(into), default)]
+    direct_io: bool,
+    /// Maximum size of the color table file in bytes, or `None` for no limit.
+    ///
+    /// Writing a fragment that would grow the file past this size fails with
+    /// [`ColorTableError::QuotaExceeded`], and the fragment is not written.
+    #[builder(default, setter(strip_option))]
+    max_file_size: Option<u64>,
+    /// Free space in bytes to leave on the volume of the color table file, or `None` for no check.
+    ///
+    /// Before a write reaches the disk (when the write buffer is full, or the preallocated space
+    /// has to grow), the free space of the volume is checked, and the write fails with
+    /// [`ColorTableError::QuotaExceeded`] if it would leave less than this. Buffered fragments
+    /// written out by a flush are not checked. Ignored on non-Unix platforms.
+    #[builder(default, setter(strip_option))]
+    min_free_space: Option<u64>,
+    /// When to flush the writer at the end of a generation.
+    #[builder(default)]
+    flush_policy: FlushPolicy,
+    /// Whether fragments with no bits set may be written. By default, they are rejected.
+    #[builder(default)]
+    zero_colors: ZeroColorPolicy,
+    /// What the color word of each fragment holds. Only used when creating a table; loaded tables
+    /// use the mode stored in their file.
+    #[builder(default)]
+    color_mode: ColorMode,
+    /// Whether to store the cardinality of the color class ending at each fragment, so the size of
+    /// any class can be found without decoding it (see [`MmapGuard::cardinality`]).
+    ///
+    /// This costs 4 bytes per fragment, kept in memory and appended to a sidecar file on
+    /// [`ColorTable::sync`]. Like the color mode, it is only used when creating a table, and is
+    /// recorded in the color table file, so older versions of this crate can't load the table.
+    #[builder(setter(into), default)]
+    running_counts: bool,
+    /// How to encode the generations file. Files in either format can be loaded.
+    #[builder(default)]
+    generations_format: GenerationsFormat,
+    /// Which generation numbers [`ColorTable::with_generation`] accepts.
+    #[builder(default)]
+    generation_policy: GenerationPolicy,
+    /// Whether to publish ended generations to a log file, so that other processes can follow the
+    /// table with a [`ColorTableReader`].
+    ///
+    /// Publishing a generation flushes the writer, regardless of the [`FlushPolicy`].
+    #[builder(setter(into), default)]
+    publish_generations: bool,
+    /// Whether to track the head of each extended color class.
+    ///
+    /// If enabled, a color class can be referred to by any of its color ids: the methods of
+    /// [`GenerationGuard`] that take a parent color id write after the class's current head instead
+    /// of the given fragment. The heads of classes extended in a generation are only updated when
+    /// the generation ends, so within a generation, forks always start from the head the class had
+    /// before it. The heads are written to a sidecar file on [`ColorTable::sync`].
+    #[builder(setter(into), default)]
+    deferred_heads: bool,
+    /// Permissions of the files created by the table, as a Unix mode (e.g. `0o660`), or `None` to
+    /// use the default permissions.
+    ///
+    /// The mode is applied to each file the table creates, and is not masked by the process umask.
+    /// Files that already exist keep their permissions. Ignored on non-Unix platforms.
+    #[builder(default, setter(strip_option))]
+    file_mode: Option<u32>,
+    /// Extra flags used when opening the color table file (e.g. `O_NOATIME`), as passed to
+    /// `OpenOptionsExt::custom_flags`. Ignored on non-Unix platforms.
+    ///
+    /// Flags that restrict how the file can be written, like `O_DIRECT`, are not accounted for by
+    /// the writer. Use [`ColorTable::from_files`] for full control over how the file is opened.
+    #[builder(default)]
+    open_flags: i32,
+}
+
+/// When to flush the color table writer at the end of a generation.
+///
+/// Flushing makes the fragments written so far visible to other handles of the color table file.
+/// The writer is always flushed when the table is mapped or synced, and when its buffer is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "typesize", derive(TypeSize))]
+pub enum FlushPolicy {
+    /// Flush at the end of every generation.
+    #[default]
+    EveryGeneration,
+    /// Flush at the end of every `n`th generation.
+    EveryNGenerations(u32),
+    /// Flush at the end of a generation once at least `n` bytes of fragments have been written since the last flush.
+    EveryNBytes(usize),
+    /// Never flush at the end of a generation.
+    Manual,
+}
+
+/// Which generation numbers a color table accepts for a new generation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "typesize", derive(TypeSize))]
+pub enum GenerationPolicy {
+    /// Each generation must be numbered higher than the last.
+    #[default]
+    StrictlyIncreasing,
+    /// Generation numbers may repeat or go backwards, e.g. wall-clock epochs from several ingest
+    /// nodes. A generation numbered lower than the last is clamped to the last, and a generation
+    /// numbered the same as the last is merged into it.
+    ///
+    /// A class still holds at most one fragment per generation: forking or extending a fragment
+    /// written earlier in a merged generation fails with
+    /// [`ColorTableError::AlreadyInGeneration`].
+    NonDecreasing,
+    /// The requested number is ignored, and each generation is numbered one higher than the last
+    /// (starting from 0). See also [`ColorTable::with_next_generation`].
+    AutoAssign,
+}
+
+/// How the generations file of a color table is encoded.
+///
+/// The format of an existing file is detected when the table is loaded, so the format can be
+/// changed by loading a table with a different config and syncing it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "typesize", derive(TypeSize))]
+pub enum GenerationsFormat {
+    /// A bincode-encoded map, which is compact but has to be decoded entry by entry.
+    #[default]
+    Bincode,
+    /// A sorted array of fixed-size little-endian `(start, end, generation)` records after a
+    /// 24-byte header, which is larger but can be read in bulk or searched in place.
+    Flat,
+}
+
+/// What the color word of each fragment holds.
+///
+/// The mode is stored in the header of the color table file when the table is created, and the
+/// stored mode is used when the table is loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "typesize", derive(TypeSize))]
+pub enum ColorMode {
+    /// One presence bit for each of 32 samples: generation `g` holds samples `32 * g..32 * (g + 1)`.
+    #[default]
+    Presence,
+    /// A 4-bit count for each of 8 samples: generation `g` holds samples `8 * g..8 * (g + 1)`, and
+    /// sample `8 * g + i` is counted in bits `4 * i..4 * (i + 1)`. Counts are written with
+    /// [`GenerationGuard::new_with_counts`] and friends, and read with [`ClassIter::into_counts`].
+    /// Removals are not supported.
+    Counts,
+}
+
+/// Whether a color table accepts fragments with no bits set.
+///
+/// Such fragments add nothing to their color class, so they only take up space in the file. The
+/// policy applies to every method of [`GenerationGuard`] that writes a fragment with a given color.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "typesize", derive(TypeSize))]
+pub enum ZeroColorPolicy {
+    /// Write them like any other fragment.
+    Allow,
+    /// Return [`ColorTableError::ZeroColor`] instead of writing them.
+    #[default]
+    Reject,
+    /// Don't write them where the result is the same without them:
+    /// [`GenerationGuard::extend_color_class`] returns the parent unchanged, and
+    /// [`GenerationGuard::new_color_class`] returns [`ColorId::NULL`]. Other methods write them,
+    /// since forks and reserved or batched ids need a fragment of their own.
+    Skip,
 }
 
 impl Default for ColorTableConfig {
@@ -55,3 +530,63 @@ impl Default for ColorTableConfig {
         ColorTableConfig::builder().build()
     }
 }
+
+impl ColorTableConfig {
+    /// Options for opening the color table file, with the configured flags.
+    pub(crate) fn color_table_options(&self) -> OpenOptions {
+        #[cfg_attr(not(any(unix, windows)), allow(unused_mut))]
+        let mut options = File::options();
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, self.open_flags);
+        // readers, mappings and compaction all open the file while the writer has it open
+        #[cfg(windows)]
+        {
+            const FILE_SHARE_READ: u32 = 0x1;
+            const FILE_SHARE_WRITE: u32 = 0x2;
+            const FILE_SHARE_DELETE: u32 = 0x4;
+            std::os::windows::fs::OpenOptionsExt::share_mode(
+                &mut options,
+                FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+            );
+        }
+        options
+    }
+
+    /// Create (or truncate) a file, with the configured permissions if it is created.
+    pub(crate) fn create_file(&self, path: &Path) -> Result<File> {
+        self.open_file(
+            File::options().write(true).create(true).truncate(true),
+            path,
+        )
+        .at(path)
+    }
+
+    /// Open a file with the given options, which must allow writing. If the file doesn't exist, it
+    /// is created with the configured permissions.
+    ///
+    /// Existing files keep their permissions: changing them would fail for users other than the
+    /// owner of the file.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub(crate) fn open_file(&self, options: &OpenOptions, path: &Path) -> std::io::Result<File> {
+        #[cfg(unix)]
+        if let Some(mode) = self.file_mode {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+            match options.clone().mode(mode).create_new(true).open(path) {
+                Ok(file) => {
+                    // the mode given at creation is masked by the umask, but the new file is ours
+                    file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+                    return Ok(file);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+        options.open(path)
+    }
+}
+
+#[cfg(feature = "typesize")]
+fn path_extra_size(path: &PathBuf) -> usize {
+    path.capacity()
+}
@@ -0,0 +1,65 @@
+use bincode::{Decode, Encode};
+
+use crate::ColorFragmentIndex;
+
+/// Auxiliary values attached to fragments (see
+/// [`GenerationGuard::set_payload`](crate::GenerationGuard::set_payload)), stored in parallel to
+/// the fragments. Fragments without a payload have the value 0.
+#[derive(Debug, Clone, Default, PartialEq, Encode, Decode)]
+pub struct Payloads {
+    // indexed by fragment index, up to the last fragment with a payload
+    values: Vec<u64>,
+}
+
+impl Payloads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the payload of a fragment, or 0 if it has none.
+    #[inline]
+    pub fn get(&self, idx: &ColorFragmentIndex) -> u64 {
+        self.values.get(idx.0 as usize).copied().unwrap_or(0)
+    }
+
+    /// Set the payload of a fragment.
+    pub fn set(&mut self, idx: ColorFragmentIndex, payload: u64) {
+        let i = idx.0 as usize;
+        if i >= self.values.len() {
+            if payload == 0 {
+                return;
+            }
+            self.values.resize(i + 1, 0);
+        }
+        self.values[i] = payload;
+    }
+
+    /// Iterate over the fragments with a payload, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (ColorFragmentIndex, u64)> + '_ {
+        self.values
+            .iter()
+            .enumerate()
+            .filter(|&(_, &payload)| payload != 0)
+            .map(|(i, &payload)| (ColorFragmentIndex(i as u32), payload))
+    }
+
+    /// Renumber the fragments, dropping payloads for which `f` returns `None`.
+    pub fn renumber(
+        &self,
+        mut f: impl FnMut(&ColorFragmentIndex) -> Option<ColorFragmentIndex>,
+    ) -> Self {
+        let mut payloads = Self::new();
+        for (idx, payload) in self.iter() {
+            if let Some(idx) = f(&idx) {
+                payloads.set(idx, payload);
+            }
+        }
+
+        payloads
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.values.iter().all(|&payload| payload == 0)
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::ColorFragmentIndex;
+
+/// Cardinality (number of samples) of the color class ending at each fragment of the ended
+/// generations, if `running_counts` is enabled in the
+/// [`ColorTableConfig`](crate::ColorTableConfig).
+///
+/// The cardinality of a fragment only depends on its chain, so the counts are extended with a
+/// single pass over the fragments of each ended generation. Unlike
+/// [`Sketches`](crate::sketches::Sketches), they are persisted, so loading a table doesn't need to
+/// read every fragment again.
+///
+/// The counts are stored as a flat array of little-endian `u32`s, indexed by fragment index, which
+/// only grows as generations end, so a sync only appends the counts added since the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunningCounts {
+    // indexed by fragment index (index 0 is the empty class), up to the end of a generation
+    counts: Vec<u32>,
+    // number of counts known to be in the file, as last read or written
+    synced: usize,
+}
+
+impl Default for RunningCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunningCounts {
+    /// Create running counts for an empty table.
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0],
+            synced: 0,
+        }
+    }
+
+    /// Read the counts from a file written by [`RunningCounts::sync_to`].
+    pub fn read_from(file: &mut File) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let counts = bytes
+            .chunks_exact(size_of::<u32>())
+            .map(|count| u32::from_le_bytes([count[0], count[1], count[2], count[3]]))
+            .collect::<Vec<_>>();
+        // a partially written count is dropped by the next sync
+        let synced = if bytes.len().is_multiple_of(size_of::<u32>()) {
+            counts.len()
+        } else {
+            0
+        };
+
+        Ok(Self { counts, synced })
+    }
+
+    /// Get the position of the first count that is not in the file yet, given the length of the
+    /// file, and the counts from there on.
+    ///
+    /// If the file doesn't hold the counts written by the last sync (e.g. after the counts were
+    /// rebuilt), it is rewritten from the start.
+    pub fn unsynced(&self, file_len: u64) -> (usize, &[u32]) {
+        let start = if file_len == (self.synced * size_of::<u32>()) as u64 {
+            self.synced
+        } else {
+            0
+        };
+
+        (start, &self.counts[start..])
+    }
+
+    /// Write the counts returned by [`RunningCounts::unsynced`] to the file at `start`, and sync it.
+    ///
+    /// Anything in the file after the written counts is discarded.
+    pub fn sync_to(file: &mut File, start: usize, counts: &[u32]) -> io::Result<()> {
+        let offset = (start * size_of::<u32>()) as u64;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut writer = BufWriter::new(&mut *file);
+        for count in counts {
+            writer.write_all(&count.to_le_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        file.sync_data()
+    }
+
+    /// Record that the first `len` counts are in the file.
+    pub fn mark_synced(&mut self, len: usize) {
+        self.synced = len;
+    }
+
+    /// Get the number of counted fragments (including fragment 0).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Get the cardinality of the class ending at a fragment, if it has been counted.
+    #[inline]
+    pub fn get(&self, idx: &ColorFragmentIndex) -> Option<u32> {
+        self.counts.get(idx.0 as usize).copied()
+    }
+
+    /// Get the counts, indexed by fragment index.
+    #[inline]
+    pub fn as_slice(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// Get the counts for extending, indexed by fragment index.
+    #[inline]
+    pub fn as_mut_vec(&mut self) -> &mut Vec<u32> {
+        &mut self.counts
+    }
+
+    /// Returns `true` if no fragment has been counted.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.counts.len() <= 1
+    }
+
+    /// Approximate heap size in bytes.
+    #[cfg(feature = "typesize")]
+    pub fn heap_size(&self) -> usize {
+        self.counts.capacity() * std::mem::size_of::<u32>()
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{ColorFragment, ColorFragmentIndex};
+
+/// MinHash sketches of the color class ending at each fragment, used to find similar classes
+/// without decoding every class (see [`ColorTable::similar_classes`](crate::ColorTable::similar_classes)).
+///
+/// The sketch of a fragment is the element-wise minimum of its parent's sketch and the hashes of
+/// its own indices, so sketches are built with a single pass over the fragments of each ended
+/// generation. Like [`Chains`](crate::chains::Chains), they are derived from the color table file
+/// and not persisted.
+#[derive(Debug, Clone)]
+pub struct Sketches {
+    // number of hash values per sketch, or 0 if sketches are disabled
+    size: usize,
+    // `size` minimum hash values for each recorded fragment (index 0 is the empty class)
+    mins: Vec<u32>,
+    // whether the sketch of each fragment is exact, i.e. its chain has no removal fragments and
+    // every fragment in it belongs to a known generation
+    exact: Vec<bool>,
+    // whether any later fragment has each fragment as its parent
+    has_child: Vec<bool>,
+}
+
+impl Sketches {
+    /// Create sketches for an empty table, with `size` hash values per sketch.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            mins: vec![u32::MAX; size],
+            exact: vec![true],
+            has_child: vec![false],
+        }
+    }
+
+    /// Get the number of recorded fragments (including fragment 0).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.exact.len()
+    }
+
+    /// Record the next fragment, written in `generation` if it is known.
+    ///
+    /// The parent of the fragment must already be recorded.
+    pub fn push(&mut self, fragment: &ColorFragment, generation: Option<u64>) {
+        let parent = fragment.parent().0 as usize;
+        self.has_child[parent] = true;
+        self.has_child.push(false);
+
+        let start = self.mins.len();
+        self.mins
+            .extend_from_within(parent * self.size..(parent + 1) * self.size);
+        // the sketch of a removal can only be built from the whole class, so it is left as a
+        // (marked) copy of the parent's sketch
+        let exact = self.exact[parent] && !fragment.is_removal() && generation.is_some();
+        self.exact.push(exact);
+        if let (false, Some(generation)) = (fragment.is_removal(), generation) {
+            let mut color = fragment.color();
+            while color != 0 {
+                let idx = generation * u64::from(u32::BITS) + u64::from(color.trailing_zeros());
+                hash_into(&mut self.mins[start..], idx);
+                color &= color - 1;
+            }
+        }
+    }
+
+    /// Get the sketch of the class ending at `idx`, and whether it is exact, if it is recorded.
+    #[inline]
+    pub fn get(&self, idx: &ColorFragmentIndex) -> Option<(&[u32], bool)> {
+        let i = idx.0 as usize;
+        let exact = *self.exact.get(i)?;
+        Some((&self.mins[i * self.size..(i + 1) * self.size], exact))
+    }
+
+    /// Iterate over the recorded fragments that are not the parent of any other fragment.
+    pub fn heads(&self) -> impl Iterator<Item = ColorFragmentIndex> + '_ {
+        self.has_child
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|&(_, &has_child)| !has_child)
+            .map(|(i, _)| ColorFragmentIndex(i as u32))
+    }
+
+    /// Compute the sketch of a class given its indices.
+    pub fn sketch(&self, indices: impl IntoIterator<Item = u64>) -> Vec<u32> {
+        let mut mins = vec![u32::MAX; self.size];
+        for idx in indices {
+            hash_into(&mut mins, idx);
+        }
+
+        mins
+    }
+
+    /// Estimate the Jaccard similarity of two classes from their sketches.
+    pub fn estimate(a: &[u32], b: &[u32]) -> f64 {
+        if a.is_empty() {
+            return 0.0;
+        }
+        let equal = a
+            .iter()
+            .zip(b)
+            .filter(|&(a, b)| a == b && *a != u32::MAX)
+            .count();
+
+        equal as f64 / a.len() as f64
+    }
+}
+
+/// Lower the minimum of each hash function in `mins` with the hash of `idx`.
+#[inline]
+fn hash_into(mins: &mut [u32], idx: u64) {
+    for (i, min) in mins.iter_mut().enumerate() {
+        *min = (*min).min(hash(idx, i as u64));
+    }
+}
+
+/// The `i`th hash of `idx` (the splitmix64 finalizer, seeded per hash function).
+#[inline]
+fn hash(idx: u64, i: u64) -> u32 {
+    let mut x = idx ^ i.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    // u32::MAX marks an empty sketch
+    (x >> 32) as u32 & !1
+}
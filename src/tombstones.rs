@@ -0,0 +1,47 @@
+use std::collections::BTreeSet;
+
+use bincode::{Decode, Encode};
+
+use crate::ColorFragmentIndex;
+
+/// Color classes deleted by [`ColorTable::tombstone`](crate::ColorTable::tombstone).
+#[derive(Debug, Clone, Default, PartialEq, Encode, Decode)]
+pub struct Tombstones {
+    ids: BTreeSet<ColorFragmentIndex>,
+}
+
+impl Tombstones {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn contains(&self, idx: &ColorFragmentIndex) -> bool {
+        self.ids.contains(idx)
+    }
+
+    /// Add a class. Returns `false` if it was already deleted.
+    pub fn insert(&mut self, idx: ColorFragmentIndex) -> bool {
+        self.ids.insert(idx)
+    }
+
+    #[cfg(feature = "roaring")]
+    pub fn iter(&self) -> impl Iterator<Item = &ColorFragmentIndex> {
+        self.ids.iter()
+    }
+
+    /// Renumber the classes, dropping those for which `f` returns `None`.
+    pub fn renumber(
+        &self,
+        mut f: impl FnMut(&ColorFragmentIndex) -> Option<ColorFragmentIndex>,
+    ) -> Self {
+        let ids = self.ids.iter().filter_map(&mut f).collect();
+
+        Self { ids }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
@@ -1,4 +1,9 @@
-use color_table::{ColorFragment, ColorId, ColorTable, ColorTableConfig};
+use color_table::{
+    AccessPattern, ColorFragment, ColorFragmentIndex, ColorId, ColorMode, ColorTable,
+    ColorTableBuilder, ColorTableConfig, ColorTableError, ColorTableReader, FlushPolicy,
+    GenerationPolicy, GenerationStatus, GenerationsFormat, IdKind, MapOptions, Metrics,
+    SealedColorTable, ShardedColorTable, VerifyLevel, ZeroColorPolicy,
+};
 
 fn random_color(max_cardinality: u32) -> u32 {
     assert!(max_cardinality <= u32::BITS);
@@ -53,7 +58,13 @@ fn new_many() {
     const N: usize = 1_000_000;
 
     let dir = tempfile::tempdir().unwrap();
-    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let ct = ColorTable::new(
+        &dir,
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    )
+    .unwrap();
 
     ct.with_generation(0, |ct| {
         let now = std::time::Instant::now();
@@ -140,7 +151,13 @@ fn fork_many_and_iter() {
     const N: usize = 1_000_000;
 
     let dir = tempfile::tempdir().unwrap();
-    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let ct = ColorTable::new(
+        &dir,
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    )
+    .unwrap();
 
     let now = std::time::Instant::now();
     let mut cc_id = ct
@@ -206,7 +223,13 @@ fn extend_many_and_iter() {
     const N: usize = 1_000_000;
 
     let dir = tempfile::tempdir().unwrap();
-    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let ct = ColorTable::new(
+        &dir,
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    )
+    .unwrap();
 
     let now = std::time::Instant::now();
     let mut cc_id = ct
@@ -249,7 +272,9 @@ fn small_threaded() {
     const TOTAL: usize = (THREADS - 1) << PER_THREAD_POW | (PER_THREAD - 1); // usize::MAX
 
     let dir = tempfile::tempdir().unwrap();
-    let config = ColorTableConfig::default();
+    let config = ColorTableConfig::builder()
+        .zero_colors(ZeroColorPolicy::Allow)
+        .build();
 
     let ct = ColorTable::new(&dir, config).unwrap();
 
@@ -502,7 +527,7 @@ fn sync_and_load() {
         .unwrap();
 
     ct.sync(None).unwrap();
-    let ct2 = ColorTable::load(&dir, config).unwrap();
+    let ct2 = ColorTable::load_read_only(&dir, config).unwrap();
 
     // concurrent read access is supported
     let ct_map = ct.map().unwrap();
@@ -535,6 +560,7 @@ fn load_and_write() {
     .unwrap();
 
     ct.sync(None).unwrap();
+    drop(ct);
 
     let ct = ColorTable::load(&dir, config).unwrap();
     assert!(ct.with_generation(0, |_| {}).is_err());
@@ -545,3 +571,4529 @@ fn load_and_write() {
     .unwrap();
     ct.sync(None).unwrap();
 }
+
+#[test]
+fn try_generation_while_in_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+
+    ct.with_generation(0, |_| {
+        std::thread::scope(|s| {
+            let res = s
+                .spawn(|| ct.try_with_generation(1, |_| ()).unwrap())
+                .join()
+                .unwrap();
+            assert!(res.is_none());
+        });
+    })
+    .unwrap();
+
+    let cc = ct
+        .try_with_generation(1, |ct| ct.new_color_class(0xF00D).unwrap())
+        .unwrap();
+    assert_eq!(cc, Some(ColorId::new(1)));
+}
+
+#[test]
+fn query_while_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+
+    let cc1 = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    let ct_map = ct.map().unwrap();
+
+    ct.with_generation(1, |guard| {
+        let cc2 = guard.new_color_class(0x2).unwrap();
+        let cc3 = guard.extend_color_class(cc1, 0x3).unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(ct_map.color_class(&cc1).collect::<Vec<_>>(), vec![(0x1, 0)]);
+                // written, but not mapped yet
+                assert_eq!(ct_map.color_class(&cc2).count(), 0);
+                assert_eq!(ct_map.color_class(&cc3).count(), 0);
+            });
+        });
+    })
+    .unwrap();
+}
+
+#[test]
+fn owned_map_across_threads() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = std::sync::Arc::new(ColorTable::new(&dir, ColorTableConfig::default()).unwrap());
+
+    let cc = ct
+        .with_generation(0, |ct| ct.new_color_class(0xC0FFEE).unwrap())
+        .unwrap();
+
+    let map = ct.map_owned().unwrap();
+    let colors = std::thread::spawn(move || map.color_class(&cc).collect::<Vec<_>>())
+        .join()
+        .unwrap();
+
+    assert_eq!(colors, vec![(0xC0FFEE, 0)]);
+}
+
+#[test]
+fn refresh_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+
+    let cc1 = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+
+    let mut ct_map = ct.map().unwrap();
+
+    let cc2 = ct
+        .with_generation(1, |ct| ct.fork_color_class(cc1, 0x2).unwrap())
+        .unwrap();
+
+    // not visible until the mapping is refreshed
+    assert_eq!(ct_map.color_class(&cc2).count(), 0);
+
+    ct_map.refresh().unwrap();
+    assert_eq!(
+        ct_map.color_class(&cc2).collect::<Vec<_>>(),
+        vec![(0x2, 1), (0x1, 0)]
+    );
+
+    // no growth, mapping is reused
+    ct_map.refresh().unwrap();
+    assert_eq!(ct_map.color_class(&cc1).collect::<Vec<_>>(), vec![(0x1, 0)]);
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn bitmap_matches_indices() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+
+    let mut cc = ct
+        .with_generation(0, |ct| ct.new_color_class(random_color(16)).unwrap())
+        .unwrap();
+    for g in 1..20 {
+        ct.with_generation(g, |ct| {
+            cc = ct.extend_color_class(cc, random_color(16) | 1).unwrap();
+        })
+        .unwrap();
+    }
+
+    let ct_map = ct.map().unwrap();
+    let mut indices = ct_map.color_class(&cc).into_indices();
+    indices.sort_unstable();
+
+    let bitmap = ct_map.color_class(&cc).into_bitmap();
+    assert_eq!(
+        bitmap.iter().map(|i| i as usize).collect::<Vec<_>>(),
+        indices
+    );
+}
+
+#[test]
+fn exact_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let (cc1, cc2) = ct
+        .with_generation(0, |ct| {
+            (
+                ct.new_color_class(0x1).unwrap(),
+                ct.new_color_class(0x2).unwrap(),
+            )
+        })
+        .unwrap();
+    let cc3 = ct
+        .with_generation(5, |ct| ct.extend_color_class(cc1, 0x3).unwrap())
+        .unwrap();
+    let cc4 = ct
+        .with_generation(9, |ct| ct.fork_color_class(cc3, 0x4).unwrap())
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    for (cc, len) in [
+        (cc1, 1),
+        (cc2, 1),
+        (cc3, 2),
+        (cc4, 3),
+        (ColorId::new(100), 0),
+    ] {
+        let mut iter = ct_map.color_class(&cc);
+        assert_eq!(iter.len(), len);
+        iter.next();
+        assert_eq!(iter.len(), len.saturating_sub(1));
+    }
+    drop(ct_map);
+
+    // depths are rebuilt on load
+    ct.sync(None).unwrap();
+    drop(ct);
+    let ct2 = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(ct2.map().unwrap().color_class(&cc4).len(), 3);
+}
+
+#[test]
+fn skip_with_checkpoints() {
+    const N: u64 = 100;
+
+    for skip_interval in [0u32, 1, 4, 7] {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ColorTableConfig::builder()
+            .skip_interval(skip_interval)
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build();
+        let ct = ColorTable::new(&dir, config).unwrap();
+
+        let mut cc = ct
+            .with_generation(0, |ct| ct.new_color_class(0).unwrap())
+            .unwrap();
+        for g in 1..N {
+            ct.with_generation(g * 2, |ct| {
+                cc = ct.extend_color_class(cc, g as u32).unwrap();
+            })
+            .unwrap();
+        }
+
+        let ct_map = ct.map().unwrap();
+
+        let mut iter = ct_map.color_class(&cc);
+        assert_eq!(iter.nth(50), Some(((N - 51) as u32, (N - 51) * 2)));
+        assert_eq!(iter.len(), N as usize - 51);
+
+        let mut iter = ct_map.color_class(&cc);
+        iter.skip_newer_than(41);
+        assert_eq!(iter.len(), 21);
+        assert_eq!(iter.next(), Some((20, 40)));
+
+        let mut iter = ct_map.color_class(&cc);
+        iter.skip_newer_than(0);
+        assert_eq!(iter.collect::<Vec<_>>(), vec![(0, 0)]);
+    }
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn materialized_bitmaps() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let mut cc = ct
+        .with_generation(0, |ct| ct.new_color_class(random_color(16)).unwrap())
+        .unwrap();
+    for g in 1..10 {
+        ct.with_generation(g, |ct| {
+            cc = ct.extend_color_class(cc, random_color(16) | 1).unwrap();
+        })
+        .unwrap();
+    }
+
+    let expected_old = ct.map().unwrap().color_class(&cc).into_bitmap();
+    assert_eq!(ct.materialize_bitmaps([cc, ColorId::new(1000)]).unwrap(), 1);
+
+    let old = cc;
+    let fork = ct
+        .with_generation(10, |ct| {
+            cc = ct.extend_color_class(cc, 0b101).unwrap();
+            ct.fork_color_class(old, 0b11).unwrap()
+        })
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let expected = |id| {
+        let mut indices = ct_map.color_class(id).into_indices();
+        indices.sort_unstable();
+        indices.into_iter().map(|i| i as u32).collect::<Vec<_>>()
+    };
+
+    for id in [&old, &cc, &fork] {
+        assert_eq!(
+            ct_map
+                .color_class(id)
+                .into_bitmap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            expected(id)
+        );
+    }
+    assert_eq!(ct_map.color_class(&old).into_bitmap(), expected_old);
+    drop(ct_map);
+
+    // materialized bitmaps are persisted
+    ct.sync(None).unwrap();
+    assert!(dir.path().join("bitmap_checkpoints").exists());
+    drop(ct);
+    let ct2 = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(
+        ct2.map().unwrap().color_class(&old).into_bitmap(),
+        expected_old
+    );
+}
+
+#[test]
+fn compact() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let mut ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let (cc1, dead1, cc2) = ct
+        .with_generation(0, |ct| {
+            (
+                ct.new_color_class(0x1).unwrap(),
+                ct.new_color_class(0x2).unwrap(),
+                ct.new_color_class(0x3).unwrap(),
+            )
+        })
+        .unwrap();
+    let (cc1, dead2) = ct
+        .with_generation(1, |ct| {
+            (
+                ct.extend_color_class(cc1, 0x4).unwrap(),
+                ct.fork_color_class(dead1, 0x5).unwrap(),
+            )
+        })
+        .unwrap();
+    // only dead fragments in this generation
+    ct.with_generation(2, |ct| ct.extend_color_class(dead2, 0x6).unwrap())
+        .unwrap();
+    let cc3 = ct
+        .with_generation(3, |ct| ct.fork_color_class(cc2, 0x7).unwrap())
+        .unwrap();
+
+    let expected = {
+        let ct_map = ct.map().unwrap();
+        [cc1, cc2, cc3].map(|cc| ct_map.color_class(&cc).collect::<Vec<_>>())
+    };
+
+    let report = ct.compact([cc1, cc3]).unwrap();
+    assert_eq!(report.fragments_before, 7);
+    assert_eq!(report.fragments_after, 4);
+    assert_eq!(report.mapping.len(), 4);
+    assert_eq!(report.mapping.get(&dead1), None);
+    assert_eq!(report.mapping.get(&dead2), None);
+
+    let new_ids = [cc1, cc2, cc3].map(|cc| report.mapping.get(&cc).unwrap());
+    assert_eq!(new_ids, [3, 2, 4].map(ColorId::new));
+
+    let check = |ct: &ColorTable| {
+        let ct_map = ct.map().unwrap();
+        for (cc, expected) in new_ids.iter().zip(&expected) {
+            assert_eq!(&ct_map.color_class(cc).collect::<Vec<_>>(), expected);
+            assert_eq!(ct_map.color_class(cc).len(), expected.len());
+        }
+    };
+    check(&ct);
+
+    // the compacted table can still be written to and reloaded
+    let cc4 = ct
+        .with_generation(4, |ct| ct.fork_color_class(new_ids[0], 0x8).unwrap())
+        .unwrap();
+    assert_eq!(cc4, ColorId::new(5));
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let ct2 = ColorTable::load(&dir, config).unwrap();
+    check(&ct2);
+    assert_eq!(
+        ct2.map().unwrap().color_class(&cc4).collect::<Vec<_>>(),
+        vec![(0x8, 4), (0x4, 1), (0x1, 0)]
+    );
+}
+
+#[test]
+fn merge_tables() {
+    let dir1 = tempfile::tempdir().unwrap();
+    let dir2 = tempfile::tempdir().unwrap();
+    let ct1 = ColorTable::new(&dir1, ColorTableConfig::default()).unwrap();
+    let ct2 = ColorTable::new(&dir2, ColorTableConfig::default()).unwrap();
+
+    let a = ct1
+        .with_generation(0, |ct| ct.new_color_class(0xA).unwrap())
+        .unwrap();
+
+    let b = ct2
+        .with_generation(0, |ct| ct.new_color_class(0xB).unwrap())
+        .unwrap();
+    let (b, c) = ct2
+        .with_generation(1, |ct| {
+            (
+                ct.extend_color_class(b, 0xBB).unwrap(),
+                ct.new_color_class(0xC).unwrap(),
+            )
+        })
+        .unwrap();
+
+    // generation 0 of ct2 would clash with generation 0 of ct1
+    assert!(ct1.merge_from(&ct2, 0).is_err());
+
+    let mapping = ct1.merge_from(&ct2, 10).unwrap();
+    assert_eq!(mapping.len(), 3);
+
+    let ct_map = ct1.map().unwrap();
+    assert_eq!(ct_map.color_class(&a).collect::<Vec<_>>(), vec![(0xA, 0)]);
+    assert_eq!(
+        ct_map
+            .color_class(&mapping.get(&b).unwrap())
+            .collect::<Vec<_>>(),
+        vec![(0xBB, 11), (0xB, 10)]
+    );
+    assert_eq!(
+        ct_map
+            .color_class(&mapping.get(&c).unwrap())
+            .collect::<Vec<_>>(),
+        vec![(0xC, 11)]
+    );
+}
+
+#[test]
+fn backup_during_generation() {
+    let dir = tempfile::tempdir().unwrap();
+    let backup = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let cc = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+
+    ct.with_generation(1, |guard| {
+        guard.extend_color_class(cc, 0x2).unwrap();
+        // the generation in progress is not part of the backup
+        ct.backup_to(&backup).unwrap();
+        guard.new_color_class(0x3).unwrap();
+    })
+    .unwrap();
+
+    let restored = ColorTable::load(&backup, config).unwrap();
+    let ct_map = restored.map().unwrap();
+    assert_eq!(ct_map.color_class(&cc).collect::<Vec<_>>(), vec![(0x1, 0)]);
+    assert_eq!(ct_map.color_class(&ColorId::new(2)).count(), 0);
+    drop(ct_map);
+
+    let cc2 = restored
+        .with_generation(1, |ct| ct.new_color_class(0x4).unwrap())
+        .unwrap();
+    assert_eq!(cc2, ColorId::new(2));
+}
+
+#[test]
+fn observe_writes() {
+    #[derive(Clone, Default)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl color_table::FragmentObserver for Recorder {
+        fn on_fragment(&self, index: ColorFragmentIndex, fragment: &ColorFragment) {
+            self.0.lock().unwrap().push(format!(
+                "fragment {} parent {} color {:x}",
+                index.0,
+                fragment.parent().0,
+                fragment.color()
+            ));
+        }
+
+        fn on_generation_start(&self, generation: u64, head: ColorFragmentIndex) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("start {generation} at {}", head.0));
+        }
+
+        fn on_generation_end(&self, generation: u64, head: ColorFragmentIndex) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("end {generation} at {}", head.0));
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let recorder = Recorder::default();
+    ct.subscribe(Box::new(recorder.clone()));
+
+    let cc = ct
+        .with_generation(0, |ct| ct.new_color_class(0xA).unwrap())
+        .unwrap();
+    ct.with_generation(1, |ct| ct.extend_color_class(cc, 0xB).unwrap())
+        .unwrap();
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec![
+            "start 0 at 1",
+            "fragment 1 parent 0 color a",
+            "end 0 at 2",
+            "start 1 at 2",
+            "fragment 2 parent 1 color b",
+            "end 1 at 3",
+        ]
+    );
+}
+
+#[test]
+fn in_memory() {
+    let mut ct = ColorTable::in_memory(ColorTableConfig::default());
+
+    let (cc1, cc2) = ct
+        .with_generation(0, |ct| {
+            (
+                ct.new_color_class(0x1).unwrap(),
+                ct.new_color_class(0x2).unwrap(),
+            )
+        })
+        .unwrap();
+
+    let mut ct_map = ct.map().unwrap();
+    assert_eq!(ct_map.color_class(&cc1).collect::<Vec<_>>(), vec![(0x1, 0)]);
+
+    let cc3 = ct
+        .with_generation(1, |ct| ct.fork_color_class(cc1, 0x3).unwrap())
+        .unwrap();
+
+    // the mapping is a snapshot until refreshed
+    assert_eq!(ct_map.color_class(&cc3).count(), 0);
+    ct_map.refresh().unwrap();
+    assert_eq!(
+        ct_map.color_class(&cc3).collect::<Vec<_>>(),
+        vec![(0x3, 1), (0x1, 0)]
+    );
+    drop(ct_map);
+
+    let backup = tempfile::tempdir().unwrap();
+    ct.backup_to(&backup).unwrap();
+    let restored = ColorTable::load(&backup, ColorTableConfig::default()).unwrap();
+    assert_eq!(
+        restored
+            .map()
+            .unwrap()
+            .color_class(&cc3)
+            .collect::<Vec<_>>(),
+        vec![(0x3, 1), (0x1, 0)]
+    );
+
+    let report = ct.compact([cc3]).unwrap();
+    assert_eq!(report.mapping.get(&cc2), None);
+    let cc3 = report.mapping.get(&cc3).unwrap();
+    assert_eq!(
+        ct.map().unwrap().color_class(&cc3).collect::<Vec<_>>(),
+        vec![(0x3, 1), (0x1, 0)]
+    );
+
+    // nothing to sync
+    ct.sync(None).unwrap();
+}
+
+#[test]
+fn append_batches_from_threads() {
+    const THREADS: u32 = 4;
+    const PER_THREAD: u32 = 1000;
+
+    let ct = ColorTable::in_memory(
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    );
+    let base = ct
+        .with_generation(0, |ct| ct.new_color_class(0xBA5E).unwrap())
+        .unwrap();
+
+    let ranges = ct
+        .with_generation(1, |ref guard| {
+            std::thread::scope(|s| {
+                let handles = (0..THREADS)
+                    .map(|t| {
+                        s.spawn(move || {
+                            let mut batch = guard.batch();
+                            for j in 0..PER_THREAD {
+                                let offset =
+                                    batch.fork_color_class(base, t * PER_THREAD + j).unwrap();
+                                assert_eq!(offset, j as usize);
+                            }
+                            (t, guard.append_batch(batch).unwrap())
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    for (t, range) in ranges {
+        assert_eq!(range.len(), PER_THREAD as usize);
+        assert_eq!(range.get(PER_THREAD as usize), None);
+        for (j, id) in range.iter().enumerate() {
+            assert!(range.contains(&id));
+            assert_eq!(
+                ct_map.color_class(&id).collect::<Vec<_>>(),
+                vec![(t * PER_THREAD + j as u32, 1), (0xBA5E, 0)]
+            );
+        }
+    }
+
+    // parents are validated while staging
+    let other = ColorTable::in_memory(ColorTableConfig::default());
+    ct.with_generation(2, |guard| {
+        let mut batch = guard.batch();
+        assert!(batch.extend_color_class(ColorId::new(1 << 20), 0).is_err());
+
+        // batches can't be appended to another table
+        batch.new_color_class(0);
+        other
+            .with_generation(0, |other| assert!(other.append_batch(batch).is_err()))
+            .unwrap();
+    })
+    .unwrap();
+}
+
+#[test]
+fn append_large_batches_from_threads() {
+    const THREADS: u32 = 4;
+    const PER_THREAD: u32 = 1000;
+
+    // batches larger than the buffer are written without holding the writer
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .buffer_size(64usize)
+        .zero_colors(ZeroColorPolicy::Allow)
+        .build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let base = ct
+        .with_generation(0, |ct| ct.new_color_class(0xBA5E).unwrap())
+        .unwrap();
+
+    let ranges = ct
+        .with_generation(1, |ref guard| {
+            std::thread::scope(|s| {
+                let handles = (0..THREADS)
+                    .map(|t| {
+                        s.spawn(move || {
+                            let mut batch = guard.batch();
+                            for j in 0..PER_THREAD {
+                                batch.fork_color_class(base, t * PER_THREAD + j).unwrap();
+                            }
+                            // interleaved with buffered writes
+                            guard.new_color_class(t).unwrap();
+                            (t, guard.append_batch(batch).unwrap())
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let ct = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(ct.fragment_count(), 1 + THREADS * (PER_THREAD + 1));
+    let ct_map = ct.map().unwrap();
+    for (t, range) in ranges {
+        for (j, id) in range.iter().enumerate() {
+            assert_eq!(
+                ct_map.color_class(&id).collect::<Vec<_>>(),
+                vec![(t * PER_THREAD + j as u32, 1), (0xBA5E, 0)]
+            );
+        }
+    }
+}
+
+#[test]
+fn reserve_ids() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+
+    let base = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+
+    let (reserved, after) = ct
+        .with_generation(1, |ct| {
+            let reserved = ct.reserve_ids(3).unwrap();
+            // written after the reserved ids, even though it's written first
+            let after = ct.new_color_class(0x5).unwrap();
+
+            let ids = reserved.iter().collect::<Vec<_>>();
+            ct.extend_color_class_at(ids[2], base, 0x4).unwrap();
+            ct.new_color_class_at(ids[0], 0x2).unwrap();
+            ct.fork_color_class_at(ids[1], base, 0x3).unwrap();
+
+            // each reserved id can only be written once
+            assert!(ct.new_color_class_at(ids[0], 0x6).is_err());
+            assert!(ct.new_color_class_at(after, 0x6).is_err());
+            // parents must precede the reserved id
+            assert!(ct.fork_color_class_at(ids[0], after, 0x6).is_err());
+
+            (reserved, after)
+        })
+        .unwrap();
+
+    assert_eq!(reserved.get(0), Some(ColorId::new(2)));
+    assert_eq!(after, ColorId::new(5));
+
+    let ct_map = ct.map().unwrap();
+    let classes = reserved
+        .iter()
+        .chain([after])
+        .map(|id| ct_map.color_class(&id).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        classes,
+        vec![
+            vec![(0x2, 1)],
+            vec![(0x3, 1), (0x1, 0)],
+            vec![(0x4, 1), (0x1, 0)],
+            vec![(0x5, 1)],
+        ]
+    );
+    drop(ct_map);
+
+    // unwritten reservations fail the generation, but are still written as empty color classes
+    let res = ct.with_generation(2, |ct| {
+        let reserved = ct.reserve_ids(2).unwrap();
+        ct.new_color_class_at(reserved.get(1).unwrap(), 0x7)
+            .unwrap();
+        reserved
+    });
+    assert!(res.is_err());
+
+    let ct_map = ct.map().unwrap();
+    assert_eq!(
+        ct_map.color_class(&ColorId::new(6)).collect::<Vec<_>>(),
+        vec![(0x0, 2)]
+    );
+    assert_eq!(
+        ct_map.color_class(&ColorId::new(7)).collect::<Vec<_>>(),
+        vec![(0x7, 2)]
+    );
+}
+
+#[test]
+fn preallocated_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = || {
+        ColorTableConfig::builder()
+            .preallocate_size(4096usize)
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build()
+    };
+    let file_len = |dir: &tempfile::TempDir| {
+        std::fs::metadata(dir.path().join("color_table"))
+            .unwrap()
+            .len()
+    };
+
+    let ct = ColorTable::new(&dir, config()).unwrap();
+    let mut ids = Vec::new();
+    for g in 0..1000 {
+        ct.with_generation(g, |ct| ids.push(ct.new_color_class(g as u32).unwrap()))
+            .unwrap();
+    }
+    assert_eq!(file_len(&dir), 8192);
+
+    let mut ct_map = ct.map().unwrap();
+    assert_eq!(
+        ct_map.color_class(&ids[999]).collect::<Vec<_>>(),
+        vec![(999, 999)]
+    );
+
+    let cc = ct
+        .with_generation(1000, |ct| ct.extend_color_class(ids[999], 1000).unwrap())
+        .unwrap();
+    ct_map.refresh().unwrap();
+    assert_eq!(
+        ct_map.color_class(&cc).collect::<Vec<_>>(),
+        vec![(1000, 1000), (999, 999)]
+    );
+    // syncing doesn't invalidate existing mappings
+    ct.sync(None).unwrap();
+    assert_eq!(
+        ct_map.color_class(&cc).collect::<Vec<_>>(),
+        vec![(1000, 1000), (999, 999)]
+    );
+    drop(ct_map);
+
+    // preallocated space is trimmed on sync
+    ct.sync(None).unwrap();
+    assert_eq!(file_len(&dir), 1002 * 8);
+
+    // fragments written after the last sync are discarded by a repair, along with the preallocated
+    // space. copy the files without syncing, as if the process was killed
+    ct.with_generation(1001, |ct| ct.new_color_class(0).unwrap())
+        .unwrap();
+    assert_eq!(file_len(&dir), 8192);
+    let crashed = tempfile::tempdir().unwrap();
+    for name in ["color_table", "generations"] {
+        std::fs::copy(dir.path().join(name), crashed.path().join(name)).unwrap();
+    }
+    drop(ct);
+
+    assert!(matches!(
+        ColorTable::load(&crashed, config()),
+        Err(ColorTableError::UncoveredFragments {
+            start: 1002,
+            end: 1024
+        })
+    ));
+    assert_eq!(file_len(&crashed), 8192);
+    let (mut ct, report) = ColorTable::load_repaired(&crashed, config()).unwrap();
+    assert_eq!(report.fragments, 1024 - 1002);
+    assert_eq!(file_len(&crashed), 1002 * 8);
+    assert_eq!(
+        ct.map().unwrap().color_class(&cc).collect::<Vec<_>>(),
+        vec![(1000, 1000), (999, 999)]
+    );
+
+    let report = ct.compact([cc]).unwrap();
+    assert_eq!(report.fragments_after, 2);
+    assert_eq!(file_len(&crashed), 3 * 8);
+    let cc = ct
+        .with_generation(1001, |ct| {
+            ct.extend_color_class(report.mapping.get(&cc).unwrap(), 1001)
+                .unwrap()
+        })
+        .unwrap();
+    assert_eq!(file_len(&crashed), 4096);
+    assert_eq!(ct.map().unwrap().color_class(&cc).count(), 3);
+}
+
+#[test]
+fn flush_policy() {
+    fn file_len(dir: &tempfile::TempDir) -> u64 {
+        std::fs::metadata(dir.path().join("color_table"))
+            .unwrap()
+            .len()
+    }
+
+    // number of fragments visible in the file after each of 4 generations of one fragment each
+    let cases = [
+        (FlushPolicy::EveryGeneration, [2, 3, 4, 5]),
+        (FlushPolicy::EveryNGenerations(2), [0, 3, 3, 5]),
+        (FlushPolicy::EveryNBytes(24), [0, 0, 4, 4]),
+        (FlushPolicy::Manual, [0, 0, 0, 0]),
+    ];
+
+    for (policy, expected) in cases {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ColorTableConfig::builder().flush_policy(policy).build();
+        let ct = ColorTable::new(&dir, config).unwrap();
+
+        let mut lens = [0; 4];
+        for (g, len) in lens.iter_mut().enumerate() {
+            ct.with_generation(g as u64, |ct| ct.new_color_class(0x1).unwrap())
+                .unwrap();
+            *len = file_len(&dir) / 8;
+        }
+        assert_eq!(lens, expected, "{policy:?}");
+
+        // mapping always flushes
+        assert_eq!(ct.map().unwrap().color_class(&ColorId::new(4)).count(), 1);
+        assert_eq!(file_len(&dir), 5 * 8);
+    }
+}
+
+#[test]
+fn background_flusher() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .flush_policy(FlushPolicy::Manual)
+        .build();
+    let ct = std::sync::Arc::new(ColorTable::new(&dir, config).unwrap());
+    let flusher = ct
+        .spawn_flusher(std::time::Duration::from_millis(10))
+        .unwrap();
+
+    ct.with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+
+    let synced = || {
+        std::fs::read(dir.path().join("generations")).is_ok_and(|g| !g.is_empty())
+            && std::fs::metadata(dir.path().join("color_table"))
+                .unwrap()
+                .len()
+                == 2 * 8
+    };
+    let now = std::time::Instant::now();
+    while !synced() {
+        assert!(now.elapsed() < std::time::Duration::from_secs(5));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    // the flusher exits once the table is dropped
+    drop(ct);
+    flusher.stop().unwrap();
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_api() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = std::sync::Arc::new(ColorTable::new(&dir, ColorTableConfig::default()).unwrap());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let extended = runtime.block_on(async {
+        let id = ct
+            .with_generation_async(0, |ct| ct.new_color_class(0b101))
+            .await
+            .unwrap()
+            .unwrap();
+        let extended = ct
+            .with_generation_async(1, move |ct| ct.extend_color_class(id, 0b1))
+            .await
+            .unwrap()
+            .unwrap();
+        let indices = ct
+            .query_async(move |map| map.color_class(&extended).into_indices())
+            .await
+            .unwrap();
+        assert_eq!(indices, [0, 2, 32]);
+
+        ct.sync_async(None).await.unwrap();
+        // generations still have to be numbered in order
+        assert!(matches!(
+            ct.with_generation_async(1, |_| {}).await,
+            Err(ColorTableError::GenerationNotIncreasing { .. })
+        ));
+        extended
+    });
+
+    drop(ct);
+    let ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    assert_eq!(
+        ct.map()
+            .unwrap()
+            .color_class(&extended)
+            .into_indices()
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn lock_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    ct.with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+
+    // the table can't be loaded for writing while it is open, but it can be loaded read-only
+    let pid = Some(std::process::id());
+    for res in [
+        ColorTable::load(&dir, ColorTableConfig::default()),
+        // must not truncate the table
+        ColorTable::load_or_new(&dir, ColorTableConfig::default()),
+    ] {
+        assert!(matches!(res, Err(ColorTableError::Locked { pid: p }) if p == pid));
+    }
+    let ro = ColorTable::load_read_only(&dir, ColorTableConfig::default()).unwrap();
+    assert!(matches!(
+        ro.with_generation(1, |_| {}),
+        Err(ColorTableError::Locked { pid: p }) if p == pid
+    ));
+
+    // fragments written after the read-only load are not seen
+    ct.with_generation(1, |ct| ct.new_color_class(0x2).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+    assert_eq!(ro.fragment_count(), 1);
+
+    // the file can't be truncated while it is mapped read-only
+    drop(ct);
+    assert!(matches!(
+        ColorTable::new(&dir, ColorTableConfig::default()),
+        Err(ColorTableError::Locked { pid: None })
+    ));
+    assert_eq!(
+        ro.map()
+            .unwrap()
+            .color_class(&ColorId::new(1))
+            .collect::<Vec<_>>(),
+        vec![(0x1, 0)]
+    );
+
+    drop(ro);
+    let ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    assert_eq!(
+        ct.map()
+            .unwrap()
+            .color_class(&ColorId::new(2))
+            .collect::<Vec<_>>(),
+        vec![(0x2, 1)]
+    );
+}
+
+#[test]
+fn follow_published_generations() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .publish_generations(true)
+        .flush_policy(FlushPolicy::Manual)
+        .build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let cc1 = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    // empty generations are not published
+    ct.with_generation(1, |_| ()).unwrap();
+
+    let mut reader = ColorTableReader::open(&dir, &config).unwrap();
+    assert_eq!(reader.color_class(&cc1).collect::<Vec<_>>(), vec![(0x1, 0)]);
+    assert!(!reader.refresh().unwrap());
+
+    ct.with_generation(2, |guard| {
+        let cc2 = guard.extend_color_class(cc1, 0x2).unwrap();
+        // the generation in progress is not visible
+        assert!(!reader.refresh().unwrap());
+        assert_eq!(reader.color_class(&cc2).count(), 0);
+    })
+    .unwrap();
+
+    assert!(reader.refresh().unwrap());
+    assert_eq!(
+        reader.color_class(&ColorId::new(2)).collect::<Vec<_>>(),
+        vec![(0x2, 2), (0x1, 0)]
+    );
+
+    // a reopened table republishes its generations
+    drop(ct);
+    let ct = ColorTable::load(&dir, config.clone()).unwrap();
+    let cc3 = ct
+        .with_generation(3, |ct| ct.fork_color_class(ColorId::new(2), 0x3).unwrap())
+        .unwrap();
+    let reader = ColorTableReader::open(&dir, &config).unwrap();
+    assert_eq!(
+        reader.color_class(&cc3).collect::<Vec<_>>(),
+        vec![(0x3, 3), (0x2, 2), (0x1, 0)]
+    );
+}
+
+#[test]
+fn verify() {
+    let small = tempfile::tempdir().unwrap();
+    let large = tempfile::tempdir().unwrap();
+
+    for (dir, n) in [(&small, 1), (&large, 3)] {
+        let ct = ColorTable::new(dir, ColorTableConfig::default()).unwrap();
+        for g in 0..n {
+            ct.with_generation(g, |ct| ct.new_color_class(0x1).unwrap())
+                .unwrap();
+        }
+
+        let report = ct.verify(VerifyLevel::Full).unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.fragments, n as u32);
+        assert_eq!(report.generations, n as usize);
+    }
+
+    // swap the generations of the two tables
+    let generations = |dir: &tempfile::TempDir| dir.path().join("generations");
+    let tmp = small.path().join("tmp");
+    std::fs::rename(generations(&small), &tmp).unwrap();
+    std::fs::rename(generations(&large), generations(&small)).unwrap();
+    std::fs::rename(&tmp, generations(&large)).unwrap();
+
+    // generations past the end of the file are caught by `load`
+    let err = ColorTable::load(&small, ColorTableConfig::default()).unwrap_err();
+    assert!(matches!(
+        err,
+        ColorTableError::CrossFileMismatch {
+            generations_end: 4,
+            head: 2
+        }
+    ));
+    assert!(err.is_corruption());
+
+    // and fragments past the end of the generations need to be repaired
+    let err = ColorTable::load(&large, ColorTableConfig::default()).unwrap_err();
+    assert!(matches!(
+        err,
+        ColorTableError::UncoveredFragments { start: 2, end: 4 }
+    ));
+    let (ct, report) = ColorTable::load_repaired(&large, ColorTableConfig::default()).unwrap();
+    assert_eq!(report.fragments, 2);
+    assert!(ct.verify(VerifyLevel::Full).unwrap().is_ok());
+}
+
+#[test]
+fn structured_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    match ColorTable::load(&missing, ColorTableConfig::default()) {
+        Err(err @ ColorTableError::File { .. }) => {
+            assert!(!err.is_corruption());
+            assert!(!err.is_transient());
+            assert_eq!(err.code(), "io");
+            assert!(err.to_string().contains("missing"), "{err}");
+        }
+        res => panic!("expected a file error, got {res:?}"),
+    }
+
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    ct.with_generation(1, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    assert!(matches!(
+        ct.with_generation(1, |_| {}),
+        Err(ColorTableError::GenerationNotIncreasing {
+            generation: 1,
+            last: 1
+        })
+    ));
+    assert!(matches!(
+        ct.merge_from(&ct, 0),
+        Err(ColorTableError::MergeIntoSelf)
+    ));
+
+    let err = ColorTable::new(&dir, ColorTableConfig::default()).unwrap_err();
+    assert!(err.is_transient());
+    assert_eq!(err.code(), "locked");
+
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    // corrupt the magic header
+    let path = dir.path().join("color_table");
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[0] ^= 0xff;
+    std::fs::write(&path, &bytes).unwrap();
+    match ColorTable::load(&dir, ColorTableConfig::default()) {
+        Err(err @ ColorTableError::BadMagic { .. }) => {
+            assert!(err.is_corruption());
+            assert!(!err.is_transient());
+        }
+        res => panic!("expected a bad magic error, got {res:?}"),
+    }
+
+    // point a fragment at itself
+    bytes[0] ^= 0xff;
+    bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(matches!(
+        ColorTable::load(&dir, ColorTableConfig::default()),
+        Err(ColorTableError::ParentNotBefore {
+            index: 1,
+            parent: 1
+        })
+    ));
+}
+
+#[test]
+fn migrate_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    ct.with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    // write a version 1 header
+    let path = dir.path().join("color_table");
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[..8].copy_from_slice(b"CTBL\0\x00\x00\x01");
+    std::fs::write(&path, &bytes).unwrap();
+    drop(ColorTable::load(&dir, ColorTableConfig::default()).unwrap());
+
+    // the file isn't rewritten while a read-only table maps it
+    let ro = ColorTable::load_read_only(&dir, ColorTableConfig::default()).unwrap();
+    let default = ColorTableConfig::default();
+    assert!(matches!(
+        ColorTable::migrate(&dir, &default, &default),
+        Err(ColorTableError::Locked { pid: None })
+    ));
+    drop(ro);
+
+    let to_config = ColorTableConfig::builder()
+        .color_table_file_name("table")
+        .build();
+    assert_eq!(
+        ColorTable::migrate(&dir, &ColorTableConfig::default(), &to_config).unwrap(),
+        1
+    );
+    assert!(!path.exists());
+    let migrated = std::fs::read(dir.path().join("table")).unwrap();
+    assert_eq!(migrated[8..], bytes[8..]);
+    assert_eq!(migrated[7], 3);
+
+    let ct = ColorTable::load(&dir, to_config.clone()).unwrap();
+    assert_eq!(
+        ct.map()
+            .unwrap()
+            .color_class(&ColorId::new(1))
+            .collect::<Vec<_>>(),
+        vec![(0x1, 0)]
+    );
+    drop(ct);
+
+    // a table from a newer version is rejected, and not overwritten
+    let mut bytes = migrated;
+    bytes[7] = 9;
+    std::fs::write(dir.path().join("table"), &bytes).unwrap();
+    assert!(matches!(
+        ColorTable::load_or_new(&dir, to_config.clone()),
+        Err(ColorTableError::UnsupportedVersion { version: 9, .. })
+    ));
+    assert!(matches!(
+        ColorTable::migrate(&dir, &to_config, &to_config),
+        Err(ColorTableError::UnsupportedVersion { version: 9, .. })
+    ));
+    assert_eq!(std::fs::read(dir.path().join("table")).unwrap(), bytes);
+}
+
+#[test]
+fn absolute_file_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let metadata = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .generations_file_name(metadata.path().join("generations"))
+        .lock_file_name(metadata.path().join("lock"))
+        .build();
+
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    ct.with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    assert!(dir.path().join("color_table").exists());
+    assert!(!dir.path().join("generations").exists());
+    assert!(metadata.path().join("generations").exists());
+
+    let ct = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(
+        ct.map()
+            .unwrap()
+            .color_class(&ColorId::new(1))
+            .collect::<Vec<_>>(),
+        vec![(0x1, 0)]
+    );
+
+    // backups are written to a single directory
+    let backup = tempfile::tempdir().unwrap();
+    ct.backup_to(&backup).unwrap();
+    assert!(backup.path().join("generations").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn file_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .file_mode(0o664)
+        .publish_generations(true)
+        .build();
+    // existing files keep their permissions
+    let lock = dir.path().join("lock");
+    std::fs::write(&lock, "").unwrap();
+    std::fs::set_permissions(&lock, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    ct.with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+
+    for name in ["color_table", "generations", "generation_log"] {
+        let mode = std::fs::metadata(dir.path().join(name))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o664, "{name}");
+    }
+    assert_eq!(
+        std::fs::metadata(&lock).unwrap().permissions().mode() & 0o777,
+        0o640
+    );
+}
+
+#[test]
+fn from_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let open = || {
+        std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.path().join("color_table"))
+            .unwrap()
+    };
+
+    let ct = ColorTable::from_files(&dir, open(), ColorTableConfig::default()).unwrap();
+    ct.with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let ct = ColorTable::from_files(&dir, open(), ColorTableConfig::default()).unwrap();
+    ct.with_generation(1, |ct| ct.new_color_class(0x2).unwrap())
+        .unwrap();
+    assert_eq!(
+        ct.map()
+            .unwrap()
+            .color_class(&ColorId::new(2))
+            .collect::<Vec<_>>(),
+        vec![(0x2, 1)]
+    );
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    let map = ct.map().unwrap();
+    assert_eq!(map.color_class(&ColorId::new(1)).count(), 1);
+    assert_eq!(map.color_class(&ColorId::new(2)).count(), 1);
+}
+
+#[test]
+fn abort_generation() {
+    #[derive(Clone, Default)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl color_table::FragmentObserver for Recorder {
+        fn on_generation_end(&self, generation: u64, _head: ColorFragmentIndex) {
+            self.0.lock().unwrap().push(format!("end {generation}"));
+        }
+
+        fn on_generation_abort(&self, generation: u64) {
+            self.0.lock().unwrap().push(format!("abort {generation}"));
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    ct.with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    // writes to a file opened read-only fail once they bypass the buffer
+    let file = std::fs::File::open(dir.path().join("color_table")).unwrap();
+    let config = ColorTableConfig::builder().buffer_size(8usize).build();
+    let ct = ColorTable::from_files(&dir, file, config).unwrap();
+    let recorder = Recorder::default();
+    ct.subscribe(Box::new(recorder.clone()));
+
+    // the unwritten reservations are only written when the generation ends
+    assert!(matches!(
+        ct.with_generation(1, |ct| ct.reserve_ids(64).unwrap()),
+        Err(ColorTableError::Io(_))
+    ));
+    assert_eq!(*recorder.0.lock().unwrap(), vec!["abort 1"]);
+    assert!(ct.wait_for_generation_end(std::time::Duration::ZERO));
+    assert!(matches!(
+        ct.with_generation(2, |_| {}),
+        Err(ColorTableError::UncoveredFragments { start: 2, end: 66 })
+    ));
+
+    // the aborted generation is not synced
+    ct.sync(None).unwrap();
+    drop(ct);
+    let (ct, report) = ColorTable::load_repaired(&dir, ColorTableConfig::default()).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(
+        ct.map()
+            .unwrap()
+            .color_class(&ColorId::new(1))
+            .collect::<Vec<_>>(),
+        vec![(0x1, 0)]
+    );
+}
+
+#[test]
+fn map_options() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(
+        &dir,
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    )
+    .unwrap();
+    let mut ids = Vec::new();
+    for g in 0..10 {
+        ct.with_generation(g, |ct| ids.push(ct.new_color_class(g as u32).unwrap()))
+            .unwrap();
+    }
+
+    let options = MapOptions::builder()
+        .access(AccessPattern::Sequential)
+        .will_need(8..=20)
+        .huge_pages(true)
+        .build();
+    let mut ct_map = ct.map_with(options).unwrap();
+    assert_eq!(
+        ct_map.color_class(&ids[9]).collect::<Vec<_>>(),
+        vec![(9, 9)]
+    );
+
+    let id = ct
+        .with_generation(10, |ct| ct.new_color_class(10).unwrap())
+        .unwrap();
+    ct_map.refresh().unwrap();
+    assert_eq!(ct_map.color_class(&id).collect::<Vec<_>>(), vec![(10, 10)]);
+}
+
+#[test]
+fn map_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = || {
+        ColorTableConfig::builder()
+            .skip_interval(2u32)
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build()
+    };
+    let file = ColorTable::new(&dir, config()).unwrap();
+    let memory = ColorTable::in_memory(config());
+
+    for ct in [&file, &memory] {
+        let mut ids = vec![
+            ct.with_generation(0, |ct| ct.new_color_class(0).unwrap())
+                .unwrap(),
+        ];
+        for g in 1..10 {
+            let parent = ids[g - 1];
+            ids.push(
+                ct.with_generation(g as u64, |ct| {
+                    ct.extend_color_class(parent, g as u32).unwrap()
+                })
+                .unwrap(),
+            );
+        }
+
+        let ct_map = ct.map_range(5..=7).unwrap();
+        let class = ct_map.color_class(&ids[7]);
+        assert_eq!(class.len(), 3);
+        assert_eq!(class.collect::<Vec<_>>(), vec![(7, 7), (6, 6), (5, 5)]);
+        assert_eq!(ct_map.color_class(&ids[8]).count(), 0);
+        assert_eq!(ct_map.color_class(&ids[2]).count(), 0);
+
+        let mut class = ct_map.color_class(&ids[7]);
+        class.skip_newer_than(5);
+        assert_eq!(class.collect::<Vec<_>>(), vec![(5, 5)]);
+
+        assert_eq!(
+            ct.map_range(20..=30).unwrap().color_class(&ids[0]).count(),
+            0
+        );
+    }
+}
+
+#[test]
+fn prefetch() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = std::sync::Arc::new(ColorTable::new(&dir, ColorTableConfig::default()).unwrap());
+    let mut id = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+    for g in 1..2000 {
+        id = ct
+            .with_generation(g, |ct| ct.extend_color_class(id, 0x1).unwrap())
+            .unwrap();
+    }
+
+    // a class spanning fewer fragments than the pages before it
+    let short = ct
+        .with_generation(2000, |ct| ct.new_color_class(0x1).unwrap())
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    ct_map.prefetch(&id).unwrap();
+    ct_map.prefetch(&short).unwrap();
+    ct_map.prefetch(&ColorId::new(1 << 20)).unwrap();
+    assert_eq!(ct_map.color_class(&id).count(), 2000);
+    assert_eq!(ct_map.color_class(&short).count(), 1);
+
+    let ct_map = ct.map_range(1000..=1999).unwrap();
+    ct_map.prefetch(&id).unwrap();
+    assert_eq!(ct_map.color_class(&id).count(), 1000);
+
+    ct.map_owned().unwrap().prefetch(&id).unwrap();
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn batched_color_classes() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(7);
+    let mut ids = Vec::new();
+    for g in 0..200 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+    ct.materialize_bitmaps(ids.iter().step_by(7).copied())
+        .unwrap();
+
+    let queries = (0..500)
+        .map(|_| ids[rng.usize(..ids.len())])
+        .chain([ColorId::new(1 << 20)])
+        .collect::<Vec<_>>();
+    let ct_map = ct.map().unwrap();
+    let expected = queries
+        .iter()
+        .map(|id| ct_map.color_class(id).into_bitmap())
+        .collect::<Vec<_>>();
+    assert_eq!(ct_map.color_classes(&queries), expected);
+
+    let ct_map = ct.map_range(100..=199).unwrap();
+    let expected = queries
+        .iter()
+        .map(|id| ct_map.color_class(id).into_bitmap())
+        .collect::<Vec<_>>();
+    assert_eq!(ct_map.color_classes(&queries), expected);
+}
+
+#[test]
+fn decode_indices() {
+    let ct = ColorTable::in_memory(
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    );
+    let mut rng = fastrand::Rng::with_seed(11);
+    let mut generation = 0;
+    let mut id = ct
+        .with_generation(generation, |ct| ct.new_color_class(rng.u32(..)).unwrap())
+        .unwrap();
+    for _ in 0..1000 {
+        // skip some generations, so chains have words with only one half set
+        generation += rng.u64(1..4);
+        // including empty fragments
+        let color = if rng.u8(..10) == 0 { 0 } else { rng.u32(..) };
+        id = ct
+            .with_generation(generation, |ct| ct.extend_color_class(id, color).unwrap())
+            .unwrap();
+    }
+
+    let ct_map = ct.map().unwrap();
+    let mut expected = Vec::new();
+    for (color, generation) in ct_map.color_class(&id) {
+        for bit in 0..u32::BITS {
+            if color & (1 << bit) != 0 {
+                expected.push((generation * 32 + bit as u64) as usize);
+            }
+        }
+    }
+    expected.sort_unstable();
+
+    let mut indices = ct_map.color_class(&id).into_indices();
+    assert_eq!(indices.len(), expected.len());
+    indices.sort_unstable();
+    assert_eq!(indices, expected);
+
+    let mut buf = vec![usize::MAX];
+    ct_map.color_class(&id).collect_indices_into(&mut buf);
+    assert_eq!(buf.remove(0), usize::MAX);
+    buf.sort_unstable();
+    assert_eq!(buf, expected);
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn treemap_indices() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let id = ct
+        .with_generation(3, |ct| ct.new_color_class(0b101))
+        .unwrap()
+        .unwrap();
+    let id = ct
+        .with_generation(200_000_000, |ct| ct.extend_color_class(id, 1 << 31))
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let treemap = ct_map.color_class(&id).into_treemap();
+    assert_eq!(
+        treemap.iter().collect::<Vec<_>>(),
+        [96, 98, 200_000_000 * 32 + 31]
+    );
+}
+
+#[cfg(feature = "bitvec")]
+#[test]
+fn bitvec_output() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1001))
+        .unwrap()
+        .unwrap();
+    let id = ct
+        .with_generation(2, |ct| ct.extend_color_class(id, 1 << 4))
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let bits = ct_map.color_class(&id).into_bitvec(80);
+    assert_eq!(bits.len(), 80);
+    assert_eq!(bits.iter_ones().collect::<Vec<_>>(), [0, 3, 68]);
+
+    let class = std::panic::AssertUnwindSafe(ct_map.color_class(&id));
+    assert!(std::panic::catch_unwind(move || { class }.0.into_bitvec(68)).is_err());
+}
+
+#[test]
+fn collect_indices_into() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let (a, b) = ct
+        .with_generation(0, |ct| {
+            let a = ct.new_color_class(0b11)?;
+            Ok::<_, ColorTableError>((a, ct.new_color_class(1 << 5)?))
+        })
+        .unwrap()
+        .unwrap();
+    let a = ct
+        .with_generation(1, |ct| ct.extend_color_class(a, 1))
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let mut buf = Vec::new();
+    ct_map.color_class(&a).collect_indices_into(&mut buf);
+    buf.sort_unstable();
+    assert_eq!(buf, [0, 1, 32]);
+
+    // the buffer is appended to, and its allocation reused
+    buf.clear();
+    let capacity = buf.capacity();
+    ct_map.color_class(&b).collect_indices_into(&mut buf);
+    assert_eq!(buf, [5]);
+    assert_eq!(buf.capacity(), capacity);
+
+    #[cfg(feature = "smallvec")]
+    {
+        let mut buf = smallvec::SmallVec::<[usize; 4]>::new();
+        ct_map
+            .color_class(&a)
+            .collect_indices_into_smallvec(&mut buf);
+        buf.sort_unstable();
+        assert_eq!(buf.as_slice(), [0, 1, 32]);
+        assert!(!buf.spilled());
+    }
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn intersect_many() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(11);
+    let mut ids = Vec::new();
+    for g in 0..100 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..) | rng.u32(..);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+
+    let ct_map = ct.map().unwrap();
+    for n in 1..6 {
+        let queries = (0..n)
+            .map(|_| ids[rng.usize(..ids.len())])
+            .collect::<Vec<_>>();
+        let expected = queries
+            .iter()
+            .map(|id| ct_map.color_class(id).into_bitmap())
+            .reduce(|a, b| a & b)
+            .unwrap();
+        assert_eq!(ct_map.intersect_many(&queries), expected);
+    }
+
+    assert!(ct_map.intersect_many(&[]).is_empty());
+    assert!(ct_map.intersect_many(&[ids[0], ColorId::new(0)]).is_empty());
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn threshold_union() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(13);
+    let mut ids = Vec::new();
+    for g in 0..100 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+
+    let ct_map = ct.map().unwrap();
+    let queries = (0..8)
+        .map(|_| ids[rng.usize(..ids.len())])
+        .collect::<Vec<_>>();
+    let bitmaps = queries
+        .iter()
+        .map(|id| ct_map.color_class(id).into_bitmap())
+        .collect::<Vec<_>>();
+    let union = bitmaps
+        .iter()
+        .fold(roaring::RoaringBitmap::new(), |acc, b| acc | b);
+    for k in 0..=9 {
+        let expected = union
+            .iter()
+            .filter(|&idx| bitmaps.iter().filter(|b| b.contains(idx)).count() >= k)
+            .collect::<roaring::RoaringBitmap>();
+        assert_eq!(ct_map.threshold_union(&queries, k), expected, "k = {k}");
+    }
+    assert_eq!(
+        ct_map.threshold_union(&queries, queries.len()),
+        ct_map.intersect_many(&queries)
+    );
+}
+
+#[test]
+fn accumulate() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(17);
+    let mut ids = Vec::new();
+    for g in 0..50 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+
+    let ct_map = ct.map().unwrap();
+    let queries = (0..20)
+        .map(|_| ids[rng.usize(..ids.len())])
+        .collect::<Vec<_>>();
+    let mut expected = vec![0; 50 * 32];
+    for id in &queries {
+        let indices = ct_map
+            .color_class(id)
+            .into_indices()
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        for idx in indices {
+            expected[idx] += 1;
+        }
+    }
+
+    let mut counts = vec![0; 50 * 32];
+    ct_map.accumulate(queries.iter().copied(), &mut counts);
+    assert_eq!(counts, expected);
+}
+
+#[test]
+fn similarity() {
+    use std::collections::HashSet;
+
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(19);
+    let mut ids = Vec::new();
+    for g in 0..50 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+
+    let ct_map = ct.map().unwrap();
+    let set = |id: &ColorId| {
+        ct_map
+            .color_class(id)
+            .into_indices()
+            .into_iter()
+            .collect::<HashSet<_>>()
+    };
+    for _ in 0..100 {
+        let (a, b) = (ids[rng.usize(..ids.len())], ids[rng.usize(..ids.len())]);
+        let (set_a, set_b) = (set(&a), set(&b));
+        let shared = set_a.intersection(&set_b).count() as f64;
+        let union = set_a.union(&set_b).count() as f64;
+        assert_eq!(ct_map.jaccard(&a, &b), shared / union);
+        assert_eq!(ct_map.containment(&a, &b), shared / set_a.len() as f64);
+    }
+
+    let a = ids[0];
+    assert_eq!(ct_map.jaccard(&a, &a), 1.0);
+    assert_eq!(ct_map.containment(&a, &ColorId::new(0)), 0.0);
+    assert_eq!(ct_map.containment(&ColorId::new(0), &a), 1.0);
+    assert_eq!(ct_map.jaccard(&ColorId::new(0), &ColorId::new(0)), 1.0);
+}
+
+#[test]
+fn class_hash() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let (a, b, c) = ct
+        .with_generation(0, |ct| {
+            let a = ct.new_color_class(0b11)?;
+            let b = ct.new_color_class(0b11)?;
+            let c = ct.new_color_class(0b10)?;
+            Ok::<_, ColorTableError>((a, b, c))
+        })
+        .unwrap()
+        .unwrap();
+    let (a, b) = ct
+        .with_generation(1, |ct| {
+            let a = ct.extend_color_class(a, 1 << 7)?;
+            let b = ct.extend_color_class(b, 1 << 7)?;
+            Ok::<_, ColorTableError>((a, b))
+        })
+        .unwrap()
+        .unwrap();
+    // same bits, in a different generation
+    let c = ct
+        .with_generation(2, |ct| ct.extend_color_class(c, 1 << 7))
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    assert_ne!(a, b);
+    assert_eq!(ct_map.class_hash(&a), ct_map.class_hash(&b));
+    assert_ne!(ct_map.class_hash(&a), ct_map.class_hash(&c));
+    // pinned, since hashes may be stored
+    assert_eq!(ct_map.class_hash(&a), 0xa7da1d0ed4a1a770b6ed8e401831143f);
+    assert_eq!(
+        ct_map.class_hash(&ColorId::new(0)),
+        0x6c62272e07bb014262b821756295c58d
+    );
+}
+
+#[test]
+fn top_k_by_cardinality() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(31);
+    let mut ids = Vec::new();
+    for g in 0..30 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..) & rng.u32(..);
+                // classes created in this generation may be forked again, so some classes have
+                // overlapping fragments in the same generation
+                let id = match rng.usize(..4) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    2 if g > 0 => ct.extend_color_class_remove(
+                        ids[rng.usize(..ids.len())],
+                        rng.u64(..g),
+                        rng.u32(..) & rng.u32(..),
+                    ),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+    let ct_map = ct.map().unwrap();
+    ct.tombstone(ct_map.iter_heads().next().unwrap()).unwrap();
+
+    let mut expected = ct_map
+        .iter_heads()
+        .map(|id| {
+            let indices = ct_map
+                .color_class(&id)
+                .into_indices()
+                .into_iter()
+                .collect::<std::collections::BTreeSet<_>>();
+            (id, indices.len() as u64)
+        })
+        .collect::<Vec<_>>();
+    expected.sort_by_key(|&(id, cardinality)| (std::cmp::Reverse(cardinality), id));
+    for k in [0, 1, 10, expected.len(), expected.len() + 5] {
+        assert_eq!(
+            ct_map.top_k_by_cardinality(k),
+            expected[..k.min(expected.len())]
+        );
+    }
+
+    let config = ColorTableConfig::builder()
+        .color_mode(ColorMode::Counts)
+        .build();
+    let ct = ColorTable::in_memory(config);
+    let a = ct
+        .with_generation(0, |ct| ct.new_with_counts(&[3, 0, 20]))
+        .unwrap()
+        .unwrap();
+    let (b, c) = ct
+        .with_generation(1, |ct| {
+            let b = ct.extend_with_counts(a, &[1, 1])?;
+            let c = ct.new_with_counts(&[0, 0, 0, 5])?;
+            Ok::<_, ColorTableError>((b, c))
+        })
+        .unwrap()
+        .unwrap();
+    let ct_map = ct.map().unwrap();
+    assert_eq!(ct_map.top_k_by_cardinality(2), [(b, 4), (c, 1)]);
+}
+
+#[test]
+fn dedup_color_classes() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let (a, b, c) = ct
+        .with_generation(0, |ct| {
+            let a = ct.new_or_existing_color_class(0b1, 1)?;
+            let b = ct.new_or_existing_color_class(0b1, 1)?;
+            let c = ct.new_or_existing_color_class(0b10, 2)?;
+            Ok::<_, ColorTableError>((a, b, c))
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    // hashes are kept across loads
+    let ct = ColorTable::load(&dir, config).unwrap();
+    let (d, e) = ct
+        .with_generation(1, |ct| {
+            let d = ct.new_or_existing_color_class(0b10, 2)?;
+            let e = ct.new_or_existing_color_class(0b100, 3)?;
+            Ok::<_, ColorTableError>((d, e))
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(d, c);
+    assert_ne!(e, c);
+
+    let ct_map = ct.map().unwrap();
+    assert_eq!(ct_map.color_class(&e).collect::<Vec<_>>(), [(0b100, 1)]);
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn classes_containing() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(23);
+    let mut ids = Vec::new();
+    for g in 0..20 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..) & rng.u32(..);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+
+    let ct_map = ct.map().unwrap();
+    let bitmaps = (1..=200)
+        .map(|id| ct_map.color_class(&ColorId::new(id)).into_bitmap())
+        .collect::<Vec<_>>();
+    drop(ct_map);
+    for sample in [0, 5, 31, 32, 100, 321, 639] {
+        let expected = (1..=200)
+            .filter(|&id| bitmaps[id as usize - 1].contains(sample))
+            .collect::<roaring::RoaringBitmap>();
+        assert_eq!(ct.classes_containing(sample.into()).unwrap(), expected);
+    }
+    assert!(ct.classes_containing(1 << 40).unwrap().is_empty());
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn transpose() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut rng = fastrand::Rng::with_seed(29);
+    let mut ids = Vec::new();
+    for g in 0..20 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..) & rng.u32(..);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+    ct.tombstone(ids[7]).unwrap();
+
+    // duplicates, deleted classes and the null class keep their positions
+    let queries = (0..50)
+        .map(|_| ids[rng.usize(..ids.len())])
+        .chain([ids[0], ids[7], ColorId::new(0)])
+        .collect::<Vec<_>>();
+    let transposed = ct.transpose(&queries).unwrap();
+    assert_eq!(transposed.len(), 20 * 32);
+
+    let ct_map = ct.map().unwrap();
+    for (sample, classes) in transposed.iter().enumerate() {
+        let expected = queries
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| ct_map.color_class(id).into_bitmap().contains(sample as u32))
+            .map(|(class, _)| class as u32)
+            .collect::<roaring::RoaringBitmap>();
+        assert_eq!(*classes, expected);
+    }
+    assert!(!transposed.iter().any(|classes| classes.contains(51)));
+    assert!(!transposed.iter().any(|classes| classes.contains(52)));
+    assert!(transposed.iter().any(|classes| classes.contains(50)));
+
+    let empty = ColorTable::in_memory(ColorTableConfig::default());
+    assert!(empty.transpose(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn generation_contents() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let a = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1))
+        .unwrap()
+        .unwrap();
+    let (b, c) = ct
+        .with_generation(5, |ct| {
+            let b = ct.new_color_class(0b10)?;
+            let c = ct.extend_color_class(a, 0b100)?;
+            Ok::<_, ColorTableError>((b, c))
+        })
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let fragments = ct_map
+        .fragments_in_generation(5)
+        .map(|(idx, fragment)| (idx.0, fragment.parent().0, fragment.color()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        fragments,
+        [(b.as_u32(), 0, 0b10), (c.as_u32(), a.as_u32(), 0b100)]
+    );
+    assert_eq!(ct_map.classes_created_in(5).collect::<Vec<_>>(), [b, c]);
+    assert_eq!(ct_map.classes_created_in(0).collect::<Vec<_>>(), [a]);
+    assert_eq!(ct_map.classes_created_in(1).count(), 0);
+}
+
+#[test]
+fn iter_heads() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let (a, b) = ct
+        .with_generation(0, |ct| {
+            let a = ct.new_color_class(0b1)?;
+            let b = ct.new_color_class(0b10)?;
+            Ok::<_, ColorTableError>((a, b))
+        })
+        .unwrap()
+        .unwrap();
+    let (c, d, e) = ct
+        .with_generation(1, |ct| {
+            let c = ct.extend_color_class(a, 0b1)?;
+            let d = ct.fork_color_class(b, 0b1)?;
+            let e = ct.new_color_class(0b100)?;
+            Ok::<_, ColorTableError>((c, d, e))
+        })
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    assert_eq!(ct_map.iter_heads().collect::<Vec<_>>(), [c, d, e]);
+    drop(ct_map);
+
+    // parents outside of a partial mapping don't hide their children
+    let ct_map = ct.map_range(1..=1).unwrap();
+    assert_eq!(ct_map.iter_heads().collect::<Vec<_>>(), [c, d, e]);
+}
+
+#[test]
+fn creation_generations() {
+    for skip_interval in [0u32, 3] {
+        let config = ColorTableConfig::builder()
+            .skip_interval(skip_interval)
+            .build();
+        let ct = ColorTable::in_memory(config);
+        let mut id = ct
+            .with_generation(2, |ct| ct.new_color_class(1))
+            .unwrap()
+            .unwrap();
+        let first = id;
+        for g in 3..50 {
+            id = ct
+                .with_generation(g, |ct| ct.extend_color_class(id, 1))
+                .unwrap()
+                .unwrap();
+        }
+
+        let ct_map = ct.map().unwrap();
+        assert_eq!(ct_map.created_in(&id), Some(2));
+        assert_eq!(ct_map.last_extended_in(&id), Some(49));
+        assert_eq!(ct_map.created_in(&first), Some(2));
+        assert_eq!(ct_map.last_extended_in(&first), Some(2));
+        assert_eq!(ct_map.created_in(&ColorId::new(0)), None);
+        assert_eq!(ct_map.color_class(&id).last(), Some((1, 2)));
+        drop(ct_map);
+
+        let ct_map = ct.map_range(10..=20).unwrap();
+        assert_eq!(ct_map.created_in(&id), None);
+    }
+}
+
+#[test]
+fn size_accessors() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder().buffer_size(1024usize).build();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    assert_eq!(ct.fragment_count(), 0);
+    assert_eq!(ct.file_len().unwrap(), 8);
+    assert_eq!(ct.directory(), Some(dir.path()));
+
+    ct.with_generation(0, |ct| {
+        for _ in 0..10 {
+            ct.new_color_class(1).unwrap();
+        }
+    })
+    .unwrap();
+    assert_eq!(ct.fragment_count(), 10);
+    assert_eq!(ct.file_len().unwrap(), 88);
+
+    // the config can be reused to load the table again
+    ct.sync(None).unwrap();
+    let config = ct.config().clone();
+    drop(ct);
+    let ct = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(ct.fragment_count(), 10);
+
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    assert_eq!(ct.directory(), None);
+    assert_eq!(ct.file_len().unwrap(), 8);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_events() {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records the message of every event.
+    #[derive(Default, Clone)]
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Messages {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0.lock().unwrap().push(format!("{value:?}"));
+            }
+        }
+    }
+
+    impl Subscriber for Messages {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            event.record(&mut self.clone());
+        }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    let messages = Messages::default();
+    let dir = tempfile::tempdir().unwrap();
+    tracing::subscriber::with_default(messages.clone(), || {
+        let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+        ct.with_generation(0, |ct| ct.new_color_class(1).unwrap())
+            .unwrap();
+        ct.map().unwrap();
+        ct.sync(None).unwrap();
+    });
+
+    let messages = messages.0.lock().unwrap();
+    for expected in [
+        "generation started",
+        "generation ended",
+        "flushed color table",
+        "mapped color table",
+        "synced color table",
+    ] {
+        assert!(messages.iter().any(|m| m == expected), "{expected}");
+    }
+}
+
+#[test]
+fn metrics() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Default)]
+    struct Counters {
+        fragments: AtomicU64,
+        bytes: AtomicU64,
+        generations: AtomicU64,
+        chain_lengths: AtomicU64,
+    }
+
+    struct Shared(Arc<Counters>);
+
+    impl Metrics for Shared {
+        fn fragments_written(&self, count: u32) {
+            self.0.fragments.fetch_add(count.into(), Ordering::Relaxed);
+        }
+        fn bytes_flushed(&self, bytes: u64) {
+            self.0.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        fn generation_ended(&self, _generation: u64, _fragments: u32) {
+            self.0.generations.fetch_add(1, Ordering::Relaxed);
+        }
+        fn chain_length(&self, length: usize) {
+            self.0
+                .chain_lengths
+                .fetch_add(length as u64, Ordering::Relaxed);
+        }
+    }
+
+    let counters = Arc::new(Counters::default());
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    ct.set_metrics(Box::new(Shared(Arc::clone(&counters))));
+
+    let id = ct
+        .with_generation(0, |ct| {
+            let mut batch = ct.batch();
+            batch.new_color_class(1);
+            batch.new_color_class(2);
+            ct.append_batch(batch)?;
+            ct.new_color_class(3)
+        })
+        .unwrap()
+        .unwrap();
+    let id = ct
+        .with_generation(1, |ct| ct.extend_color_class(id, 4))
+        .unwrap()
+        .unwrap();
+    ct.map().unwrap().color_class(&id).for_each(drop);
+
+    assert_eq!(counters.fragments.load(Ordering::Relaxed), 4);
+    assert_eq!(counters.bytes.load(Ordering::Relaxed), 4 * 8);
+    assert_eq!(counters.generations.load(Ordering::Relaxed), 2);
+    assert_eq!(counters.chain_lengths.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn archive_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let mut rng = fastrand::Rng::with_seed(29);
+    let mut ids = Vec::new();
+    for g in (0..100).step_by(3) {
+        ct.with_generation(g, |ct| {
+            for _ in 0..50 {
+                // generations with only a few samples
+                let color = rng.u32(1..1 << 12);
+                let id = match rng.usize(..3) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(
+                        ids[rng.usize(ids.len().saturating_sub(100)..ids.len())],
+                        color,
+                    ),
+                    _ => ct.extend_color_class(
+                        ids[rng.usize(ids.len().saturating_sub(100)..ids.len())],
+                        color,
+                    ),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+
+    let archive = dir.path().join("archive");
+    let len = ct.write_archive(&archive).unwrap();
+    assert_eq!(std::fs::metadata(&archive).unwrap().len(), len);
+    assert!(len < ct.file_len().unwrap() / 2);
+
+    let dir2 = tempfile::tempdir().unwrap();
+    let ct2 = ColorTable::from_archive(&archive, &dir2, ColorTableConfig::default()).unwrap();
+    assert_eq!(ct2.fragment_count(), ct.fragment_count());
+    let (ct_map, ct2_map) = (ct.map().unwrap(), ct2.map().unwrap());
+    for id in &ids {
+        assert_eq!(
+            ct_map.color_class(id).collect::<Vec<_>>(),
+            ct2_map.color_class(id).collect::<Vec<_>>()
+        );
+    }
+    drop(ct2_map);
+
+    // archives can be written to again
+    ct2.with_generation(100, |ct| ct.new_color_class(1).unwrap())
+        .unwrap();
+
+    assert!(matches!(
+        ColorTable::from_archive(
+            dir.path().join("color_table"),
+            &dir2,
+            ColorTableConfig::default()
+        ),
+        Err(ColorTableError::BadMagic { .. } | ColorTableError::Locked { .. })
+    ));
+}
+
+#[test]
+fn archive_single_bit_colors() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let ids = ct
+        .with_generation(0, |ct| {
+            (0..1000)
+                .map(|i| ct.new_color_class(1 << (i % 32)))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .unwrap()
+        .unwrap();
+
+    let archive = dir.path().join("archive");
+    let len = ct.write_archive(&archive).unwrap();
+    // 2 bytes per fragment, plus the header and generations
+    assert!(len < 2 * 1000 + 32, "{len}");
+
+    let dir2 = tempfile::tempdir().unwrap();
+    let ct2 = ColorTable::from_archive(&archive, &dir2, ColorTableConfig::default()).unwrap();
+    let (ct_map, ct2_map) = (ct.map().unwrap(), ct2.map().unwrap());
+    for id in &ids {
+        assert_eq!(
+            ct_map.color_class(id).collect::<Vec<_>>(),
+            ct2_map.color_class(id).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn removal_fragments() {
+    use std::collections::BTreeSet;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder().skip_interval(3u32).build();
+    let mut ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let mut rng = fastrand::Rng::with_seed(13);
+    let bits = |color: u32, generation: u64| {
+        (0..u32::BITS)
+            .filter(move |bit| color & 1 << bit != 0)
+            .map(move |bit| generation as u32 * u32::BITS + bit)
+    };
+    // the header records that the table contains removal fragments
+    let removals_flag =
+        |dir: &std::path::Path| std::fs::read(dir.join("color_table")).unwrap()[4] & 0b100 != 0;
+
+    let mut ids = Vec::new();
+    let mut expected = Vec::<BTreeSet<u32>>::new();
+    for g in 0..20 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..20 {
+                let (id, set) = match rng.usize(..4) {
+                    _ if ids.is_empty() => {
+                        let color = rng.u32(..);
+                        (ct.new_color_class(color), bits(color, g).collect())
+                    }
+                    0 => {
+                        let color = rng.u32(..);
+                        (ct.new_color_class(color), bits(color, g).collect())
+                    }
+                    1 if g > 0 => {
+                        let i = rng.usize(..ids.len());
+                        let (generation, mask) = (rng.u64(..g), rng.u32(..) & rng.u32(..));
+                        let set = expected[i]
+                            .difference(&bits(mask, generation).collect())
+                            .copied()
+                            .collect();
+                        (ct.extend_color_class_remove(ids[i], generation, mask), set)
+                    }
+                    _ => {
+                        let (i, color) = (rng.usize(..ids.len()), rng.u32(..));
+                        let mut set = expected[i].clone();
+                        set.extend(bits(color, g));
+                        (ct.extend_color_class(ids[i], color), set)
+                    }
+                };
+                ids.push(id.unwrap());
+                expected.push(set);
+            }
+        })
+        .unwrap();
+        if g == 10 {
+            ct.materialize_bitmaps(ids.iter().copied().step_by(3))
+                .unwrap();
+        }
+    }
+
+    let check = |ct: &ColorTable, ids: &[ColorId]| {
+        let ct_map = ct.map().unwrap();
+        let bitmaps = ct_map.color_classes(ids);
+        for ((id, set), bitmap) in ids.iter().zip(&expected).zip(bitmaps) {
+            let set = set.iter().copied().collect::<roaring::RoaringBitmap>();
+            assert_eq!(ct_map.color_class(id).into_bitmap(), set);
+            assert_eq!(bitmap, set);
+            let mut indices = ct_map.color_class(id).into_indices();
+            // a class extended in the generation it was created in may repeat indices
+            indices.sort_unstable();
+            indices.dedup();
+            assert!(indices.iter().map(|&i| i as u32).eq(set.iter()));
+            assert_eq!(
+                ct_map.color_class(id).last(),
+                ct_map.color_class(id).collect::<Vec<_>>().last().copied()
+            );
+        }
+        for pair in ids.windows(2) {
+            let [a, b] = pair else { unreachable!() };
+            assert_eq!(
+                ct_map.intersect_many(pair),
+                ct_map.color_class(a).into_bitmap() & ct_map.color_class(b).into_bitmap()
+            );
+        }
+    };
+    check(&ct, &ids);
+    assert!(removals_flag(dir.path()));
+
+    let sample = expected.last().unwrap().iter().next().copied().unwrap_or(0);
+    let containing = ct.classes_containing(sample.into()).unwrap();
+    for (id, set) in ids.iter().zip(&expected) {
+        assert_eq!(containing.contains(id.as_u32()), set.contains(&sample));
+    }
+
+    let archive = dir.path().join("archive");
+    ct.write_archive(&archive).unwrap();
+    let dir2 = tempfile::tempdir().unwrap();
+    let ct2 = ColorTable::from_archive(&archive, &dir2, config.clone()).unwrap();
+    check(&ct2, &ids);
+    assert!(removals_flag(dir2.path()));
+
+    let report = ct.compact(ids.iter().copied()).unwrap();
+    let ids = ids
+        .iter()
+        .map(|id| report.mapping.get(id).unwrap())
+        .collect::<Vec<_>>();
+    check(&ct, &ids);
+
+    ct.sync(None).unwrap();
+    assert!(removals_flag(dir.path()));
+    drop(ct);
+    let ct = ColorTable::load(&dir, config).unwrap();
+    check(&ct, &ids);
+
+    let err = ct.with_generation(20, |ct| ct.extend_color_class_remove(ids[0], 20, 1));
+    assert!(matches!(
+        err.unwrap(),
+        Err(ColorTableError::InvalidGeneration(20))
+    ));
+}
+
+#[test]
+fn tombstone() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let (cc1, cc2) = ct
+        .with_generation(0, |ct| {
+            (
+                ct.new_color_class(0x1).unwrap(),
+                ct.new_color_class(0x2).unwrap(),
+            )
+        })
+        .unwrap();
+    let cc3 = ct
+        .with_generation(1, |ct| ct.extend_color_class(cc2, 0x4).unwrap())
+        .unwrap();
+
+    ct.tombstone(cc1).unwrap();
+    ct.tombstone(cc2).unwrap();
+    ct.tombstone(cc2).unwrap();
+    assert!(matches!(
+        ct.tombstone(ColorId::new(100)),
+        Err(ColorTableError::InvalidColorId(100))
+    ));
+    assert!(ct.is_tombstoned(&cc1));
+    assert!(!ct.is_tombstoned(&cc3));
+    {
+        let ct_map = ct.map().unwrap();
+        assert_eq!(ct_map.color_class(&cc1).count(), 0);
+        assert_eq!(ct_map.color_class(&cc2).count(), 0);
+        // classes built on a deleted class are kept
+        assert_eq!(
+            ct_map.color_class(&cc3).collect::<Vec<_>>(),
+            vec![(0x4, 1), (0x2, 0)]
+        );
+        assert_eq!(ct_map.iter_heads().collect::<Vec<_>>(), vec![cc3]);
+    }
+
+    ct.sync(None).unwrap();
+    drop(ct);
+    let mut ct = ColorTable::load(&dir, config.clone()).unwrap();
+    assert!(ct.is_tombstoned(&cc1));
+    assert!(ct.is_tombstoned(&cc2));
+
+    let report = ct.compact([cc1, cc2, cc3]).unwrap();
+    assert_eq!(report.fragments_after, 2);
+    assert_eq!(report.mapping.get(&cc1), None);
+    let (cc2, cc3) = (
+        report.mapping.get(&cc2).unwrap(),
+        report.mapping.get(&cc3).unwrap(),
+    );
+    assert!(ct.is_tombstoned(&cc2));
+    assert!(!ct.is_tombstoned(&cc3));
+    assert_eq!(ct.map().unwrap().color_class(&cc2).count(), 0);
+    assert_eq!(ct.map().unwrap().color_class(&cc3).count(), 2);
+}
+
+#[test]
+fn fork_color_class_as_of() {
+    // earlier generations may still be buffered when the chain is walked
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .flush_policy(FlushPolicy::Manual)
+        .build();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    let mut id = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1))
+        .unwrap()
+        .unwrap();
+    for g in [2, 4, 6] {
+        id = ct
+            .with_generation(g, |ct| ct.extend_color_class(id, 1 << g))
+            .unwrap()
+            .unwrap();
+    }
+
+    let forks = ct
+        .with_generation(7, |ct| {
+            let same_gen = ct.new_color_class(0x2).unwrap();
+            // the chain of a class written in this generation can't be walked yet
+            assert!(matches!(
+                ct.fork_color_class_as_of(same_gen, 6, 0x80),
+                Err(ColorTableError::InvalidColorId(_))
+            ));
+            (
+                ct.fork_color_class_as_of(id, 3, 0x80).unwrap(),
+                ct.fork_color_class_as_of(id, 4, 0x80).unwrap(),
+                ct.fork_color_class_as_of(id, 10, 0x80).unwrap(),
+                ct.fork_color_class_as_of(ColorId::NULL, 6, 0x80).unwrap(),
+                ct.fork_color_class_as_of(same_gen, 7, 0x80).unwrap(),
+            )
+        })
+        .unwrap();
+    assert!(
+        ct.with_generation(8, |ct| ct.fork_color_class_as_of(ColorId::new(100), 0, 0x1))
+            .unwrap()
+            .is_err()
+    );
+
+    let ct_map = ct.map().unwrap();
+    let class = |id| ct_map.color_class(&id).collect::<Vec<_>>();
+    assert_eq!(class(forks.0), vec![(0x80, 7), (0x4, 2), (0x1, 0)]);
+    assert_eq!(
+        class(forks.1),
+        vec![(0x80, 7), (0x10, 4), (0x4, 2), (0x1, 0)]
+    );
+    assert_eq!(
+        class(forks.2),
+        vec![(0x80, 7), (0x40, 6), (0x10, 4), (0x4, 2), (0x1, 0)]
+    );
+    assert_eq!(class(forks.3), vec![(0x80, 7)]);
+    assert_eq!(class(forks.4), vec![(0x80, 7), (0x2, 7)]);
+}
+
+#[test]
+fn color_class_as_of() {
+    let config = ColorTableConfig::builder().skip_interval(2u32).build();
+    let ct = std::sync::Arc::new(ColorTable::in_memory(config));
+    let mut id = ct
+        .with_generation(0, |ct| ct.new_color_class(0x1))
+        .unwrap()
+        .unwrap();
+    for g in 1..10 {
+        id = ct
+            .with_generation(g * 2, |ct| ct.extend_color_class(id, 1 << g))
+            .unwrap()
+            .unwrap();
+    }
+    let removed = ct
+        .with_generation(20, |ct| ct.extend_color_class_remove(id, 2, 0x2))
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let owned_map = ct.map_owned().unwrap();
+    for generation in 0..22 {
+        let expected = ct_map
+            .color_class(&removed)
+            .filter(|&(_, g)| g <= generation)
+            .map(|(color, g)| {
+                if generation < 20 && g == 2 {
+                    (0x2, g)
+                } else {
+                    (color, g)
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ct_map
+                .color_class_as_of(&removed, generation)
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            owned_map
+                .color_class_as_of(&removed, generation)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn diff() {
+    let ct = ColorTable::in_memory(
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    );
+    let (a, b) = ct
+        .with_generation(0, |ct| {
+            (
+                ct.new_color_class(0x1).unwrap(),
+                ct.new_color_class(0x2).unwrap(),
+            )
+        })
+        .unwrap();
+    let (a2, c) = ct
+        .with_generation(1, |ct| {
+            (
+                ct.extend_color_class(a, 0x4).unwrap(),
+                ct.new_color_class(0x8).unwrap(),
+            )
+        })
+        .unwrap();
+    let b2 = ct
+        .with_generation(3, |ct| {
+            let b2 = ct.extend_color_class(b, 0x10).unwrap();
+            // removals are not gains
+            let _b3 = ct.extend_color_class_remove(b2, 0, 0x2).unwrap();
+            b2
+        })
+        .unwrap();
+    // neither are empty fragments
+    ct.with_generation(4, |ct| ct.new_color_class(0).unwrap())
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    assert_eq!(
+        ct_map.diff(0, 3).collect::<Vec<_>>(),
+        vec![(a2, 0x4), (c, 0x8), (b2, 0x10)]
+    );
+    assert_eq!(ct_map.diff(1, 4).collect::<Vec<_>>(), vec![(b2, 0x10)]);
+    assert_eq!(ct_map.diff(2, 2).count(), 0);
+    assert_eq!(ct_map.diff(3, 0).count(), 0);
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn renumber_generations() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let mut ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    let mut id = ct
+        .with_generation(10, |ct| ct.new_color_class(0x3))
+        .unwrap()
+        .unwrap();
+    id = ct
+        .with_generation(20, |ct| ct.extend_color_class(id, 0x4))
+        .unwrap()
+        .unwrap();
+    ct.materialize_bitmaps([id]).unwrap();
+    let (removed, dedup) = ct
+        .with_generation(30, |ct| {
+            (
+                ct.extend_color_class_remove(id, 10, 0x1).unwrap(),
+                ct.new_or_existing_color_class(0x8, 1).unwrap(),
+            )
+        })
+        .unwrap();
+    // an empty generation
+    ct.with_generation(40, |_| ()).unwrap();
+
+    let renumber = |i: u32| i / 32 / 10 * 32 + i % 32;
+    let before = [id, removed, dedup].map(|id| ct.map().unwrap().color_class(&id).into_bitmap());
+
+    assert!(matches!(
+        ct.renumber_generations(|g| 100 - g),
+        Err(ColorTableError::GenerationNotIncreasing { .. })
+    ));
+    assert!(matches!(
+        ct.renumber_generations(|g| g << 30),
+        Err(ColorTableError::InvalidGeneration(_))
+    ));
+    assert_eq!(ct.map().unwrap().color_class(&id).into_bitmap(), before[0]);
+
+    ct.renumber_generations(|g| g / 10).unwrap();
+    let check = |ct: &ColorTable| {
+        let ct_map = ct.map().unwrap();
+        for (id, before) in [id, removed, dedup].iter().zip(&before) {
+            let expected = before
+                .iter()
+                .map(renumber)
+                .collect::<roaring::RoaringBitmap>();
+            assert_eq!(ct_map.color_class(id).into_bitmap(), expected);
+        }
+        assert_eq!(ct_map.created_in(&removed), Some(1));
+        assert_eq!(ct_map.last_extended_in(&removed), Some(3));
+    };
+    check(&ct);
+    assert!(
+        ct.with_generation(4, |_| ()).is_err(),
+        "the last generation is now 4"
+    );
+    // the hashes of deduplicated classes are recomputed
+    let hash = ct.map().unwrap().class_hash(&dedup);
+    ct.with_generation(5, |ct| {
+        assert_eq!(ct.new_or_existing_color_class(0x8, hash).unwrap(), dedup);
+    })
+    .unwrap();
+
+    drop(ct);
+    let ct = ColorTable::load(&dir, config).unwrap();
+    check(&ct);
+}
+
+#[test]
+fn generation_policy() {
+    // strictly increasing: repeats are rejected, and the automatic number follows the last
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    ct.with_generation(10, |_| ()).unwrap();
+    assert!(ct.with_generation(10, |_| ()).is_err());
+    assert_eq!(
+        ct.with_next_generation(|ct| ct.generation()).unwrap(),
+        (11, 11)
+    );
+
+    // non-decreasing: a repeated generation is merged with the last one, and an earlier one is
+    // clamped to it
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .generation_policy(GenerationPolicy::NonDecreasing)
+        .build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1))
+        .unwrap()
+        .unwrap();
+    let id = ct
+        .with_generation(1, |ct| ct.extend_color_class(id, 0b01))
+        .unwrap()
+        .unwrap();
+    let (generation, other) = ct
+        .with_generation(0, |ct| (ct.generation(), ct.new_color_class(0b10)))
+        .unwrap();
+    assert_eq!(generation, 1);
+    let other = other.unwrap();
+
+    // a class can't get a second fragment in a merged generation
+    for res in [
+        ct.with_generation(1, |ct| ct.extend_color_class(id, 0b100)),
+        ct.with_generation(1, |ct| ct.fork_color_class(other, 0b100)),
+    ] {
+        assert!(matches!(
+            res.unwrap(),
+            Err(ColorTableError::AlreadyInGeneration { generation: 1, .. })
+        ));
+    }
+    let check = |ct: &ColorTable| {
+        let ct_map = ct.map().unwrap();
+        let mut indices = ct_map.color_class(&id).into_indices();
+        indices.sort_unstable();
+        assert_eq!(indices, [0, 32]);
+        assert_eq!(ct_map.color_class(&other).into_indices(), [33]);
+        assert_eq!(ct_map.created_in(&other), Some(1));
+        assert_eq!(ct_map.last_extended_in(&id), Some(1));
+    };
+    check(&ct);
+    drop(ct);
+    check(&ColorTable::load(&dir, config).unwrap());
+
+    // auto-assign: requested numbers are ignored
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .generation_policy(GenerationPolicy::AutoAssign)
+        .build();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    assert_eq!(ct.with_generation(100, |ct| ct.generation()).unwrap(), 0);
+    assert_eq!(
+        ct.try_with_generation(100, |ct| ct.generation()).unwrap(),
+        Some(1)
+    );
+    assert_eq!(ct.with_next_generation(|_| ()).unwrap().0, 2);
+}
+
+#[test]
+fn with_next_generation() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let (generation, id) = ct
+        .with_next_generation(|ct| ct.new_color_class(0b1))
+        .unwrap();
+    assert_eq!(generation, 0);
+    let id = id.unwrap();
+    ct.with_generation(7, |_| ()).unwrap();
+    drop(ct);
+
+    // the next number is picked from the table's own state after reloading
+    let ct = ColorTable::load(&dir, config).unwrap();
+    let (generation, id) = ct
+        .with_next_generation(|ct| ct.extend_color_class(id, 0b10))
+        .unwrap();
+    assert_eq!(generation, 8);
+    let id = id.unwrap();
+    let ct_map = ct.map().unwrap();
+    assert_eq!(ct_map.created_in(&id), Some(0));
+    assert_eq!(ct_map.last_extended_in(&id), Some(8));
+}
+
+#[test]
+fn generation_state() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    assert_eq!(ct.generation_state(), GenerationStatus::NotStarted);
+
+    ct.with_generation(3, |guard| {
+        assert_eq!(
+            ct.generation_state(),
+            GenerationStatus::InProgress {
+                generation: 3,
+                fragments: 0
+            }
+        );
+        guard.new_color_class(0b1).unwrap();
+        guard.new_color_class(0b10).unwrap();
+        assert_eq!(
+            ct.generation_state(),
+            GenerationStatus::InProgress {
+                generation: 3,
+                fragments: 2
+            }
+        );
+    })
+    .unwrap();
+    assert_eq!(ct.generation_state(), GenerationStatus::Idle { last: 3 });
+}
+
+#[test]
+fn wait_for_generation_end() {
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let ct = std::sync::Arc::new(ColorTable::new(&dir, ColorTableConfig::default()).unwrap());
+    assert!(ct.wait_for_generation_end(Duration::ZERO));
+
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (end_tx, end_rx) = std::sync::mpsc::channel::<()>();
+    let writer = std::thread::spawn({
+        let ct = ct.clone();
+        move || {
+            ct.with_generation(1, |ct| {
+                let id = ct.new_color_class(0b1).unwrap();
+                started_tx.send(id).unwrap();
+                end_rx.recv().unwrap();
+            })
+            .unwrap();
+        }
+    });
+
+    let id = started_rx.recv().unwrap();
+    assert!(!ct.wait_for_generation_end(Duration::from_millis(10)));
+    end_tx.send(()).unwrap();
+    assert!(ct.wait_for_generation_end(Duration::from_secs(60)));
+    assert_eq!(ct.map().unwrap().created_in(&id), Some(1));
+    writer.join().unwrap();
+}
+
+#[test]
+fn concurrent_batches() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .generation_policy(GenerationPolicy::NonDecreasing)
+        .build();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    let base = ct
+        .with_generation(0, |ct| {
+            (0..4)
+                .map(|_| ct.new_color_class(0b1).unwrap())
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+    // the batches are all bound to generation 1, so they are merged into it whatever order the
+    // threads commit them in
+    let batches = (0..base.len()).map(|_| ct.batch(1)).collect::<Vec<_>>();
+
+    // each thread extends its own color class and creates new ones, without holding the generation lock
+    let ranges = std::thread::scope(|s| {
+        let handles = base
+            .iter()
+            .zip(batches)
+            .enumerate()
+            .map(|(i, (&id, mut batch))| {
+                let ct = &ct;
+                s.spawn(move || {
+                    let extended = batch.extend_color_class(id, 1 << i).unwrap();
+                    for _ in 0..100 {
+                        batch.new_color_class(1 << i);
+                    }
+                    let range = ct.commit_batch(batch).unwrap();
+                    (range.get(extended).unwrap(), range)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let ct_map = ct.map().unwrap();
+    for (i, (extended, range)) in ranges.iter().enumerate() {
+        assert_eq!(ct_map.last_extended_in(extended), Some(1));
+        let mut indices = ct_map.color_class(extended).into_indices();
+        indices.sort_unstable();
+        assert_eq!(indices, [0, 32 + i]);
+        assert_eq!(range.len(), 101);
+        for id in range.iter().skip(1) {
+            assert_eq!(ct_map.color_class(&id).into_indices(), [32 + i]);
+        }
+    }
+    drop(ct_map);
+
+    // the policy still applies to batches: an earlier generation is clamped to the last one, which
+    // can't hold another fragment of a class
+    let mut batch = ct.batch(0);
+    assert_eq!(batch.generation(), 1);
+    batch.extend_color_class(ranges[0].0, 0b1).unwrap();
+    assert!(matches!(
+        ct.commit_batch(batch),
+        Err(ColorTableError::AlreadyInGeneration { generation: 1, .. })
+    ));
+
+    // batches are numbered in the order they are created under auto-assign
+    let config = ColorTableConfig::builder()
+        .generation_policy(GenerationPolicy::AutoAssign)
+        .build();
+    let ct = ColorTable::in_memory(config);
+    ct.with_generation(0, |_| ()).unwrap();
+    let batches = [ct.batch(0), ct.batch(0)];
+    assert_eq!(batches.each_ref().map(|batch| batch.generation()), [1, 2]);
+    drop(batches);
+    assert_eq!(ct.with_generation(0, |ct| ct.generation()).unwrap(), 1);
+}
+
+#[test]
+fn batch_generation_order() {
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    let mut first = ct.batch(5);
+    let mut second = ct.batch(6);
+    first.new_color_class(0b1);
+    second.new_color_class(0b10);
+
+    // the batch for generation 6 can't be committed before the batch for generation 5
+    let second = match ct.commit_batch(second) {
+        Err(
+            e @ ColorTableError::EarlierBatchPending {
+                batch: 6,
+                pending: 5,
+            },
+        ) => {
+            assert!(e.is_transient());
+            let mut second = ct.batch(6);
+            second.new_color_class(0b10);
+            second
+        }
+        res => panic!("expected the commit to fail, got {res:?}"),
+    };
+    let first_range = ct.commit_batch(first).unwrap();
+    let second_range = ct.commit_batch(second).unwrap();
+    let ct_map = ct.map().unwrap();
+    assert_eq!(
+        ct_map
+            .color_class(&first_range.get(0).unwrap())
+            .into_indices(),
+        [5 * 32]
+    );
+    assert_eq!(
+        ct_map
+            .color_class(&second_range.get(0).unwrap())
+            .into_indices(),
+        [6 * 32 + 1]
+    );
+    drop(ct_map);
+
+    // a dropped batch doesn't hold back later ones
+    drop(ct.batch(7));
+    let mut batch = ct.batch(8);
+    batch.new_color_class(0b1);
+    ct.commit_batch(batch).unwrap();
+
+    // a batch bound to another generation can't be appended in a generation
+    let batch = ct.batch(9);
+    assert!(matches!(
+        ct.with_generation(10, |guard| guard.append_batch(batch))
+            .unwrap(),
+        Err(ColorTableError::BatchGeneration {
+            batch: 9,
+            generation: 10
+        })
+    ));
+}
+
+#[test]
+fn batches_extend_one_class() {
+    let config = ColorTableConfig::builder().deferred_heads(true).build();
+    let ct = ColorTable::in_memory(config);
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1).unwrap())
+        .unwrap();
+
+    // both batches are filled before either is committed, so the second one only finds the
+    // extension made by the first when it is committed
+    let mut first = ct.batch(1);
+    let mut second = ct.batch(2);
+    first.extend_color_class(id, 0b10).unwrap();
+    let offset = second.extend_color_class(id, 0b100).unwrap();
+    ct.commit_batch(first).unwrap();
+    let extended = ct.commit_batch(second).unwrap().get(offset).unwrap();
+
+    let mut indices = ct.map().unwrap().color_class(&extended).into_indices();
+    indices.sort_unstable();
+    assert_eq!(indices, [0, 33, 66]);
+    assert_eq!(ct.canonicalize(id), extended);
+}
+
+#[test]
+fn deferred_heads() {
+    fn indices(ct: &ColorTable, id: &ColorId) -> Vec<usize> {
+        let mut indices = ct.map().unwrap().color_class(id).into_indices();
+        indices.sort_unstable();
+        indices
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder().deferred_heads(true).build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1))
+        .unwrap()
+        .unwrap();
+
+    // forks within the generation start from the old head
+    let (extended, forked) = ct
+        .with_generation(1, |ct| {
+            let extended = ct.extend_color_class(id, 0b1).unwrap();
+            (extended, ct.fork_color_class(id, 0b10).unwrap())
+        })
+        .unwrap();
+    assert_eq!(indices(&ct, &extended), [0, 32]);
+    assert_eq!(indices(&ct, &forked), [0, 33]);
+    let first_extended = extended;
+
+    // the original id now writes after the new head
+    let extended = ct
+        .with_generation(2, |ct| ct.extend_color_class(id, 0b1))
+        .unwrap()
+        .unwrap();
+    assert_eq!(indices(&ct, &extended), [0, 32, 64]);
+    let range = ct
+        .with_generation(3, |ct| {
+            let mut batch = ct.batch();
+            batch.extend_color_class(id, 0b1).unwrap();
+            ct.append_batch(batch)
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(indices(&ct, &range.get(0).unwrap()), [0, 32, 64, 96]);
+
+    // heads are kept across reloads
+    ct.sync(None).unwrap();
+    drop(ct);
+    let heads_file = dir.path().join("heads");
+    let synced = std::fs::read(&heads_file).unwrap();
+    let ct = ColorTable::load(&dir, config.clone()).unwrap();
+    let extended = ct
+        .with_generation(4, |ct| ct.extend_color_class(id, 0b1))
+        .unwrap()
+        .unwrap();
+    assert_eq!(indices(&ct, &extended), [0, 32, 64, 96, 128]);
+
+    // a sync only appends the new links (from the old head and from the original id)
+    ct.sync(None).unwrap();
+    let appended = std::fs::read(&heads_file).unwrap();
+    assert_eq!(appended.len(), synced.len() + 2 * 8);
+    assert_eq!(appended[..synced.len()], synced);
+    // ids of earlier heads resolve to the current one
+    assert_eq!(ct.canonicalize(first_extended), extended);
+    assert_eq!(ct.canonicalize(id), extended);
+    drop(ct);
+    let ct = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(ct.canonicalize(first_extended), extended);
+
+    // without deferred heads, the original id refers to the first fragment
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1))
+        .unwrap()
+        .unwrap();
+    ct.with_generation(1, |ct| ct.extend_color_class(id, 0b1).unwrap())
+        .unwrap();
+    let extended = ct
+        .with_generation(2, |ct| ct.extend_color_class(id, 0b1))
+        .unwrap()
+        .unwrap();
+    assert_eq!(indices(&ct, &extended), [0, 64]);
+}
+
+#[test]
+fn canonicalize() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder().deferred_heads(true).build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1))
+        .unwrap()
+        .unwrap();
+    assert_eq!(ct.canonicalize(id), id);
+
+    let first = ct
+        .with_generation(1, |guard| {
+            let extended = guard.extend_color_class(id, 0b1).unwrap();
+            // the new head is published when the generation ends
+            assert_eq!(ct.canonicalize(id), id);
+            extended
+        })
+        .unwrap();
+    assert_eq!(ct.canonicalize(id), first);
+    assert_eq!(ct.canonicalize(first), first);
+
+    let second = ct
+        .with_generation(2, |ct| ct.extend_color_class(first, 0b1))
+        .unwrap()
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let ct = ColorTable::load(&dir, config).unwrap();
+    for old in [id, first, second] {
+        assert_eq!(ct.canonicalize(old), second);
+    }
+    let mut indices = ct
+        .map()
+        .unwrap()
+        .color_class(&ct.canonicalize(id))
+        .into_indices();
+    indices.sort_unstable();
+    assert_eq!(indices, [0, 32, 64]);
+}
+
+#[test]
+fn id_kind() {
+    for deferred_heads in [false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ColorTableConfig::builder()
+            .deferred_heads(deferred_heads)
+            .build();
+        let ct = ColorTable::new(&dir, config).unwrap();
+        let (id, other) = ct
+            .with_generation(0, |ct| {
+                (
+                    ct.new_color_class(0b1).unwrap(),
+                    ct.new_color_class(0b10).unwrap(),
+                )
+            })
+            .unwrap();
+        let extended = ct
+            .with_generation(1, |ct| ct.extend_color_class(id, 0b1))
+            .unwrap()
+            .unwrap();
+
+        let ct_map = ct.map().unwrap();
+        let head = deferred_heads.then_some(extended);
+        assert_eq!(ct_map.id_kind(&id), IdKind::Interior { head });
+        assert_eq!(ct_map.id_kind(&other), IdKind::Head);
+        assert_eq!(ct_map.id_kind(&extended), IdKind::Head);
+        assert_eq!(ct_map.id_kind(&ColorId::new(0)), IdKind::Null);
+        assert_eq!(ct_map.id_kind(&ColorId::new(100)), IdKind::OutOfRange);
+        drop(ct_map);
+        let ct = std::sync::Arc::new(ct);
+        let owned = ct.map_owned().unwrap();
+        assert_eq!(owned.id_kind(&other), IdKind::Head);
+    }
+}
+
+#[test]
+fn null_color_class() {
+    assert!(ColorId::NULL.is_null());
+    assert_eq!(ColorId::NULL, ColorId::new(0));
+
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(
+        &dir,
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    )
+    .unwrap();
+    assert_eq!(ct.map().unwrap().color_class(&ColorId::NULL).count(), 0);
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0))
+        .unwrap()
+        .unwrap();
+    assert!(!id.is_null());
+    assert_eq!(ct.map().unwrap().color_class(&ColorId::NULL).count(), 0);
+
+    let dir = tempfile::tempdir().unwrap();
+    // rejected by default
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    ct.with_generation(0, |ct| {
+        assert!(matches!(
+            ct.new_color_class(0),
+            Err(ColorTableError::ZeroColor)
+        ));
+        let id = ct.new_color_class(0b1).unwrap();
+        assert!(matches!(
+            ct.extend_color_class(id, 0),
+            Err(ColorTableError::ZeroColor)
+        ));
+        let mut batch = ct.batch();
+        batch.new_color_class(0);
+        assert!(matches!(
+            ct.append_batch(batch),
+            Err(ColorTableError::ZeroColor)
+        ));
+    })
+    .unwrap();
+    assert_eq!(ct.fragment_count(), 1);
+}
+
+#[test]
+fn skip_zero_colors() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .zero_colors(ZeroColorPolicy::Skip)
+        .build();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    let id = ct
+        .with_generation(0, |ct| {
+            assert_eq!(ct.new_color_class(0).unwrap(), ColorId::NULL);
+            ct.new_color_class(0b1).unwrap()
+        })
+        .unwrap();
+    let (extended, forked) = ct
+        .with_generation(1, |ct| {
+            (
+                ct.extend_color_class(id, 0).unwrap(),
+                ct.fork_color_class(id, 0).unwrap(),
+            )
+        })
+        .unwrap();
+    assert_eq!(extended, id);
+    // forks still get an id of their own
+    assert_ne!(forked, id);
+    assert_eq!(ct.fragment_count(), 2);
+    assert_eq!(ct.map().unwrap().color_class(&forked).into_indices(), [0]);
+}
+
+#[test]
+fn little_endian_fragments() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let id = ct
+        .with_generation(0, |ct| ct.new_color_class(0x0102_0304))
+        .unwrap()
+        .unwrap();
+    ct.with_generation(1, |ct| ct.extend_color_class(id, 0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let path = dir.path().join("color_table");
+    let bytes = std::fs::read(&path).unwrap();
+    assert_eq!(bytes[7], 3);
+    assert_eq!(bytes[8..16], [0, 0, 0, 0, 4, 3, 2, 1]);
+    assert_eq!(bytes[16..24], [1, 0, 0, 0, 1, 0, 0, 0]);
+
+    // version 2 files have the same layout on little-endian hosts
+    if cfg!(target_endian = "little") {
+        let mut bytes = bytes;
+        bytes[7] = 2;
+        std::fs::write(&path, &bytes).unwrap();
+        let ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+        assert_eq!(ct.map().unwrap().color_class(&ColorId::new(2)).count(), 2);
+    }
+}
+
+#[test]
+fn flat_generations_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let flat = ColorTableConfig::builder()
+        .generations_format(GenerationsFormat::Flat)
+        .build();
+    let ct = ColorTable::new(&dir, flat.clone()).unwrap();
+    let mut ids = Vec::new();
+    for generation in [0, 3, 7] {
+        ids.push(
+            ct.with_generation(generation, |ct| ct.new_color_class(0b1))
+                .unwrap()
+                .unwrap(),
+        );
+    }
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let bytes = std::fs::read(dir.path().join("generations")).unwrap();
+    assert_eq!(&bytes[..4], b"CTGN");
+    assert_eq!(bytes.len(), 24 + 3 * 16);
+
+    // the format is detected on load, and the configured format is used for the next sync
+    let ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    let check = |ct: &ColorTable| {
+        let ct_map = ct.map().unwrap();
+        for (id, generation) in ids.iter().zip([0, 3, 7]) {
+            assert_eq!(ct_map.created_in(id), Some(generation));
+        }
+    };
+    check(&ct);
+    ct.sync(None).unwrap();
+    drop(ct);
+    let bytes = std::fs::read(dir.path().join("generations")).unwrap();
+    assert_ne!(&bytes[..4], b"CTGN");
+    let ct = ColorTable::load(&dir, flat).unwrap();
+    check(&ct);
+
+    // a truncated flat file is rejected
+    drop(ct);
+    std::fs::write(dir.path().join("generations"), &b"CTGN\x01\0\0\0"[..]).unwrap();
+    assert!(matches!(
+        ColorTable::load(&dir, ColorTableConfig::default()),
+        Err(ColorTableError::Deserialization(_))
+    ));
+}
+
+#[test]
+fn mapped_flat_generations() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .generations_format(GenerationsFormat::Flat)
+        .generation_policy(GenerationPolicy::NonDecreasing)
+        .build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let mut ids = Vec::new();
+    for generation in [1, 2, 4] {
+        ids.push((
+            ct.with_generation(generation, |ct| ct.new_color_class(0b1))
+                .unwrap()
+                .unwrap(),
+            generation,
+        ));
+    }
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    // generations read in place from the file are combined with ones started after loading,
+    // including a repeat of the last mapped generation
+    let ct = ColorTable::load(&dir, config.clone()).unwrap();
+    for generation in [4, 5] {
+        ids.push((
+            ct.with_generation(generation, |ct| ct.new_color_class(0b10))
+                .unwrap()
+                .unwrap(),
+            generation,
+        ));
+    }
+    let check = |ct: &ColorTable| {
+        let ct_map = ct.map().unwrap();
+        for (id, generation) in &ids {
+            assert_eq!(ct_map.created_in(id), Some(*generation));
+        }
+    };
+    check(&ct);
+
+    // the mapped file is replaced, not truncated, by a sync
+    ct.sync(None).unwrap();
+    check(&ct);
+    drop(ct);
+    let bytes = std::fs::read(dir.path().join("generations")).unwrap();
+    assert_eq!(bytes.len(), 24 + 4 * 16);
+
+    let ct = ColorTable::load(&dir, config).unwrap();
+    check(&ct);
+    assert!(ct.verify(VerifyLevel::Full).unwrap().is_ok());
+}
+
+#[test]
+fn tsv_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let a = ct
+        .with_generation(0, |ct| ct.new_color_class(0b101))
+        .unwrap()
+        .unwrap();
+    let b = ct
+        .with_generation(2, |ct| ct.extend_color_class(a, 0b1))
+        .unwrap()
+        .unwrap();
+    let c = ct
+        .with_generation(3, |ct| ct.new_color_class(1 << 31))
+        .unwrap()
+        .unwrap();
+
+    let mut tsv = Vec::new();
+    ct.dump_tsv(&mut tsv, [a, b, c, ColorId::NULL]).unwrap();
+    let text = String::from_utf8(tsv.clone()).unwrap();
+    assert_eq!(
+        text,
+        format!(
+            "{}\t0,2\n{}\t0,2,64\n{}\t127\n0\t\n",
+            a.as_u32(),
+            b.as_u32(),
+            c.as_u32()
+        )
+    );
+
+    let imported_dir = tempfile::tempdir().unwrap();
+    let imported = ColorTable::new(&imported_dir, ColorTableConfig::default()).unwrap();
+    let mapping = imported.import_tsv(&tsv[..]).unwrap();
+    assert_eq!(mapping.len(), 4);
+    assert_eq!(mapping.get(&ColorId::NULL), Some(ColorId::NULL));
+    let ct_map = imported.map().unwrap();
+    for (id, indices) in [(a, vec![0, 2]), (b, vec![0, 2, 64]), (c, vec![127])] {
+        let mut got = ct_map
+            .color_class(&mapping.get(&id).unwrap())
+            .into_indices();
+        got.sort_unstable();
+        assert_eq!(got, indices);
+    }
+    // one generation per window of 32 indices that holds any index
+    assert_eq!(ct_map.created_in(&mapping.get(&c).unwrap()), Some(3));
+    drop(ct_map);
+
+    let err = imported.import_tsv(&b"1\t3\n1\t4\n"[..]).unwrap_err();
+    assert!(matches!(err, ColorTableError::InvalidTsv { line: 2, .. }));
+    let err = imported.import_tsv(&b"1 3\n"[..]).unwrap_err();
+    assert_eq!(err.code(), "invalid_tsv");
+}
+
+#[test]
+fn dump_structure() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let a = ct
+        .with_generation(1, |ct| ct.new_color_class(0b101))
+        .unwrap()
+        .unwrap();
+    ct.with_generation(2, |ct| {
+        ct.extend_color_class(a, 0b1).unwrap();
+        ct.new_color_class(0xff).unwrap();
+    })
+    .unwrap();
+
+    let mut json = Vec::new();
+    ct.dump_structure(&mut json, None).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains(r#""fragment_count": 3,"#));
+    assert!(json.contains(r#""state": {"kind": "idle", "last": 2},"#));
+    assert!(json.contains(r#"{"generation": 2, "start": 2, "end": 4, "fragments": 2}"#));
+    assert!(!json.contains("\"fragments\": ["));
+
+    let mut json = Vec::new();
+    ct.dump_structure(&mut json, Some(2)).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains(r#"{"index": 2, "parent": 1, "color": "0x00000001", "removal": false}"#));
+    assert!(!json.contains(r#""index": 3"#));
+    assert!(json.contains(r#""fragments_truncated": true"#));
+    assert!(json.trim_end().ends_with('}'));
+}
+
+#[test]
+fn dot_graph() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let (base, forked, extended) = ct
+        .with_generation(1, |ct| {
+            let base = ct.new_color_class(0b11).unwrap();
+            let forked = ct.fork_color_class(base, 0b100).unwrap();
+            let extended = ct.extend_color_class(base, 0b1000).unwrap();
+            (base, forked, extended)
+        })
+        .unwrap();
+
+    let mut dot = Vec::new();
+    ct.map()
+        .unwrap()
+        .to_dot(&[forked, extended], &mut dot)
+        .unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+    assert!(dot.starts_with("digraph"));
+    // the shared base fragment appears once, with an edge from each child
+    let base_node = format!(
+        "  f{} [label=\"{}\\ngen 1\\n2 bits\"];",
+        base.as_u32(),
+        base.as_u32()
+    );
+    assert_eq!(dot.matches(&base_node).count(), 1, "{dot}");
+    for child in [forked, extended] {
+        assert!(dot.contains(&format!("  f{} -> f{};", child.as_u32(), base.as_u32())));
+        assert!(dot.contains(&format!("  f{} [label=", child.as_u32())));
+    }
+    assert!(dot.contains(", style=bold];"));
+    assert_eq!(dot.matches(" -> ").count(), 2);
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn import_bitmaps() {
+    use color_table::roaring::RoaringBitmap;
+
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let bitmaps = [
+        RoaringBitmap::from_iter([0, 5, 40, 1000]),
+        RoaringBitmap::new(),
+        RoaringBitmap::from_iter([40, 41]),
+    ];
+    let ids = ct.import_bitmaps(&bitmaps).unwrap();
+    assert_eq!(ids.len(), 3);
+    assert!(ids[1].is_null());
+
+    let ct_map = ct.map().unwrap();
+    for (id, bitmap) in ids.iter().zip(&bitmaps) {
+        assert_eq!(&ct_map.color_class(id).into_bitmap(), bitmap);
+    }
+    assert_eq!(ct_map.created_in(&ids[2]), Some(1));
+    drop(ct_map);
+    assert_eq!(
+        ct.classes_containing(41).unwrap(),
+        RoaringBitmap::from_iter([ids[2].as_u32()])
+    );
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn color_resolver() {
+    use color_table::ColorResolver;
+
+    fn count_samples(resolver: &impl ColorResolver, ids: &[ColorId]) -> u64 {
+        ids.iter().map(|id| resolver.samples(*id).len()).sum()
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let ct = std::sync::Arc::new(ColorTable::new(&dir, ColorTableConfig::default()).unwrap());
+    let ids = ct
+        .with_generation(0, |ct| {
+            [
+                ct.new_color_class(0b11).unwrap(),
+                ct.new_color_class(0b100).unwrap(),
+            ]
+        })
+        .unwrap();
+
+    assert_eq!(count_samples(&ct.map().unwrap(), &ids), 3);
+    let owned = ct.map_owned().unwrap();
+    assert_eq!(owned.samples(ids[1]).iter().collect::<Vec<_>>(), [2]);
+    assert_eq!(count_samples(&&owned, &ids), 3);
+}
+
+#[test]
+fn matrix_builder() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let mut builder = ColorTableBuilder::new(&ct, 0);
+
+    // 40 samples: two windows
+    let mut a = [false; 40];
+    a[1] = true;
+    a[35] = true;
+    let mut b = [false; 40];
+    b[0] = true;
+    builder
+        .add_epoch([("a", &a[..]), ("b", &b[..]), ("empty", &[false; 40][..])])
+        .unwrap();
+    assert_eq!(builder.next_generation(), 2);
+    assert_eq!(builder.get(&"empty"), None);
+
+    // a second epoch of packed rows extends known keys; the empty first window writes nothing
+    builder
+        .add_packed_epoch([("a", &[0, 0b10][..]), ("c", &[0, 0b1][..])])
+        .unwrap();
+    assert_eq!(builder.next_generation(), 4);
+
+    let ids = builder.into_ids();
+    let ct_map = ct.map().unwrap();
+    let indices = |key: &str| {
+        let mut indices = ct_map.color_class(&ids[key]).into_indices();
+        indices.sort_unstable();
+        indices
+    };
+    assert_eq!(indices("a"), [1, 35, 97]);
+    assert_eq!(indices("b"), [0]);
+    assert_eq!(indices("c"), [96]);
+    assert_eq!(ct_map.created_in(&ids["c"]), Some(3));
+}
+
+#[test]
+fn write_with_samples() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(
+        &dir,
+        ColorTableConfig::builder()
+            .zero_colors(ZeroColorPolicy::Allow)
+            .build(),
+    )
+    .unwrap();
+    let a = ct
+        .with_generation(0, |ct| ct.new_with_samples(&[0, 31, 31]))
+        .unwrap()
+        .unwrap();
+    let (extended, forked) = ct
+        .with_generation(1, |ct| {
+            (
+                ct.extend_with_samples(a, &[4]).unwrap(),
+                ct.fork_with_samples(a, &[]).unwrap(),
+            )
+        })
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let mut indices = ct_map.color_class(&extended).into_indices();
+    indices.sort_unstable();
+    assert_eq!(indices, [0, 31, 36]);
+    assert_eq!(
+        ct_map.color_class(&forked).collect::<Vec<_>>(),
+        [(0, 1), ((1 << 31) | 1, 0)]
+    );
+}
+
+#[test]
+fn fragment_payloads() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let (a, b) = ct
+        .with_generation(0, |ct| {
+            let a = ct.new_color_class(0b1).unwrap();
+            let b = ct.new_color_class(0b10).unwrap();
+            ct.set_payload(a, 42).unwrap();
+            ct.set_payload(b, 7).unwrap();
+            assert!(matches!(
+                ct.set_payload(ColorId::new(100), 1),
+                Err(ColorTableError::InvalidColorId(100))
+            ));
+            (a, b)
+        })
+        .unwrap();
+
+    let check = |ct: &ColorTable, a: ColorId, b: ColorId| {
+        let ct_map = ct.map().unwrap();
+        assert_eq!(ct_map.payload_of(&a.into()), Some(42));
+        assert_eq!(ct_map.payload_of(&b.into()), Some(7));
+        assert_eq!(
+            ct_map.payload_of(&ColorFragmentIndex::from(ColorId::new(100))),
+            None
+        );
+    };
+    check(&ct, a, b);
+
+    // payloads are persisted, and move with their fragments on compaction
+    ct.sync(None).unwrap();
+    drop(ct);
+    let mut ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    check(&ct, a, b);
+    let report = ct.compact([b]).unwrap();
+    let b = report.mapping.get(&b).unwrap();
+    let ct_map = ct.map().unwrap();
+    assert_eq!(ct_map.payload_of(&b.into()), Some(7));
+}
+
+#[test]
+fn counts_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .color_mode(ColorMode::Counts)
+        .build();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    let a = ct
+        .with_generation(0, |ct| ct.new_with_counts(&[3, 0, 20]))
+        .unwrap()
+        .unwrap();
+    let b = ct
+        .with_generation(2, |ct| {
+            assert!(matches!(
+                ct.extend_color_class_remove(a, 0, 1),
+                Err(ColorTableError::UnsupportedColorMode {
+                    mode: ColorMode::Counts
+                })
+            ));
+            ct.extend_with_counts(a, &[0, 0, 0, 0, 0, 0, 0, 1])
+        })
+        .unwrap()
+        .unwrap();
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    // the mode is stored in the file, so it is used even if the config says otherwise
+    let ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    assert_eq!(
+        ct.map().unwrap().color_class(&b).into_counts(),
+        [(0, 3), (2, 15), (23, 1)]
+    );
+    ct.with_generation(3, |ct| {
+        assert!(ct.new_with_counts(&[1]).is_ok());
+    })
+    .unwrap();
+
+    // presence tables can't be written with counts, or merged with count tables
+    let presence_dir = tempfile::tempdir().unwrap();
+    let presence = ColorTable::new(&presence_dir, ColorTableConfig::default()).unwrap();
+    presence
+        .with_generation(0, |ct| {
+            assert!(matches!(
+                ct.new_with_counts(&[1]),
+                Err(ColorTableError::UnsupportedColorMode {
+                    mode: ColorMode::Presence
+                })
+            ));
+        })
+        .unwrap();
+    assert!(matches!(
+        presence.merge_from(&ct, 10),
+        Err(ColorTableError::UnsupportedColorMode { .. })
+    ));
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn color_classes_shared_prefixes() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    let base = ct
+        .with_generation(0, |ct| ct.new_color_class(0b1011))
+        .unwrap()
+        .unwrap();
+    let base = ct
+        .with_generation(1, |ct| ct.extend_color_class(base, 0b1))
+        .unwrap()
+        .unwrap();
+    // a tree of forks sharing `base`, one of which removes an index of the shared prefix
+    let (a, b, removed) = ct
+        .with_generation(2, |ct| {
+            (
+                ct.fork_color_class(base, 0b10).unwrap(),
+                ct.fork_color_class(base, 0b100).unwrap(),
+                ct.fork_color_class(base, 0b1000).unwrap(),
+            )
+        })
+        .unwrap();
+    let (a2, removed) = ct
+        .with_generation(3, |ct| {
+            (
+                ct.fork_color_class(a, 0b1).unwrap(),
+                ct.extend_color_class_remove(removed, 0, 0b10).unwrap(),
+            )
+        })
+        .unwrap();
+    let tombstoned = ct
+        .with_generation(4, |ct| ct.fork_color_class(b, 0b1))
+        .unwrap()
+        .unwrap();
+    ct.tombstone(tombstoned).unwrap();
+
+    let ct_map = ct.map().unwrap();
+    let ids = [a2, a, b, removed, base, a, ColorId::NULL, tombstoned];
+    let batch = ct_map.color_classes(&ids);
+    for (id, bitmap) in ids.iter().zip(&batch) {
+        assert_eq!(bitmap, &ct_map.color_class(id).into_bitmap(), "{id:?}");
+    }
+    assert!(!batch[3].contains(1));
+    assert!(batch[4].contains(1));
+}
+
+#[test]
+fn similar_classes() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder().sketch_size(128usize).build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let (a, b, c) = ct
+        .with_generation(0, |ct| {
+            (
+                ct.new_color_class(u32::MAX).unwrap(),
+                ct.new_color_class(u32::MAX).unwrap(),
+                ct.new_color_class(0xffff).unwrap(),
+            )
+        })
+        .unwrap();
+    let (a, b, c) = ct
+        .with_generation(1, |ct| {
+            (
+                ct.extend_color_class(a, 0xff).unwrap(),
+                ct.extend_color_class(b, 0x7f).unwrap(),
+                ct.extend_color_class(c, 0xff00_0000).unwrap(),
+            )
+        })
+        .unwrap();
+
+    // a and b share 39 of 40 indices, c shares 16 of 48 with a
+    assert_eq!(ct.similar_classes(&a, 0.9).unwrap(), [b]);
+    assert_eq!(ct.similar_classes(&a, 0.2).unwrap(), [b, c]);
+
+    // removals are verified exactly: c now shares 16 of 40 indices with a
+    let c = ct
+        .with_generation(2, |ct| ct.extend_color_class_remove(c, 1, 0xff00_0000))
+        .unwrap()
+        .unwrap();
+    assert_eq!(ct.similar_classes(&a, 0.35).unwrap(), [b, c]);
+    assert_eq!(ct.similar_classes(&a, 0.5).unwrap(), [b]);
+
+    // sketches are rebuilt when the table is loaded
+    ct.sync(None).unwrap();
+    drop(ct);
+    let ct = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(ct.similar_classes(&a, 0.35).unwrap(), [b, c]);
+    assert_eq!(ct.similar_classes(&ColorId::NULL, 0.0).unwrap(), []);
+
+    let ct = ColorTable::in_memory(ColorTableConfig::default());
+    assert!(matches!(
+        ct.similar_classes(&a, 0.5),
+        Err(ColorTableError::SketchesDisabled)
+    ));
+}
+
+#[test]
+fn disk_quota() {
+    let dir = tempfile::tempdir().unwrap();
+    // the header and 3 fragments
+    let config = ColorTableConfig::builder().max_file_size(32u64).build();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let res = ct
+        .with_generation(0, |ct| {
+            let a = ct.new_color_class(1)?;
+            ct.extend_color_class(a, 2)?;
+            ct.new_color_class(4)?;
+            ct.new_color_class(8)
+        })
+        .unwrap();
+    assert!(matches!(
+        res,
+        Err(ColorTableError::QuotaExceeded {
+            bytes: 8,
+            available: 0
+        })
+    ));
+    assert_eq!(ct.fragment_count(), 3);
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let ct = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(ct.fragment_count(), 3);
+
+    // writes that reach the disk need more free space than any volume has
+    #[cfg(unix)]
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ColorTableConfig::builder()
+            .buffer_size(16usize)
+            .min_free_space(u64::MAX)
+            .build();
+        let ct = ColorTable::new(&dir, config).unwrap();
+        let res = ct
+            .with_generation(0, |ct| {
+                (0..4).try_for_each(|_| ct.new_color_class(1).map(drop))
+            })
+            .unwrap();
+        assert!(matches!(res, Err(ColorTableError::QuotaExceeded { .. })));
+        assert_eq!(ct.fragment_count(), 1);
+    }
+}
+
+#[test]
+fn load_repaired() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    for generation in 0..2 {
+        ct.with_generation(generation, |ct| ct.new_color_class(1))
+            .unwrap()
+            .unwrap();
+    }
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    // a crash while writing a third generation leaves 2 whole fragments and part of a third
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("color_table"))
+        .unwrap();
+    file.write_all(&[0; 2 * size_of::<ColorFragment>() + 5])
+        .unwrap();
+    drop(file);
+    assert!(matches!(
+        ColorTable::load(&dir, config.clone()),
+        Err(ColorTableError::TrailingBytes { len, .. }) if len as usize == 5 * size_of::<ColorFragment>() + 5
+    ));
+
+    let (ct, report) = ColorTable::load_repaired(&dir, config.clone()).unwrap();
+    assert_eq!((report.trailing_bytes, report.fragments), (5, 2));
+    assert!(!report.is_clean());
+    assert_eq!(ct.fragment_count(), 2);
+    assert!(ct.verify(VerifyLevel::Full).unwrap().is_ok());
+    drop(ct);
+
+    let (_, report) = ColorTable::load_repaired(&dir, config).unwrap();
+    assert!(report.is_clean());
+}
+
+#[test]
+fn uncovered_fragments() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+
+    // fragments of the generation in progress can be iterated
+    let classes = ct
+        .with_generation(0, |g| {
+            let a = g.new_color_class(0x1).unwrap();
+            let b = g.extend_color_class(a, 0x2).unwrap();
+            let c = g.extend_color_class(b, 0x4).unwrap();
+            ct.map().unwrap().color_class(&c).collect::<Vec<_>>()
+        })
+        .unwrap();
+    assert_eq!(classes, [(0x4, 0), (0x2, 0), (0x1, 0)]);
+    ct.sync(None).unwrap();
+
+    // a generations file synced before the last generation ended
+    let generations = dir.path().join("generations");
+    let stale = std::fs::read(&generations).unwrap();
+    ct.with_generation(1, |g| g.new_color_class(0x1))
+        .unwrap()
+        .unwrap();
+    drop(ct);
+    std::fs::write(&generations, stale).unwrap();
+
+    // new generations would leave the fragments uncovered for good, so the table must be repaired
+    assert!(matches!(
+        ColorTable::load(&dir, config.clone()),
+        Err(ColorTableError::UncoveredFragments { start: 4, end: 5 })
+    ));
+    assert!(matches!(
+        ColorTable::load_or_new(&dir, config.clone()),
+        Err(ColorTableError::UncoveredFragments { start: 4, end: 5 })
+    ));
+
+    let (ct, report) = ColorTable::load_repaired(&dir, config).unwrap();
+    assert_eq!(report.fragments, 1);
+    assert_eq!(ct.map().unwrap().color_class(&ColorId::new(3)).len(), 3);
+}
+
+#[test]
+fn load_repaired_generations() {
+    // a generations file in the flat encoding, with the given state and ranges
+    fn flat_generations(state: u32, start: u32, last: u64, ranges: &[(u32, u32, u64)]) -> Vec<u8> {
+        let mut bytes = b"CTGN".to_vec();
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(state.to_le_bytes());
+        bytes.extend(start.to_le_bytes());
+        bytes.extend(last.to_le_bytes());
+        for &(start, end, generation) in ranges {
+            bytes.extend(start.to_le_bytes());
+            bytes.extend(end.to_le_bytes());
+            bytes.extend(generation.to_le_bytes());
+        }
+        bytes
+    }
+
+    let config = ColorTableConfig::default();
+    let table = |generations: u64| {
+        let dir = tempfile::tempdir().unwrap();
+        let ct = ColorTable::new(&dir, config.clone()).unwrap();
+        for generation in 0..generations {
+            ct.with_generation(generation, |g| g.new_color_class(0x1))
+                .unwrap()
+                .unwrap();
+        }
+        drop(ct);
+        dir
+    };
+
+    // generation 1 recorded as in progress after writing its first fragment
+    let dir = table(2);
+    let generations = dir.path().join("generations");
+    std::fs::write(
+        &generations,
+        flat_generations(2, 2, 1, &[(1, 2, 0), (2, 3, 1)]),
+    )
+    .unwrap();
+    assert!(matches!(
+        ColorTable::load(&dir, config.clone()),
+        Err(ColorTableError::UncoveredFragments { start: 2, end: 3 })
+    ));
+    let (ct, report) = ColorTable::load_repaired(&dir, config.clone()).unwrap();
+    assert_eq!(report.in_progress, Some(1));
+    assert_eq!((report.fragments, report.generations), (1, 0));
+    assert_eq!(ct.generation_state(), GenerationStatus::Idle { last: 0 });
+    ct.with_generation(1, |g| g.new_color_class(0x2))
+        .unwrap()
+        .unwrap();
+    drop(ct);
+    assert!(
+        ColorTable::load_repaired(&dir, config.clone())
+            .unwrap()
+            .1
+            .is_clean()
+    );
+
+    // generation 2 started after fragment 2, which doesn't belong to any generation
+    let dir = table(3);
+    std::fs::write(
+        dir.path().join("generations"),
+        flat_generations(1, 0, 2, &[(1, 2, 0), (3, 4, 2)]),
+    )
+    .unwrap();
+    assert!(matches!(
+        ColorTable::load(&dir, config.clone()),
+        Err(ColorTableError::UncoveredFragments { start: 2, end: 3 })
+    ));
+    let (ct, report) = ColorTable::load_repaired(&dir, config.clone()).unwrap();
+    assert_eq!(report.in_progress, None);
+    assert_eq!((report.fragments, report.generations), (2, 1));
+    assert_eq!(ct.fragment_count(), 1);
+    assert_eq!(ct.generation_state(), GenerationStatus::Idle { last: 0 });
+    assert!(ct.verify(VerifyLevel::Full).unwrap().is_ok());
+    ct.with_generation(1, |g| g.new_color_class(0x2))
+        .unwrap()
+        .unwrap();
+}
+
+#[test]
+fn sync_during_generation() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    ct.with_generation(0, |g| g.new_color_class(0x1))
+        .unwrap()
+        .unwrap();
+
+    // copy the files synced while the generation is in progress, as if the process was killed
+    let crashed = tempfile::tempdir().unwrap();
+    ct.with_generation(1, |g| {
+        g.new_color_class(0x2).unwrap();
+        ct.sync(None).unwrap();
+        for name in ["color_table", "generations"] {
+            std::fs::copy(dir.path().join(name), crashed.path().join(name)).unwrap();
+        }
+    })
+    .unwrap();
+    drop(ct);
+
+    // only the ended generation was recorded
+    let (ct, report) = ColorTable::load_repaired(&crashed, config).unwrap();
+    assert_eq!(report.fragments, 1);
+    assert_eq!(ct.generation_state(), GenerationStatus::Idle { last: 0 });
+    let id = ct
+        .with_generation(1, |g| g.new_color_class(0x4))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        ct.map().unwrap().color_class(&id).collect::<Vec<_>>(),
+        [(0x4, 1)]
+    );
+}
+
+#[test]
+fn truncated_while_mapped() {
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+
+    // more than a page of fragments, so that truncating the file leaves whole pages unbacked
+    let head = ct
+        .with_generation(0, |g| {
+            let mut id = g.new_color_class(0x1)?;
+            for _ in 0..2048 {
+                id = g.extend_color_class(id, 0x1)?;
+            }
+            Ok::<_, ColorTableError>(id)
+        })
+        .unwrap()
+        .unwrap();
+
+    let ct_map = ct.map().unwrap();
+    assert_eq!(
+        ct_map
+            .checked(|ct_map| ct_map.color_class(&head).len())
+            .unwrap(),
+        2049
+    );
+    drop(ct_map);
+
+    #[cfg(all(target_os = "linux", feature = "fault-guard"))]
+    {
+        let ct_map = ct.map().unwrap();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("color_table"))
+            .unwrap();
+        file.set_len(8).unwrap();
+
+        // the query fails instead of killing the process, and keeps failing
+        for _ in 0..2 {
+            assert!(matches!(
+                ct_map.checked(|ct_map| ct_map.color_class(&head).count()),
+                Err(ColorTableError::MappingFaulted)
+            ));
+        }
+    }
+}
+
+#[test]
+fn preallocated_fragments() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .preallocate_fragments(1 << 16)
+        .build();
+
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let id = ct
+        .with_generation(0, |g| g.new_color_class(0x1).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+
+    // the allocated space is not part of the file
+    assert_eq!(ct.file_len().unwrap(), 16);
+    assert_eq!(
+        std::fs::metadata(dir.path().join("color_table"))
+            .unwrap()
+            .len(),
+        16
+    );
+    #[cfg(target_os = "linux")]
+    assert!(ct.allocated_len().unwrap().unwrap() >= 8 << 16);
+    drop(ct);
+
+    let ct = ColorTable::load(&dir, config).unwrap();
+    assert_eq!(ct.map().unwrap().color_class(&id).len(), 1);
+    assert_eq!(ct.file_len().unwrap(), 16);
+    assert_eq!(
+        ColorTable::in_memory(ColorTableConfig::default())
+            .allocated_len()
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn direct_io_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder()
+        .direct_io(true)
+        .buffer_size(4096usize)
+        .build();
+
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let mut ids = Vec::new();
+    for g in 0..1500 {
+        ct.with_generation(g, |ct| ids.push(ct.new_color_class(g as u32 + 1).unwrap()))
+            .unwrap();
+        // flushing writes the partial block at the end, which is written again once it is full
+        if g % 100 == 0 {
+            let ct_map = ct.map().unwrap();
+            assert_eq!(
+                ct_map.color_class(&ids[g as usize]).collect::<Vec<_>>(),
+                [(g as u32 + 1, g)]
+            );
+        }
+    }
+    assert_eq!(ct.file_len().unwrap(), 1501 * 8);
+    ct.sync(None).unwrap();
+    drop(ct);
+
+    let ct = ColorTable::load(&dir, config).unwrap();
+    let ct_map = ct.map().unwrap();
+    for (g, id) in ids.iter().enumerate() {
+        assert_eq!(
+            ct_map.color_class(id).collect::<Vec<_>>(),
+            [(g as u32 + 1, g as u64)]
+        );
+    }
+}
+
+#[test]
+fn seal() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    let ct = ColorTable::new(&dir, config.clone()).unwrap();
+    let a = ct
+        .with_generation(0, |g| g.new_color_class(0x1).unwrap())
+        .unwrap();
+    let b = ct
+        .with_generation(1, |g| g.extend_color_class(a, 0x2).unwrap())
+        .unwrap();
+
+    let sealed = ct.seal().unwrap();
+    assert_eq!(sealed.seal_info().fragment_count, 2);
+    assert_eq!(sealed.seal_info().head, 3);
+    assert_eq!(sealed.seal_info().last_generation, Some(1));
+    sealed.verify_seal().unwrap();
+    // sealed tables can't be modified
+    assert!(matches!(
+        sealed
+            .color_table()
+            .with_generation(2, |g| g.new_color_class(0x1)),
+        Err(ColorTableError::Sealed)
+    ));
+    assert!(matches!(
+        sealed.color_table().tombstone(a),
+        Err(ColorTableError::Sealed)
+    ));
+    let info = *sealed.seal_info();
+    drop(sealed);
+
+    // sealed tables are loaded read-only, without locking
+    assert!(matches!(
+        ColorTable::load(&dir, config.clone()),
+        Err(ColorTableError::Sealed)
+    ));
+    let first = SealedColorTable::load(&dir, config.clone()).unwrap();
+    let second = SealedColorTable::load(&dir, config.clone()).unwrap();
+    assert_eq!(*second.seal_info(), info);
+    second.verify_seal().unwrap();
+    assert_eq!(
+        first.map().unwrap().color_class(&b).collect::<Vec<_>>(),
+        [(0x2, 1), (0x1, 0)]
+    );
+    drop((first, second));
+
+    // a truncated or modified table doesn't match its seal
+    let path = dir.path().join("color_table");
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[12] ^= 0x4;
+    std::fs::write(&path, &bytes).unwrap();
+    let sealed = SealedColorTable::load(&dir, config.clone()).unwrap();
+    assert!(matches!(
+        sealed.verify_seal(),
+        Err(ColorTableError::SealMismatch)
+    ));
+    drop(sealed);
+    std::fs::write(&path, &bytes[..16]).unwrap();
+    assert!(matches!(
+        SealedColorTable::load(&dir, config.clone()),
+        Err(ColorTableError::SealMismatch)
+    ));
+
+    let unsealed = tempfile::tempdir().unwrap();
+    ColorTable::new(&unsealed, config.clone()).unwrap();
+    assert!(matches!(
+        SealedColorTable::load(&unsealed, config),
+        Err(ColorTableError::NotSealed)
+    ));
+}
+
+#[test]
+fn shard() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::default();
+    std::fs::create_dir(dir.path().join("table")).unwrap();
+    let ct = ColorTable::new(dir.path().join("table"), config.clone()).unwrap();
+
+    // a shared ancestor, forked into classes in both halves
+    let (a, b) = ct
+        .with_generation(0, |g| {
+            let a = g.new_color_class(0x1).unwrap();
+            (a, g.new_color_class(0x2).unwrap())
+        })
+        .unwrap();
+    let (c, d, e) = ct
+        .with_generation(1, |g| {
+            let c = g.fork_color_class(a, 0x4).unwrap();
+            let d = g.fork_color_class(a, 0x8).unwrap();
+            g.set_payload(d, 7).unwrap();
+            (c, d, g.extend_color_class(b, 0x10).unwrap())
+        })
+        .unwrap();
+    let deleted = ct
+        .with_generation(2, |g| g.new_color_class(0x20).unwrap())
+        .unwrap();
+    ct.tombstone(deleted).unwrap();
+
+    assert!(matches!(
+        ct.shard(dir.path().join("shards"), 0),
+        Err(ColorTableError::InvalidShardCount(0))
+    ));
+    let dirs = ct.shard(dir.path().join("shards"), 2).unwrap();
+    assert_eq!(dirs.len(), 2);
+
+    let sharded = ShardedColorTable::load(&dirs, config).unwrap();
+    let ct_map = ct.map().unwrap();
+    let shards_map = sharded.map().unwrap();
+    for id in [a, b, c, d, e] {
+        assert_eq!(
+            shards_map.color_class(&id).unwrap().collect::<Vec<_>>(),
+            ct_map.color_class(&id).collect::<Vec<_>>()
+        );
+    }
+    // the heads (c, d and e) are split between the shards, and the shared ancestor is in both
+    assert_eq!(sharded.route(&c).unwrap().0, 0);
+    assert_eq!(sharded.route(&d).unwrap().0, 1);
+    assert_eq!(sharded.route(&e).unwrap().0, 1);
+    assert_eq!(sharded.shards()[0].fragment_count(), 2);
+    assert_eq!(sharded.shards()[1].fragment_count(), 4);
+    let (shard, id) = sharded.route(&d).unwrap();
+    assert_eq!(shards_map.shards()[shard].payload_of(&id.into()), Some(7));
+    assert!(sharded.route(&deleted).is_none());
+    assert!(shards_map.color_class(&deleted).is_none());
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn multi_table() {
+    use color_table::MultiTable;
+
+    let cohort = |generation, colors: &[u32]| {
+        let ct = ColorTable::in_memory(ColorTableConfig::default());
+        let ids = ct
+            .with_generation(generation, |g| {
+                colors
+                    .iter()
+                    .map(|&color| g.new_color_class(color).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap();
+        (ct, ids)
+    };
+
+    // the second table holds two generations, so it needs 64 samples
+    let (a, _) = cohort(0, &[0b1]);
+    let (b, _) = cohort(1, &[0b1]);
+    assert!(matches!(
+        MultiTable::new([(a, 0), (b, u32::MAX - 32)]),
+        Err(ColorTableError::OverlappingSampleSpaces { table: 1 })
+    ));
+    let (a, _) = cohort(0, &[0b1]);
+    let (b, _) = cohort(0, &[0b1]);
+    assert!(matches!(
+        MultiTable::new([(a, 100), (b, 120)]),
+        Err(ColorTableError::OverlappingSampleSpaces { table: 0 })
+    ));
+
+    let (a, a_ids) = cohort(0, &[0b0111, 0b0110]);
+    let (b, b_ids) = cohort(1, &[0b0011, 0b0110]);
+    let multi = MultiTable::new([(a, 10), (b, 42)]).unwrap();
+    assert_eq!(multi.locate(9), None);
+    assert_eq!(multi.locate(41), Some((0, 31)));
+    assert_eq!(multi.locate(42 + 33), Some((1, 33)));
+
+    let map = multi.map().unwrap();
+    let null = ColorId::new(0);
+    assert_eq!(
+        map.color_class(&[a_ids[0], b_ids[0]])
+            .iter()
+            .collect::<Vec<_>>(),
+        [10, 11, 12, 42 + 32, 42 + 33]
+    );
+    assert_eq!(
+        map.color_class(&[null, b_ids[1]])
+            .iter()
+            .collect::<Vec<_>>(),
+        [42 + 33, 42 + 34]
+    );
+    assert_eq!(
+        map.intersect_many(&[[a_ids[0], b_ids[0]], [a_ids[1], b_ids[1]]])
+            .iter()
+            .collect::<Vec<_>>(),
+        [11, 12, 42 + 33]
+    );
+    assert!(
+        map.intersect_many(&[[a_ids[0], null], [a_ids[1], b_ids[1]]])
+            .iter()
+            .eq([11, 12])
+    );
+    assert!(map.intersect_many(&[] as &[[ColorId; 2]]).is_empty());
+}
+
+#[test]
+fn running_counts() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ColorTableConfig::builder().running_counts(true).build();
+    let ct = ColorTable::new(&dir, config).unwrap();
+    let mut rng = fastrand::Rng::with_seed(37);
+    let mut ids = Vec::new();
+    for g in 0..30 {
+        ct.with_generation(g, |ct| {
+            for _ in 0..10 {
+                let color = rng.u32(..) & rng.u32(..);
+                let id = match rng.usize(..4) {
+                    _ if ids.is_empty() => ct.new_color_class(color),
+                    0 => ct.new_color_class(color),
+                    1 => ct.fork_color_class(ids[rng.usize(..ids.len())], color),
+                    2 if g > 0 => ct.extend_color_class_remove(
+                        ids[rng.usize(..ids.len())],
+                        rng.u64(..g),
+                        rng.u32(..) & rng.u32(..),
+                    ),
+                    _ => ct.extend_color_class(ids[rng.usize(..ids.len())], color),
+                };
+                ids.push(id.unwrap());
+            }
+        })
+        .unwrap();
+    }
+
+    let check = |ct: &ColorTable, ids: &[ColorId]| {
+        let ct_map = ct.map().unwrap();
+        for id in ids {
+            let indices = ct_map
+                .color_class(id)
+                .into_indices()
+                .into_iter()
+                .collect::<std::collections::BTreeSet<_>>();
+            assert_eq!(ct_map.cardinality(id), indices.len() as u64);
+        }
+    };
+    check(&ct, &ids);
+    #[cfg(feature = "roaring")]
+    {
+        // classes are intersected smallest first
+        let ct_map = ct.map().unwrap();
+        for n in 2..6 {
+            let queries = (0..n)
+                .map(|_| ids[rng.usize(..ids.len())])
+                .collect::<Vec<_>>();
+            let expected = queries
+                .iter()
+                .map(|id| ct_map.color_class(id).into_bitmap())
+                .reduce(|a, b| a & b)
+                .unwrap();
+            assert_eq!(ct_map.intersect_many(&queries), expected);
+        }
+    }
+    ct.tombstone(ids[3]).unwrap();
+    assert_eq!(ct.map().unwrap().cardinality(&ids[3]), 0);
+
+    // the flag is stored in the table, so it is kept whatever the config it is loaded with
+    ct.sync(None).unwrap();
+    let counts_len = || {
+        std::fs::metadata(dir.path().join("running_counts"))
+            .unwrap()
+            .len()
+    };
+    let synced = u64::from(ct.fragment_count() + 1) * 4;
+    assert_eq!(counts_len(), synced);
+    drop(ct);
+    let mut ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    check(&ct, &ids);
+
+    // only the counts of new generations are appended
+    let id = ct
+        .with_generation(30, |ct| ct.extend_color_class(ids[0], 0b1).unwrap())
+        .unwrap();
+    ids.push(id);
+    ct.sync(None).unwrap();
+    assert_eq!(counts_len(), synced + 4);
+
+    let live = ids.iter().copied().step_by(7).collect::<Vec<_>>();
+    let report = ct.compact(live.iter().copied()).unwrap();
+    let live = live
+        .iter()
+        .filter_map(|id| report.mapping.get(id))
+        .collect::<Vec<_>>();
+    check(&ct, &live);
+
+    // the counts are rewritten after compaction
+    ct.sync(None).unwrap();
+    assert_eq!(counts_len(), u64::from(ct.fragment_count() + 1) * 4);
+    drop(ct);
+    let ct = ColorTable::load(&dir, ColorTableConfig::default()).unwrap();
+    check(&ct, &live);
+
+    // fragments of a generation in progress are counted from the mapping
+    let extended = ct
+        .with_generation(40, |g| {
+            let id = g.extend_color_class(live[0], 0xff).unwrap();
+            let ct_map = ct.map().unwrap();
+            assert_eq!(ct_map.cardinality(&id), ct_map.cardinality(&live[0]) + 8);
+            id
+        })
+        .unwrap();
+    check(&ct, &[extended]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let ct = ColorTable::new(&dir, ColorTableConfig::default()).unwrap();
+    ct.with_generation(0, |ct| ct.new_color_class(0b11).unwrap())
+        .unwrap();
+    ct.sync(None).unwrap();
+    assert!(!dir.path().join("running_counts").exists());
+}